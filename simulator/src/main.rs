@@ -0,0 +1,404 @@
+//! The vending machine on the development machine.
+//!
+//! Runs the firmware's logic from vending-core against an in-memory screen,
+//! a fake BME680 and, if one is given, an MQTT broker, following a script of
+//! touches and sensor changes on simulated time. Every frame that differs
+//! from the one before is written to the frames directory as a PPM image,
+//! so flows can be checked without a device, in CI too.
+//!
+//! ```text
+//! cargo run -- --script scripts/demo.txt --frames frames --broker localhost:1883
+//! ```
+
+mod mqtt;
+mod script;
+mod sensor;
+
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use embedded_graphics::prelude::Size;
+use heapless::String as HString;
+use vending_core::{
+    app::{self, App, Update, PRODUCTS},
+    catalog::Catalog,
+    filter::{Pipeline, ReadingFilters},
+    nav::Gesture,
+    pages::Router,
+    publish::{Message, Publisher, QoS},
+    sensor::{check_plausible, SensorFault},
+    snapshot::Snapshot,
+    telemetry::TelemetryFormat,
+    vending::VendingMachine,
+};
+
+use mqtt::{LogPublisher, MqttPublisher};
+use script::Step;
+use sensor::FakeBme680;
+
+const CLIENT_ID: &str = "espbox-simulator";
+const USAGE: &str = "\
+usage: vending-simulator --script <file> [--frames <dir>] [--broker <host:port>]
+                         [--telemetry per-topic|json|cbor] [--sample-interval <seconds>]";
+
+struct Options {
+    script: PathBuf,
+    frames: PathBuf,
+    broker: Option<String>,
+    telemetry_format: TelemetryFormat,
+    sample_interval_ms: u64,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut script = None;
+        let mut options = Self {
+            script: PathBuf::new(),
+            frames: PathBuf::from("frames"),
+            broker: std::env::var("MQTT_BROKER").ok().filter(|broker| !broker.is_empty()),
+            telemetry_format: TelemetryFormat::PerTopic,
+            sample_interval_ms: 60_000,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--script" => script = Some(PathBuf::from(value()?)),
+                "--frames" => options.frames = PathBuf::from(value()?),
+                "--broker" => options.broker = Some(value()?),
+                "--telemetry" => {
+                    let name = value()?;
+                    options.telemetry_format =
+                        TelemetryFormat::from_name(&name).ok_or(format!("unknown telemetry format {}", name))?;
+                }
+                "--sample-interval" => {
+                    let seconds: u64 = value()?.parse().map_err(|_| "invalid sample interval")?;
+                    options.sample_interval_ms = seconds.max(1) * 1000;
+                }
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+
+        options.script = script.ok_or("missing --script")?;
+        Ok(options)
+    }
+}
+
+/// Where published messages go.
+enum Output {
+    /// Reconnected before the next publish after the connection was lost.
+    Broker { address: String, session: Option<MqttPublisher> },
+    Log(LogPublisher),
+}
+
+/// Topic and payload of an answer to a command.
+type Ack = (HString<64>, HString<96>);
+
+impl Output {
+    /// Publishes the answers to commands and then the queued events.
+    fn publish(&mut self, app: &RefCell<App>, acks: &mut Vec<Ack>) {
+        match self {
+            Output::Broker { address, session } => {
+                if session.is_none() {
+                    match MqttPublisher::connect(address, CLIENT_ID) {
+                        Ok(connected) => *session = Some(connected),
+                        Err(e) => return println!("Connecting to {} failed: {}", address, e),
+                    }
+                }
+                if let Some(publisher) = session {
+                    if !publish_acks(publisher, acks) || embassy_futures::block_on(app::drain(app, publisher)).connection_lost {
+                        println!("Connection to {} lost, {} events stay queued", address, app.borrow().queue.len());
+                        *session = None;
+                    }
+                }
+            }
+            Output::Log(publisher) => {
+                publish_acks(publisher, acks);
+                embassy_futures::block_on(app::drain(app, publisher));
+            }
+        }
+    }
+}
+
+/// Returns `false` if the connection was lost, in which case the answers
+/// that weren't published stay in `acks`.
+fn publish_acks<P: Publisher>(publisher: &mut P, acks: &mut Vec<Ack>) -> bool
+where
+    P::Error: core::fmt::Debug,
+{
+    while let Some((topic, payload)) = acks.first() {
+        let ack = Message::new(topic, payload.as_bytes(), QoS::AtLeastOnce, false);
+        if let Err(e) = embassy_futures::block_on(publisher.publish(&ack)) {
+            println!("Publishing to {} failed: {:?}", topic, e);
+            if P::is_connection_lost(&e) {
+                return false;
+            }
+        }
+        acks.remove(0);
+    }
+    true
+}
+
+/// The app as the firmware boots with the defaults of `cfg.toml`.
+fn boot(telemetry_format: TelemetryFormat) -> App {
+    let pipeline = |spec| Pipeline::parse(spec).unwrap_or_default();
+    let mut app = App::new();
+    app.machine = VendingMachine::with_catalog(Catalog::from_items(&PRODUCTS).expect("PRODUCTS exceed CATALOG_CAPACITY"));
+    app.filters = ReadingFilters {
+        temperature: pipeline("median:3"),
+        humidity: pipeline("median:3"),
+        pressure: pipeline("median:3"),
+        gas_resistance: pipeline("median:3,ewma:0.5"),
+    };
+    app.telemetry_format = telemetry_format;
+    app.log = |args| println!("{}", args);
+    let config = app.config();
+    app.sensor_started(config, 0);
+    app
+}
+
+struct Simulation {
+    app: RefCell<App>,
+    sensor: FakeBme680,
+    /// Milliseconds between readings.
+    sample_interval_ms: u64,
+    next_sample_ms: u64,
+    /// Answers to commands, published right away instead of queued.
+    acks: Vec<Ack>,
+    router: Router<Snapshot>,
+    output: Output,
+    frames: PathBuf,
+    now_ms: u64,
+    last_frame: Option<Snapshot>,
+    frames_written: usize,
+}
+
+impl Simulation {
+    fn new(options: &Options, output: Output) -> std::io::Result<Self> {
+        fs::create_dir_all(&options.frames)?;
+        let mut simulation = Self {
+            app: RefCell::new(boot(options.telemetry_format)),
+            sensor: FakeBme680::new(),
+            sample_interval_ms: options.sample_interval_ms,
+            next_sample_ms: 0,
+            acks: Vec::new(),
+            router: Router::new(Snapshot::new(Size::new(320, 240))),
+            output,
+            frames: options.frames.clone(),
+            now_ms: 0,
+            last_frame: None,
+            frames_written: 0,
+        };
+        simulation.router.start(&simulation.app, 0).unwrap();
+        // the first reading is taken at boot
+        simulation.advance(0)?;
+        Ok(simulation)
+    }
+
+    fn run(&mut self, step: &Step) -> std::io::Result<()> {
+        let now = self.now_ms;
+        match step {
+            Step::Wait(ms) => return self.advance(now + ms),
+            Step::Tap(point) => {
+                if let Some(id) = self.router.gesture(&self.app, Gesture::Tap(*point), now).unwrap() {
+                    if self.app.borrow_mut().purchase(id, now).is_ok() {
+                        self.router.update(&self.app, Update::Inventory, now).unwrap();
+                    }
+                }
+            }
+            Step::Swipe(swipe) => {
+                self.router.gesture(&self.app, *swipe, now).unwrap();
+            }
+            Step::Button => self.router.button(&self.app, now).unwrap(),
+            Step::Sensor(channel, value) => self.sensor.set(*channel, *value),
+            Step::Fault(fault) => self.sensor.fail(*fault),
+            Step::Recover => self.sensor.recover(),
+            Step::Command { topic, payload } => {
+                let handled = {
+                    let mut app = self.app.borrow_mut();
+                    let handled = app.command(topic, payload.as_bytes(), now);
+                    if handled.config_requested {
+                        // the sensor takes new settings right away here
+                        let config = app.requested_config();
+                        app.apply_config(config, now);
+                    }
+                    handled
+                };
+                self.acks.extend(handled.ack);
+                for update in handled.updates {
+                    self.router.update(&self.app, update, now).unwrap();
+                }
+            }
+            Step::Frame(name) => return self.write_frame(&self.frames.join(format!("{}.ppm", name))),
+        }
+        self.settle()
+    }
+
+    /// Lets time pass until `until_ms`, measuring whenever it's due.
+    fn advance(&mut self, until_ms: u64) -> std::io::Result<()> {
+        while self.next_sample_ms <= until_ms {
+            self.now_ms = self.next_sample_ms;
+            for update in self.sample() {
+                self.router.update(&self.app, update, self.now_ms).unwrap();
+            }
+            self.settle()?;
+        }
+        self.now_ms = until_ms;
+        Ok(())
+    }
+
+    /// Measures once, as `sensor_task` does on every tick.
+    fn sample(&mut self) -> Vec<Update> {
+        let now = self.now_ms;
+        let mut app = self.app.borrow_mut();
+        match self.sensor.measure(now).and_then(|raw| check_plausible(&raw).map(|()| raw)) {
+            Ok(raw) => {
+                // the firmware initialises the sensor again after a bus fault
+                if !matches!(app.health().current_fault(), None | Some(SensorFault::Implausible | SensorFault::GasInvalid)) {
+                    let config = app.config();
+                    app.sensor_started(config, now);
+                }
+                self.next_sample_ms = now + self.sample_interval_ms;
+                app.record_reading(&raw, now).into_iter().collect()
+            }
+            Err(fault) => {
+                let retry_in = app.record_fault(fault, now);
+                self.next_sample_ms = now + retry_in.as_millis() as u64;
+                vec![Update::SensorFault(fault)]
+            }
+        }
+    }
+
+    /// Publishes what was queued and writes the screen if it changed.
+    fn settle(&mut self) -> std::io::Result<()> {
+        self.output.publish(&self.app, &mut self.acks);
+        self.router.flush().unwrap();
+
+        if self.last_frame.as_ref() != Some(self.router.screen()) {
+            self.frames_written += 1;
+            let path = self.frames.join(format!("{:04}.ppm", self.frames_written));
+            self.write_frame(&path)?;
+            self.last_frame = Some(self.router.screen().clone());
+        }
+        Ok(())
+    }
+
+    fn write_frame(&self, path: &Path) -> std::io::Result<()> {
+        println!("[{:>8} ms] {:?} -> {}", self.now_ms, self.router.current(), path.display());
+        fs::write(path, self.router.screen().to_ppm())
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let steps = match fs::read_to_string(&options.script).map_err(|e| e.to_string()).and_then(|script| {
+        script::parse(&script).map_err(|e| e.to_string())
+    }) {
+        Ok(steps) => steps,
+        Err(e) => {
+            eprintln!("{}: {}", options.script.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let output = match options.broker.clone() {
+        Some(address) => Output::Broker { address, session: None },
+        None => Output::Log(LogPublisher::default()),
+    };
+    let result = Simulation::new(&options, output).and_then(|mut simulation| {
+        for step in &steps {
+            simulation.run(step)?;
+        }
+        Ok(simulation)
+    });
+
+    match result {
+        Ok(simulation) => {
+            println!(
+                "Simulated {} s, wrote {} frames to {}, {} events left queued",
+                simulation.now_ms / 1000,
+                simulation.frames_written,
+                options.frames.display(),
+                simulation.app.borrow().queue.len()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Writing frames to {} failed: {}", options.frames.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vending_core::alarm::Level;
+
+    fn simulate(script: &str) -> (Simulation, PathBuf) {
+        let frames = std::env::temp_dir().join(format!("vending-simulator-{}-{}", std::process::id(), script.len()));
+        let options = Options {
+            script: PathBuf::new(),
+            frames: frames.clone(),
+            broker: None,
+            telemetry_format: TelemetryFormat::Json,
+            sample_interval_ms: 60_000,
+        };
+        let mut simulation = Simulation::new(&options, Output::Log(LogPublisher::default())).unwrap();
+        for step in script::parse(script).unwrap() {
+            simulation.run(&step).unwrap();
+        }
+        (simulation, frames)
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let args = ["--script", "a.txt", "--telemetry", "cbor", "--sample-interval", "5"].map(String::from);
+        let options = Options::parse(args.into_iter()).unwrap();
+
+        assert_eq!(options.script, PathBuf::from("a.txt"));
+        assert_eq!(options.telemetry_format, TelemetryFormat::Cbor);
+        assert_eq!(options.sample_interval_ms, 5_000);
+        assert!(Options::parse(["--frames", "x"].map(String::from).into_iter()).is_err());
+    }
+
+    #[test]
+    fn demo_script_runs() {
+        let script = include_str!("../scripts/demo.txt");
+        let (simulation, frames) = simulate(script);
+
+        assert!(frames.join("inventory.ppm").exists());
+        assert!(frames.join("alarm.ppm").exists());
+        assert!(simulation.frames_written > 5);
+        // two bought, and the sandwiches restocked
+        let amounts: Vec<u32> = simulation.app.borrow().catalog().items().iter().map(|item| item.amount).collect();
+        assert_eq!(amounts, [9, 20, 10]);
+        // everything was published
+        assert!(simulation.app.borrow().queue.is_empty());
+        assert!(simulation.acks.is_empty());
+        fs::remove_dir_all(frames).ok();
+    }
+
+    #[test]
+    fn hot_room_raises_the_alarm() {
+        let (simulation, frames) = simulate(
+            "command espbox/alarm/temperature/set {\"high\": 30}\n\
+             sensor temperature 35\n\
+             wait 5m\n",
+        );
+
+        assert_eq!(simulation.app.borrow().alarms.active().collect::<Vec<_>>(), [(vending_core::alarm::Metric::Temperature, Level::High)]);
+        fs::remove_dir_all(frames).ok();
+    }
+}
@@ -0,0 +1,202 @@
+//! Publishing to a broker on the development machine.
+//!
+//! [`MqttPublisher`] speaks just enough MQTT 3.1.1 to connect and publish,
+//! which any local broker like mosquitto accepts. Without a broker the
+//! messages go to [`LogPublisher`] instead.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use vending_core::publish::{Message, Publisher, QoS};
+
+const KEEP_ALIVE_S: u16 = 60;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+
+/// A clean session on a broker, closed when dropped.
+pub struct MqttPublisher {
+    stream: TcpStream,
+    next_packet_id: u16,
+}
+
+impl MqttPublisher {
+    /// Connects to the broker at `address`, e.g. `localhost:1883`.
+    pub fn connect(address: &str, client_id: &str) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        stream.write_all(&connect_packet(client_id))?;
+
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        match connack {
+            [CONNACK, 2, _, 0] => Ok(Self { stream, next_packet_id: 1 }),
+            [CONNACK, 2, _, code] => Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("broker refused with {}", code))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "expected CONNACK")),
+        }
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let id = self.next_packet_id;
+        // 0 isn't a valid packet identifier
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        id
+    }
+}
+
+impl Publisher for MqttPublisher {
+    type Error = io::Error;
+
+    /// Exactly-once messages are sent at least once, there's no one to
+    /// deduplicate them for here.
+    async fn publish(&mut self, message: &Message<'_>) -> Result<(), io::Error> {
+        let packet_id = match message.qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce | QoS::ExactlyOnce => Some(self.packet_id()),
+        };
+        self.stream.write_all(&publish_packet(message, packet_id))?;
+
+        if let Some(id) = packet_id {
+            let mut puback = [0; 4];
+            self.stream.read_exact(&mut puback)?;
+            if puback != [PUBACK, 2, (id >> 8) as u8, id as u8] {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "expected PUBACK"));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_connection_lost(_error: &io::Error) -> bool {
+        // the session can't tell a refused message from a broken one
+        true
+    }
+
+    fn is_refused(_error: &io::Error) -> bool {
+        false
+    }
+}
+
+/// Prints every message instead of publishing it.
+#[derive(Debug, Default)]
+pub struct LogPublisher {
+    pub published: usize,
+}
+
+impl Publisher for LogPublisher {
+    type Error = core::convert::Infallible;
+
+    async fn publish(&mut self, message: &Message<'_>) -> Result<(), Self::Error> {
+        match core::str::from_utf8(message.payload) {
+            Ok(text) => println!("publish {} {}", message.topic, text),
+            // a CBOR document
+            Err(_) => println!("publish {} <{} bytes>", message.topic, message.payload.len()),
+        }
+        self.published += 1;
+        Ok(())
+    }
+
+    fn is_connection_lost(error: &Self::Error) -> bool {
+        match *error {}
+    }
+
+    fn is_refused(error: &Self::Error) -> bool {
+        match *error {}
+    }
+}
+
+fn connect_packet(client_id: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT");
+    // protocol level 4 is 3.1.1, with a clean session
+    body.extend_from_slice(&[4, 0x02]);
+    body.extend_from_slice(&KEEP_ALIVE_S.to_be_bytes());
+    put_str(&mut body, client_id);
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(message: &Message<'_>, packet_id: Option<u16>) -> Vec<u8> {
+    let qos = if packet_id.is_some() { 1 } else { 0 };
+    let flags = qos << 1 | message.retain as u8;
+
+    let mut body = Vec::new();
+    put_str(&mut body, message.topic);
+    if let Some(id) = packet_id {
+        body.extend_from_slice(&id.to_be_bytes());
+    }
+    body.extend_from_slice(message.payload);
+    packet(PUBLISH | flags, &body)
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    // the remaining length, 7 bits at a time
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        packet.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn put_str(buf: &mut Vec<u8>, text: &str) {
+    buf.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    buf.extend_from_slice(text.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    #[test]
+    fn encodes_long_remaining_lengths() {
+        assert_eq!(packet(PUBLISH, &[])[..], [PUBLISH, 0]);
+        assert_eq!(packet(PUBLISH, &[0; 127])[..2], [PUBLISH, 127]);
+        assert_eq!(packet(PUBLISH, &[0; 128])[..3], [PUBLISH, 0x80, 1]);
+        assert_eq!(packet(PUBLISH, &[0; 16_384])[..4], [PUBLISH, 0x80, 0x80, 1]);
+    }
+
+    #[test]
+    fn encodes_publish() {
+        let message = Message::new("a/b", b"42", QoS::AtLeastOnce, true);
+
+        assert_eq!(publish_packet(&message, Some(7)), [0x33, 9, 0, 3, b'a', b'/', b'b', 0, 7, b'4', b'2']);
+    }
+
+    #[test]
+    fn publishes_to_a_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let broker = std::thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut connect = [0; 2 + 10 + 2 + 8];
+            client.read_exact(&mut connect).unwrap();
+            client.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+            let mut publish = [0; 2 + 2 + 3 + 2 + 2];
+            client.read_exact(&mut publish).unwrap();
+            client.write_all(&[PUBACK, 2, 0, 1]).unwrap();
+            (connect, publish)
+        });
+
+        let mut publisher = MqttPublisher::connect(&address, "espbox-1").unwrap();
+        let message = Message::new("a/b", b"42", QoS::AtLeastOnce, false);
+        embassy_futures::block_on(publisher.publish(&message)).unwrap();
+
+        let (connect, publish) = broker.join().unwrap();
+        assert_eq!(connect[..], connect_packet("espbox-1")[..]);
+        assert_eq!(publish[..], publish_packet(&message, Some(1))[..]);
+    }
+}
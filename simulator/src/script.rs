@@ -0,0 +1,182 @@
+//! The steps a simulation goes through, one per line of a script:
+//!
+//! ```text
+//! # comments and blank lines are skipped
+//! wait 5m                          # let time pass: 250ms, 30s, 5m or 2h
+//! tap 270 40                       # touch and release at a display point
+//! swipe left                       # or right
+//! button                           # the home button
+//! sensor temperature 31.5          # fix a channel of the fake BME680
+//! sensor temperature auto          # let it drift again
+//! fault nack                       # fail measurements: nack, timeout, bus, implausible
+//! recover                          # measure again
+//! command espbox/inventory/Hotdog/set {"amount":20}
+//! frame inventory                  # write the screen as frames/inventory.ppm
+//! ```
+
+use std::fmt;
+
+use embedded_graphics::prelude::Point;
+use vending_core::{nav::Gesture, sensor::SensorFault};
+
+use crate::sensor::Channel;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Milliseconds to let pass.
+    Wait(u64),
+    Tap(Point),
+    Swipe(Gesture),
+    Button,
+    /// A fixed value for the channel, or `None` to let it drift.
+    Sensor(Channel, Option<f32>),
+    Fault(SensorFault),
+    Recover,
+    /// A message as if it was received from the broker.
+    Command { topic: String, payload: String },
+    /// Writes the screen under this name.
+    Frame(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    /// Counted from 1.
+    pub line: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub fn parse(script: &str) -> Result<Vec<Step>, ScriptError> {
+    let mut steps = Vec::new();
+    for (index, line) in script.lines().enumerate() {
+        let line = line.split_once('#').map_or(line, |(step, _)| step).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let step = parse_step(line).map_err(|message| ScriptError { line: index + 1, message })?;
+        steps.push(step);
+    }
+    Ok(steps)
+}
+
+fn parse_step(line: &str) -> Result<Step, &'static str> {
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim();
+    let mut words = args.split_whitespace();
+    let mut word = |what| words.next().ok_or(what);
+
+    let step = match name {
+        "wait" => Step::Wait(parse_duration(word("missing duration")?)?),
+        "tap" => {
+            let x = word("missing x")?.parse().map_err(|_| "invalid x")?;
+            let y = word("missing y")?.parse().map_err(|_| "invalid y")?;
+            Step::Tap(Point::new(x, y))
+        }
+        "swipe" => match word("missing direction")? {
+            "left" => Step::Swipe(Gesture::SwipeLeft),
+            "right" => Step::Swipe(Gesture::SwipeRight),
+            _ => return Err("direction must be left or right"),
+        },
+        "button" => Step::Button,
+        "sensor" => {
+            let channel = Channel::from_name(word("missing channel")?).ok_or("unknown channel")?;
+            let value = match word("missing value")? {
+                "auto" => None,
+                value => Some(value.parse().map_err(|_| "invalid value")?),
+            };
+            Step::Sensor(channel, value)
+        }
+        "fault" => Step::Fault(match word("missing fault")? {
+            "nack" => SensorFault::Nack,
+            "timeout" => SensorFault::Timeout,
+            "bus" => SensorFault::Bus,
+            "implausible" => SensorFault::Implausible,
+            "gas" => SensorFault::GasInvalid,
+            _ => return Err("unknown fault"),
+        }),
+        "recover" => Step::Recover,
+        "command" => {
+            // the payload may contain spaces
+            let (topic, payload) = args.split_once(char::is_whitespace).ok_or("missing payload")?;
+            return Ok(Step::Command { topic: topic.into(), payload: payload.trim().into() });
+        }
+        "frame" => {
+            let name = word("missing name")?;
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err("frame names are letters, digits, - and _");
+            }
+            Step::Frame(name.into())
+        }
+        _ => return Err("unknown step"),
+    };
+
+    if words.next().is_some() {
+        return Err("too many arguments");
+    }
+    Ok(step)
+}
+
+fn parse_duration(text: &str) -> Result<u64, &'static str> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).ok_or("missing unit")?;
+    let value: u64 = text[..split].parse().map_err(|_| "invalid duration")?;
+    let unit_ms = match &text[split..] {
+        "ms" => 1,
+        "s" => 1_000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        _ => return Err("unit must be ms, s, m or h"),
+    };
+    Ok(value * unit_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_step() {
+        let script = "\
+            # restock and buy\n\
+            wait 90s\n\
+            tap 270 40 # the first buy button\n\
+            \n\
+            swipe left\n\
+            button\n\
+            sensor temperature 31.5\n\
+            sensor gas auto\n\
+            fault nack\n\
+            recover\n\
+            command espbox/inventory/Hotdog/set {\"amount\": 20}\n\
+            frame after-restock\n";
+
+        assert_eq!(
+            parse(script).unwrap(),
+            [
+                Step::Wait(90_000),
+                Step::Tap(Point::new(270, 40)),
+                Step::Swipe(Gesture::SwipeLeft),
+                Step::Button,
+                Step::Sensor(Channel::Temperature, Some(31.5)),
+                Step::Sensor(Channel::Gas, None),
+                Step::Fault(SensorFault::Nack),
+                Step::Recover,
+                Step::Command { topic: "espbox/inventory/Hotdog/set".into(), payload: "{\"amount\": 20}".into() },
+                Step::Frame("after-restock".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_an_error() {
+        assert_eq!(parse("wait 1s\n\ntap 10\n"), Err(ScriptError { line: 3, message: "missing y" }));
+        assert_eq!(parse("wait 5 min").unwrap_err().message, "missing unit");
+        assert_eq!(parse("wait 5d").unwrap_err().message, "unit must be ms, s, m or h");
+        assert_eq!(parse("button twice").unwrap_err().message, "too many arguments");
+        assert_eq!(parse("frame ../escape").unwrap_err().message, "frame names are letters, digits, - and _");
+    }
+}
@@ -0,0 +1,128 @@
+//! A BME680 that isn't there.
+//!
+//! Each channel drifts slowly around a typical indoor value, the same way on
+//! every run, unless a script fixes it. Faults are injected until the script
+//! recovers the sensor.
+
+use core::f32::consts::TAU;
+
+use vending_core::sensor::{SensorFault, SensorReading};
+
+/// The channels the BME680 measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Temperature,
+    Humidity,
+    Pressure,
+    Gas,
+}
+
+impl Channel {
+    const ALL: [Channel; 4] = [Channel::Temperature, Channel::Humidity, Channel::Pressure, Channel::Gas];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|channel| channel.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Temperature => "temperature",
+            Channel::Humidity => "humidity",
+            Channel::Pressure => "pressure",
+            Channel::Gas => "gas",
+        }
+    }
+
+    // (middle, amplitude, period in minutes) of the drift
+    fn drift(&self) -> (f32, f32, f32) {
+        match self {
+            Channel::Temperature => (22.5, 1.5, 90.0),
+            Channel::Humidity => (42.0, 6.0, 150.0),
+            Channel::Pressure => (1012.0, 2.5, 600.0),
+            Channel::Gas => (150_000.0, 20_000.0, 45.0),
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FakeBme680 {
+    fixed: [Option<f32>; 4],
+    fault: Option<SensorFault>,
+}
+
+impl FakeBme680 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reports `value` for `channel` from now on, or drifts again for `None`.
+    pub fn set(&mut self, channel: Channel, value: Option<f32>) {
+        self.fixed[channel.index()] = value;
+    }
+
+    /// Fails every measurement with `fault` until [`Self::recover`].
+    pub fn fail(&mut self, fault: SensorFault) {
+        self.fault = Some(fault);
+    }
+
+    pub fn recover(&mut self) {
+        self.fault = None;
+    }
+
+    /// Measures at `now_ms` after boot.
+    pub fn measure(&self, now_ms: u64) -> Result<SensorReading, SensorFault> {
+        if let Some(fault) = self.fault {
+            return Err(fault);
+        }
+
+        let minutes = now_ms as f32 / 60_000.0;
+        let value = |channel: Channel| {
+            self.fixed[channel.index()].unwrap_or_else(|| {
+                let (middle, amplitude, period) = channel.drift();
+                middle + amplitude * (TAU * minutes / period).sin()
+            })
+        };
+        Ok(SensorReading {
+            temperature: value(Channel::Temperature),
+            humidity: value(Channel::Humidity),
+            pressure: value(Channel::Pressure),
+            gas_resistance: value(Channel::Gas),
+            iaq: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drifts_within_plausible_values() {
+        let sensor = FakeBme680::new();
+
+        for minute in 0..600 {
+            let reading = sensor.measure(minute * 60_000).unwrap();
+            assert_eq!(vending_core::sensor::check_plausible(&reading), Ok(()));
+        }
+        assert_ne!(sensor.measure(0), sensor.measure(10 * 60_000));
+    }
+
+    #[test]
+    fn fixed_values_and_faults() {
+        let mut sensor = FakeBme680::new();
+        sensor.set(Channel::Temperature, Some(31.5));
+
+        assert_eq!(sensor.measure(123_456).unwrap().temperature, 31.5);
+
+        sensor.fail(SensorFault::Timeout);
+        assert_eq!(sensor.measure(0), Err(SensorFault::Timeout));
+
+        sensor.recover();
+        sensor.set(Channel::Temperature, None);
+        assert_eq!(sensor.measure(0).unwrap().temperature, 22.5);
+    }
+}
@@ -1,17 +1,178 @@
 use display_interface_spi::SPIInterfaceNoCS;
 use display_interface::DisplayError;
+#[cfg(feature = "framebuffer")]
+use display_interface::{DataFormat, WriteOnlyDataCommand};
 use hal::{
     peripherals::SPI2,
     gpio::{PushPull, Output, GpioPin},
     spi::FullDuplexMode,
-    spi::master::Spi,
 };
+#[cfg(not(feature = "framebuffer"))]
+use hal::spi::master::Spi;
+#[cfg(feature = "framebuffer")]
+use hal::{gdma::Channel0, spi::master::dma::SpiDma};
 
 use mipidsi::models::ILI9342CRgb565;
 use embedded_graphics::{prelude::{DrawTarget, Dimensions}, pixelcolor::Rgb565, Pixel, primitives::Rectangle};
+use vending_core::{redraw::RedrawStats, screen::Screen};
+#[cfg(not(feature = "framebuffer"))]
+use vending_core::redraw::DirtyRegions;
+#[cfg(feature = "framebuffer")]
+use embedded_graphics::{pixelcolor::raw::RawU16, prelude::{OriginDimensions, Size}};
+#[cfg(feature = "framebuffer")]
+use vending_core::framebuffer::{self, DoubleBuffered, Window, WindowTarget};
 
+#[cfg(not(feature = "framebuffer"))]
+pub type Bus<'a> = Spi<'a, SPI2, FullDuplexMode>;
+/// Sends the framebuffer's windows by DMA.
+#[cfg(feature = "framebuffer")]
+pub type Bus<'a> = SpiDma<'a, SPI2, Channel0, FullDuplexMode>;
+
+pub type Ili9342c<'a> = mipidsi::Display<SPIInterfaceNoCS<Bus<'a>, GpioPin<Output<PushPull>, 4>>, ILI9342CRgb565, GpioPin<Output<PushPull>, 48>>;
+
+/// Skips the parts of a redraw the panel already shows; nothing appears of
+/// deferred fills until `flush` is called.
+#[cfg(not(feature = "framebuffer"))]
+pub type Panel<'a> = DirtyRegions<Ili9342c<'a>>;
+/// Draws into a framebuffer in PSRAM; nothing appears until `flush` sends
+/// the rows that changed.
+#[cfg(feature = "framebuffer")]
+pub type Panel<'a> = DoubleBuffered<'static, DmaPanel<'a>>;
+
+/// Bytes the SPI DMA sends in one transfer, with the 8 descriptors main
+/// gives the channel: 51 rows of the panel.
+#[cfg(feature = "framebuffer")]
+const STAGING_BYTES: usize = 51 * 320 * 2;
+
+#[cfg(feature = "framebuffer")]
+const SET_COLUMN_ADDRESS: u8 = 0x2A;
+#[cfg(feature = "framebuffer")]
+const SET_PAGE_ADDRESS: u8 = 0x2B;
+#[cfg(feature = "framebuffer")]
+const WRITE_MEMORY_START: u8 = 0x2C;
+
+/// The panel after mipidsi initialised it, taking the framebuffer's windows
+/// in DMA transfers.
+///
+/// DMA can't read the framebuffers in PSRAM, so each window is copied to a
+/// buffer in internal RAM, big endian as the panel wants it. The address
+/// window is set once, then every full buffer goes out as one transfer.
+#[cfg(feature = "framebuffer")]
+pub struct DmaPanel<'a> {
+    di: SPIInterfaceNoCS<Bus<'a>, GpioPin<Output<PushPull>, 4>>,
+    size: Size,
+    staging: &'static mut [u8; STAGING_BYTES],
+    _reset: Option<GpioPin<Output<PushPull>, 48>>,
+}
+
+#[cfg(feature = "framebuffer")]
+impl<'a> DmaPanel<'a> {
+    pub fn new(display: Ili9342c<'a>, staging: &'static mut [u8; STAGING_BYTES]) -> Self {
+        let size = display.bounding_box().size;
+        // the orientation and color order stay as mipidsi set them
+        let (di, _, reset) = display.release();
+        Self { di, size, staging, _reset: reset }
+    }
+
+    /// Points the panel's memory writes at `area`, which has to be inside
+    /// it.
+    fn set_window(&mut self, area: &Rectangle) -> Result<(), DisplayError> {
+        let Some(bottom_right) = area.bottom_right() else {
+            return Ok(());
+        };
+        let (x0, y0) = (area.top_left.x as u16, area.top_left.y as u16);
+        let (x1, y1) = (bottom_right.x as u16, bottom_right.y as u16);
+
+        self.di.send_commands(DataFormat::U8(&[SET_COLUMN_ADDRESS]))?;
+        self.di.send_data(DataFormat::U8(&[(x0 >> 8) as u8, x0 as u8, (x1 >> 8) as u8, x1 as u8]))?;
+        self.di.send_commands(DataFormat::U8(&[SET_PAGE_ADDRESS]))?;
+        self.di.send_data(DataFormat::U8(&[(y0 >> 8) as u8, y0 as u8, (y1 >> 8) as u8, y1 as u8]))?;
+        self.di.send_commands(DataFormat::U8(&[WRITE_MEMORY_START]))
+    }
+}
+
+#[cfg(feature = "framebuffer")]
+impl OriginDimensions for DmaPanel<'_> {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+/// Only for drawing around the framebuffer, one window per pixel.
+#[cfg(feature = "framebuffer")]
+impl DrawTarget for DmaPanel<'_> {
+    type Color = Rgb565;
+    type Error = DisplayError;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if self.bounding_box().contains(point) {
+                self.set_window(&Rectangle::new(point, Size::new(1, 1)))?;
+                self.di.send_data(DataFormat::U8(&RawU16::from(color).into_inner().to_be_bytes()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "framebuffer")]
+impl WindowTarget for DmaPanel<'_> {
+    fn write_window(&mut self, window: &Window<'_>) -> Result<(), DisplayError> {
+        if window.area().intersection(&self.bounding_box()) != window.area() {
+            return self.fill_contiguous(&window.area(), window.pixels());
+        }
+        self.set_window(&window.area())?;
+
+        let mut len = 0;
+        for row in window.rows() {
+            if len + row.len() * 2 > STAGING_BYTES {
+                self.di.send_data(DataFormat::U8(&self.staging[..len]))?;
+                len = 0;
+            }
+            for (bytes, raw) in self.staging[len..].chunks_exact_mut(2).zip(row) {
+                bytes.copy_from_slice(&raw.to_be_bytes());
+            }
+            len += row.len() * 2;
+        }
+        if len > 0 {
+            self.di.send_data(DataFormat::U8(&self.staging[..len]))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "framebuffer"))]
+pub fn panel(display: Ili9342c<'static>) -> Panel<'static> {
+    DirtyRegions::new(display)
+}
+
+/// Places both framebuffers at the start of PSRAM, which has to be
+/// initialised already.
+#[cfg(feature = "framebuffer")]
+pub fn panel(display: Ili9342c<'static>) -> Panel<'static> {
+    let display = DmaPanel::new(display, static_cell::make_static!([0u8; STAGING_BYTES]));
+    let len = framebuffer::buffer_len(display.bounding_box().size);
+    assert!(2 * len * core::mem::size_of::<u16>() <= hal::psram::PSRAM_BYTES, "framebuffers don't fit in PSRAM");
+
+    // SAFETY: nothing else uses PSRAM, and it stays mapped at this address
+    // for as long as the firmware runs.
+    let (back, front) = unsafe {
+        let start = hal::psram::psram_vaddr_start() as *mut u16;
+        core::ptr::write_bytes(start, 0, 2 * len);
+        (
+            core::slice::from_raw_parts_mut(start, len),
+            core::slice::from_raw_parts_mut(start.add(len), len),
+        )
+    };
+    DoubleBuffered::new(display, back, front).expect("display too tall for the framebuffer")
+}
+
+/// The [`Screen`] the pages draw on.
 pub struct EmbassyTaskDisplay<'a> {
-    pub display: mipidsi::Display<SPIInterfaceNoCS<Spi<'a, SPI2, FullDuplexMode>, GpioPin<Output<PushPull>, 4>>, ILI9342CRgb565, GpioPin<Output<PushPull>, 48>>,
+    pub display: Panel<'a>,
 }
 
 impl DrawTarget for EmbassyTaskDisplay<'static> {
@@ -24,6 +185,17 @@ impl DrawTarget for EmbassyTaskDisplay<'static> {
     {
         self.display.draw_iter(pixels)
     }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        self.display.fill_contiguous(area, colors)
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        self.display.fill_solid(area, color)
+    }
 }
 
 impl Dimensions for EmbassyTaskDisplay<'static> {
@@ -32,6 +204,12 @@ impl Dimensions for EmbassyTaskDisplay<'static> {
     }
 }
 
+impl Screen for EmbassyTaskDisplay<'static> {
+    fn flush(&mut self) -> Result<RedrawStats, Self::Error> {
+        Screen::flush(&mut self.display)
+    }
+}
+
 impl<'a, 'b> DrawTarget for &'a mut EmbassyTaskDisplay<'b> {
     type Color = Rgb565;
     type Error = DisplayError;
@@ -42,6 +220,17 @@ impl<'a, 'b> DrawTarget for &'a mut EmbassyTaskDisplay<'b> {
     {
         self.display.draw_iter(pixels)
     }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        self.display.fill_contiguous(area, colors)
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        self.display.fill_solid(area, color)
+    }
 }
 
 impl<'a, 'b> Dimensions for &'a mut EmbassyTaskDisplay<'b> {
@@ -8,18 +8,35 @@ use critical_section::Mutex;
 
 // display and graphics imports
 use embedded_graphics::{
-    pixelcolor::Rgb565, prelude::*,
+    prelude::*,
 };
 use display_interface_spi::SPIInterfaceNoCS;
+use display_interface::DisplayError;
 mod embassy_task_ili9342c;
 use embassy_task_ili9342c::EmbassyTaskDisplay;
 
-// esp-box UI elements imports
-use esp_box_ui::{
-    sensor_data::{SensorData, SensorType, update_sensor_data},
-    build_sensor_ui,
-    food_item::{ FoodItem, update_field },
-    build_inventory,
+use vending_core::{
+    alarm::{Metric, Threshold, ALARM_SET_TOPIC_FILTER},
+    app::{self, App, Shared, Update as AppUpdate, CATALOG_CAPACITY, PRODUCTS},
+    bme680::{self, Bme680},
+    catalog::{Catalog, ItemId},
+    command::SET_TOPIC_FILTER,
+    connection::{Backoff, ConnectionManager, Failure},
+    filter::{Pipeline, ReadingFilters},
+    inbox::{Inbox, InboxIo},
+    keepalive::KeepAlive,
+    latency::LatencyMonitor,
+    nav::{Gesture, GestureTracker},
+    pages::Router,
+    persist::{self, InventoryStore},
+    publish::{Message, Publisher, QoS},
+    queue::OverflowPolicy,
+    sensor::{self, SensorFault, SensorReading},
+    sensor_config::{SensorConfig, CONFIG_SET_TOPIC},
+    telemetry::TelemetryFormat,
+    touch::TouchTransform,
+    ui::AlarmBanner,
+    vending::VendingMachine,
 };
 
 // peripherals imports
@@ -30,14 +47,20 @@ use hal::{
         master::Spi, 
         SpiMode
     },
-    gpio::{ Event, GpioPin, Input, PullUp },
-    peripherals::{Peripherals, Interrupt, I2C0},
+    gpio::{ self, GpioPin, Input, PullUp },
+    peripherals::{Peripherals, Interrupt, I2C0, I2C1},
     prelude::{_fugit_RateExtU32, *},
     timer::TimerGroup,
-    Rng, IO, Delay,
+    Rng, IO,
     embassy, interrupt,
     rsa::Rsa,
 };
+#[cfg(feature = "framebuffer")]
+use hal::{
+    dma::DmaPriority,
+    gdma::Gdma,
+    spi::master::dma::WithDmaSpi2,
+};
 
 //wifi imports
 use embedded_svc::wifi::{ClientConfiguration, Configuration, Wifi};
@@ -49,12 +72,17 @@ use embassy_executor::Spawner;
 use embassy_net::tcp::TcpSocket;
 use embassy_net::dns::DnsQueryType;
 use embassy_net::{Config, Stack, StackResources};
-use embassy_time::{Duration, Instant, Timer};
+use embassy_time::{Delay, Duration, Instant, Ticker, Timer};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_futures::{
+    poll_once,
+    select::{select, select3, select4, Either, Either3, Either4},
+};
 
 // mqtt imports
 use rust_mqtt::{
     client::{client::MqttClient, client_config::ClientConfig},
-    packet::v5::reason_codes::ReasonCode,
+    packet::v5::{publish_packet::QualityOfService, reason_codes::ReasonCode},
     utils::rng_generator::CountingRng,
 };
 
@@ -62,10 +90,9 @@ use rust_mqtt::{
 use esp_mbedtls::{asynch::Session, set_debug, Mode, TlsVersion};
 use esp_mbedtls::{Certificates, X509};
 
-use bme680::*;
 
-use heapless::String;
-use core::fmt::Write;
+use esp_storage::FlashStorage;
+
 use static_cell::make_static;
 
 use esp_backtrace as _;
@@ -81,13 +108,229 @@ const CLIENT_ID: &'static str = include_str!("../secrets/client_id.txt");
 
 use tt21100_async::TT21100;
 
-static TEMPERATURE_DATA: Mutex<RefCell<SensorData>> = Mutex::new(RefCell::new(SensorData { sensor_type: SensorType::Temperature, pos_x: 35, value: 0.0 }));
-static HUMIDITY_DATA: Mutex<RefCell<SensorData>> = Mutex::new(RefCell::new(SensorData { sensor_type: SensorType::Humidity, pos_x: 120, value: 0.0 }));
-static PRESSURE_DATA: Mutex<RefCell<SensorData>> = Mutex::new(RefCell::new(SensorData {sensor_type: SensorType::Pressure, pos_x: 205, value: 0.0 }));
+#[toml_cfg::toml_config]
+pub struct AppConfig {
+    // "per-topic", "json" or "cbor"
+    #[default("per-topic")]
+    telemetry_format: &'static str,
+    // "drop-oldest" or "drop-newest"
+    #[default("drop-oldest")]
+    offline_queue_overflow: &'static str,
+    // current sea-level pressure in hPa, for the altitude
+    #[default(1013.25)]
+    sea_level_pressure_hpa: f32,
+    // BME680 defaults, changeable at runtime on espbox/sensor/config/set
+    #[default(2)]
+    humidity_oversampling: u8,
+    #[default(4)]
+    pressure_oversampling: u8,
+    #[default(8)]
+    temperature_oversampling: u8,
+    #[default(3)]
+    iir_filter_size: u8,
+    #[default(320)]
+    heater_temperature: u16,
+    #[default(1500)]
+    heater_duration_ms: u16,
+    // filters applied to each channel before the values are shown and published
+    #[default("median:3")]
+    temperature_filter: &'static str,
+    #[default("median:3")]
+    humidity_filter: &'static str,
+    #[default("median:3")]
+    pressure_filter: &'static str,
+    #[default("median:3,ewma:0.5")]
+    gas_resistance_filter: &'static str,
+    // alarm thresholds as JSON, changeable at runtime on espbox/alarm/<metric>/set; "" checks nothing
+    #[default("")]
+    temperature_alarm: &'static str,
+    #[default("")]
+    humidity_alarm: &'static str,
+    #[default("")]
+    pressure_alarm: &'static str,
+    #[default("")]
+    iaq_alarm: &'static str,
+}
+
+const ORIENTATION: mipidsi::Orientation = mipidsi::Orientation::PortraitInverted(false);
+const TOUCH_TRANSFORM: TouchTransform = TouchTransform::new(ORIENTATION, Size::new(320, 240));
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+// latest reading of sensor_task or why there is none, for the sensor screen
+static SENSOR_READING: Signal<CriticalSectionRawMutex, Result<SensorReading, SensorFault>> = Signal::new();
+// longest the sensor task may hold the executor in one go, i.e. processing
+// a reading between two awaits
+const SENSOR_BLOCKING_BUDGET_US: u64 = 10_000;
+
+// signalled when other BME680 settings were requested over MQTT, applied by sensor_task between two measurements
+static SENSOR_CONFIG_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The BME680 settings of `cfg.toml`, or the defaults if they're invalid.
+fn configured_sensor_config() -> SensorConfig {
+    let config = SensorConfig::new(
+        APP_CONFIG.humidity_oversampling,
+        APP_CONFIG.pressure_oversampling,
+        APP_CONFIG.temperature_oversampling,
+        APP_CONFIG.iir_filter_size,
+        APP_CONFIG.heater_temperature,
+        APP_CONFIG.heater_duration_ms,
+    );
+    config.unwrap_or_else(|e| {
+        println!("Invalid BME680 settings in cfg.toml ({}), using defaults", e.as_str());
+        SensorConfig::DEFAULT
+    })
+}
+
+/// The filters of `cfg.toml`, passing a channel through unfiltered if its
+/// filters are invalid.
+fn configured_filters() -> ReadingFilters {
+    let pipeline = |channel, spec| {
+        Pipeline::parse(spec).unwrap_or_else(|e| {
+            println!("Invalid {} filter \"{}\" in cfg.toml ({}), not filtering it", channel, spec, e.as_str());
+            Pipeline::new()
+        })
+    };
+
+    ReadingFilters {
+        temperature: pipeline("temperature", APP_CONFIG.temperature_filter),
+        humidity: pipeline("humidity", APP_CONFIG.humidity_filter),
+        pressure: pipeline("pressure", APP_CONFIG.pressure_filter),
+        gas_resistance: pipeline("gas resistance", APP_CONFIG.gas_resistance_filter),
+    }
+}
+
+// signalled when an alarm was raised or cleared so touch_controller_task updates the banner
+static ALARM_BANNER: Signal<CriticalSectionRawMutex, Option<AlarmBanner>> = Signal::new();
+
+/// Sets the alarm thresholds of `cfg.toml`, skipping invalid ones.
+fn configure_alarms() {
+    for metric in Metric::ALL {
+        let spec = match metric {
+            Metric::Temperature => APP_CONFIG.temperature_alarm,
+            Metric::Humidity => APP_CONFIG.humidity_alarm,
+            Metric::Pressure => APP_CONFIG.pressure_alarm,
+            Metric::Iaq => APP_CONFIG.iaq_alarm,
+        };
+        match Threshold::parse(spec.as_bytes()) {
+            Ok(threshold) => {
+                show(APP.with(|app| app.set_alarm_threshold(metric, threshold, Instant::now().as_millis())));
+                EVENT_QUEUED.signal(());
+            }
+            Err(e) => println!("Invalid {} alarm in cfg.toml ({}), not checking it", metric.name(), e.as_str()),
+        }
+    }
+}
+
+// stock, alarms, history, offline queue and sensor settings, shared by all tasks
+static APP: Mutex<RefCell<App>> = Mutex::new(RefCell::new(App::new()));
+
+
+// signalled whenever stock changes so inventory_store_task saves it
+static INVENTORY_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// signalled when a set command changed an item so touch_controller_task redraws it
+static REMOTE_INVENTORY_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// signalled when an event was queued so main publishes it right away when online
+static EVENT_QUEUED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Hands what the app changed to touch_controller_task.
+fn show(updates: impl IntoIterator<Item = AppUpdate>) {
+    for update in updates {
+        match update {
+            AppUpdate::Inventory => REMOTE_INVENTORY_CHANGED.signal(()),
+            AppUpdate::Reading(reading) => SENSOR_READING.signal(Ok(reading)),
+            AppUpdate::SensorFault(fault) => SENSOR_READING.signal(Err(fault)),
+            AppUpdate::AlarmBanner(banner) => ALARM_BANNER.signal(banner),
+        }
+    }
+}
+
+const CONNECTION_STATS_TOPIC: &str = "espbox/diagnostics/connection";
+const RECONNECT_BACKOFF: Backoff = Backoff::new(CoreDuration::from_secs(1), CoreDuration::from_secs(300));
+
+/// Bytes of the largest MQTT packet sent or received.
+const MQTT_BUFFER_LEN: usize = 4096;
+
+type MqttSession<'a, T> = MqttClient<'a, InboxIo<'a, T, MQTT_BUFFER_LEN>, 5, CountingRng>;
+
+/// [`Publisher`] over the MQTT session of the current connection.
+struct MqttPublisher<'c, 'a, T: embedded_io_async::Read + embedded_io_async::Write> {
+    client: &'c mut MqttSession<'a, T>,
+    inbox: &'a Inbox<T, MQTT_BUFFER_LEN>,
+}
+
+impl<T: embedded_io_async::Read + embedded_io_async::Write> Publisher for MqttPublisher<'_, '_, T> {
+    type Error = ReasonCode;
+
+    async fn publish(&mut self, message: &Message<'_>) -> Result<(), ReasonCode> {
+        let qos = match message.qos {
+            QoS::AtMostOnce => QualityOfService::QoS0,
+            QoS::AtLeastOnce => QualityOfService::QoS1,
+            QoS::ExactlyOnce => QualityOfService::QoS2,
+        };
+        self.client.send_message(message.topic, message.payload, qos, message.retain).await
+    }
+
+    fn is_connection_lost(error: &ReasonCode) -> bool {
+        is_network_error(error)
+    }
+
+    fn is_refused(error: &ReasonCode) -> bool {
+        // rust-mqtt reports a packet other than the PUBACK as
+        // ImplementationSpecificError, and another message's PUBACK as
+        // PacketIdentifierNotFound
+        !matches!(
+            error,
+            ReasonCode::NetworkError | ReasonCode::ImplementationSpecificError | ReasonCode::PacketIdentifierNotFound
+        )
+    }
+
+    fn incoming_pending(&mut self) -> bool {
+        packet_pending(self.inbox)
+    }
+}
+
+/// Whether a whole packet is buffered, or arrived without waiting for it.
+fn packet_pending<T: embedded_io_async::Read + embedded_io_async::Write, const N: usize>(inbox: &Inbox<T, N>) -> bool {
+    // wait_packet is cancel-safe, so giving up on it keeps what it read
+    poll_once(inbox.wait_packet()).is_ready()
+}
+
+// anything else is the broker refusing a single packet
+fn is_network_error(error: &ReasonCode) -> bool {
+    matches!(error, ReasonCode::NetworkError)
+}
+
+fn mqtt_failure(error: &ReasonCode) -> Failure {
+    if is_network_error(error) {
+        Failure::Network
+    } else {
+        Failure::Rejected
+    }
+}
+
+/// Publishes the queued events oldest first. Returns `false` if the connection
+/// was lost, in which case the unpublished events stay queued.
+async fn drain_offline_queue<T: embedded_io_async::Read + embedded_io_async::Write>(
+    publisher: &mut MqttPublisher<'_, '_, T>,
+    keep_alive: &mut KeepAlive,
+) -> bool {
+    let drained = app::drain(&APP, publisher).await;
+    if drained.sent > 0 {
+        keep_alive.activity(Instant::now().as_millis());
+    }
+    if drained.interrupted {
+        // publish the rest once the command that came in is handled
+        EVENT_QUEUED.signal(());
+    }
+    !drained.connection_lost
+}
+
+fn catalog() -> Catalog<CATALOG_CAPACITY> {
+    APP.with(|app| app.catalog().clone())
+}
 
-static HOTDOG: Mutex<RefCell<FoodItem>> = Mutex::new(RefCell::new(FoodItem { name: "Hotdog", pos_y: 17, amount: 10, price: 2.50, highlighted: false, purchased: false }));
-static SANDWICH: Mutex<RefCell<FoodItem>> = Mutex::new(RefCell::new(FoodItem { name: "Sandwich", pos_y: 87, amount: 9, price: 3.50, highlighted: false, purchased: false }));
-static ENERGY_DRINK: Mutex<RefCell<FoodItem>> = Mutex::new(RefCell::new(FoodItem { name: "Energy Drink", pos_y: 157, amount: 11, price: 2.00, highlighted: false, purchased: false }));
 
 #[main]
 async fn main(spawner: Spawner) {
@@ -127,7 +370,7 @@ async fn main(spawner: Spawner) {
         timer_group0,
     );
 
-    let mut delay = Delay::new(&clocks);
+    let mut delay = hal::Delay::new(&clocks);
 
     let sclk = io.pins.gpio7;
     let mosi = io.pins.gpio6;
@@ -149,17 +392,28 @@ async fn main(spawner: Spawner) {
         Some(miso),
         Some(cs),
     );
+    #[cfg(feature = "framebuffer")]
+    let spi = {
+        hal::psram::init_psram(peripherals.PSRAM);
+        let dma = Gdma::new(peripherals.DMA);
+        spi.with_dma(dma.channel0.configure(
+            false,
+            make_static!([0u32; 8 * 3]),
+            make_static!([0u32; 8 * 3]),
+            DmaPriority::Priority0,
+        ))
+    };
 
     let di = SPIInterfaceNoCS::new(spi, dc);
     delay.delay_ms(500u32);
 
-    let mut display_struct = EmbassyTaskDisplay {
+    let display_struct = EmbassyTaskDisplay {
         display: match mipidsi::Builder::ili9342c_rgb565(di)
             .with_display_size(320, 240)
-            .with_orientation(mipidsi::Orientation::PortraitInverted(false))
+            .with_orientation(ORIENTATION)
             .with_color_order(mipidsi::ColorOrder::Bgr)
             .init(&mut delay, Some(reset)) {
-            Ok(display) => display,
+            Ok(display) => embassy_task_ili9342c::panel(display),
             Err(e) => {
                 println!("Display initialization failed: {:?}", e);
                 panic!("Display initialization failed");
@@ -169,22 +423,43 @@ async fn main(spawner: Spawner) {
 
     backlight.set_high().unwrap();
 
-    display_struct.display.clear(Rgb565::WHITE).unwrap();
-
-    let hotdog = critical_section::with(|cs| HOTDOG.borrow(cs).borrow().clone());
-    let sandwich = critical_section::with(|cs| SANDWICH.borrow(cs).borrow().clone());
-    let energy_drink = critical_section::with(|cs| ENERGY_DRINK.borrow(cs).borrow().clone());
-
-    build_inventory(
-        &mut display_struct.display,
-        &hotdog,
-        &sandwich,
-        &energy_drink,
-    );
+    let telemetry_format = TelemetryFormat::from_name(APP_CONFIG.telemetry_format).unwrap_or_else(|| {
+        println!("Unknown telemetry_format {:?}, publishing per topic", APP_CONFIG.telemetry_format);
+        TelemetryFormat::PerTopic
+    });
+    let overflow_policy = OverflowPolicy::from_name(APP_CONFIG.offline_queue_overflow).unwrap_or_else(|| {
+        println!("Unknown offline_queue_overflow {:?}, dropping oldest", APP_CONFIG.offline_queue_overflow);
+        OverflowPolicy::DropOldest
+    });
+    let filters = configured_filters();
+    let sensor_config = configured_sensor_config();
+    APP.with(|app| {
+        let catalog = Catalog::from_items(&PRODUCTS).expect("PRODUCTS exceed CATALOG_CAPACITY");
+        app.machine = VendingMachine::with_catalog(catalog);
+        app.filters = filters;
+        app.set_config(sensor_config);
+        app.telemetry_format = telemetry_format;
+        app.queue.set_policy(overflow_policy);
+        app.sea_level_pressure = APP_CONFIG.sea_level_pressure_hpa;
+        app.log = |args| println!("{}", args);
+    });
+
+    // the `inventory` partition of partitions.csv, wherever the flashed table put it
+    let mut flash = FlashStorage::new();
+    let partition = persist::find_partition(&mut flash, persist::DATA_PARTITION, persist::INVENTORY_PARTITION_SUBTYPE)
+        .expect("failed to read the partition table")
+        .expect("no inventory partition, flash with --partition-table partitions.csv");
+    let mut inventory_store = InventoryStore::new(flash, partition.offset, partition.size);
+    match inventory_store.restore() {
+        Ok(Some(amounts)) => {
+            println!("Restored inventory: {:?}", amounts);
+            APP.with(|app| app.machine.catalog_mut().set_amounts(&amounts));
+        }
+        Ok(None) => println!("No saved inventory, starting with defaults"),
+        Err(e) => println!("Failed to restore inventory: {:?}", e),
+    }
 
-    update_field(&mut display_struct.display, &hotdog);
-    update_field(&mut display_struct.display, &sandwich);
-    update_field(&mut display_struct.display, &energy_drink);
+    spawner.spawn(inventory_store_task(inventory_store)).ok();
 
     let i2c0 = I2C::new(
         peripherals.I2C0,
@@ -203,15 +478,19 @@ async fn main(spawner: Spawner) {
     );
 
     interrupt::enable(Interrupt::I2C_EXT0, interrupt::Priority::Priority1).unwrap();
+    interrupt::enable(Interrupt::I2C_EXT1, interrupt::Priority::Priority1).unwrap();
     interrupt::enable(Interrupt::GPIO, interrupt::Priority::Priority1).unwrap();
 
     let mut irq_pin = io.pins.gpio3.into_pull_up_input();
-    irq_pin.listen(Event::RisingEdge);
+    irq_pin.listen(gpio::Event::RisingEdge);
 
     let touch_controller = TT21100::new(i2c0, irq_pin);
 
     spawner.spawn(touch_controller_task(touch_controller, display_struct)).ok();
 
+    configure_alarms();
+    spawner.spawn(sensor_task(i2c1)).ok();
+
     let config = Config::dhcpv4(Default::default());
 
     let seed = 1234;
@@ -249,8 +528,15 @@ async fn main(spawner: Spawner) {
 
     let mut rsa = Rsa::new(peripherals.RSA);
 
-    loop {
-        sleep(1000).await;
+    // the time it took to get here is as good a seed for the jitter as any
+    let mut link = ConnectionManager::new(RECONNECT_BACKOFF, Instant::now().as_ticks() as u32);
+
+    'connection: loop {
+        if let Some(backoff) = link.pending_backoff() {
+            println!("Reconnecting in {} ms", backoff.as_millis());
+            Timer::after(Duration::from_millis(backoff.as_millis() as u64)).await;
+        }
+        link.start();
 
         let mut socket = TcpSocket::new(&stack, &mut rx_buffer, &mut tx_buffer);
 
@@ -264,18 +550,22 @@ async fn main(spawner: Spawner) {
             Ok(address) => address,
             Err(e) => {
                 println!("DNS lookup error: {e:?}");
+                link.fail(Failure::Network);
                 continue;
             }
         };
+        link.advance();
 
         let remote_endpoint = (address, 8883);
         println!("connecting...");
         let connection = socket.connect(remote_endpoint).await;
         if let Err(e) = connection {
             println!("connect error: {:?}", e);
+            link.fail(Failure::Network);
             continue;
         }
         println!("connected!");
+        link.advance();
 
         set_debug(0);
 
@@ -290,21 +580,36 @@ async fn main(spawner: Spawner) {
             password: None,
         };
 
-        let tls: Session<_, 4096> = Session::new(
+        let tls: Session<_, 4096> = match Session::new(
             &mut socket,
             ENDPOINT,
             Mode::Client,
             TlsVersion::Tls1_3,
             certificates,
             Some(&mut rsa),
-        )
-        .unwrap();
+        ) {
+            Ok(tls) => tls,
+            Err(e) => {
+                // the certificates in secrets/ didn't load, retrying soon won't fix that
+                println!("TLS setup error: {:?}", e);
+                link.fail(Failure::Rejected);
+                continue;
+            }
+        };
 
         println!("Start tls connect");
 
-        let connected_tls = tls.connect().await.expect("TLS connect failed");
+        let connected_tls = match tls.connect().await {
+            Ok(connected_tls) => connected_tls,
+            Err(e) => {
+                println!("TLS connect error: {:?}", e);
+                link.fail(Failure::Network);
+                continue;
+            }
+        };
     
         println!("Tls connected!");
+        link.advance();
 
         let mut config = ClientConfig::new(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
@@ -313,247 +618,159 @@ async fn main(spawner: Spawner) {
         config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
         config.add_client_id(CLIENT_ID);
         config.max_packet_size = 149504;
-        println!("{:?}", config.keep_alive);
-        let mut recv_buffer = [0; 4096];
-        let mut write_buffer = [0; 4096];
-
-        let mut client =
-            MqttClient::<_, 5, _>::new(connected_tls, &mut write_buffer, 4096, &mut recv_buffer, 4096, config);
+        println!("Keep alive {} s", config.keep_alive);
+        let keep_alive_interval = CoreDuration::from_secs(config.keep_alive.into());
+        let mut recv_buffer = [0; MQTT_BUFFER_LEN];
+        let mut write_buffer = [0; MQTT_BUFFER_LEN];
+
+        // lets the loop below wait for a packet in a select without cutting
+        // one in half, and holds back commands that arrive during a publish
+        let inbox: Inbox<_, MQTT_BUFFER_LEN> = Inbox::new(connected_tls);
+
+        let mut client = MqttClient::<_, 5, _>::new(
+            inbox.io(),
+            &mut write_buffer,
+            MQTT_BUFFER_LEN,
+            &mut recv_buffer,
+            MQTT_BUFFER_LEN,
+            config,
+        );
 
         match client.connect_to_broker().await {
             Ok(()) => {}
             Err(mqtt_error) => match mqtt_error {
                 ReasonCode::NetworkError => {
                     println!("MQTT Network Error");
+                    link.fail(Failure::Network);
                     continue;
                 }
                 _ => {
                     println!("Other MQTT Error: {:?}", mqtt_error);
+                    link.fail(Failure::Rejected);
                     continue;
                 }
             },
         }
 
-        //initialize BME680
-        let mut bme = Bme680::init(i2c1, &mut delay, I2CAddress::Primary).expect("Failed to initialize Bme680");
-        let settings = SettingsBuilder::new()
-            .with_humidity_oversampling(OversamplingSetting::OS2x)
-            .with_pressure_oversampling(OversamplingSetting::OS4x)
-            .with_temperature_oversampling(OversamplingSetting::OS8x)
-            .with_temperature_filter(IIRFilterSize::Size3)
-            .with_gas_measurement(CoreDuration::from_millis(1500), 320, 25)
-            .with_run_gas(true)
-            .build();
-        bme.set_sensor_settings(&mut delay, settings).expect("Failed to set the settings");
-
-        loop {
-            bme.set_sensor_mode(&mut delay, PowerMode::ForcedMode).expect("Failed to set sensor mode");
-
-            let profile_duration = bme.get_profile_dur(&settings.0).expect("Failed to get profile duration");
-            let duration_ms = profile_duration.as_millis() as u32;
-            delay.delay_ms(duration_ms);
-
-            let (data, _state) = bme.get_sensor_data(&mut delay).expect("Failed to get sensor data");
-            
-            let temp = data.temperature_celsius();
-            let hum = data.humidity_percent();
-            let pres = data.pressure_hpa();
-            let gas = data.gas_resistance_ohm();
-
-            critical_section::with(|cs| {
-                TEMPERATURE_DATA.borrow(cs).borrow_mut().value = temp;
-                HUMIDITY_DATA.borrow(cs).borrow_mut().value = hum;
-                PRESSURE_DATA.borrow(cs).borrow_mut().value = pres;
-            });
-
-            let hotdog_amount = critical_section::with(|cs| HOTDOG.borrow(cs).borrow().amount);
-            let sandwich_amount = critical_section::with(|cs| SANDWICH.borrow(cs).borrow().amount);
-            let energy_drink_amount = critical_section::with(|cs| ENERGY_DRINK.borrow(cs).borrow().amount);
-
-            println!("|========================|");
-            println!("| Temperature {:.2}°C    |", temp);
-            println!("| Humidity {:.2}%        |", hum);
-            println!("| Pressure {:.2}hPa     |", pres);
-            println!("| Gas Resistance {:.2}Ω ", gas);
-            println!("|========================|");
-
-            // Convert data into Strings
-            let mut temperature_string: String<32> = String::new();
-            write!(temperature_string, "{:.2}", temp).expect("write! failed!");
-
-            let mut pressure_string: String<32> = String::new();
-            write!(pressure_string, "{:.2}", pres).expect("write! failed!");
-
-            let mut humidity_string: String<32> = String::new();
-            write!(humidity_string, "{:.2}", hum).expect("write! failed!");
-
-            let mut gas_string: String<32> = String::new();
-            write!(gas_string, "{:.2}", gas).expect("write! failed!");
-
-            let mut hotdog_string: String<32> = String::new();
-            write!(hotdog_string, "{}", hotdog_amount).expect("write! failed!");
-
-            let mut sandwich_string: String<32> = String::new();
-            write!(sandwich_string, "{}", sandwich_amount).expect("write! failed!");
-
-            let mut energy_drink_string: String<32> = String::new();
-            write!(energy_drink_string, "{}", energy_drink_amount).expect("write! failed!");
-
-            match client
-                .send_message(
-                    "espbox/sensor/Temperature",
-                    temperature_string.as_bytes(),
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                    true,
-                )
-                .await
-            {
-                Ok(()) => {}
-                Err(mqtt_error) => match mqtt_error {
-                    ReasonCode::NetworkError => {
-                        println!("MQTT Network Error");
-                        continue;
-                    }
-                    _ => {
-                        println!("Other MQTT Error: {:?}", mqtt_error);
-                        continue;
-                    }
-                },
+        for topic in [SET_TOPIC_FILTER, CONFIG_SET_TOPIC, ALARM_SET_TOPIC_FILTER] {
+            match client.subscribe_to_topic(topic).await {
+                Ok(()) => println!("Subscribed to {}", topic),
+                Err(mqtt_error) => {
+                    println!("MQTT subscribe error: {:?}", mqtt_error);
+                    link.fail(mqtt_failure(&mqtt_error));
+                    continue 'connection;
+                }
             }
-
-            match client
-                .send_message(
-                    "espbox/sensor/Pressure",
-                    pressure_string.as_bytes(),
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                    true,
-                )
-                .await
-            {
-                Ok(()) => {}
-                Err(mqtt_error) => match mqtt_error {
-                    ReasonCode::NetworkError => {
-                        println!("MQTT Network Error");
-                        continue;
-                    }
-                    _ => {
-                        println!("Other MQTT Error: {:?}", mqtt_error);
-                        continue;
-                    }
-                },
+        }
+        link.advance();
+        let mut keep_alive = KeepAlive::new(keep_alive_interval, Instant::now().as_millis());
+
+        let stats = link.stats();
+        println!("Online after {} attempts ({} connects, {} disconnects)", stats.attempts, stats.connects, stats.disconnects);
+        let stats_json = stats.to_json();
+        let stats_message = Message::new(CONNECTION_STATS_TOPIC, stats_json.as_bytes(), QoS::AtLeastOnce, true);
+        if let Err(mqtt_error) = MqttPublisher { client: &mut client, inbox: &inbox }.publish(&stats_message).await {
+            println!("MQTT diagnostics error: {:?}", mqtt_error);
+            if is_network_error(&mqtt_error) {
+                link.connection_lost();
+                continue;
             }
+        }
 
-            match client
-                .send_message(
-                    "espbox/sensor/Humidity",
-                    humidity_string.as_bytes(),
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                    true,
-                )
-                .await
-            {
-                Ok(()) => {}
-                Err(mqtt_error) => match mqtt_error {
-                    ReasonCode::NetworkError => {
-                        println!("MQTT Network Error");
-                        continue;
-                    }
-                    _ => {
-                        println!("Other MQTT Error: {:?}", mqtt_error);
-                        continue;
-                    }
-                },
-            }
+        // Publish what piled up while offline
+        if !drain_offline_queue(&mut MqttPublisher { client: &mut client, inbox: &inbox }, &mut keep_alive).await {
+            println!("MQTT connection lost, reconnecting");
+            link.connection_lost();
+            continue;
+        }
 
-            match client
-                .send_message(
-                    "espbox/sensor/Gas",
-                    gas_string.as_bytes(),
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                    true,
-                )
-                .await
-            {
-                Ok(()) => {}
-                Err(mqtt_error) => match mqtt_error {
-                    ReasonCode::NetworkError => {
-                        println!("MQTT Network Error");
-                        continue;
-                    }
-                    _ => {
-                        println!("Other MQTT Error: {:?}", mqtt_error);
-                        continue;
+        loop {
+            // Handle set commands and publish queued events, pinging the broker when it's quiet
+            let next_ping = Instant::from_millis(keep_alive.next_ping_ms());
+            let received = match select3(inbox.wait_packet(), Timer::at(next_ping), EVENT_QUEUED.wait()).await {
+                // the whole packet is buffered, so receiving it doesn't wait on the network
+                Either3::First(Ok(())) => Either3::First(client.receive_message().await),
+                Either3::First(Err(e)) => {
+                    println!("MQTT receive error: {:?}", e);
+                    link.connection_lost();
+                    continue 'connection;
+                }
+                Either3::Second(()) => Either3::Second(()),
+                Either3::Third(()) => Either3::Third(()),
+            };
+            let (ack_topic, ack) = match received {
+                Either3::First(Ok((topic, payload))) => {
+                    keep_alive.activity(Instant::now().as_millis());
+                    let handled = APP.with(|app| app.command(topic, payload, Instant::now().as_millis()));
+                    // sensor_task publishes requested settings once they're active
+                    if handled.config_requested {
+                        SENSOR_CONFIG_CHANGED.signal(());
                     }
-                },
-            }
-
-            match client
-                .send_message(
-                    "espbox/inventory/Hotdog",
-                    hotdog_string.as_bytes(),
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                    true,
-                )
-                .await
-            {
-                Ok(()) => {}
-                Err(mqtt_error) => match mqtt_error {
-                    ReasonCode::NetworkError => {
-                        println!("MQTT Network Error");
-                        continue;
+                    if handled.stock_changed {
+                        INVENTORY_CHANGED.signal(());
                     }
-                    _ => {
-                        println!("Other MQTT Error: {:?}", mqtt_error);
-                        continue;
+                    show(handled.updates);
+                    EVENT_QUEUED.signal(());
+                    match handled.ack {
+                        Some(ack) => ack,
+                        None => continue,
                     }
-                },
-            }
-
-            match client
-                .send_message(
-                    "espbox/inventory/Sandwich",
-                    sandwich_string.as_bytes(),
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                    true,
-                )
-                .await
-            {
-                Ok(()) => {}
-                Err(mqtt_error) => match mqtt_error {
-                    ReasonCode::NetworkError => {
-                        println!("MQTT Network Error");
-                        continue;
+                }
+                Either3::First(Err(mqtt_error)) => {
+                    println!("MQTT receive error: {:?}", mqtt_error);
+                    if is_network_error(&mqtt_error) {
+                        link.connection_lost();
+                        continue 'connection;
                     }
-                    _ => {
-                        println!("Other MQTT Error: {:?}", mqtt_error);
-                        continue;
+                    continue;
+                }
+                Either3::Second(()) => {
+                    // a packet that's already there is handled first, the ping is still due afterwards
+                    if keep_alive.ping_due(Instant::now().as_millis()) && !packet_pending(&inbox) {
+                        let timeout = Duration::from_millis(keep_alive.response_timeout().as_millis() as u64);
+                        match select(client.send_ping(), Timer::after(timeout)).await {
+                            Either::First(Ok(())) => keep_alive.activity(Instant::now().as_millis()),
+                            Either::First(Err(mqtt_error)) if is_network_error(&mqtt_error) => {
+                                println!("MQTT ping error: {:?}", mqtt_error);
+                                link.connection_lost();
+                                continue 'connection;
+                            }
+                            // the broker answered with something other than
+                            // the PINGRESP, so it's still there
+                            Either::First(Err(mqtt_error)) => {
+                                println!("MQTT ping answered with {:?}", mqtt_error);
+                                keep_alive.activity(Instant::now().as_millis());
+                            }
+                            Either::Second(()) => {
+                                println!("Broker didn't answer ping within {} ms", timeout.as_millis());
+                                link.connection_lost();
+                                continue 'connection;
+                            }
+                        }
                     }
-                },
-            }
-
-            match client
-                .send_message(
-                    "espbox/inventory/EnergyDrink",
-                    energy_drink_string.as_bytes(),
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                    true,
-                )
-                .await
-            {
-                Ok(()) => {}
-                Err(mqtt_error) => match mqtt_error {
-                    ReasonCode::NetworkError => {
-                        println!("MQTT Network Error");
-                        continue;
+                    continue;
+                }
+                Either3::Third(()) => {
+                    if !drain_offline_queue(&mut MqttPublisher { client: &mut client, inbox: &inbox }, &mut keep_alive).await {
+                        println!("MQTT connection lost, reconnecting");
+                        link.connection_lost();
+                        continue 'connection;
                     }
-                    _ => {
-                        println!("Other MQTT Error: {:?}", mqtt_error);
-                        continue;
+                    continue;
+                }
+            };
+
+            let ack = Message::new(&ack_topic, ack.as_bytes(), QoS::AtLeastOnce, false);
+            match MqttPublisher { client: &mut client, inbox: &inbox }.publish(&ack).await {
+                Ok(()) => keep_alive.activity(Instant::now().as_millis()),
+                Err(mqtt_error) => {
+                    println!("MQTT ack error: {:?}", mqtt_error);
+                    if is_network_error(&mqtt_error) {
+                        link.connection_lost();
+                        continue 'connection;
                     }
-                },
+                }
             }
-
-            sleep(59000).await;
         }
     }
 }
@@ -612,96 +829,220 @@ async fn net_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>) {
     stack.run().await;
 }
 
+#[embassy_executor::task]
+async fn inventory_store_task(mut store: InventoryStore<FlashStorage, CATALOG_CAPACITY>) {
+    loop {
+        INVENTORY_CHANGED.wait().await;
+
+        let amounts = catalog().amounts();
+        if let Err(e) = store.save(&amounts) {
+            println!("Failed to save inventory: {:?}", e);
+        }
+    }
+}
+
+type Sensor<'a> = Bme680<&'a mut I2C<'static, I2C1>>;
+
+/// Borrows the bus, so it survives a failed [`Bme680::init`] and the sensor
+/// can be initialised again.
+async fn init_sensor<'a>(i2c: &'a mut I2C<'static, I2C1>, config: &SensorConfig) -> Result<Sensor<'a>, SensorFault> {
+    Bme680::init(i2c, &mut Delay, bme680::PRIMARY_ADDRESS, config).await.map_err(|e| e.fault())
+}
+
+/// Runs one forced measurement. The transfers and the wait for the heater
+/// yield to the other tasks; only the compensation runs in one go.
+async fn measure(bme: &mut Sensor<'_>) -> Result<SensorReading, SensorFault> {
+    let raw = bme.measure(&mut Delay).await.map_err(|e| e.fault())?;
+
+    let reading = bme.calibration().compensate(&raw);
+    sensor::check_plausible(&reading)?;
+    Ok(reading)
+}
+
+/// Records a failed attempt, tells the screen and the broker if the fault is
+/// new, and waits until it's time to retry.
+async fn sensor_failed(fault: SensorFault) {
+    let retry_in = APP.with(|app| app.record_fault(fault, Instant::now().as_millis()));
+    EVENT_QUEUED.signal(());
+    SENSOR_READING.signal(Err(fault));
+    Timer::after(Duration::from_millis(retry_in.as_millis() as u64)).await;
+}
+
+#[embassy_executor::task]
+async fn sensor_task(mut i2c: I2C<'static, I2C1>) {
+    let mut latency = LatencyMonitor::new(SENSOR_BLOCKING_BUDGET_US);
+
+    'sensor: loop {
+        //initialize BME680, again after a bus fault in case it lost power
+        let config = APP.with(|app| app.requested_config());
+        let mut bme = match init_sensor(&mut i2c, &config).await {
+            Ok(bme) => bme,
+            Err(fault) => {
+                sensor_failed(fault).await;
+                continue;
+            }
+        };
+        APP.with(|app| app.sensor_started(config, Instant::now().as_millis()));
+        EVENT_QUEUED.signal(());
+        let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+
+        loop {
+            let raw = match measure(&mut bme).await {
+                Ok(reading) => reading,
+                Err(fault @ (SensorFault::Implausible | SensorFault::GasInvalid)) => {
+                    // the bus works, so just measure again
+                    sensor_failed(fault).await;
+                    continue;
+                }
+                Err(fault) => {
+                    sensor_failed(fault).await;
+                    continue 'sensor;
+                }
+            };
+            // from here to the next await nothing yields
+            let started = Instant::now();
+            let updates = APP.with(|app| app.record_reading(&raw, Instant::now().as_millis()));
+            EVENT_QUEUED.signal(());
+            let reading = updates
+                .iter()
+                .find_map(|update| match update {
+                    AppUpdate::Reading(reading) => Some(*reading),
+                    _ => None,
+                })
+                .unwrap_or(raw);
+            show(updates);
+
+            let elapsed_us = started.elapsed().as_micros();
+            if !latency.record(elapsed_us) {
+                println!("Sensor task held the executor for {} us, budget is {} us", elapsed_us, latency.budget_us());
+            }
+
+            println!("|========================|");
+            println!("| Temperature {:.2}°C    |", reading.temperature);
+            println!("| Humidity {:.2}%        |", reading.humidity);
+            println!("| Pressure {:.2}hPa     |", reading.pressure);
+            println!("| Gas Resistance {:.2}Ω ", reading.gas_resistance);
+            if let Some(iaq) = reading.iaq {
+                println!("| IAQ {} ({})", iaq.0, iaq.category().as_str());
+            }
+            println!("| Held executor {} us, at most {} us", elapsed_us, latency.max_us());
+            println!("|========================|");
+
+            // The sensor sleeps until the next forced measurement, so settings can change meanwhile
+            while let Either::Second(()) = select(ticker.next(), SENSOR_CONFIG_CHANGED.wait()).await {
+                let (config, requested) = APP.with(|app| (app.config(), app.requested_config()));
+                if requested == config {
+                    continue;
+                }
+
+                match bme.configure(&requested).await {
+                    Ok(()) => APP.with(|app| app.apply_config(requested, Instant::now().as_millis())),
+                    Err(e) => {
+                        println!("Failed to apply sensor config: {:?}", e);
+                        APP.with(|app| app.reject_config(Instant::now().as_millis()));
+                        EVENT_QUEUED.signal(());
+                        if let Err(e) = bme.configure(&config).await {
+                            sensor_failed(e.fault()).await;
+                            continue 'sensor;
+                        }
+                    }
+                }
+                EVENT_QUEUED.signal(());
+            }
+        }
+    }
+}
+
 const TOUCH_TIMEOUT: u64 = 1000;
 
+type TouchScreen = Router<EmbassyTaskDisplay<'static>>;
+
+/// Shows what the router drew, or why it couldn't.
+fn redraw(router: &mut TouchScreen, drawn: Result<(), DisplayError>) {
+    match drawn.and_then(|()| router.flush()) {
+        Ok(sent) if sent.writes > 0 => println!("Redraw sent {} bytes in {} writes", sent.bytes, sent.writes),
+        Ok(_) => {}
+        Err(e) => println!("Redraw failed: {:?}", e),
+    }
+}
+
+/// Sells the item whose buy button was tapped and redraws its row.
+fn buy(router: &mut TouchScreen, id: ItemId, now: u64) -> Result<(), DisplayError> {
+    if APP.with(|app| app.purchase(id, now)).is_err() {
+        return Ok(());
+    }
+    INVENTORY_CHANGED.signal(());
+    EVENT_QUEUED.signal(());
+    router.update(&APP, AppUpdate::Inventory, now)
+}
+
 #[embassy_executor::task]
-async fn touch_controller_task(mut touch_controller: TT21100<I2C<'static, I2C0>, GpioPin<Input<PullUp>, 3>>, mut display_struct: EmbassyTaskDisplay<'static>) {
+async fn touch_controller_task(mut touch_controller: TT21100<I2C<'static, I2C0>, GpioPin<Input<PullUp>, 3>>, display_struct: EmbassyTaskDisplay<'static>) {
     let mut last_touch_time = 0u64;
-    let mut is_sensor_data_displayed = false;
+    let mut gestures = GestureTracker::new();
+    let mut router = Router::new(display_struct);
+
+    let drawn = router.start(&APP, Instant::now().as_millis());
+    redraw(&mut router, drawn);
 
     loop {
-        touch_controller.data_available().await.unwrap();
+        let update = match select4(
+            touch_controller.data_available(),
+            REMOTE_INVENTORY_CHANGED.wait(),
+            SENSOR_READING.wait(),
+            ALARM_BANNER.wait(),
+        ).await {
+            Either4::First(result) => {
+                result.unwrap();
+                None
+            }
+            Either4::Second(()) => Some(AppUpdate::Inventory),
+            Either4::Third(Ok(reading)) => Some(AppUpdate::Reading(reading)),
+            Either4::Third(Err(fault)) => Some(AppUpdate::SensorFault(fault)),
+            Either4::Fourth(banner) => Some(AppUpdate::AlarmBanner(banner)),
+        };
+
         let current_time = Instant::now().as_millis();
+        if let Some(update) = update {
+            let drawn = router.update(&APP, update, current_time);
+            redraw(&mut router, drawn);
+            continue;
+        }
+
         if let Ok(event) = touch_controller.event().await {
-            if current_time - last_touch_time > TOUCH_TIMEOUT {
-                match event {
-                    tt21100_async::Event::Button(button) => {
-                        let currently_pressed = button.btn_val != 0;
-                        if currently_pressed {
-                            is_sensor_data_displayed = !is_sensor_data_displayed;
-                            
-                            if is_sensor_data_displayed {
-                                // Show sensor data UI
-                                let temperature_data = critical_section::with(|cs| TEMPERATURE_DATA.borrow(cs).borrow().clone());
-                                let humidity_data = critical_section::with(|cs| HUMIDITY_DATA.borrow(cs).borrow().clone());
-                                let pressure_data = critical_section::with(|cs| PRESSURE_DATA.borrow(cs).borrow().clone());
-
-                                build_sensor_ui(&mut display_struct.display, &temperature_data, &humidity_data, &pressure_data);
-                                update_sensor_data(&mut display_struct.display, &temperature_data);
-                                update_sensor_data(&mut display_struct.display, &humidity_data);
-                                update_sensor_data(&mut display_struct.display, &pressure_data);
-                            } else {
-                                // Hide sensor data UI and show inventory
-                                display_struct.display.clear(Rgb565::WHITE).unwrap();
-
-                                let hotdog = critical_section::with(|cs| HOTDOG.borrow(cs).borrow().clone());
-                                let sandwich = critical_section::with(|cs| SANDWICH.borrow(cs).borrow().clone());
-                                let energy_drink = critical_section::with(|cs| ENERGY_DRINK.borrow(cs).borrow().clone());
-
-                                build_inventory(&mut display_struct.display, &hotdog, &sandwich, &energy_drink);
-                                update_field(&mut display_struct.display, &hotdog);
-                                update_field(&mut display_struct.display, &sandwich);
-                                update_field(&mut display_struct.display, &energy_drink);
-                            }
+            let drawn = match event {
+                tt21100_async::Event::Button(button) => {
+                    let currently_pressed = button.btn_val != 0;
+                    if currently_pressed && current_time - last_touch_time > TOUCH_TIMEOUT {
+                        last_touch_time = current_time;
+                        router.button(&APP, current_time)
+                    } else {
+                        Ok(())
+                    }
+                },
+                tt21100_async::Event::Touch { report: _, touches } => match touches.0 {
+                    Some(touch) => {
+                        if let Some(point) = TOUCH_TRANSFORM.to_display(Point::new(touch.x as i32, touch.y as i32)) {
+                            gestures.press(point);
                         }
-                    },
-                    tt21100_async::Event::Touch { report: _, touches } => {
-                        if let Some(touch) = touches.0 {
-
-                            let max_x = 320;
-                            let corrected_x = max_x - touch.x;
-
-                            if corrected_x > 230 && corrected_x < 310 {
-                                // touch y > 17 + 10 < 45 for hotdog
-                                if touch.y > 17 && touch.y < 55 {
-                                    critical_section::with(|cs| {
-                                        let mut hotdog = HOTDOG.borrow(cs).borrow_mut();
-                                        if hotdog.amount > 0 {
-                                            hotdog.amount -= 1;
-                                            println!("Hotdog bought!");
-                                            update_field(&mut display_struct.display, &*hotdog);
-                                        }
-                                    });
-                                // touch y > 87 + 10 < 105 for sandwich
-                                } else if touch.y > 87 && touch.y < 125 {
-                                    critical_section::with(|cs| {
-                                        let mut sandwich = SANDWICH.borrow(cs).borrow_mut();
-                                        if sandwich.amount > 0 {
-                                            sandwich.amount -= 1;
-                                            println!("Sandwich bought!");
-                                            update_field(&mut display_struct.display, &*sandwich);
-                                        }
-                                    });
-                                // touch y > 157 + 10 < 185 for energy drink  
-                                } else if touch.y > 167 && touch.y < 205 {
-                                    critical_section::with(|cs| {
-                                        let mut energy_drink = ENERGY_DRINK.borrow(cs).borrow_mut();
-                                        if energy_drink.amount > 0 {
-                                            energy_drink.amount -= 1;
-                                            println!("Energy drink bought!");
-                                            update_field(&mut display_struct.display, &*energy_drink);
-                                        }
-                                    });
-                                }
+                        Ok(())
+                    }
+                    // the report after the finger was lifted has no touches
+                    None => match gestures.release() {
+                        Some(Gesture::Tap(point)) if current_time - last_touch_time > TOUCH_TIMEOUT => {
+                            last_touch_time = current_time;
+                            match router.gesture(&APP, Gesture::Tap(point), current_time) {
+                                Ok(Some(id)) => buy(&mut router, id, current_time),
+                                tapped => tapped.map(|_| ()),
                             }
                         }
-                    }
-                }
-                last_touch_time = current_time;
-            }
+                        Some(Gesture::Tap(_)) | None => Ok(()),
+                        Some(swipe) => router.gesture(&APP, swipe, current_time).map(|_| ()),
+                    },
+                },
+            };
+            redraw(&mut router, drawn);
         }
-
-        sleep(100).await;
     }
 }
 
@@ -0,0 +1,485 @@
+//! High and low thresholds on the environmental readings.
+//!
+//! A [`Threshold`] is set per [`Metric`] in `cfg.toml` and over MQTT as a JSON
+//! object like `{"low":2.0,"high":8.0,"hysteresis":0.5,"min_duration_s":300}`,
+//! where a bound that's left out isn't checked. An alarm is raised once the
+//! value stayed past a bound for `min_duration_s`, so a door opened for a
+//! moment doesn't raise one, and cleared once the value is back inside by more
+//! than `hysteresis`, so a value hovering around the bound doesn't flap.
+
+use core::fmt::Write;
+
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::sensor::SensorReading;
+
+/// Filter to subscribe to for threshold changes of all metrics.
+pub const ALARM_SET_TOPIC_FILTER: &str = "espbox/alarm/+/set";
+/// Longest `min_duration_s`, a day.
+pub const MAX_MIN_DURATION_S: u32 = 86_400;
+
+const ALARM_PREFIX: &str = "espbox/alarm/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    Temperature,
+    Humidity,
+    Pressure,
+    Iaq,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 4] = [Metric::Temperature, Metric::Humidity, Metric::Pressure, Metric::Iaq];
+
+    /// The name in topics and `cfg.toml` keys.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::Pressure => "pressure",
+            Metric::Iaq => "iaq",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|metric| metric.name() == name)
+    }
+
+    /// The name on the display.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Metric::Temperature => "Temperature",
+            Metric::Humidity => "Humidity",
+            Metric::Pressure => "Pressure",
+            Metric::Iaq => "IAQ",
+        }
+    }
+
+    /// The value of this metric in `reading`, `None` for the IAQ during the
+    /// gas sensor's burn-in.
+    pub fn value(&self, reading: &SensorReading) -> Option<f32> {
+        match self {
+            Metric::Temperature => Some(reading.temperature),
+            Metric::Humidity => Some(reading.humidity),
+            Metric::Pressure => Some(reading.pressure),
+            Metric::Iaq => reading.iaq.map(|iaq| iaq.0 as f32),
+        }
+    }
+
+    pub(crate) fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Low,
+    High,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Low => "low",
+            Level::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThresholdError {
+    /// The payload isn't a JSON object with only [`Threshold`] fields.
+    Malformed,
+    /// `low` isn't below `high`, or `hysteresis` is negative or doesn't fit
+    /// between them.
+    InvalidBounds,
+    DurationOutOfRange,
+}
+
+impl ThresholdError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThresholdError::Malformed => "malformed payload",
+            ThresholdError::InvalidBounds => "invalid bounds",
+            ThresholdError::DurationOutOfRange => "min duration out of range",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Threshold {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub low: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub high: Option<f32>,
+    #[serde(default)]
+    pub hysteresis: f32,
+    #[serde(default)]
+    pub min_duration_s: u32,
+}
+
+impl Threshold {
+    /// Checks nothing.
+    pub const NONE: Self = Self { low: None, high: None, hysteresis: 0.0, min_duration_s: 0 };
+
+    /// Parses and validates a JSON threshold. An empty payload is
+    /// [`Threshold::NONE`].
+    pub fn parse(payload: &[u8]) -> Result<Self, ThresholdError> {
+        if payload.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Self::NONE);
+        }
+        let (threshold, _) = serde_json_core::from_slice::<Threshold>(payload).map_err(|_| ThresholdError::Malformed)?;
+        threshold.validate()?;
+        Ok(threshold)
+    }
+
+    fn validate(&self) -> Result<(), ThresholdError> {
+        let finite = |bound: Option<f32>| bound.is_none_or(f32::is_finite);
+        let hysteresis_valid = self.hysteresis >= 0.0 && self.hysteresis.is_finite();
+        if !(finite(self.low) && finite(self.high) && hysteresis_valid) {
+            return Err(ThresholdError::InvalidBounds);
+        }
+        if let (Some(low), Some(high)) = (self.low, self.high) {
+            if low + self.hysteresis >= high - self.hysteresis {
+                return Err(ThresholdError::InvalidBounds);
+            }
+        }
+        if self.min_duration_s > MAX_MIN_DURATION_S {
+            return Err(ThresholdError::DurationOutOfRange);
+        }
+        Ok(())
+    }
+
+    /// The bound `value` is past, if any.
+    fn exceeded(&self, value: f32) -> Option<Level> {
+        match (self.low, self.high) {
+            (Some(low), _) if value < low => Some(Level::Low),
+            (_, Some(high)) if value > high => Some(Level::High),
+            _ => None,
+        }
+    }
+
+    /// Whether an alarm at `level` can clear for `value`.
+    fn cleared(&self, level: Level, value: f32) -> bool {
+        match level {
+            Level::Low => self.low.is_none_or(|low| value > low + self.hysteresis),
+            Level::High => self.high.is_none_or(|high| value < high - self.hysteresis),
+        }
+    }
+
+    fn bound(&self, level: Level) -> f32 {
+        match level {
+            Level::Low => self.low,
+            Level::High => self.high,
+        }
+        .unwrap_or(f32::NAN)
+    }
+
+    pub fn to_json(&self) -> String<128> {
+        serde_json_core::to_string(self).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Normal,
+    /// Past a bound since `since_ms`, but not for long enough yet.
+    Pending { level: Level, since_ms: u64 },
+    Active(Level),
+}
+
+/// An alarm that was raised or cleared.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlarmChange {
+    pub metric: Metric,
+    pub level: Level,
+    pub raised: bool,
+    /// The value that raised or cleared the alarm, `None` when it was
+    /// cleared by replacing its threshold.
+    pub value: Option<f32>,
+    /// The bound that was crossed.
+    pub threshold: f32,
+}
+
+impl AlarmChange {
+    /// `espbox/alarm/<metric>`, where the change is published.
+    pub fn topic(&self) -> String<64> {
+        alarm_topic(self.metric, "")
+    }
+}
+
+/// `espbox/alarm/<metric><suffix>`.
+pub fn alarm_topic(metric: Metric, suffix: &str) -> String<64> {
+    let mut topic = String::new();
+    write!(topic, "{}{}{}", ALARM_PREFIX, metric.name(), suffix).ok();
+    topic
+}
+
+/// The metric of an `espbox/alarm/<metric>/set` topic.
+pub fn set_topic_metric(topic: &str) -> Option<Metric> {
+    Metric::from_name(topic.strip_prefix(ALARM_PREFIX)?.strip_suffix("/set")?)
+}
+
+#[derive(Serialize)]
+struct AlarmDocument {
+    ts_ms: u64,
+    state: &'static str,
+    level: &'static str,
+    value: Option<f32>,
+    threshold: f32,
+}
+
+/// The document published on [`AlarmChange::topic`] for a change at
+/// `timestamp_ms`.
+pub fn alarm_json(timestamp_ms: u64, change: &AlarmChange) -> String<128> {
+    let document = AlarmDocument {
+        ts_ms: timestamp_ms,
+        state: if change.raised { "raised" } else { "cleared" },
+        level: change.level.as_str(),
+        value: change.value,
+        threshold: change.threshold,
+    };
+    serde_json_core::to_string(&document).unwrap_or_default()
+}
+
+/// Threshold and state of every [`Metric`].
+#[derive(Debug, Clone)]
+pub struct Alarms {
+    thresholds: [Threshold; 4],
+    states: [State; 4],
+}
+
+impl Default for Alarms {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Alarms {
+    pub const fn new() -> Self {
+        Self { thresholds: [Threshold::NONE; 4], states: [State::Normal; 4] }
+    }
+
+    pub fn threshold(&self, metric: Metric) -> Threshold {
+        self.thresholds[metric.index()]
+    }
+
+    /// Replaces the threshold of `metric`. Its alarm starts over, so an
+    /// active one is cleared, which is returned without a `value`.
+    pub fn set_threshold(&mut self, metric: Metric, threshold: Threshold) -> Option<AlarmChange> {
+        let previous = core::mem::replace(&mut self.thresholds[metric.index()], threshold);
+        match core::mem::replace(&mut self.states[metric.index()], State::Normal) {
+            State::Active(level) => {
+                Some(AlarmChange { metric, level, raised: false, value: None, threshold: previous.bound(level) })
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks a reading taken at `now_ms`, returning the alarms it raised or
+    /// cleared.
+    pub fn update(&mut self, reading: &SensorReading, now_ms: u64) -> Vec<AlarmChange, 4> {
+        let mut changes = Vec::new();
+        for metric in Metric::ALL {
+            if let Some(change) = metric.value(reading).and_then(|value| self.check(metric, value, now_ms)) {
+                changes.push(change).ok();
+            }
+        }
+        changes
+    }
+
+    fn check(&mut self, metric: Metric, value: f32, now_ms: u64) -> Option<AlarmChange> {
+        if value.is_nan() {
+            return None;
+        }
+        let threshold = self.thresholds[metric.index()];
+        let state = &mut self.states[metric.index()];
+        let change = |level, raised| AlarmChange { metric, level, raised, value: Some(value), threshold: threshold.bound(level) };
+
+        match *state {
+            State::Active(level) => {
+                if threshold.cleared(level, value) {
+                    *state = State::Normal;
+                    return Some(change(level, false));
+                }
+                None
+            }
+            State::Normal | State::Pending { .. } => {
+                let Some(level) = threshold.exceeded(value) else {
+                    *state = State::Normal;
+                    return None;
+                };
+                let since_ms = match *state {
+                    State::Pending { level: pending, since_ms } if pending == level => since_ms,
+                    _ => now_ms,
+                };
+                if now_ms.saturating_sub(since_ms) >= u64::from(threshold.min_duration_s) * 1000 {
+                    *state = State::Active(level);
+                    Some(change(level, true))
+                } else {
+                    *state = State::Pending { level, since_ms };
+                    None
+                }
+            }
+        }
+    }
+
+    /// The alarms currently raised.
+    pub fn active(&self) -> impl Iterator<Item = (Metric, Level)> + '_ {
+        Metric::ALL.into_iter().filter_map(|metric| match self.states[metric.index()] {
+            State::Active(level) => Some((metric, level)),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temperature(value: f32) -> SensorReading {
+        SensorReading { temperature: value, humidity: 40.0, pressure: 1013.0, gas_resistance: 50_000.0, iaq: None }
+    }
+
+    fn fridge() -> Alarms {
+        let mut alarms = Alarms::new();
+        let threshold = Threshold::parse(br#"{"low":2.0,"high":8.0,"hysteresis":0.5,"min_duration_s":120}"#).unwrap();
+        alarms.set_threshold(Metric::Temperature, threshold);
+        alarms
+    }
+
+    #[test]
+    fn raises_after_min_duration() {
+        let mut alarms = fridge();
+
+        assert!(alarms.update(&temperature(9.0), 0).is_empty());
+        assert!(alarms.update(&temperature(9.5), 60_000).is_empty());
+        let changes = alarms.update(&temperature(9.2), 120_000);
+
+        assert_eq!(
+            changes[..],
+            [AlarmChange { metric: Metric::Temperature, level: Level::High, raised: true, value: Some(9.2), threshold: 8.0 }]
+        );
+        assert_eq!(alarms.active().collect::<std::vec::Vec<_>>(), [(Metric::Temperature, Level::High)]);
+    }
+
+    #[test]
+    fn short_excursion_is_ignored() {
+        let mut alarms = fridge();
+
+        alarms.update(&temperature(9.0), 0);
+        alarms.update(&temperature(7.0), 60_000);
+        // the timer starts over
+        assert!(alarms.update(&temperature(9.0), 120_000).is_empty());
+        assert!(alarms.update(&temperature(9.0), 180_000).is_empty());
+        assert_eq!(alarms.update(&temperature(9.0), 240_000).len(), 1);
+    }
+
+    #[test]
+    fn clears_with_hysteresis() {
+        let mut alarms = fridge();
+        alarms.update(&temperature(1.0), 0);
+        assert!(alarms.update(&temperature(1.0), 120_000)[0].raised);
+
+        assert!(alarms.update(&temperature(2.3), 180_000).is_empty());
+        let changes = alarms.update(&temperature(2.6), 240_000);
+
+        assert_eq!(
+            changes[..],
+            [AlarmChange { metric: Metric::Temperature, level: Level::Low, raised: false, value: Some(2.6), threshold: 2.0 }]
+        );
+        assert_eq!(alarms.active().count(), 0);
+    }
+
+    #[test]
+    fn without_min_duration_raises_at_once() {
+        let mut alarms = Alarms::new();
+        alarms.set_threshold(Metric::Humidity, Threshold { high: Some(70.0), ..Threshold::NONE });
+
+        let changes = alarms.update(&SensorReading { humidity: 75.0, ..temperature(20.0) }, 0);
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].metric, Metric::Humidity);
+    }
+
+    #[test]
+    fn iaq_is_skipped_during_burn_in() {
+        let mut alarms = Alarms::new();
+        alarms.set_threshold(Metric::Iaq, Threshold { high: Some(150.0), ..Threshold::NONE });
+
+        assert!(alarms.update(&temperature(20.0), 0).is_empty());
+        let reading = SensorReading { iaq: Some(crate::iaq::Iaq(200)), ..temperature(20.0) };
+        assert_eq!(alarms.update(&reading, 60_000).len(), 1);
+    }
+
+    #[test]
+    fn new_threshold_clears_active_alarm() {
+        let mut alarms = fridge();
+        alarms.update(&temperature(1.0), 0);
+        alarms.update(&temperature(1.0), 120_000);
+
+        let cleared = alarms.set_threshold(Metric::Temperature, Threshold::NONE).unwrap();
+
+        assert!(!cleared.raised);
+        assert_eq!((cleared.level, cleared.value, cleared.threshold), (Level::Low, None, 2.0));
+        assert_eq!(alarms.active().count(), 0);
+        assert!(alarms.update(&temperature(-20.0), 180_000).is_empty());
+    }
+
+    #[test]
+    fn parse_threshold() {
+        assert_eq!(Threshold::parse(b""), Ok(Threshold::NONE));
+        assert_eq!(
+            Threshold::parse(br#"{"high":8.0}"#),
+            Ok(Threshold { high: Some(8.0), ..Threshold::NONE })
+        );
+        assert_eq!(Threshold::parse(br#"{"high":"8"}"#), Err(ThresholdError::Malformed));
+        assert_eq!(Threshold::parse(br#"{"max":8.0}"#), Err(ThresholdError::Malformed));
+        assert_eq!(Threshold::parse(br#"{"low":8.0,"high":2.0}"#), Err(ThresholdError::InvalidBounds));
+        assert_eq!(
+            Threshold::parse(br#"{"low":2.0,"high":3.0,"hysteresis":1.0}"#),
+            Err(ThresholdError::InvalidBounds)
+        );
+        assert_eq!(Threshold::parse(br#"{"high":8.0,"hysteresis":-1.0}"#), Err(ThresholdError::InvalidBounds));
+        assert_eq!(
+            Threshold::parse(br#"{"high":8.0,"min_duration_s":100000}"#),
+            Err(ThresholdError::DurationOutOfRange)
+        );
+    }
+
+    #[test]
+    fn threshold_round_trips() {
+        let threshold = Threshold { low: Some(2.0), high: None, hysteresis: 0.5, min_duration_s: 60 };
+
+        assert_eq!(threshold.to_json(), r#"{"low":2.0,"hysteresis":0.5,"min_duration_s":60}"#);
+        assert_eq!(Threshold::parse(threshold.to_json().as_bytes()), Ok(threshold));
+    }
+
+    #[test]
+    fn topics() {
+        assert_eq!(set_topic_metric("espbox/alarm/temperature/set"), Some(Metric::Temperature));
+        assert_eq!(set_topic_metric("espbox/alarm/iaq/set"), Some(Metric::Iaq));
+        assert_eq!(set_topic_metric("espbox/alarm/gas/set"), None);
+        assert_eq!(set_topic_metric("espbox/alarm/temperature"), None);
+        assert_eq!(alarm_topic(Metric::Humidity, "/config"), "espbox/alarm/humidity/config");
+    }
+
+    #[test]
+    fn alarm_document() {
+        let change = AlarmChange { metric: Metric::Temperature, level: Level::High, raised: true, value: Some(9.25), threshold: 8.0 };
+
+        assert_eq!(change.topic(), "espbox/alarm/temperature");
+        assert_eq!(
+            alarm_json(120_000, &change),
+            r#"{"ts_ms":120000,"state":"raised","level":"high","value":9.25,"threshold":8.0}"#
+        );
+
+        let cleared = AlarmChange { raised: false, value: None, ..change };
+        assert_eq!(
+            alarm_json(180_000, &cleared),
+            r#"{"ts_ms":180000,"state":"cleared","level":"high","value":null,"threshold":8.0}"#
+        );
+    }
+}
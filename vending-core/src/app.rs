@@ -0,0 +1,863 @@
+//! The vending machine's state and what the sensor, the touch screen and the
+//! broker do to it.
+//!
+//! [`App`] holds everything that outlives a single reading: the inventory,
+//! the alarms, the history, the offline queue and the sensor's settings and
+//! filters. The firmware keeps it in a static its tasks share and the
+//! simulator owns one; both reach it through [`Shared`]. The tasks around it
+//! only wait for the hardware and hand over what they got:
+//! [`App::record_reading`] and [`App::record_fault`] take the sensor's
+//! results, [`App::command`] a message from the broker, [`App::purchase`] a
+//! tap on a buy button, and [`drain`] publishes the events they queued.
+//!
+//! Times are milliseconds since boot.
+
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+    ops::Range,
+    time::Duration,
+};
+
+use heapless::{String, Vec};
+
+use crate::{
+    alarm::{self, AlarmChange, Alarms, Metric, Threshold},
+    catalog::{Catalog, Item, ItemId, Stock},
+    command::{self, Ack},
+    connection::Backoff,
+    derived::{DerivedMetrics, STANDARD_SEA_LEVEL_PRESSURE},
+    event::{self, Event, EventKind, PURCHASE_TOPIC, SENSOR_STATUS_TOPIC},
+    filter::{Filter, Pipeline, ReadingFilters},
+    history::SensorHistory,
+    iaq::AirQuality,
+    publish::{publish_all, Message, Outcome, Publisher, QoS},
+    queue::{OfflineQueue, OverflowPolicy},
+    sensor::{SensorFault, SensorHealth, SensorReading},
+    sensor_config::{SensorConfig, CONFIG_ACK_TOPIC, CONFIG_SET_TOPIC, CONFIG_TOPIC},
+    telemetry::{Telemetry, TelemetryFormat, MAX_DOCUMENT_LEN, TELEMETRY_TOPIC},
+    ui::AlarmBanner,
+    vending::{Purchase, PurchaseError, VendingMachine},
+};
+
+pub const CATALOG_CAPACITY: usize = 8;
+
+/// Products offered at boot, shown three to an inventory page.
+pub const PRODUCTS: [Item; 3] = [
+    Item::new("Hotdog", 2.50, 10),
+    Item::new("Sandwich", 3.50, 9),
+    Item::new("Energy Drink", 2.00, 11),
+];
+
+/// Events kept while the broker is unreachable.
+pub const OFFLINE_QUEUE_CAPACITY: usize = 128;
+/// Five minutes of heater operation before the gas baseline is taken.
+pub const IAQ_BURN_IN_SAMPLES: u32 = 5;
+/// Retries of a faulty sensor, at most one per sample interval.
+pub const SENSOR_RETRY_BACKOFF: Backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+pub const SENSOR_TOPICS: [&str; 4] =
+    ["espbox/sensor/Temperature", "espbox/sensor/Pressure", "espbox/sensor/Humidity", "espbox/sensor/Gas"];
+pub const DERIVED_TOPICS: [&str; 4] = [
+    "espbox/sensor/DewPoint",
+    "espbox/sensor/HeatIndex",
+    "espbox/sensor/AbsoluteHumidity",
+    "espbox/sensor/Altitude",
+];
+/// The unfiltered values of [`SENSOR_TOPICS`].
+pub const RAW_SENSOR_TOPICS: [&str; 4] = [
+    "espbox/sensor/raw/Temperature",
+    "espbox/sensor/raw/Pressure",
+    "espbox/sensor/raw/Humidity",
+    "espbox/sensor/raw/Gas",
+];
+pub const IAQ_TOPIC: &str = "espbox/sensor/IAQ";
+/// When the values on the other topics were measured.
+pub const TIMESTAMP_TOPIC: &str = "espbox/sensor/Timestamp";
+
+/// Messages of one event at most: the sensor values, raw and filtered, the
+/// derived metrics, IAQ, timestamp and one stock count per item in per-topic
+/// mode.
+pub const PUBLISH_BATCH_SIZE: usize =
+    SENSOR_TOPICS.len() + RAW_SENSOR_TOPICS.len() + DERIVED_TOPICS.len() + 2 + CATALOG_CAPACITY;
+/// Bytes of the topics and payloads of one event, a full length topic and
+/// number for each message.
+pub const BATCH_BYTES: usize = PUBLISH_BATCH_SIZE * (64 + 32);
+
+const _: () = assert!(BATCH_BYTES >= 64 + MAX_DOCUMENT_LEN, "a telemetry document doesn't fit a batch");
+
+macro_rules! log {
+    ($app:expr, $($arg:tt)*) => {
+        ($app.log)(format_args!($($arg)*))
+    };
+}
+
+/// Something the screen has to show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Update {
+    /// The stock or prices changed.
+    Inventory,
+    Reading(SensorReading),
+    SensorFault(SensorFault),
+    AlarmBanner(Option<AlarmBanner>),
+}
+
+/// The updates of one call: a reading and the alarm banner at most.
+pub type Updates = Vec<Update, 2>;
+
+/// What [`App::command`] did with a message.
+#[derive(Debug, Default)]
+pub struct Handled {
+    pub updates: Updates,
+    /// Topic and payload of the answer, published right away instead of
+    /// queued.
+    pub ack: Option<(String<64>, String<96>)>,
+    /// Other sensor settings were requested, see [`App::requested_config`].
+    pub config_requested: bool,
+    /// The stock changed and should be saved.
+    pub stock_changed: bool,
+}
+
+/// An [`App`] that other tasks may use too.
+pub trait Shared<T> {
+    /// Runs `f` with exclusive access, which `f` mustn't ask for again.
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R;
+}
+
+impl<T> Shared<T> for RefCell<T> {
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.borrow_mut())
+    }
+}
+
+/// For a static shared with interrupts; keep `f` short.
+impl<T> Shared<T> for critical_section::Mutex<RefCell<T>> {
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        critical_section::with(|cs| f(&mut self.borrow(cs).borrow_mut()))
+    }
+}
+
+fn ignore(_: fmt::Arguments<'_>) {}
+
+pub struct App {
+    pub machine: VendingMachine<CATALOG_CAPACITY>,
+    pub alarms: Alarms,
+    pub history: SensorHistory,
+    pub queue: OfflineQueue<Event, OFFLINE_QUEUE_CAPACITY>,
+    pub filters: ReadingFilters,
+    pub telemetry_format: TelemetryFormat,
+    /// Current sea-level pressure in hPa, for the altitude.
+    pub sea_level_pressure: f32,
+    /// Where the app says what it did, nowhere by default.
+    pub log: fn(fmt::Arguments<'_>),
+    /// The settings the sensor runs with.
+    config: SensorConfig,
+    /// Settings requested over MQTT, applied between two measurements.
+    requested_config: SensorConfig,
+    /// The settings last published, so initialising the sensor again doesn't
+    /// publish them again.
+    reported_config: Option<SensorConfig>,
+    air_quality: AirQuality,
+    health: SensorHealth,
+    sequence: u32,
+}
+
+impl App {
+    /// An empty machine whose readings aren't filtered, to be configured
+    /// before the tasks start.
+    pub const fn new() -> Self {
+        Self {
+            machine: VendingMachine::new(),
+            alarms: Alarms::new(),
+            history: SensorHistory::new(),
+            queue: OfflineQueue::new(OverflowPolicy::DropOldest),
+            filters: ReadingFilters {
+                temperature: Pipeline::new(),
+                humidity: Pipeline::new(),
+                pressure: Pipeline::new(),
+                gas_resistance: Pipeline::new(),
+            },
+            telemetry_format: TelemetryFormat::PerTopic,
+            sea_level_pressure: STANDARD_SEA_LEVEL_PRESSURE,
+            log: ignore,
+            config: SensorConfig::DEFAULT,
+            requested_config: SensorConfig::DEFAULT,
+            reported_config: None,
+            air_quality: AirQuality::new(IAQ_BURN_IN_SAMPLES),
+            health: SensorHealth::new(SENSOR_RETRY_BACKOFF),
+            sequence: 0,
+        }
+    }
+
+    pub fn catalog(&self) -> &Catalog<CATALOG_CAPACITY> {
+        self.machine.catalog()
+    }
+
+    /// The settings the sensor runs with.
+    pub fn config(&self) -> SensorConfig {
+        self.config
+    }
+
+    /// The settings to run the sensor with from the next measurement on.
+    pub fn requested_config(&self) -> SensorConfig {
+        self.requested_config
+    }
+
+    pub fn health(&self) -> &SensorHealth {
+        &self.health
+    }
+
+    /// The settings to initialise the sensor with, before it's started.
+    pub fn set_config(&mut self, config: SensorConfig) {
+        self.config = config;
+        self.requested_config = config;
+    }
+
+    /// The sensor was initialised with `config`, again after a bus fault. It
+    /// may have lost power, so the filters don't smooth across the gap.
+    pub fn sensor_started(&mut self, config: SensorConfig, now_ms: u64) {
+        self.filters.reset();
+        self.config = config;
+        if self.reported_config != Some(config) {
+            self.report_config(now_ms);
+        }
+    }
+
+    /// The sensor runs with `config` from the next measurement on.
+    pub fn apply_config(&mut self, config: SensorConfig, now_ms: u64) {
+        log!(self, "Sensor config {}", config.to_json());
+        if !config.same_heater_profile(&self.config) {
+            // the gas resistance isn't comparable to the old baseline
+            self.air_quality = AirQuality::new(IAQ_BURN_IN_SAMPLES);
+            self.filters.gas_resistance.reset();
+        }
+        self.config = config;
+        self.requested_config = config;
+        self.report_config(now_ms);
+    }
+
+    /// The requested settings couldn't be applied, the sensor keeps running
+    /// with the ones it had, which are published again.
+    pub fn reject_config(&mut self, now_ms: u64) {
+        self.requested_config = self.config;
+        self.report_config(now_ms);
+    }
+
+    /// Records a failed measurement, returning how long to wait before the
+    /// next attempt.
+    pub fn record_fault(&mut self, fault: SensorFault, now_ms: u64) -> Duration {
+        let previous = self.health.current_fault();
+        let retry_in = self.health.fault(fault);
+        log!(
+            self,
+            "Sensor fault: {} ({} in a row), retrying in {} ms",
+            fault.as_str(),
+            self.health.consecutive_faults(),
+            retry_in.as_millis()
+        );
+        if previous != Some(fault) {
+            self.queue_event(Event::sensor_status(now_ms, Some(fault), self.health.consecutive_faults()));
+        }
+        retry_in
+    }
+
+    /// Filters `raw`, checks the alarms, records the history and queues the
+    /// reading with the stock of the moment.
+    pub fn record_reading(&mut self, raw: &SensorReading, now_ms: u64) -> Updates {
+        if self.health.recovered() {
+            log!(self, "Sensor recovered");
+            self.queue_event(Event::sensor_status(now_ms, None, 0));
+        }
+        let mut reading = self.filters.apply(raw);
+        reading.iaq = self.air_quality.update(reading.gas_resistance, reading.humidity);
+
+        let mut updates = Updates::new();
+        updates.push(Update::Reading(reading)).ok();
+        let changes = self.alarms.update(&reading, now_ms);
+        updates.extend(self.report_alarms(&changes, now_ms));
+        self.history.record(&reading, now_ms);
+
+        self.sequence = self.sequence.wrapping_add(1);
+        let stock = Stock::of(self.catalog());
+        self.queue_event(Event::reading(now_ms, self.sequence, reading, *raw, stock));
+        updates
+    }
+
+    /// Sells one of `id`, as a tap on its buy button does.
+    pub fn purchase(&mut self, id: ItemId, now_ms: u64) -> Result<Purchase, PurchaseError> {
+        let result = self.machine.purchase(id);
+        match result {
+            Ok(purchase) => {
+                let item = self.catalog().items()[id].clone();
+                log!(self, "{} bought!", item.name);
+                self.queue_event(Event::purchase(now_ms, &item, &purchase));
+            }
+            Err(PurchaseError::OutOfStock) => {}
+            Err(e) => log!(self, "Purchase failed: {:?}", e),
+        }
+        result
+    }
+
+    /// Applies `threshold` to the alarm of `metric`.
+    pub fn set_alarm_threshold(&mut self, metric: Metric, threshold: Threshold, now_ms: u64) -> Updates {
+        let cleared = self.alarms.set_threshold(metric, threshold);
+        self.queue_event(Event::alarm_threshold(now_ms, metric, threshold));
+        cleared.and_then(|change| self.report_alarms(&[change], now_ms)).into_iter().collect()
+    }
+
+    /// Handles a message received on `topic`: a set command for the
+    /// inventory, an alarm or the sensor settings.
+    pub fn command(&mut self, topic: &str, payload: &[u8], now_ms: u64) -> Handled {
+        let mut handled = Handled::default();
+
+        if topic == CONFIG_SET_TOPIC {
+            match self.requested_config.update(payload) {
+                // applied by whoever drives the sensor, which publishes it then
+                Ok(config) => {
+                    log!(self, "Requested sensor config {}", config.to_json());
+                    self.requested_config = config;
+                    handled.config_requested = true;
+                }
+                Err(e) => {
+                    log!(self, "Rejected sensor config: {}", e.as_str());
+                    let ack = Ack { status: "rejected", error: Some(e.as_str()), amount: None, price: None };
+                    handled.ack = Some((String::try_from(CONFIG_ACK_TOPIC).unwrap(), ack.to_json()));
+                }
+            }
+        } else if let Some(metric) = alarm::set_topic_metric(topic) {
+            match Threshold::parse(payload) {
+                // published on espbox/alarm/<metric>/config with the queued events
+                Ok(threshold) => {
+                    log!(self, "{} alarm threshold {}", metric.name(), threshold.to_json());
+                    handled.updates = self.set_alarm_threshold(metric, threshold, now_ms);
+                }
+                Err(e) => {
+                    log!(self, "Rejected {} alarm threshold: {}", metric.name(), e.as_str());
+                    let ack = Ack { status: "rejected", error: Some(e.as_str()), amount: None, price: None };
+                    handled.ack = Some((alarm::alarm_topic(metric, "/ack"), ack.to_json()));
+                }
+            }
+        } else {
+            let Some(ack_topic) = command::set_topic_item(topic).and_then(command::ack_topic) else {
+                log!(self, "Ignoring command on {}: not an inventory command", topic);
+                return handled;
+            };
+            match command::handle_set(self.machine.catalog_mut(), topic, payload) {
+                Ok(id) => {
+                    let item = &self.catalog().items()[id];
+                    log!(self, "{} set to {} at {:.2}", item.name, item.amount, item.price);
+                    handled.ack = Some((ack_topic, Ack::applied(item).to_json()));
+                    handled.updates.push(Update::Inventory).ok();
+                    handled.stock_changed = true;
+                }
+                Err(e) => {
+                    log!(self, "Rejected command on {}: {}", topic, e.as_str());
+                    handled.ack = Some((ack_topic, Ack::rejected(e).to_json()));
+                }
+            }
+        }
+        handled
+    }
+
+    /// Publishes alarms that were raised or cleared, returning the banner
+    /// that shows the ones still active.
+    fn report_alarms(&mut self, changes: &[AlarmChange], now_ms: u64) -> Option<Update> {
+        if changes.is_empty() {
+            return None;
+        }
+        for change in changes {
+            let state = if change.raised { "raised" } else { "cleared" };
+            match change.value {
+                Some(value) => log!(
+                    self,
+                    "Alarm {} {} {}: {:.2} (threshold {:.2})",
+                    change.metric.name(),
+                    change.level.as_str(),
+                    state,
+                    value,
+                    change.threshold
+                ),
+                None => log!(
+                    self,
+                    "Alarm {} {} {} by a new threshold",
+                    change.metric.name(),
+                    change.level.as_str(),
+                    state
+                ),
+            }
+            self.queue_event(Event::alarm(now_ms, *change));
+        }
+        Some(Update::AlarmBanner(AlarmBanner::from_active(self.alarms.active())))
+    }
+
+    fn report_config(&mut self, now_ms: u64) {
+        self.queue_event(Event::sensor_config(now_ms, self.config));
+        self.reported_config = Some(self.config);
+    }
+
+    fn queue_event(&mut self, event: Event) {
+        if !self.queue.push(event) {
+            log!(self, "Offline queue full, dropped {:?}", event.kind);
+        }
+    }
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Entry {
+    topic: Range<usize>,
+    payload: Range<usize>,
+    retain: bool,
+}
+
+/// A message didn't fit the [`Batch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchFull;
+
+/// The messages of one event, their topics and payloads in one buffer.
+pub struct Batch {
+    bytes: Vec<u8, BATCH_BYTES>,
+    entries: Vec<Entry, PUBLISH_BATCH_SIZE>,
+}
+
+impl Batch {
+    pub const fn new() -> Self {
+        Self { bytes: Vec::new(), entries: Vec::new() }
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.entries.clear();
+    }
+
+    /// Adds an at-least-once message.
+    pub fn push(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), BatchFull> {
+        let start = self.bytes.len();
+        let entry = Entry {
+            topic: start..start + topic.len(),
+            payload: start + topic.len()..start + topic.len() + payload.len(),
+            retain,
+        };
+        let pushed = self.bytes.extend_from_slice(topic.as_bytes()).and_then(|()| self.bytes.extend_from_slice(payload));
+        if pushed.is_err() || self.entries.push(entry).is_err() {
+            self.bytes.truncate(start);
+            return Err(BatchFull);
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn messages(&self) -> Vec<Message<'_>, PUBLISH_BATCH_SIZE> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let topic = core::str::from_utf8(&self.bytes[entry.topic.clone()]).unwrap_or_default();
+                Message::new(topic, &self.bytes[entry.payload.clone()], QoS::AtLeastOnce, entry.retain)
+            })
+            .collect()
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What [`drain`] got through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Drained {
+    /// Messages the broker took.
+    pub sent: usize,
+    /// The session has to be reconnected; the events that weren't published
+    /// stay queued.
+    pub connection_lost: bool,
+    /// Stopped before the next event to handle what came in; drain again
+    /// afterwards.
+    pub interrupted: bool,
+    /// The broker didn't acknowledge a message, so its event stays queued for
+    /// the next drain.
+    pub unacknowledged: bool,
+}
+
+/// Publishes the queued events oldest first. `app` is only borrowed to take
+/// the next event and to complete it, never while publishing.
+pub async fn drain<A: Shared<App>, P: Publisher>(app: &A, publisher: &mut P) -> Drained
+where
+    P::Error: fmt::Debug,
+{
+    let mut drained = Drained::default();
+    let mut batch = Batch::new();
+
+    loop {
+        let next = app.with(|app| {
+            let event = *app.queue.front()?;
+            Some((event, app.catalog().clone(), app.telemetry_format, app.sea_level_pressure, app.log))
+        });
+        let Some((event, catalog, telemetry_format, sea_level_pressure, log)) = next else {
+            return drained;
+        };
+        if publisher.incoming_pending() {
+            drained.interrupted = true;
+            return drained;
+        }
+
+        batch.clear();
+        event_messages(&event, &catalog, telemetry_format, sea_level_pressure, log, &mut batch);
+        let messages = batch.messages();
+        let report = publish_all::<_, PUBLISH_BATCH_SIZE>(publisher, &messages).await;
+        for (message, outcome) in messages.iter().zip(report.outcomes()) {
+            if let Outcome::Failed(e) = outcome {
+                log(format_args!("Publishing to {} failed: {:?}", message.topic, e));
+            }
+        }
+        drained.sent += report.sent();
+        if report.connection_lost() {
+            drained.connection_lost = true;
+            return drained;
+        }
+        let unacknowledged = |outcome: &Outcome<P::Error>| matches!(outcome, Outcome::Failed(e) if !P::is_refused(e));
+        if report.outcomes().iter().any(unacknowledged) {
+            // publishing it again right away would likely go the same way
+            drained.unacknowledged = true;
+            return drained;
+        }
+        // messages the broker refused won't go through on a retry either
+        app.with(|app| app.queue.complete(&event));
+    }
+}
+
+/// Fills `batch` with what's published for `event` in `telemetry_format`,
+/// logging events that can't be.
+fn event_messages(
+    event: &Event,
+    catalog: &Catalog<CATALOG_CAPACITY>,
+    telemetry_format: TelemetryFormat,
+    sea_level_pressure: f32,
+    log: fn(fmt::Arguments<'_>),
+    batch: &mut Batch,
+) {
+    let number = |value: f32| {
+        let mut payload: String<32> = String::new();
+        write!(payload, "{:.2}", value).ok();
+        payload
+    };
+
+    match event.kind {
+        EventKind::Reading { reading, raw, stock, .. } if telemetry_format == TelemetryFormat::PerTopic => {
+            let values = |reading: &SensorReading| [reading.temperature, reading.pressure, reading.humidity, reading.gas_resistance];
+            let derived = DerivedMetrics::from_reading(&reading, sea_level_pressure);
+            let derived = [derived.dew_point, derived.heat_index, derived.absolute_humidity, derived.altitude];
+
+            for (topic, value) in SENSOR_TOPICS.iter().zip(values(&reading)) {
+                batch.push(topic, number(value).as_bytes(), true).ok();
+            }
+            for (topic, value) in RAW_SENSOR_TOPICS.iter().zip(values(&raw)) {
+                batch.push(topic, number(value).as_bytes(), true).ok();
+            }
+            for (topic, value) in DERIVED_TOPICS.iter().zip(derived) {
+                batch.push(topic, number(value).as_bytes(), true).ok();
+            }
+            if let Some(iaq) = reading.iaq {
+                let mut payload: String<8> = String::new();
+                write!(payload, "{}", iaq.0).ok();
+                batch.push(IAQ_TOPIC, payload.as_bytes(), true).ok();
+            }
+            // the stock when the reading was taken, not now
+            for (item, amount) in catalog.items().iter().zip(stock.amounts()) {
+                let mut topic: String<64> = String::new();
+                write!(topic, "espbox/inventory/{}", item.topic_name()).ok();
+                let mut payload: String<12> = String::new();
+                write!(payload, "{}", amount).ok();
+                batch.push(&topic, payload.as_bytes(), true).ok();
+            }
+            let mut timestamp: String<24> = String::new();
+            write!(timestamp, "{}", event.timestamp_ms).ok();
+            batch.push(TIMESTAMP_TOPIC, timestamp.as_bytes(), true).ok();
+        }
+        EventKind::Reading { sequence, reading, raw, stock } => {
+            let mut catalog = catalog.clone();
+            catalog.set_amounts(stock.amounts());
+            let derived = DerivedMetrics::from_reading(&reading, sea_level_pressure);
+            let telemetry = Telemetry { sequence, uptime_ms: event.timestamp_ms, reading, raw, derived, catalog: &catalog };
+            let mut document = [0u8; MAX_DOCUMENT_LEN];
+            match telemetry.encode(telemetry_format, &mut document) {
+                Ok(len) => {
+                    batch.push(TELEMETRY_TOPIC, &document[..len], false).ok();
+                }
+                Err(e) => log(format_args!("Skipping telemetry #{}: {:?}", sequence, e)),
+            }
+        }
+        EventKind::Purchase { .. } => match event::purchase_json(event) {
+            Some(payload) => {
+                batch.push(PURCHASE_TOPIC, payload.as_bytes(), false).ok();
+            }
+            None => log(format_args!("Skipping purchase: document too large")),
+        },
+        EventKind::SensorStatus { .. } => match event::sensor_status_json(event) {
+            Some(payload) => {
+                batch.push(SENSOR_STATUS_TOPIC, payload.as_bytes(), true).ok();
+            }
+            None => log(format_args!("Skipping sensor status: document too large")),
+        },
+        EventKind::Alarm(change) => {
+            let payload = alarm::alarm_json(event.timestamp_ms, &change);
+            batch.push(&change.topic(), payload.as_bytes(), true).ok();
+        }
+        EventKind::AlarmThreshold { metric, threshold } => {
+            let topic = alarm::alarm_topic(metric, "/config");
+            batch.push(&topic, threshold.to_json().as_bytes(), true).ok();
+        }
+        EventKind::SensorConfig(config) => {
+            batch.push(CONFIG_TOPIC, config.to_json().as_bytes(), true).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use embassy_futures::block_on;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum BrokerError {
+        ConnectionLost,
+        NoPuback,
+    }
+
+    /// Takes everything until it's told to lose the connection, to not
+    /// acknowledge a message or to send a command.
+    #[derive(Default)]
+    struct Broker {
+        published: std::vec::Vec<(std::string::String, std::vec::Vec<u8>)>,
+        lose_after: Option<usize>,
+        no_puback_after: Option<usize>,
+        command_after: Option<usize>,
+    }
+
+    impl Publisher for Broker {
+        type Error = BrokerError;
+
+        async fn publish(&mut self, message: &Message<'_>) -> Result<(), BrokerError> {
+            if self.lose_after == Some(self.published.len()) {
+                return Err(BrokerError::ConnectionLost);
+            }
+            if self.no_puback_after == Some(self.published.len()) {
+                self.no_puback_after = None;
+                return Err(BrokerError::NoPuback);
+            }
+            self.published.push((message.topic.into(), message.payload.into()));
+            Ok(())
+        }
+
+        fn is_connection_lost(error: &BrokerError) -> bool {
+            *error == BrokerError::ConnectionLost
+        }
+
+        fn is_refused(_: &BrokerError) -> bool {
+            false
+        }
+
+        fn incoming_pending(&mut self) -> bool {
+            self.command_after.take_if(|after| *after == self.published.len()).is_some()
+        }
+    }
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.machine = VendingMachine::with_catalog(Catalog::from_items(&PRODUCTS).unwrap());
+        app
+    }
+
+    fn raw() -> SensorReading {
+        SensorReading { temperature: 22.5, humidity: 40.0, pressure: 1013.0, gas_resistance: 50_000.0, iaq: None }
+    }
+
+    #[test]
+    fn a_reading_is_queued_with_the_stock_of_the_moment() {
+        let mut app = app();
+        app.purchase(0, 5).unwrap();
+
+        let updates = app.record_reading(&raw(), 10);
+
+        assert!(matches!(updates[..], [Update::Reading(reading)] if reading.temperature == 22.5));
+        let purchase = *app.queue.front().unwrap();
+        app.queue.complete(&purchase);
+        let Some(Event { kind: EventKind::Reading { sequence: 1, stock, .. }, .. }) = app.queue.front().copied() else {
+            panic!("no reading queued");
+        };
+        assert_eq!(stock.amounts(), [9, 9, 11]);
+    }
+
+    #[test]
+    fn only_a_new_fault_is_queued() {
+        let mut app = app();
+
+        app.record_fault(SensorFault::Timeout, 0);
+        app.record_fault(SensorFault::Timeout, 1_000);
+        assert_eq!(app.queue.len(), 1);
+
+        app.record_reading(&raw(), 2_000);
+        // the recovery and the reading
+        assert_eq!(app.queue.len(), 3);
+        assert_eq!(app.health().current_fault(), None);
+    }
+
+    #[test]
+    fn sensor_config_is_requested_then_applied() {
+        let mut app = app();
+
+        let handled = app.command(CONFIG_SET_TOPIC, br#"{"heater_temperature": 300}"#, 0);
+
+        assert!(handled.config_requested);
+        assert_eq!(app.requested_config().heater_temperature, 300);
+        assert_eq!(app.config(), SensorConfig::DEFAULT);
+        assert!(app.queue.is_empty());
+
+        app.apply_config(app.requested_config(), 10);
+        assert_eq!(app.config().heater_temperature, 300);
+        assert_eq!(app.queue.len(), 1);
+    }
+
+    #[test]
+    fn rejected_config_is_taken_back() {
+        let mut app = app();
+        app.command(CONFIG_SET_TOPIC, br#"{"heater_temperature": 300}"#, 0);
+
+        app.reject_config(10);
+
+        assert_eq!(app.requested_config(), SensorConfig::DEFAULT);
+        assert!(matches!(app.queue.front(), Some(Event { kind: EventKind::SensorConfig(config), .. }) if *config == SensorConfig::DEFAULT));
+    }
+
+    #[test]
+    fn inventory_commands_are_acked() {
+        let mut app = app();
+
+        let handled = app.command("espbox/inventory/Sandwich/set", br#"{"amount": 20}"#, 0);
+        assert!(handled.stock_changed);
+        assert_eq!(handled.updates[..], [Update::Inventory]);
+        let (topic, payload) = handled.ack.unwrap();
+        assert_eq!(topic, "espbox/inventory/Sandwich/ack");
+        assert!(payload.contains("\"ok\""));
+        assert_eq!(app.catalog().items()[1].amount, 20);
+
+        let handled = app.command("espbox/inventory/Nothing/set", br#"{"amount": 20}"#, 0);
+        assert!(!handled.stock_changed);
+        assert!(handled.ack.unwrap().1.contains("rejected"));
+
+        let handled = app.command("espbox/other", b"", 0);
+        assert!(handled.ack.is_none());
+    }
+
+    #[test]
+    fn clearing_an_alarm_updates_the_banner() {
+        let mut app = app();
+        app.set_alarm_threshold(Metric::Temperature, Threshold::parse(br#"{"high": 20}"#).unwrap(), 0);
+        let updates = app.record_reading(&raw(), 10);
+        assert!(matches!(updates[..], [_, Update::AlarmBanner(Some(_))]));
+
+        let handled = app.command("espbox/alarm/temperature/set", br#"{"high": 30}"#, 20);
+
+        assert_eq!(handled.updates[..], [Update::AlarmBanner(None)]);
+    }
+
+    #[test]
+    fn drain_publishes_an_alarm_cleared_by_a_new_threshold() {
+        let app = RefCell::new(app());
+        app.borrow_mut().set_alarm_threshold(Metric::Temperature, Threshold::parse(br#"{"high": 20}"#).unwrap(), 0);
+        app.borrow_mut().record_reading(&raw(), 10);
+        app.borrow_mut().set_alarm_threshold(Metric::Temperature, Threshold::NONE, 20);
+
+        let mut broker = Broker::default();
+        block_on(drain(&app, &mut broker));
+
+        assert!(app.borrow().queue.is_empty());
+        let cleared = broker.published.iter().rfind(|(topic, _)| topic == "espbox/alarm/temperature").unwrap();
+        assert_eq!(cleared.1, br#"{"ts_ms":20,"state":"cleared","level":"high","value":null,"threshold":20.0}"#);
+    }
+
+    #[test]
+    fn drain_publishes_per_topic_readings() {
+        let app = RefCell::new(app());
+        app.borrow_mut().record_reading(&raw(), 1_500);
+
+        let mut broker = Broker::default();
+        let drained = block_on(drain(&app, &mut broker));
+
+        assert_eq!(drained, Drained { sent: 4 + 4 + 4 + 3 + 1, ..Drained::default() });
+        assert!(broker.published.contains(&("espbox/sensor/Temperature".into(), b"22.50".to_vec())));
+        assert!(broker.published.contains(&("espbox/inventory/Sandwich".into(), b"9".to_vec())));
+        assert_eq!(broker.published.last().unwrap(), &("espbox/sensor/Timestamp".into(), b"1500".to_vec()));
+        assert!(app.borrow().queue.is_empty());
+    }
+
+    #[test]
+    fn drain_keeps_what_wasnt_published() {
+        let app = RefCell::new(app());
+        app.borrow_mut().telemetry_format = TelemetryFormat::Json;
+        app.borrow_mut().record_reading(&raw(), 0);
+        app.borrow_mut().record_reading(&raw(), 60_000);
+
+        let mut broker = Broker { lose_after: Some(1), ..Broker::default() };
+        let drained = block_on(drain(&app, &mut broker));
+
+        assert_eq!(drained, Drained { sent: 1, connection_lost: true, ..Drained::default() });
+        assert_eq!(broker.published[0].0, TELEMETRY_TOPIC);
+        assert_eq!(app.borrow().queue.len(), 1);
+    }
+
+    #[test]
+    fn drain_keeps_an_event_without_puback() {
+        let app = RefCell::new(app());
+        app.borrow_mut().telemetry_format = TelemetryFormat::Json;
+        app.borrow_mut().record_reading(&raw(), 0);
+
+        let mut broker = Broker { no_puback_after: Some(0), ..Broker::default() };
+        let drained = block_on(drain(&app, &mut broker));
+
+        assert_eq!(drained, Drained { unacknowledged: true, ..Drained::default() });
+        assert_eq!(app.borrow().queue.len(), 1);
+        // the next drain publishes it again
+        let drained = block_on(drain(&app, &mut broker));
+        assert_eq!(drained, Drained { sent: 1, ..Drained::default() });
+        assert!(app.borrow().queue.is_empty());
+    }
+
+    #[test]
+    fn drain_stops_for_a_command() {
+        let app = RefCell::new(app());
+        app.borrow_mut().telemetry_format = TelemetryFormat::Json;
+        app.borrow_mut().record_reading(&raw(), 0);
+        app.borrow_mut().record_reading(&raw(), 60_000);
+
+        let mut broker = Broker { command_after: Some(1), ..Broker::default() };
+        let drained = block_on(drain(&app, &mut broker));
+
+        assert_eq!(drained, Drained { sent: 1, interrupted: true, ..Drained::default() });
+        assert_eq!(app.borrow().queue.len(), 1);
+        assert_eq!(block_on(drain(&app, &mut broker)).sent, 1);
+    }
+
+    #[test]
+    fn batch_rejects_what_doesnt_fit() {
+        let mut batch = Batch::new();
+        let document = [0u8; BATCH_BYTES];
+
+        assert!(batch.push("a", b"1", true).is_ok());
+        assert!(batch.push("b", &document, false).is_err());
+        assert_eq!(batch.messages()[..], [Message::new("a", b"1", QoS::AtLeastOnce, true)]);
+    }
+}
@@ -0,0 +1,692 @@
+//! Async driver for the BME680, so no register transfer or wait blocks the
+//! executor.
+//!
+//! Every bus transfer goes through [`embedded_hal_async::i2c::I2c`] and every
+//! wait through [`DelayNs`], including the heater profile and the polls for
+//! new data. [`Bme680::measure`] returns the [`RawData`] of a forced
+//! measurement; turning it into a [`SensorReading`] with
+//! [`Calibration::compensate`] is the only synchronous part and takes a few
+//! dozen float operations. The compensation follows the floating point
+//! formulas of Bosch's BME680 API.
+
+use embedded_hal_async::{
+    delay::DelayNs,
+    i2c::{ErrorKind, I2c},
+};
+
+use crate::sensor::{SensorFault, SensorReading};
+use crate::sensor_config::{FilterSize, Oversampling, SensorConfig};
+
+/// Address with SDO pulled low, as on the ESP32-S3-BOX sensor board.
+pub const PRIMARY_ADDRESS: u8 = 0x76;
+pub const SECONDARY_ADDRESS: u8 = 0x77;
+
+const CHIP_ID: u8 = 0x61;
+/// Assumed for the heater resistance, in °C.
+const AMBIENT_TEMPERATURE: f32 = 25.0;
+/// Polls for new data after the profile duration has passed.
+const NEW_DATA_POLLS: u32 = 10;
+const NEW_DATA_POLL_INTERVAL_MS: u32 = 10;
+
+const REG_COEFF3: u8 = 0x00;
+const REG_FIELD0: u8 = 0x1D;
+const REG_RES_HEAT_0: u8 = 0x5A;
+const REG_GAS_WAIT_0: u8 = 0x64;
+const REG_CTRL_GAS_1: u8 = 0x71;
+const REG_CTRL_HUM: u8 = 0x72;
+const REG_CTRL_MEAS: u8 = 0x74;
+const REG_CONFIG: u8 = 0x75;
+const REG_COEFF1: u8 = 0x89;
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_RESET: u8 = 0xE0;
+const REG_COEFF2: u8 = 0xE1;
+
+const SOFT_RESET: u8 = 0xB6;
+const RUN_GAS: u8 = 0x10;
+const FORCED_MODE: u8 = 0x01;
+const NEW_DATA: u8 = 0x80;
+const GAS_VALID: u8 = 0x20;
+const HEATER_STABLE: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    I2c(E),
+    /// Something other than a BME680 answered, with this chip id.
+    UnknownChip(u8),
+    /// The measurement didn't finish in time.
+    NoNewData,
+    /// The gas measurement didn't finish with the heater stable, so its
+    /// resistance means nothing.
+    GasInvalid,
+}
+
+impl<E: embedded_hal_async::i2c::Error> Error<E> {
+    pub fn fault(&self) -> SensorFault {
+        match self {
+            Error::I2c(e) => match e.kind() {
+                ErrorKind::NoAcknowledge(_) => SensorFault::Nack,
+                _ => SensorFault::Bus,
+            },
+            Error::UnknownChip(_) => SensorFault::Bus,
+            Error::NoNewData => SensorFault::Timeout,
+            Error::GasInvalid => SensorFault::GasInvalid,
+        }
+    }
+}
+
+/// ADC values of one measurement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RawData {
+    pub temperature: u32,
+    pub pressure: u32,
+    pub humidity: u16,
+    pub gas_resistance: u16,
+    pub gas_range: u8,
+    /// The gas measurement finished with the heater at its target temperature.
+    pub gas_valid: bool,
+}
+
+impl RawData {
+    fn from_field(field: &[u8; 15]) -> Self {
+        Self {
+            pressure: (field[2] as u32) << 12 | (field[3] as u32) << 4 | (field[4] as u32) >> 4,
+            temperature: (field[5] as u32) << 12 | (field[6] as u32) << 4 | (field[7] as u32) >> 4,
+            humidity: (field[8] as u16) << 8 | field[9] as u16,
+            gas_resistance: (field[13] as u16) << 2 | (field[14] as u16) >> 6,
+            gas_range: field[14] & 0x0F,
+            gas_valid: field[14] & (GAS_VALID | HEATER_STABLE) == GAS_VALID | HEATER_STABLE,
+        }
+    }
+}
+
+/// Factory trimming of one sensor, read once at [`Bme680::init`].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Calibration {
+    t1: f32,
+    t2: f32,
+    t3: f32,
+    p1: f32,
+    p2: f32,
+    p3: f32,
+    p4: f32,
+    p5: f32,
+    p6: f32,
+    p7: f32,
+    p8: f32,
+    p9: f32,
+    p10: f32,
+    h1: f32,
+    h2: f32,
+    h3: f32,
+    h4: f32,
+    h5: f32,
+    h6: f32,
+    h7: f32,
+    gh1: f32,
+    gh2: f32,
+    gh3: f32,
+    res_heat_range: u8,
+    res_heat_val: f32,
+    range_switching_error: f32,
+}
+
+impl Calibration {
+    /// `coeff1` and `coeff2` are the registers from 0x89 and 0xE1, `coeff3`
+    /// the five from 0x00.
+    pub fn from_registers(coeff1: &[u8; 25], coeff2: &[u8; 16], coeff3: &[u8; 5]) -> Self {
+        let mut c = [0u8; 41];
+        c[..25].copy_from_slice(coeff1);
+        c[25..].copy_from_slice(coeff2);
+        let u16_at = |lsb: usize| u16::from_le_bytes([c[lsb], c[lsb + 1]]) as f32;
+        let i16_at = |lsb: usize| i16::from_le_bytes([c[lsb], c[lsb + 1]]) as f32;
+        let i8_at = |index: usize| c[index] as i8 as f32;
+
+        Self {
+            t1: u16_at(33),
+            t2: i16_at(1),
+            t3: i8_at(3),
+            p1: u16_at(5),
+            p2: i16_at(7),
+            p3: i8_at(9),
+            p4: i16_at(11),
+            p5: i16_at(13),
+            p6: i8_at(16),
+            p7: i8_at(15),
+            p8: i16_at(19),
+            p9: i16_at(21),
+            p10: c[23] as f32,
+            h1: ((c[27] as u16) << 4 | (c[26] & 0x0F) as u16) as f32,
+            h2: ((c[25] as u16) << 4 | (c[26] >> 4) as u16) as f32,
+            h3: i8_at(28),
+            h4: i8_at(29),
+            h5: i8_at(30),
+            h6: c[31] as f32,
+            h7: i8_at(32),
+            gh1: i8_at(37),
+            gh2: i16_at(35),
+            gh3: i8_at(38),
+            res_heat_range: (coeff3[2] & 0x30) >> 4,
+            res_heat_val: coeff3[0] as i8 as f32,
+            range_switching_error: ((coeff3[4] as i8) >> 4) as f32,
+        }
+    }
+
+    pub fn compensate(&self, raw: &RawData) -> SensorReading {
+        let t_fine = self.t_fine(raw.temperature);
+        SensorReading {
+            temperature: t_fine / 5120.0,
+            humidity: self.humidity(raw.humidity, t_fine),
+            pressure: self.pressure(raw.pressure, t_fine) / 100.0,
+            gas_resistance: self.gas_resistance(raw.gas_resistance, raw.gas_range),
+            iaq: None,
+        }
+    }
+
+    fn t_fine(&self, adc: u32) -> f32 {
+        let adc = adc as f32;
+        let var1 = (adc / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = (adc / 131072.0 - self.t1 / 8192.0) * (adc / 131072.0 - self.t1 / 8192.0) * (self.t3 * 16.0);
+        var1 + var2
+    }
+
+    /// In Pa.
+    fn pressure(&self, adc: u32, t_fine: f32) -> f32 {
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * (self.p6 / 131072.0);
+        var2 += var1 * self.p5 * 2.0;
+        var2 = var2 / 4.0 + self.p4 * 65536.0;
+        var1 = (self.p3 * var1 * var1 / 16384.0 + self.p2 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * self.p1;
+        if var1 == 0.0 {
+            return 0.0;
+        }
+
+        let mut pressure = 1048576.0 - adc as f32;
+        pressure = (pressure - var2 / 4096.0) * 6250.0 / var1;
+        let var1 = self.p9 * pressure * pressure / 2147483648.0;
+        let var2 = pressure * (self.p8 / 32768.0);
+        let scaled = pressure / 256.0;
+        let var3 = scaled * scaled * scaled * (self.p10 / 131072.0);
+        pressure + (var1 + var2 + var3 + self.p7 * 128.0) / 16.0
+    }
+
+    fn humidity(&self, adc: u16, t_fine: f32) -> f32 {
+        let temperature = t_fine / 5120.0;
+        let var1 = adc as f32 - (self.h1 * 16.0 + self.h3 / 2.0 * temperature);
+        let var2 = var1
+            * (self.h2 / 262144.0
+                * (1.0 + self.h4 / 16384.0 * temperature + self.h5 / 1048576.0 * temperature * temperature));
+        let var3 = self.h6 / 16384.0;
+        let var4 = self.h7 / 2097152.0;
+        (var2 + (var3 + var4 * temperature) * var2 * var2).clamp(0.0, 100.0)
+    }
+
+    /// In Ω.
+    fn gas_resistance(&self, adc: u16, range: u8) -> f32 {
+        const K1: [f32; 16] = [0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, -0.8, 0.0, 0.0, -0.2, -0.5, 0.0, -1.0, 0.0, 0.0];
+        const K2: [f32; 16] = [0.0, 0.0, 0.0, 0.0, 0.1, 0.7, 0.0, -0.8, -0.1, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+
+        let range = (range & 0x0F) as usize;
+        let var1 = 1340.0 + 5.0 * self.range_switching_error;
+        let var2 = var1 * (1.0 + K1[range] / 100.0);
+        let var3 = 1.0 + K2[range] / 100.0;
+        1.0 / (var3 * 0.000000125 * (1u32 << range) as f32 * ((adc as f32 - 512.0) / var2 + 1.0))
+    }
+
+    /// Value of `res_heat_0` that heats the hot plate to `target` °C.
+    fn heater_resistance(&self, target: u16) -> u8 {
+        let target = target.min(400) as f32;
+        let var1 = self.gh1 / 16.0 + 49.0;
+        let var2 = self.gh2 / 32768.0 * 0.0005 + 0.00235;
+        let var3 = self.gh3 / 1024.0;
+        let var4 = var1 * (1.0 + var2 * target);
+        let var5 = var4 + var3 * AMBIENT_TEMPERATURE;
+        let range = 4.0 / (4.0 + self.res_heat_range as f32);
+        let value = 1.0 / (1.0 + self.res_heat_val * 0.002);
+        (3.4 * (var5 * range * value - 25.0)) as u8
+    }
+}
+
+/// Value of `gas_wait_0` for heating `duration_ms`, which the sensor stores
+/// as six bits times a power of four.
+pub fn gas_wait(duration_ms: u16) -> u8 {
+    if duration_ms >= 0xFC0 {
+        return 0xFF;
+    }
+    let mut duration = duration_ms;
+    let mut factor = 0;
+    while duration > 0x3F {
+        duration /= 4;
+        factor += 1;
+    }
+    duration as u8 + factor * 64
+}
+
+/// How long a forced measurement with `config` takes, heating included, in
+/// ms.
+pub fn profile_duration_ms(config: &SensorConfig) -> u32 {
+    let cycles = |oversampling: Oversampling| oversampling.factor() as u32;
+    let measurement_cycles = cycles(config.temperature_oversampling)
+        + cycles(config.pressure_oversampling)
+        + cycles(config.humidity_oversampling);
+
+    // switching between the measurements and the gas measurement, rounded up
+    let tph_us = measurement_cycles * 1963 + 477 * 4 + 477 * 5 + 500;
+    // plus 1 ms to wake up
+    tph_us / 1000 + 1 + config.heater_duration_ms as u32
+}
+
+fn oversampling_bits(oversampling: Oversampling) -> u8 {
+    match oversampling {
+        Oversampling::Skipped => 0,
+        Oversampling::X1 => 1,
+        Oversampling::X2 => 2,
+        Oversampling::X4 => 3,
+        Oversampling::X8 => 4,
+        Oversampling::X16 => 5,
+    }
+}
+
+fn filter_bits(filter: FilterSize) -> u8 {
+    match filter {
+        FilterSize::Size0 => 0,
+        FilterSize::Size1 => 1,
+        FilterSize::Size3 => 2,
+        FilterSize::Size7 => 3,
+        FilterSize::Size15 => 4,
+        FilterSize::Size31 => 5,
+        FilterSize::Size63 => 6,
+        FilterSize::Size127 => 7,
+    }
+}
+
+/// A BME680 on `I2C`, measuring with the latest [`SensorConfig`].
+pub struct Bme680<I2C> {
+    i2c: I2C,
+    address: u8,
+    calibration: Calibration,
+    config: SensorConfig,
+}
+
+impl<I2C: I2c> Bme680<I2C> {
+    /// Resets the sensor, reads its calibration and applies `config`.
+    pub async fn init<D: DelayNs>(
+        i2c: I2C,
+        delay: &mut D,
+        address: u8,
+        config: &SensorConfig,
+    ) -> Result<Self, Error<I2C::Error>> {
+        let mut sensor = Self { i2c, address, calibration: Calibration::default(), config: *config };
+
+        sensor.write(&[REG_RESET, SOFT_RESET]).await?;
+        delay.delay_ms(10).await;
+
+        let mut chip_id = [0];
+        sensor.read(REG_CHIP_ID, &mut chip_id).await?;
+        if chip_id[0] != CHIP_ID {
+            return Err(Error::UnknownChip(chip_id[0]));
+        }
+
+        let mut coeff1 = [0; 25];
+        let mut coeff2 = [0; 16];
+        let mut coeff3 = [0; 5];
+        sensor.read(REG_COEFF1, &mut coeff1).await?;
+        sensor.read(REG_COEFF2, &mut coeff2).await?;
+        sensor.read(REG_COEFF3, &mut coeff3).await?;
+        sensor.calibration = Calibration::from_registers(&coeff1, &coeff2, &coeff3);
+
+        sensor.configure(config).await?;
+        Ok(sensor)
+    }
+
+    /// Applies `config` to the following measurements. The sensor must be
+    /// asleep, which it is between two [`Self::measure`] calls.
+    pub async fn configure(&mut self, config: &SensorConfig) -> Result<(), Error<I2C::Error>> {
+        let heater_resistance = self.calibration.heater_resistance(config.heater_temperature);
+        // the humidity setting takes effect with the write to ctrl_meas after it
+        self.write(&[
+            REG_CTRL_HUM,
+            oversampling_bits(config.humidity_oversampling),
+            REG_CTRL_MEAS,
+            self.ctrl_meas(config, false),
+            REG_CONFIG,
+            filter_bits(config.filter) << 2,
+            REG_GAS_WAIT_0,
+            gas_wait(config.heater_duration_ms),
+            REG_RES_HEAT_0,
+            heater_resistance,
+            REG_CTRL_GAS_1,
+            RUN_GAS,
+        ])
+        .await?;
+
+        self.config = *config;
+        Ok(())
+    }
+
+    /// Runs one forced measurement, waiting on `delay` while the sensor heats
+    /// up and measures.
+    pub async fn measure<D: DelayNs>(&mut self, delay: &mut D) -> Result<RawData, Error<I2C::Error>> {
+        self.write(&[REG_CTRL_MEAS, self.ctrl_meas(&self.config, true)]).await?;
+        delay.delay_ms(profile_duration_ms(&self.config)).await;
+
+        let mut field = [0; 15];
+        for _ in 0..NEW_DATA_POLLS {
+            self.read(REG_FIELD0, &mut field).await?;
+            if field[0] & NEW_DATA != 0 {
+                let raw = RawData::from_field(&field);
+                return if raw.gas_valid { Ok(raw) } else { Err(Error::GasInvalid) };
+            }
+            delay.delay_ms(NEW_DATA_POLL_INTERVAL_MS).await;
+        }
+        Err(Error::NoNewData)
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    fn ctrl_meas(&self, config: &SensorConfig, forced: bool) -> u8 {
+        let mode = if forced { FORCED_MODE } else { 0 };
+        oversampling_bits(config.temperature_oversampling) << 5 | oversampling_bits(config.pressure_oversampling) << 2 | mode
+    }
+
+    async fn write(&mut self, registers_and_values: &[u8]) -> Result<(), Error<I2C::Error>> {
+        self.i2c.write(self.address, registers_and_values).await.map_err(Error::I2c)
+    }
+
+    async fn read(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), Error<I2C::Error>> {
+        self.i2c.write_read(self.address, &[register], buffer).await.map_err(Error::I2c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use embassy_futures::block_on;
+    use embedded_hal_async::i2c::{ErrorType, NoAcknowledgeSource, Operation};
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum BusError {
+        Nack,
+    }
+
+    impl embedded_hal_async::i2c::Error for BusError {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)
+        }
+    }
+
+    /// Register file of a BME680 that finishes measurements instantly.
+    struct FakeSensor {
+        registers: [u8; 256],
+        present: bool,
+        /// Reads of the data field before it reports new data.
+        busy_polls: u32,
+    }
+
+    impl FakeSensor {
+        fn new() -> Self {
+            let mut registers = [0; 256];
+            registers[REG_CHIP_ID as usize] = CHIP_ID;
+            let (coeff1, coeff2, coeff3) = calibration_registers();
+            registers[REG_COEFF1 as usize..][..25].copy_from_slice(&coeff1);
+            registers[REG_COEFF2 as usize..][..16].copy_from_slice(&coeff2);
+            registers[REG_COEFF3 as usize..][..5].copy_from_slice(&coeff3);
+            Self { registers, present: true, busy_polls: 0 }
+        }
+    }
+
+    impl ErrorType for FakeSensor {
+        type Error = BusError;
+    }
+
+    impl I2c for FakeSensor {
+        async fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), BusError> {
+            if !self.present || address != PRIMARY_ADDRESS {
+                return Err(BusError::Nack);
+            }
+            let mut pointer = 0;
+            for operation in operations {
+                match operation {
+                    Operation::Write([register]) => pointer = *register as usize,
+                    Operation::Write(pairs) => {
+                        for pair in pairs.chunks(2) {
+                            self.registers[pair[0] as usize] = pair[1];
+                        }
+                    }
+                    Operation::Read(buffer) => {
+                        if pointer == REG_FIELD0 as usize {
+                            if self.busy_polls > 0 {
+                                self.busy_polls -= 1;
+                                buffer.fill(0);
+                                return Ok(());
+                            }
+                            self.registers[pointer] |= NEW_DATA;
+                        }
+                        buffer.copy_from_slice(&self.registers[pointer..][..buffer.len()]);
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeDelay {
+        waited_ms: u32,
+    }
+
+    impl DelayNs for FakeDelay {
+        async fn delay_ns(&mut self, ns: u32) {
+            self.waited_ms += ns / 1_000_000;
+        }
+
+        async fn delay_ms(&mut self, ms: u32) {
+            self.waited_ms += ms;
+        }
+    }
+
+    /// Calibration of a sensor in the usual range, as (coeff1, coeff2,
+    /// coeff3).
+    fn calibration_registers() -> ([u8; 25], [u8; 16], [u8; 5]) {
+        let mut c = [0u8; 41];
+        let mut put_u16 = |lsb: usize, value: u16| c[lsb..lsb + 2].copy_from_slice(&value.to_le_bytes());
+        put_u16(33, 26_000); // t1
+        put_u16(1, 26_000); // t2
+        put_u16(5, 36_000); // p1
+        put_u16(7, (-10_400i16) as u16); // p2
+        put_u16(11, 2_000); // p4
+        put_u16(13, (-100i16) as u16); // p5
+        put_u16(19, (-300i16) as u16); // p8
+        put_u16(21, (-2_000i16) as u16); // p9
+        put_u16(35, (-12_000i16) as u16); // gh2
+        c[3] = 3; // t3
+        c[9] = 88; // p3
+        c[15] = 30; // p7
+        c[16] = 30; // p6
+        c[23] = 30; // p10
+        // h1 and h2 share the nibbles of index 26
+        let (h1, h2) = (805u16, 1003u16);
+        c[25] = (h2 >> 4) as u8;
+        c[26] = ((h2 & 0x0F) << 4 | (h1 & 0x0F)) as u8;
+        c[27] = (h1 >> 4) as u8;
+        c[28] = 0; // h3
+        c[29] = 45; // h4
+        c[30] = 20; // h5
+        c[31] = 120; // h6
+        c[32] = (-100i8) as u8; // h7
+        c[37] = (-30i8) as u8; // gh1
+        c[38] = 18; // gh3
+
+        let mut coeff1 = [0; 25];
+        let mut coeff2 = [0; 16];
+        coeff1.copy_from_slice(&c[..25]);
+        coeff2.copy_from_slice(&c[25..]);
+        // res_heat_val 40, res_heat_range 1, range_sw_err -1
+        (coeff1, coeff2, [40, 0, 0x10, 0, 0xF0])
+    }
+
+    fn calibration() -> Calibration {
+        let (coeff1, coeff2, coeff3) = calibration_registers();
+        Calibration::from_registers(&coeff1, &coeff2, &coeff3)
+    }
+
+    fn config() -> SensorConfig {
+        SensorConfig {
+            humidity_oversampling: Oversampling::X2,
+            pressure_oversampling: Oversampling::X4,
+            temperature_oversampling: Oversampling::X8,
+            filter: FilterSize::Size3,
+            heater_temperature: 320,
+            heater_duration_ms: 150,
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() <= tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn calibration_fields_are_unpacked() {
+        let calibration = calibration();
+
+        assert_eq!((calibration.t1, calibration.t2, calibration.t3), (26_000.0, 26_000.0, 3.0));
+        assert_eq!((calibration.p1, calibration.p2, calibration.p6, calibration.p7), (36_000.0, -10_400.0, 30.0, 30.0));
+        assert_eq!((calibration.h1, calibration.h2, calibration.h7), (805.0, 1003.0, -100.0));
+        assert_eq!((calibration.gh1, calibration.gh2, calibration.gh3), (-30.0, -12_000.0, 18.0));
+        assert_eq!((calibration.res_heat_range, calibration.res_heat_val), (1, 40.0));
+        assert_eq!(calibration.range_switching_error, -1.0);
+    }
+
+    #[test]
+    fn compensates_into_plausible_units() {
+        let raw = RawData {
+            temperature: 500_000,
+            pressure: 425_000,
+            humidity: 20_000,
+            gas_resistance: 512,
+            gas_range: 4,
+            gas_valid: true,
+        };
+
+        let reading = calibration().compensate(&raw);
+
+        // reference values worked out by hand from the Bosch formulas
+        assert_close(reading.temperature, 26.04, 0.01);
+        assert_close(reading.pressure, 1033.69, 0.05);
+        assert_close(reading.humidity, 34.85, 0.05);
+        // an ADC value of 512 is the nominal resistance of the range
+        assert_close(reading.gas_resistance, 499_500.5, 5.0);
+        assert!(crate::sensor::check_plausible(&reading).is_ok());
+    }
+
+    #[test]
+    fn gas_wait_encoding() {
+        assert_eq!(gas_wait(63), 63);
+        // 25 ms times 4
+        assert_eq!(gas_wait(100), 0x59);
+        assert_eq!(gas_wait(150), 37 + 64);
+        // 23 ms times 64
+        assert_eq!(gas_wait(1500), 0xD7);
+        assert_eq!(gas_wait(4032), 0xFF);
+    }
+
+    #[test]
+    fn profile_duration_includes_heating() {
+        // 14 cycles of 1963 µs plus switching, and the heater
+        assert_eq!(profile_duration_ms(&config()), 32 + 1 + 150);
+    }
+
+    #[test]
+    fn heater_resistance_rises_with_temperature() {
+        let calibration = calibration();
+
+        assert!(calibration.heater_resistance(200) < calibration.heater_resistance(320));
+        assert_eq!(calibration.heater_resistance(400), calibration.heater_resistance(450));
+    }
+
+    #[test]
+    fn init_configures_the_sensor() {
+        let mut delay = FakeDelay::default();
+
+        let sensor = block_on(Bme680::init(FakeSensor::new(), &mut delay, PRIMARY_ADDRESS, &config())).unwrap();
+
+        let registers = &sensor.i2c.registers;
+        assert_eq!(registers[REG_CTRL_HUM as usize], 2);
+        assert_eq!(registers[REG_CTRL_MEAS as usize], 4 << 5 | 3 << 2);
+        assert_eq!(registers[REG_CONFIG as usize], 2 << 2);
+        assert_eq!(registers[REG_GAS_WAIT_0 as usize], gas_wait(150));
+        assert_eq!(registers[REG_RES_HEAT_0 as usize], calibration().heater_resistance(320));
+        assert_eq!(registers[REG_CTRL_GAS_1 as usize], RUN_GAS);
+        assert_eq!(sensor.calibration(), &calibration());
+        assert_eq!(delay.waited_ms, 10);
+    }
+
+    #[test]
+    fn measure_waits_for_the_profile_and_new_data() {
+        let mut delay = FakeDelay::default();
+        let mut sensor = block_on(Bme680::init(FakeSensor::new(), &mut delay, PRIMARY_ADDRESS, &config())).unwrap();
+        let field = [0, 0, 0x67, 0xC2, 0x80, 0x7A, 0x12, 0x00, 0x4E, 0x20, 0, 0, 0, 0x80, 0x34];
+        sensor.i2c.registers[REG_FIELD0 as usize..][..15].copy_from_slice(&field);
+        sensor.i2c.busy_polls = 2;
+        delay.waited_ms = 0;
+
+        let raw = block_on(sensor.measure(&mut delay)).unwrap();
+
+        assert_eq!(sensor.i2c.registers[REG_CTRL_MEAS as usize] & 0x03, FORCED_MODE);
+        assert_eq!(delay.waited_ms, profile_duration_ms(&config()) + 2 * NEW_DATA_POLL_INTERVAL_MS);
+        assert_eq!(
+            raw,
+            RawData {
+                temperature: 500_000,
+                pressure: 425_000,
+                humidity: 20_000,
+                gas_resistance: 512,
+                gas_range: 4,
+                gas_valid: true,
+            }
+        );
+    }
+
+    #[test]
+    fn gas_without_a_stable_heater_is_a_fault() {
+        let mut delay = FakeDelay::default();
+        let mut sensor = block_on(Bme680::init(FakeSensor::new(), &mut delay, PRIMARY_ADDRESS, &config())).unwrap();
+        // gas valid, but the heater-stable bit cleared
+        let field = [0, 0, 0x67, 0xC2, 0x80, 0x7A, 0x12, 0x00, 0x4E, 0x20, 0, 0, 0, 0x80, 0x24];
+        sensor.i2c.registers[REG_FIELD0 as usize..][..15].copy_from_slice(&field);
+
+        let error = block_on(sensor.measure(&mut delay)).unwrap_err();
+
+        assert_eq!(error, Error::GasInvalid);
+        assert_eq!(error.fault(), SensorFault::GasInvalid);
+    }
+
+    #[test]
+    fn measurement_that_never_finishes_times_out() {
+        let mut delay = FakeDelay::default();
+        let mut sensor = block_on(Bme680::init(FakeSensor::new(), &mut delay, PRIMARY_ADDRESS, &config())).unwrap();
+        sensor.i2c.busy_polls = u32::MAX;
+
+        let error = block_on(sensor.measure(&mut delay)).unwrap_err();
+
+        assert_eq!(error, Error::NoNewData);
+        assert_eq!(error.fault(), SensorFault::Timeout);
+    }
+
+    #[test]
+    fn faults_from_errors() {
+        let mut sensor = FakeSensor::new();
+        sensor.present = false;
+        let error = block_on(Bme680::init(sensor, &mut FakeDelay::default(), PRIMARY_ADDRESS, &config())).err().unwrap();
+        assert_eq!(error.fault(), SensorFault::Nack);
+
+        let mut sensor = FakeSensor::new();
+        sensor.registers[REG_CHIP_ID as usize] = 0x60;
+        let error = block_on(Bme680::init(sensor, &mut FakeDelay::default(), PRIMARY_ADDRESS, &config())).err().unwrap();
+        assert_eq!(error, Error::UnknownChip(0x60));
+        assert_eq!(error.fault(), SensorFault::Bus);
+    }
+}
@@ -0,0 +1,244 @@
+//! The products the machine sells.
+
+use core::fmt;
+
+use heapless::Vec;
+
+/// Index of an item inside a [`Catalog`].
+pub type ItemId = usize;
+
+/// A product offered by the machine together with its remaining stock.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub name: &'static str,
+    pub price: f32,
+    pub amount: u32,
+}
+
+impl Item {
+    pub const fn new(name: &'static str, price: f32, amount: u32) -> Self {
+        Self { name, price, amount }
+    }
+
+    pub fn in_stock(&self) -> bool {
+        self.amount > 0
+    }
+
+    /// The item's name as used in MQTT topics, i.e. without whitespace
+    /// (`Energy Drink` is published as `EnergyDrink`).
+    pub fn topic_name(&self) -> TopicName<'_> {
+        TopicName(self.name)
+    }
+}
+
+/// [`Display`](fmt::Display) adapter returned by [`Item::topic_name`].
+pub struct TopicName<'a>(&'a str);
+
+impl PartialEq<str> for TopicName<'_> {
+    fn eq(&self, other: &str) -> bool {
+        self.0.chars().filter(|c| !c.is_whitespace()).eq(other.chars())
+    }
+}
+
+impl fmt::Display for TopicName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for word in self.0.split_whitespace() {
+            f.write_str(word)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returned when adding more items than the catalog can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CatalogFull;
+
+/// Up to `N` items, identified by their position.
+#[derive(Debug, Clone)]
+pub struct Catalog<const N: usize> {
+    items: Vec<Item, N>,
+}
+
+impl<const N: usize> Catalog<N> {
+    pub const fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+
+    pub fn from_items(items: &[Item]) -> Result<Self, CatalogFull> {
+        let mut catalog = Self::new();
+        for item in items {
+            catalog.add(item.clone())?;
+        }
+        Ok(catalog)
+    }
+
+    /// Appends `item`, returning the id it can be looked up by.
+    pub fn add(&mut self, item: Item) -> Result<ItemId, CatalogFull> {
+        self.items.push(item).map_err(|_| CatalogFull)?;
+        Ok(self.items.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn get(&self, id: ItemId) -> Option<&Item> {
+        self.items.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: ItemId) -> Option<&mut Item> {
+        self.items.get_mut(id)
+    }
+
+    /// The item published under `topic_name`, see [`Item::topic_name`].
+    pub fn find_by_topic_name(&self, topic_name: &str) -> Option<ItemId> {
+        self.items.iter().position(|item| item.topic_name() == *topic_name)
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ItemId, &Item)> {
+        self.items.iter().enumerate()
+    }
+
+    /// Stock of every item, in id order.
+    pub fn amounts(&self) -> Vec<u32, N> {
+        self.items.iter().map(|item| item.amount).collect()
+    }
+
+    /// Overwrites the stock of the first `amounts.len()` items, e.g. with
+    /// counts restored from flash. Extra amounts are ignored.
+    pub fn set_amounts(&mut self, amounts: &[u32]) {
+        for (item, &amount) in self.items.iter_mut().zip(amounts) {
+            item.amount = amount;
+        }
+    }
+}
+
+/// Most items a [`Stock`] holds the counts of.
+pub const MAX_STOCK_ITEMS: usize = 8;
+
+/// The stock of every item at one point in time, in id order. Unlike a
+/// [`Catalog`] it's `Copy`, so it can be queued with an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stock {
+    amounts: [u32; MAX_STOCK_ITEMS],
+    len: u8,
+}
+
+impl Stock {
+    /// The current stock of `catalog`. Items past [`MAX_STOCK_ITEMS`] are
+    /// left out.
+    pub fn of<const N: usize>(catalog: &Catalog<N>) -> Self {
+        let mut stock = Self::default();
+        for (slot, item) in stock.amounts.iter_mut().zip(catalog.items()) {
+            *slot = item.amount;
+            stock.len += 1;
+        }
+        stock
+    }
+
+    pub fn amounts(&self) -> &[u32] {
+        &self.amounts[..self.len as usize]
+    }
+}
+
+impl<const N: usize> Default for Catalog<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ITEMS: [Item; 3] = [
+        Item::new("Hotdog", 2.50, 10),
+        Item::new("Sandwich", 3.50, 9),
+        Item::new("Energy Drink", 2.00, 11),
+    ];
+
+    #[test]
+    fn items_are_identified_by_position() {
+        let catalog = Catalog::<4>::from_items(&ITEMS).unwrap();
+
+        assert_eq!(catalog.len(), 3);
+        assert_eq!(catalog.get(2), Some(&ITEMS[2]));
+        assert_eq!(catalog.iter().map(|(id, _)| id).collect::<std::vec::Vec<_>>(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn add_returns_new_id() {
+        let mut catalog = Catalog::<4>::from_items(&ITEMS).unwrap();
+
+        let id = catalog.add(Item::new("Coffee", 1.20, 5)).unwrap();
+
+        assert_eq!(id, 3);
+        assert_eq!(catalog.get(id).unwrap().name, "Coffee");
+    }
+
+    #[test]
+    fn capacity_is_enforced() {
+        assert_eq!(Catalog::<2>::from_items(&ITEMS).unwrap_err(), CatalogFull);
+
+        let mut catalog = Catalog::<3>::from_items(&ITEMS).unwrap();
+        assert_eq!(catalog.add(Item::new("Coffee", 1.20, 5)), Err(CatalogFull));
+        assert_eq!(catalog.len(), 3);
+    }
+
+    #[test]
+    fn amounts_round_trip() {
+        let mut catalog = Catalog::<4>::from_items(&ITEMS).unwrap();
+        assert_eq!(catalog.amounts(), [10, 9, 11]);
+
+        catalog.set_amounts(&[1, 2]);
+        assert_eq!(catalog.amounts(), [1, 2, 11]);
+
+        catalog.set_amounts(&[3, 4, 5, 6]);
+        assert_eq!(catalog.amounts(), [3, 4, 5]);
+    }
+
+    #[test]
+    fn stock_is_a_snapshot() {
+        let mut catalog = Catalog::<4>::from_items(&ITEMS).unwrap();
+        let stock = Stock::of(&catalog);
+
+        catalog.get_mut(0).unwrap().amount = 0;
+
+        assert_eq!(stock.amounts(), [10, 9, 11]);
+        catalog.set_amounts(stock.amounts());
+        assert_eq!(catalog.amounts(), [10, 9, 11]);
+    }
+
+    #[test]
+    fn stock_holds_at_most_max_items() {
+        let items: std::vec::Vec<Item> = (0..10).map(|amount| Item::new("Gum", 1.0, amount)).collect();
+        let catalog = Catalog::<10>::from_items(&items).unwrap();
+
+        assert_eq!(Stock::of(&catalog).amounts(), [0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn topic_name_strips_whitespace() {
+        assert_eq!(ITEMS[0].topic_name().to_string(), "Hotdog");
+        assert_eq!(ITEMS[2].topic_name().to_string(), "EnergyDrink");
+        assert_eq!(Item::new(" Iced  Tea ", 1.0, 1).topic_name().to_string(), "IcedTea");
+    }
+
+    #[test]
+    fn find_by_topic_name() {
+        let catalog = Catalog::<4>::from_items(&ITEMS).unwrap();
+
+        assert_eq!(catalog.find_by_topic_name("EnergyDrink"), Some(2));
+        assert_eq!(catalog.find_by_topic_name("Hotdog"), Some(0));
+        assert_eq!(catalog.find_by_topic_name("Energy Drink"), None);
+        assert_eq!(catalog.find_by_topic_name("Energy"), None);
+    }
+}
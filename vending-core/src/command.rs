@@ -0,0 +1,246 @@
+//! Restock and price commands sent by operators over MQTT.
+//!
+//! An operator publishes `{"amount": 12, "price": 2.75}` to
+//! `espbox/inventory/<item>/set`, where `<item>` is the item's
+//! [topic name](crate::catalog::Item::topic_name) and either field may be left
+//! out. The machine answers on `espbox/inventory/<item>/ack` with the new state
+//! of the item or the reason the command was rejected.
+
+use core::fmt::Write;
+
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::catalog::{Catalog, Item, ItemId};
+
+/// Filter to subscribe to for all set commands.
+pub const SET_TOPIC_FILTER: &str = "espbox/inventory/+/set";
+pub const MAX_AMOUNT: u32 = 999;
+pub const MAX_PRICE: f32 = 999.99;
+
+const INVENTORY_PREFIX: &str = "espbox/inventory/";
+const ACK_SUFFIX: &str = "/ack";
+
+/// Longest `<item>` accepted in a command topic, so its
+/// [`ack_topic`] fits in 64 bytes.
+pub const MAX_ITEM_NAME_LEN: usize = 64 - INVENTORY_PREFIX.len() - ACK_SUFFIX.len();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    /// The topic isn't an `espbox/inventory/<item>/set` topic.
+    NotACommand,
+    UnknownItem,
+    /// The payload isn't a JSON object with only `amount` and `price`.
+    Malformed,
+    /// Neither `amount` nor `price` was given.
+    Empty,
+    AmountOutOfRange,
+    InvalidPrice,
+}
+
+impl CommandError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandError::NotACommand => "not a command",
+            CommandError::UnknownItem => "unknown item",
+            CommandError::Malformed => "malformed payload",
+            CommandError::Empty => "nothing to set",
+            CommandError::AmountOutOfRange => "amount out of range",
+            CommandError::InvalidPrice => "invalid price",
+        }
+    }
+}
+
+/// Payload of a set command.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetItem {
+    pub amount: Option<u32>,
+    pub price: Option<f32>,
+}
+
+impl SetItem {
+    /// Parses and validates a JSON payload.
+    pub fn parse(payload: &[u8]) -> Result<Self, CommandError> {
+        let (command, _) = serde_json_core::from_slice::<SetItem>(payload).map_err(|_| CommandError::Malformed)?;
+
+        if command.amount.is_none() && command.price.is_none() {
+            return Err(CommandError::Empty);
+        }
+        if command.amount.is_some_and(|amount| amount > MAX_AMOUNT) {
+            return Err(CommandError::AmountOutOfRange);
+        }
+        if command.price.is_some_and(|price| !(price > 0.0 && price <= MAX_PRICE)) {
+            return Err(CommandError::InvalidPrice);
+        }
+
+        Ok(command)
+    }
+
+    pub fn apply(&self, item: &mut Item) {
+        if let Some(amount) = self.amount {
+            item.amount = amount;
+        }
+        if let Some(price) = self.price {
+            item.price = price;
+        }
+    }
+}
+
+/// The `<item>` part of a set command topic. Items longer than
+/// [`MAX_ITEM_NAME_LEN`] can't be acknowledged and aren't commands.
+pub fn set_topic_item(topic: &str) -> Option<&str> {
+    topic
+        .strip_prefix(INVENTORY_PREFIX)?
+        .strip_suffix("/set")
+        .filter(|item| !item.is_empty() && item.len() <= MAX_ITEM_NAME_LEN && !item.contains('/'))
+}
+
+/// Applies the set command received on `topic` to `catalog`, returning the id
+/// of the updated item.
+pub fn handle_set<const N: usize>(catalog: &mut Catalog<N>, topic: &str, payload: &[u8]) -> Result<ItemId, CommandError> {
+    let name = set_topic_item(topic).ok_or(CommandError::NotACommand)?;
+    let id = catalog.find_by_topic_name(name).ok_or(CommandError::UnknownItem)?;
+    let command = SetItem::parse(payload)?;
+
+    if let Some(item) = catalog.get_mut(id) {
+        command.apply(item);
+    }
+    Ok(id)
+}
+
+/// Topic acknowledging a set command for `item` (its topic name), or `None`
+/// if `item` is longer than [`MAX_ITEM_NAME_LEN`].
+pub fn ack_topic(item: &str) -> Option<String<64>> {
+    let mut topic = String::new();
+    write!(topic, "{}{}{}", INVENTORY_PREFIX, item, ACK_SUFFIX).ok()?;
+    Some(topic)
+}
+
+/// Answer to a set command.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Ack<'a> {
+    pub status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<f32>,
+}
+
+impl Ack<'static> {
+    pub fn applied(item: &Item) -> Self {
+        Self {
+            status: "ok",
+            error: None,
+            amount: Some(item.amount),
+            price: Some(item.price),
+        }
+    }
+
+    pub fn rejected(error: CommandError) -> Self {
+        Self {
+            status: "rejected",
+            error: Some(error.as_str()),
+            amount: None,
+            price: None,
+        }
+    }
+}
+
+impl Ack<'_> {
+    pub fn to_json(&self) -> String<96> {
+        serde_json_core::to_string(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> Catalog<4> {
+        Catalog::from_items(&[Item::new("Hotdog", 2.50, 10), Item::new("Energy Drink", 2.00, 11)]).unwrap()
+    }
+
+    #[test]
+    fn set_topic_names_item() {
+        assert_eq!(set_topic_item("espbox/inventory/Hotdog/set"), Some("Hotdog"));
+        assert_eq!(set_topic_item("espbox/inventory/Hotdog"), None);
+        assert_eq!(set_topic_item("espbox/inventory//set"), None);
+        assert_eq!(set_topic_item("espbox/inventory/a/b/set"), None);
+        assert_eq!(set_topic_item("espbox/sensor/Gas/set"), None);
+    }
+
+    #[test]
+    fn parse_accepts_either_field() {
+        assert_eq!(SetItem::parse(br#"{"amount": 12}"#), Ok(SetItem { amount: Some(12), price: None }));
+        assert_eq!(SetItem::parse(br#"{"price": 2.75}"#), Ok(SetItem { amount: None, price: Some(2.75) }));
+        assert_eq!(
+            SetItem::parse(br#"{"price":1.5,"amount":0}"#),
+            Ok(SetItem { amount: Some(0), price: Some(1.5) })
+        );
+    }
+
+    #[test]
+    fn parse_rejects_bad_payloads() {
+        assert_eq!(SetItem::parse(b"12"), Err(CommandError::Malformed));
+        assert_eq!(SetItem::parse(br#"{"amount": -1}"#), Err(CommandError::Malformed));
+        assert_eq!(SetItem::parse(br#"{"amount": 1, "colour": "red"}"#), Err(CommandError::Malformed));
+        assert_eq!(SetItem::parse(b"{}"), Err(CommandError::Empty));
+        assert_eq!(SetItem::parse(br#"{"amount": 1000}"#), Err(CommandError::AmountOutOfRange));
+        assert_eq!(SetItem::parse(br#"{"price": 0}"#), Err(CommandError::InvalidPrice));
+        assert_eq!(SetItem::parse(br#"{"price": 1000.0}"#), Err(CommandError::InvalidPrice));
+    }
+
+    #[test]
+    fn handle_set_updates_item() {
+        let mut catalog = catalog();
+
+        let id = handle_set(&mut catalog, "espbox/inventory/EnergyDrink/set", br#"{"amount": 20, "price": 2.25}"#);
+
+        assert_eq!(id, Ok(1));
+        assert_eq!(catalog.get(1), Some(&Item::new("Energy Drink", 2.25, 20)));
+        assert_eq!(catalog.get(0), Some(&Item::new("Hotdog", 2.50, 10)));
+    }
+
+    #[test]
+    fn handle_set_leaves_catalog_alone_on_error() {
+        let mut catalog = catalog();
+
+        assert_eq!(
+            handle_set(&mut catalog, "espbox/inventory/Coffee/set", br#"{"amount": 1}"#),
+            Err(CommandError::UnknownItem)
+        );
+        assert_eq!(
+            handle_set(&mut catalog, "espbox/inventory/Hotdog/set", br#"{"amount": 1, "price": -1}"#),
+            Err(CommandError::InvalidPrice)
+        );
+        assert_eq!(catalog.get(0), Some(&Item::new("Hotdog", 2.50, 10)));
+    }
+
+    #[test]
+    fn ack_reports_item_or_error() {
+        assert_eq!(Ack::applied(&Item::new("Hotdog", 2.75, 12)).to_json(), r#"{"status":"ok","amount":12,"price":2.75}"#);
+        assert_eq!(
+            Ack::rejected(CommandError::UnknownItem).to_json(),
+            r#"{"status":"rejected","error":"unknown item"}"#
+        );
+        assert_eq!(ack_topic("EnergyDrink").unwrap(), "espbox/inventory/EnergyDrink/ack");
+    }
+
+    #[test]
+    fn rejects_item_names_too_long_to_ack() {
+        let longest = "x".repeat(MAX_ITEM_NAME_LEN);
+        let too_long = "x".repeat(MAX_ITEM_NAME_LEN + 1);
+
+        assert_eq!(ack_topic(&longest).unwrap().len(), 64);
+        assert_eq!(ack_topic(&too_long), None);
+        assert_eq!(set_topic_item(&format!("espbox/inventory/{}/set", longest)), Some(longest.as_str()));
+        assert_eq!(set_topic_item(&format!("espbox/inventory/{}/set", too_long)), None);
+        assert_eq!(
+            handle_set(&mut catalog(), &format!("espbox/inventory/{}/set", too_long), br#"{"amount": 1}"#),
+            Err(CommandError::NotACommand)
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! Bringing the broker connection up and keeping it there.
+//!
+//! A connection goes through the [`Stage`]s DNS → TCP → TLS → MQTT CONNECT
+//! before it's [`State::Online`]. The firmware performs each stage itself and
+//! reports the result to a [`ConnectionManager`], which decides how long to
+//! back off after a failure and keeps [`ConnectionStats`] for diagnostics.
+//!
+//! Backoff doubles with every consecutive failure up to a limit and is
+//! jittered, so a fleet of machines that lost the broker at the same time
+//! doesn't reconnect in lockstep.
+
+use core::time::Duration;
+
+use heapless::String;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Dns,
+    Tcp,
+    Tls,
+    Mqtt,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Dns, Stage::Tcp, Stage::Tls, Stage::Mqtt];
+
+    fn next(self) -> Option<Stage> {
+        match self {
+            Stage::Dns => Some(Stage::Tcp),
+            Stage::Tcp => Some(Stage::Tls),
+            Stage::Tls => Some(Stage::Mqtt),
+            Stage::Mqtt => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Dns => "DNS",
+            Stage::Tcp => "TCP",
+            Stage::Tls => "TLS",
+            Stage::Mqtt => "MQTT",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Never tried to connect.
+    Idle,
+    Connecting(Stage),
+    Online,
+    /// Waiting this long before the next attempt.
+    Backoff(Duration),
+}
+
+/// Why a stage failed, which decides how soon it's retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Timeouts, resets and other trouble on the way to the broker that
+    /// usually goes away by itself.
+    Network,
+    /// The broker or the TLS layer turned the machine down, e.g. because of
+    /// bad credentials. Retrying quickly won't help, so this backs off for the
+    /// maximum delay straight away.
+    Rejected,
+}
+
+/// Counters since boot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionStats {
+    pub attempts: u32,
+    /// Attempts that made it online.
+    pub connects: u32,
+    /// Online connections that were lost later.
+    pub disconnects: u32,
+    pub dns_failures: u32,
+    pub tcp_failures: u32,
+    pub tls_failures: u32,
+    pub mqtt_failures: u32,
+    /// Failed attempts since the last time the machine was online.
+    pub consecutive_failures: u32,
+}
+
+impl ConnectionStats {
+    pub fn failures(&self, stage: Stage) -> u32 {
+        match stage {
+            Stage::Dns => self.dns_failures,
+            Stage::Tcp => self.tcp_failures,
+            Stage::Tls => self.tls_failures,
+            Stage::Mqtt => self.mqtt_failures,
+        }
+    }
+
+    pub fn to_json(&self) -> String<256> {
+        serde_json_core::to_string(self).unwrap_or_default()
+    }
+
+    fn failures_mut(&mut self, stage: Stage) -> &mut u32 {
+        match stage {
+            Stage::Dns => &mut self.dns_failures,
+            Stage::Tcp => &mut self.tcp_failures,
+            Stage::Tls => &mut self.tls_failures,
+            Stage::Mqtt => &mut self.mqtt_failures,
+        }
+    }
+}
+
+/// Exponential backoff between `min` and `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    pub const fn new(min: Duration, max: Duration) -> Self {
+        Self { min, max }
+    }
+
+    /// Upper bound of the delay after `failures` consecutive failures.
+    pub fn ceiling(&self, failures: u32) -> Duration {
+        let factor = 1u32.checked_shl(failures.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.min.checked_mul(factor).map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+pub struct ConnectionManager {
+    backoff: Backoff,
+    state: State,
+    stats: ConnectionStats,
+    // xorshift32 state for the jitter
+    random: u32,
+}
+
+impl ConnectionManager {
+    /// `seed` only decorrelates the jitter between machines, it needn't be
+    /// cryptographically random.
+    pub fn new(backoff: Backoff, seed: u32) -> Self {
+        Self {
+            backoff,
+            state: State::Idle,
+            stats: ConnectionStats::default(),
+            random: seed.max(1),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.state == State::Online
+    }
+
+    /// The delay to wait before calling [`start`](Self::start).
+    pub fn pending_backoff(&self) -> Option<Duration> {
+        match self.state {
+            State::Backoff(delay) => Some(delay),
+            _ => None,
+        }
+    }
+
+    /// Begins a new attempt with the DNS lookup.
+    pub fn start(&mut self) -> Stage {
+        assert!(
+            matches!(self.state, State::Idle | State::Backoff(_)),
+            "connection attempt started while {:?}",
+            self.state
+        );
+        self.stats.attempts += 1;
+        self.state = State::Connecting(Stage::Dns);
+        Stage::Dns
+    }
+
+    /// The current stage succeeded. Returns the state that follows, which is
+    /// [`State::Online`] after the MQTT stage.
+    pub fn advance(&mut self) -> State {
+        let State::Connecting(stage) = self.state else {
+            panic!("stage completed while {:?}", self.state);
+        };
+        self.state = match stage.next() {
+            Some(next) => State::Connecting(next),
+            None => {
+                self.stats.connects += 1;
+                self.stats.consecutive_failures = 0;
+                State::Online
+            }
+        };
+        self.state
+    }
+
+    /// The current stage failed. Returns how long to back off.
+    pub fn fail(&mut self, failure: Failure) -> Duration {
+        let State::Connecting(stage) = self.state else {
+            panic!("stage failed while {:?}", self.state);
+        };
+        *self.stats.failures_mut(stage) += 1;
+        self.stats.consecutive_failures += 1;
+
+        let ceiling = match failure {
+            Failure::Network => self.backoff.ceiling(self.stats.consecutive_failures),
+            Failure::Rejected => self.backoff.max,
+        };
+        self.back_off(ceiling)
+    }
+
+    /// The online connection broke. Returns how long to back off before
+    /// reconnecting, which is short since the broker was reachable until now.
+    pub fn connection_lost(&mut self) -> Duration {
+        assert!(self.state == State::Online, "connection lost while {:?}", self.state);
+        self.stats.disconnects += 1;
+        self.back_off(self.backoff.min)
+    }
+
+    /// Picks a delay between half of `ceiling` and `ceiling`.
+    fn back_off(&mut self, ceiling: Duration) -> Duration {
+        let half = ceiling / 2;
+        let jitter_ms = half.as_millis() as u64;
+        let delay = if jitter_ms == 0 {
+            ceiling
+        } else {
+            half + Duration::from_millis(u64::from(self.next_random()) % (jitter_ms + 1))
+        };
+        self.state = State::Backoff(delay);
+        delay
+    }
+
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.random;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKOFF: Backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(300));
+
+    /// Stands in for the network: each attempt fails at the given stage or,
+    /// if `None`, gets online.
+    struct MockTransport {
+        script: std::vec::Vec<Option<(Stage, Failure)>>,
+    }
+
+    impl MockTransport {
+        fn new(script: &[Option<(Stage, Failure)>]) -> Self {
+            Self { script: script.to_vec() }
+        }
+
+        /// Runs one attempt the way the firmware does, returning the backoff
+        /// if it failed.
+        fn attempt(&mut self, manager: &mut ConnectionManager) -> Option<Duration> {
+            let outcome = self.script.remove(0);
+            let mut stage = manager.start();
+            loop {
+                if let Some((failing, failure)) = outcome {
+                    if failing == stage {
+                        return Some(manager.fail(failure));
+                    }
+                }
+                match manager.advance() {
+                    State::Connecting(next) => stage = next,
+                    State::Online => return None,
+                    state => panic!("unexpected {:?}", state),
+                }
+            }
+        }
+    }
+
+    fn in_jitter_range(delay: Duration, ceiling: Duration) -> bool {
+        delay >= ceiling / 2 && delay <= ceiling
+    }
+
+    #[test]
+    fn goes_through_every_stage() {
+        let mut manager = ConnectionManager::new(BACKOFF, 7);
+        assert_eq!(manager.state(), State::Idle);
+
+        assert_eq!(manager.start(), Stage::Dns);
+        assert_eq!(manager.advance(), State::Connecting(Stage::Tcp));
+        assert_eq!(manager.advance(), State::Connecting(Stage::Tls));
+        assert_eq!(manager.advance(), State::Connecting(Stage::Mqtt));
+        assert_eq!(manager.advance(), State::Online);
+
+        assert!(manager.is_online());
+        assert_eq!(manager.stats().attempts, 1);
+        assert_eq!(manager.stats().connects, 1);
+    }
+
+    #[test]
+    fn failures_are_counted_per_stage() {
+        let mut manager = ConnectionManager::new(BACKOFF, 7);
+        let mut transport = MockTransport::new(&[
+            Some((Stage::Dns, Failure::Network)),
+            Some((Stage::Tls, Failure::Network)),
+            Some((Stage::Tls, Failure::Network)),
+            Some((Stage::Mqtt, Failure::Rejected)),
+            None,
+        ]);
+
+        for _ in 0..4 {
+            assert!(transport.attempt(&mut manager).is_some());
+            assert!(manager.pending_backoff().is_some());
+        }
+        assert_eq!(manager.stats().consecutive_failures, 4);
+        assert!(transport.attempt(&mut manager).is_none());
+
+        let stats = manager.stats();
+        assert_eq!(Stage::ALL.map(|stage| stats.failures(stage)), [1, 0, 2, 1]);
+        assert_eq!(stats.attempts, 5);
+        assert_eq!(stats.connects, 1);
+        assert_eq!(stats.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_max() {
+        let mut manager = ConnectionManager::new(BACKOFF, 42);
+        let mut transport = MockTransport::new(&[Some((Stage::Tcp, Failure::Network)); 12]);
+
+        for failures in 1..=12 {
+            let delay = transport.attempt(&mut manager).unwrap();
+            let ceiling = BACKOFF.ceiling(failures);
+            assert!(in_jitter_range(delay, ceiling), "{:?} outside of {:?}", delay, ceiling);
+        }
+        assert_eq!(BACKOFF.ceiling(1), Duration::from_secs(1));
+        assert_eq!(BACKOFF.ceiling(4), Duration::from_secs(8));
+        assert_eq!(BACKOFF.ceiling(12), Duration::from_secs(300));
+        assert_eq!(BACKOFF.ceiling(100), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn rejection_backs_off_for_longest() {
+        let mut manager = ConnectionManager::new(BACKOFF, 42);
+        let mut transport = MockTransport::new(&[Some((Stage::Mqtt, Failure::Rejected))]);
+
+        let delay = transport.attempt(&mut manager).unwrap();
+
+        assert!(in_jitter_range(delay, BACKOFF.max));
+    }
+
+    #[test]
+    fn success_resets_backoff() {
+        let mut manager = ConnectionManager::new(BACKOFF, 3);
+        let mut transport = MockTransport::new(&[
+            Some((Stage::Dns, Failure::Network)),
+            Some((Stage::Dns, Failure::Network)),
+            Some((Stage::Dns, Failure::Network)),
+            None,
+            Some((Stage::Dns, Failure::Network)),
+        ]);
+        for _ in 0..4 {
+            transport.attempt(&mut manager);
+        }
+        manager.connection_lost();
+
+        let delay = transport.attempt(&mut manager).unwrap();
+
+        assert!(in_jitter_range(delay, BACKOFF.min));
+    }
+
+    #[test]
+    fn lost_connection_reconnects_quickly() {
+        let mut manager = ConnectionManager::new(BACKOFF, 3);
+        MockTransport::new(&[None]).attempt(&mut manager);
+
+        let delay = manager.connection_lost();
+
+        assert!(in_jitter_range(delay, BACKOFF.min));
+        assert_eq!(manager.state(), State::Backoff(delay));
+        assert_eq!(manager.stats().disconnects, 1);
+        assert_eq!(manager.start(), Stage::Dns);
+    }
+
+    #[test]
+    fn jitter_spreads_machines_apart() {
+        let delays = [1, 2, 3, 4].map(|seed| {
+            let mut manager = ConnectionManager::new(BACKOFF, seed);
+            MockTransport::new(&[Some((Stage::Dns, Failure::Rejected))]).attempt(&mut manager).unwrap()
+        });
+
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn stats_as_json() {
+        let mut manager = ConnectionManager::new(BACKOFF, 3);
+        MockTransport::new(&[Some((Stage::Tcp, Failure::Network)), None]).attempt(&mut manager);
+        MockTransport::new(&[None]).attempt(&mut manager);
+
+        assert_eq!(
+            manager.stats().to_json(),
+            concat!(
+                r#"{"attempts":2,"connects":1,"disconnects":0,"dns_failures":0,"tcp_failures":1,"#,
+                r#""tls_failures":0,"mqtt_failures":0,"consecutive_failures":0}"#
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn advancing_while_online_is_a_bug() {
+        let mut manager = ConnectionManager::new(BACKOFF, 3);
+        MockTransport::new(&[None]).attempt(&mut manager);
+
+        manager.advance();
+    }
+}
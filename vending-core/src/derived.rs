@@ -0,0 +1,178 @@
+//! Metrics computed from the raw temperature, humidity and pressure.
+
+use libm::{expf, logf, powf, sqrtf};
+
+use crate::sensor::SensorReading;
+
+/// Mean sea-level pressure of the standard atmosphere, in hPa.
+pub const STANDARD_SEA_LEVEL_PRESSURE: f32 = 1013.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DerivedMetrics {
+    /// °C
+    pub dew_point: f32,
+    /// Apparent temperature, °C
+    pub heat_index: f32,
+    /// g/m³
+    pub absolute_humidity: f32,
+    /// Height above the sea-level reference, m
+    pub altitude: f32,
+}
+
+impl DerivedMetrics {
+    /// `sea_level_pressure` is the current pressure at sea level in hPa, as
+    /// reported by a nearby weather station, or
+    /// [`STANDARD_SEA_LEVEL_PRESSURE`].
+    pub fn from_reading(reading: &SensorReading, sea_level_pressure: f32) -> Self {
+        Self {
+            dew_point: dew_point(reading.temperature, reading.humidity),
+            heat_index: heat_index(reading.temperature, reading.humidity),
+            absolute_humidity: absolute_humidity(reading.temperature, reading.humidity),
+            altitude: altitude(reading.pressure, sea_level_pressure),
+        }
+    }
+}
+
+/// Humidity below which the dew point is no longer meaningful; 0 % would put
+/// it at -inf.
+const MIN_DEW_POINT_HUMIDITY: f32 = 0.1;
+
+/// Magnus formula with the constants of Sonntag (1990), in °C. Humidity is
+/// clamped to 0.1 % so bone-dry readings stay finite.
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    const A: f32 = 17.62;
+    const B: f32 = 243.12;
+
+    let humidity = humidity.max(MIN_DEW_POINT_HUMIDITY);
+    let gamma = logf(humidity / 100.0) + A * temperature / (B + temperature);
+    B * gamma / (A - gamma)
+}
+
+/// Water vapour per volume of air, in g/m³.
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let saturation_pressure = 6.112 * expf(17.67 * temperature / (temperature + 243.5));
+    saturation_pressure * humidity * 2.1674 / (273.15 + temperature)
+}
+
+/// The US National Weather Service heat index, in °C. Below about 27 °C it's
+/// close to the air temperature.
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let fahrenheit = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        // Rothfusz regression
+        let mut index = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+            - 0.224_755_4 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            let distance = if t > 95.0 { t - 95.0 } else { 95.0 - t };
+            index -= (13.0 - rh) / 4.0 * sqrtf((17.0 - distance) / 17.0);
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            index += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+        }
+        index
+    };
+
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+/// Barometric altitude of the international standard atmosphere, in m.
+pub fn altitude(pressure: f32, sea_level_pressure: f32) -> f32 {
+    44_330.0 * (1.0 - powf(pressure / sea_level_pressure, 1.0 / 5.255))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn dew_point_reference_values() {
+        assert_close(dew_point(25.0, 60.0), 16.7, 0.05);
+        assert_close(dew_point(30.0, 80.0), 26.2, 0.05);
+        assert_close(dew_point(-10.0, 50.0), -18.5, 0.05);
+        // saturated air is at its dew point
+        assert_close(dew_point(0.0, 100.0), 0.0, 0.01);
+        assert_close(dew_point(21.3, 100.0), 21.3, 0.01);
+    }
+
+    #[test]
+    fn dew_point_is_finite_in_dry_air() {
+        let dry = dew_point(25.0, 0.0);
+
+        assert!(dry.is_finite());
+        assert_eq!(dry, dew_point(25.0, MIN_DEW_POINT_HUMIDITY));
+        assert!(dry < dew_point(25.0, 1.0));
+    }
+
+    #[test]
+    fn absolute_humidity_reference_values() {
+        assert_close(absolute_humidity(25.0, 60.0), 13.8, 0.05);
+        assert_close(absolute_humidity(20.0, 50.0), 8.6, 0.05);
+        assert_close(absolute_humidity(0.0, 100.0), 4.85, 0.05);
+    }
+
+    #[test]
+    fn heat_index_matches_nws_table() {
+        // 90 °F at 70 % reads 106 °F in the NWS table
+        assert_close(heat_index(32.22, 70.0), 41.1, 0.1);
+        // 95 °F at 50 % reads 105 °F
+        assert_close(heat_index(35.0, 50.0), 40.7, 0.1);
+    }
+
+    #[test]
+    fn heat_index_adjustments() {
+        // dry heat feels cooler than the regression alone says
+        assert_close(heat_index(40.0, 10.0), 36.7, 0.1);
+        // as does humid, moderate heat
+        assert_close(heat_index(29.0, 90.0), 37.2, 0.1);
+    }
+
+    #[test]
+    fn heat_index_is_close_to_temperature_when_mild() {
+        assert_close(heat_index(20.0, 50.0), 19.4, 0.1);
+    }
+
+    #[test]
+    fn altitude_reference_values() {
+        assert_close(altitude(STANDARD_SEA_LEVEL_PRESSURE, STANDARD_SEA_LEVEL_PRESSURE), 0.0, 0.01);
+        // standard atmosphere at 1000 m and 2000 m
+        assert_close(altitude(898.76, STANDARD_SEA_LEVEL_PRESSURE), 1000.0, 1.0);
+        assert_close(altitude(795.0, STANDARD_SEA_LEVEL_PRESSURE), 2000.0, 1.0);
+    }
+
+    #[test]
+    fn altitude_uses_sea_level_reference() {
+        assert_close(altitude(1000.0, 1020.0), 166.7, 0.5);
+        assert!(altitude(1013.25, 1000.0) < 0.0);
+    }
+
+    #[test]
+    fn from_reading() {
+        let reading = SensorReading { temperature: 25.0, humidity: 60.0, pressure: 898.76, ..Default::default() };
+
+        let metrics = DerivedMetrics::from_reading(&reading, STANDARD_SEA_LEVEL_PRESSURE);
+
+        assert_close(metrics.dew_point, 16.7, 0.05);
+        assert_close(metrics.absolute_humidity, 13.8, 0.05);
+        assert_close(metrics.heat_index, 25.0, 1.0);
+        assert_close(metrics.altitude, 1000.0, 1.0);
+    }
+}
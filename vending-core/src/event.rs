@@ -0,0 +1,144 @@
+//! Things the machine reports to the broker, stamped with the time they
+//! happened so they keep it when published late from the
+//! [offline queue](crate::queue).
+
+use heapless::String;
+use serde::Serialize;
+
+use crate::alarm::{AlarmChange, Metric, Threshold};
+use crate::catalog::{Item, Stock};
+use crate::sensor::{SensorFault, SensorReading};
+use crate::sensor_config::SensorConfig;
+use crate::vending::Purchase;
+
+pub const PURCHASE_TOPIC: &str = "espbox/events/purchase";
+pub const SENSOR_STATUS_TOPIC: &str = "espbox/sensor/status";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    /// Milliseconds since boot.
+    pub timestamp_ms: u64,
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    /// A measurement, numbered so gaps show readings that were lost. `reading`
+    /// is [filtered](crate::filter), `raw` what the sensor returned and `stock`
+    /// the inventory at the time.
+    Reading { sequence: u32, reading: SensorReading, raw: SensorReading, stock: Stock },
+    Purchase { item: &'static str, price: f32, remaining: u32 },
+    /// The BME680 settings that became active.
+    SensorConfig(SensorConfig),
+    /// The sensor failed, failed differently, or recovered when `fault` is `None`.
+    SensorStatus { fault: Option<SensorFault>, consecutive_faults: u32 },
+    Alarm(AlarmChange),
+    /// The threshold of `metric` that became active.
+    AlarmThreshold { metric: Metric, threshold: Threshold },
+}
+
+impl Event {
+    pub fn reading(timestamp_ms: u64, sequence: u32, reading: SensorReading, raw: SensorReading, stock: Stock) -> Self {
+        Self { timestamp_ms, kind: EventKind::Reading { sequence, reading, raw, stock } }
+    }
+
+    /// `item` is the item that was bought.
+    pub fn purchase(timestamp_ms: u64, item: &Item, purchase: &Purchase) -> Self {
+        Self {
+            timestamp_ms,
+            kind: EventKind::Purchase { item: item.name, price: purchase.price, remaining: purchase.remaining },
+        }
+    }
+
+    pub fn sensor_config(timestamp_ms: u64, config: SensorConfig) -> Self {
+        Self { timestamp_ms, kind: EventKind::SensorConfig(config) }
+    }
+
+    pub fn alarm(timestamp_ms: u64, change: AlarmChange) -> Self {
+        Self { timestamp_ms, kind: EventKind::Alarm(change) }
+    }
+
+    pub fn alarm_threshold(timestamp_ms: u64, metric: Metric, threshold: Threshold) -> Self {
+        Self { timestamp_ms, kind: EventKind::AlarmThreshold { metric, threshold } }
+    }
+
+    pub fn sensor_status(timestamp_ms: u64, fault: Option<SensorFault>, consecutive_faults: u32) -> Self {
+        Self { timestamp_ms, kind: EventKind::SensorStatus { fault, consecutive_faults } }
+    }
+}
+
+#[derive(Serialize)]
+struct PurchaseDocument<'a> {
+    ts_ms: u64,
+    item: &'a str,
+    price: f32,
+    remaining: u32,
+}
+
+#[derive(Serialize)]
+struct SensorStatusDocument<'a> {
+    ts_ms: u64,
+    online: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fault: Option<&'a str>,
+    consecutive_faults: u32,
+}
+
+/// The document published on [`PURCHASE_TOPIC`], or `None` for other events.
+pub fn purchase_json(event: &Event) -> Option<String<128>> {
+    let EventKind::Purchase { item, price, remaining } = event.kind else {
+        return None;
+    };
+    serde_json_core::to_string(&PurchaseDocument { ts_ms: event.timestamp_ms, item, price, remaining }).ok()
+}
+
+/// The document published on [`SENSOR_STATUS_TOPIC`], or `None` for other
+/// events.
+pub fn sensor_status_json(event: &Event) -> Option<String<128>> {
+    let EventKind::SensorStatus { fault, consecutive_faults } = event.kind else {
+        return None;
+    };
+    let document = SensorStatusDocument {
+        ts_ms: event.timestamp_ms,
+        online: fault.is_none(),
+        fault: fault.map(|fault| fault.as_str()),
+        consecutive_faults,
+    };
+    serde_json_core::to_string(&document).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn purchase_document() {
+        let item = Item::new("Energy Drink", 2.00, 10);
+        let event = Event::purchase(61_500, &item, &Purchase { item: 2, price: 2.00, remaining: 10 });
+
+        assert_eq!(
+            purchase_json(&event).unwrap(),
+            r#"{"ts_ms":61500,"item":"Energy Drink","price":2.0,"remaining":10}"#
+        );
+    }
+
+    #[test]
+    fn readings_have_no_purchase_document() {
+        let event = Event::reading(1_000, 1, SensorReading::default(), SensorReading::default(), Stock::default());
+
+        assert_eq!(purchase_json(&event), None);
+        assert_eq!(sensor_status_json(&event), None);
+    }
+
+    #[test]
+    fn sensor_status_documents() {
+        let offline = Event::sensor_status(120_000, Some(SensorFault::Nack), 3);
+        let online = Event::sensor_status(180_000, None, 0);
+
+        assert_eq!(
+            sensor_status_json(&offline).unwrap(),
+            r#"{"ts_ms":120000,"online":false,"fault":"no ack","consecutive_faults":3}"#
+        );
+        assert_eq!(sensor_status_json(&online).unwrap(), r#"{"ts_ms":180000,"online":true,"consecutive_faults":0}"#);
+    }
+}
@@ -0,0 +1,427 @@
+//! Smoothing and outlier rejection between acquisition and publishing.
+//!
+//! Each channel of a [`SensorReading`] runs through its own [`Pipeline`] of
+//! [`Filter`] stages, configured in `cfg.toml` as a comma separated list:
+//!
+//! - `average:N` is the mean of the last `N` values,
+//! - `median:N` the median of the last `N` values, which drops single spikes,
+//! - `ewma:ALPHA` an exponentially weighted moving average, where `ALPHA` in
+//!   (0, 1] is the weight of the newest value,
+//! - `clamp:STEP` limits the change from one value to the next to `STEP`.
+//!
+//! `"median:5,ewma:0.3"` drops spikes and then smooths what's left, and an
+//! empty list passes the raw values through.
+
+use heapless::{Deque, Vec};
+
+use crate::sensor::SensorReading;
+
+/// Most values a windowed filter looks at.
+pub const MAX_WINDOW: usize = 9;
+/// Most stages of one pipeline.
+pub const MAX_STAGES: usize = 4;
+
+pub trait Filter {
+    /// Feeds the next raw value, returning the filtered one.
+    fn apply(&mut self, value: f32) -> f32;
+
+    /// Forgets the values seen so far, e.g. after the sensor was
+    /// reinitialised.
+    fn reset(&mut self);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterError {
+    UnknownFilter,
+    /// A window outside 1..=[`MAX_WINDOW`], an `ewma` weight outside (0, 1] or
+    /// a `clamp` step that's not positive.
+    InvalidParameter,
+    TooManyStages,
+}
+
+impl FilterError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterError::UnknownFilter => "unknown filter",
+            FilterError::InvalidParameter => "invalid filter parameter",
+            FilterError::TooManyStages => "too many filter stages",
+        }
+    }
+}
+
+/// The last values fed to a windowed filter.
+#[derive(Debug, Clone)]
+struct Window {
+    size: usize,
+    values: Deque<f32, MAX_WINDOW>,
+}
+
+impl Window {
+    fn new(size: usize) -> Self {
+        assert!((1..=MAX_WINDOW).contains(&size), "window of {} values", size);
+        Self { size, values: Deque::new() }
+    }
+
+    fn push(&mut self, value: f32) {
+        if self.values.len() == self.size {
+            self.values.pop_front();
+        }
+        self.values.push_back(value).ok();
+    }
+}
+
+/// Mean of the last `size` values.
+#[derive(Debug, Clone)]
+pub struct MovingAverage(Window);
+
+impl MovingAverage {
+    /// `size` must be in 1..=[`MAX_WINDOW`].
+    pub fn new(size: usize) -> Self {
+        Self(Window::new(size))
+    }
+}
+
+impl Filter for MovingAverage {
+    fn apply(&mut self, value: f32) -> f32 {
+        self.0.push(value);
+        self.0.values.iter().sum::<f32>() / self.0.values.len() as f32
+    }
+
+    fn reset(&mut self) {
+        self.0.values.clear();
+    }
+}
+
+/// Median of the last `size` values. Until the window is full it's the
+/// median of the values so far, the mean of the middle two for an even count.
+#[derive(Debug, Clone)]
+pub struct Median(Window);
+
+impl Median {
+    /// `size` must be in 1..=[`MAX_WINDOW`].
+    pub fn new(size: usize) -> Self {
+        Self(Window::new(size))
+    }
+}
+
+impl Filter for Median {
+    fn apply(&mut self, value: f32) -> f32 {
+        self.0.push(value);
+
+        let mut sorted: Vec<f32, MAX_WINDOW> = self.0.values.iter().copied().collect();
+        sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+        let middle = sorted.len() / 2;
+        if sorted.len() % 2 == 1 {
+            sorted[middle]
+        } else {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        }
+    }
+
+    fn reset(&mut self) {
+        self.0.values.clear();
+    }
+}
+
+/// Exponentially weighted moving average, starting at the first value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ewma {
+    alpha: f32,
+    average: Option<f32>,
+}
+
+impl Ewma {
+    /// `alpha` in (0, 1] is the weight of each new value, 1 doesn't smooth.
+    pub fn new(alpha: f32) -> Self {
+        assert!(alpha > 0.0 && alpha <= 1.0, "EWMA weight {}", alpha);
+        Self { alpha, average: None }
+    }
+}
+
+impl Filter for Ewma {
+    fn apply(&mut self, value: f32) -> f32 {
+        let average = match self.average {
+            Some(average) => average + self.alpha * (value - average),
+            None => value,
+        };
+        self.average = Some(average);
+        average
+    }
+
+    fn reset(&mut self) {
+        self.average = None;
+    }
+}
+
+/// Limits how far a value may move from the previous output, so a single
+/// outlier only nudges the result while a lasting change is followed
+/// step by step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateClamp {
+    max_step: f32,
+    last: Option<f32>,
+}
+
+impl RateClamp {
+    /// `max_step` must be positive.
+    pub fn new(max_step: f32) -> Self {
+        assert!(max_step > 0.0, "rate clamp step {}", max_step);
+        Self { max_step, last: None }
+    }
+}
+
+impl Filter for RateClamp {
+    fn apply(&mut self, value: f32) -> f32 {
+        let clamped = match self.last {
+            Some(last) => value.clamp(last - self.max_step, last + self.max_step),
+            None => value,
+        };
+        self.last = Some(clamped);
+        clamped
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// One stage of a [`Pipeline`].
+#[derive(Debug, Clone)]
+pub enum Stage {
+    MovingAverage(MovingAverage),
+    Median(Median),
+    Ewma(Ewma),
+    RateClamp(RateClamp),
+}
+
+impl Stage {
+    /// Parses one stage like `median:5`.
+    pub fn parse(spec: &str) -> Result<Self, FilterError> {
+        let (name, parameter) = spec.trim().split_once(':').ok_or(FilterError::UnknownFilter)?;
+        let window = || match parameter.trim().parse::<usize>() {
+            Ok(size) if (1..=MAX_WINDOW).contains(&size) => Ok(size),
+            _ => Err(FilterError::InvalidParameter),
+        };
+        let number = || parameter.trim().parse::<f32>().map_err(|_| FilterError::InvalidParameter);
+
+        match name.trim() {
+            "average" => Ok(Stage::MovingAverage(MovingAverage::new(window()?))),
+            "median" => Ok(Stage::Median(Median::new(window()?))),
+            "ewma" => match number()? {
+                alpha if alpha > 0.0 && alpha <= 1.0 => Ok(Stage::Ewma(Ewma::new(alpha))),
+                _ => Err(FilterError::InvalidParameter),
+            },
+            "clamp" => match number()? {
+                step if step > 0.0 => Ok(Stage::RateClamp(RateClamp::new(step))),
+                _ => Err(FilterError::InvalidParameter),
+            },
+            _ => Err(FilterError::UnknownFilter),
+        }
+    }
+
+    fn filter(&mut self) -> &mut dyn Filter {
+        match self {
+            Stage::MovingAverage(filter) => filter,
+            Stage::Median(filter) => filter,
+            Stage::Ewma(filter) => filter,
+            Stage::RateClamp(filter) => filter,
+        }
+    }
+}
+
+impl Filter for Stage {
+    fn apply(&mut self, value: f32) -> f32 {
+        self.filter().apply(value)
+    }
+
+    fn reset(&mut self) {
+        self.filter().reset()
+    }
+}
+
+/// Stages applied one after the other.
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    stages: Vec<Stage, MAX_STAGES>,
+}
+
+impl Pipeline {
+    /// Passes values through unchanged.
+    pub const fn new() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    /// Parses a comma separated list of stages, see the [module](self) docs.
+    pub fn parse(spec: &str) -> Result<Self, FilterError> {
+        let mut pipeline = Self::new();
+        for stage in spec.split(',').filter(|stage| !stage.trim().is_empty()) {
+            pipeline.push(Stage::parse(stage)?)?;
+        }
+        Ok(pipeline)
+    }
+
+    pub fn push(&mut self, stage: Stage) -> Result<(), FilterError> {
+        self.stages.push(stage).map_err(|_| FilterError::TooManyStages)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+impl Filter for Pipeline {
+    fn apply(&mut self, value: f32) -> f32 {
+        if !value.is_finite() {
+            // would poison the state of every stage
+            return value;
+        }
+        self.stages.iter_mut().fold(value, |value, stage| stage.apply(value))
+    }
+
+    fn reset(&mut self) {
+        self.stages.iter_mut().for_each(Filter::reset);
+    }
+}
+
+/// A pipeline for each channel of the BME680.
+#[derive(Debug, Clone, Default)]
+pub struct ReadingFilters {
+    pub temperature: Pipeline,
+    pub humidity: Pipeline,
+    pub pressure: Pipeline,
+    pub gas_resistance: Pipeline,
+}
+
+impl ReadingFilters {
+    /// The filtered values of `raw`. The IAQ is left as is, it's scored from
+    /// the filtered values afterwards.
+    pub fn apply(&mut self, raw: &SensorReading) -> SensorReading {
+        SensorReading {
+            temperature: self.temperature.apply(raw.temperature),
+            humidity: self.humidity.apply(raw.humidity),
+            pressure: self.pressure.apply(raw.pressure),
+            gas_resistance: self.gas_resistance.apply(raw.gas_resistance),
+            iaq: raw.iaq,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.temperature.reset();
+        self.humidity.reset();
+        self.pressure.reset();
+        self.gas_resistance.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(filter: &mut impl Filter, values: &[f32]) -> std::vec::Vec<f32> {
+        values.iter().map(|&value| filter.apply(value)).collect()
+    }
+
+    #[test]
+    fn moving_average() {
+        let mut filter = MovingAverage::new(3);
+
+        assert_eq!(run(&mut filter, &[3.0, 6.0, 9.0, 12.0, 3.0]), [3.0, 4.5, 6.0, 9.0, 8.0]);
+    }
+
+    #[test]
+    fn median_drops_spikes() {
+        let mut filter = Median::new(3);
+
+        assert_eq!(run(&mut filter, &[21.0, 21.2, 85.0, 21.4, 21.3]), [21.0, 21.1, 21.2, 21.4, 21.4]);
+    }
+
+    #[test]
+    fn median_of_even_count() {
+        let mut filter = Median::new(4);
+
+        assert_eq!(run(&mut filter, &[1.0, 4.0, 2.0, 3.0, 10.0]), [1.0, 2.5, 2.0, 2.5, 3.5]);
+    }
+
+    #[test]
+    fn ewma() {
+        let mut filter = Ewma::new(0.5);
+
+        assert_eq!(run(&mut filter, &[10.0, 20.0, 20.0, 0.0]), [10.0, 15.0, 17.5, 8.75]);
+    }
+
+    #[test]
+    fn ewma_of_one_passes_through() {
+        let mut filter = Ewma::new(1.0);
+
+        assert_eq!(run(&mut filter, &[10.0, 20.0, 5.0]), [10.0, 20.0, 5.0]);
+    }
+
+    #[test]
+    fn rate_clamp_limits_steps() {
+        let mut filter = RateClamp::new(1.0);
+
+        assert_eq!(run(&mut filter, &[20.0, 20.5, 30.0, 30.0, 10.0]), [20.0, 20.5, 21.5, 22.5, 21.5]);
+    }
+
+    #[test]
+    fn reset_forgets_history() {
+        let mut filter = MovingAverage::new(3);
+        run(&mut filter, &[100.0, 100.0]);
+
+        filter.reset();
+
+        assert_eq!(filter.apply(1.0), 1.0);
+    }
+
+    #[test]
+    fn pipeline_chains_stages() {
+        let mut pipeline = Pipeline::parse("median:3, ewma:0.5").unwrap();
+
+        // the spike is dropped by the median before it reaches the average
+        assert_eq!(run(&mut pipeline, &[10.0, 10.0, 90.0, 10.0]), [10.0, 10.0, 10.0, 10.0]);
+    }
+
+    #[test]
+    fn empty_pipeline_passes_through() {
+        let mut pipeline = Pipeline::parse("").unwrap();
+
+        assert!(pipeline.is_empty());
+        assert_eq!(run(&mut pipeline, &[1.0, 90.0, 2.0]), [1.0, 90.0, 2.0]);
+    }
+
+    #[test]
+    fn pipeline_ignores_nan() {
+        let mut pipeline = Pipeline::parse("average:2").unwrap();
+
+        pipeline.apply(10.0);
+        assert!(pipeline.apply(f32::NAN).is_nan());
+        assert_eq!(pipeline.apply(20.0), 15.0);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Pipeline::parse("mean:3").unwrap_err(), FilterError::UnknownFilter);
+        assert_eq!(Pipeline::parse("median").unwrap_err(), FilterError::UnknownFilter);
+        assert_eq!(Pipeline::parse("median:0").unwrap_err(), FilterError::InvalidParameter);
+        assert_eq!(Pipeline::parse("median:10").unwrap_err(), FilterError::InvalidParameter);
+        assert_eq!(Pipeline::parse("ewma:0").unwrap_err(), FilterError::InvalidParameter);
+        assert_eq!(Pipeline::parse("ewma:1.5").unwrap_err(), FilterError::InvalidParameter);
+        assert_eq!(Pipeline::parse("clamp:-1").unwrap_err(), FilterError::InvalidParameter);
+        assert_eq!(Pipeline::parse("clamp:x").unwrap_err(), FilterError::InvalidParameter);
+        assert_eq!(
+            Pipeline::parse("ewma:0.5,ewma:0.5,ewma:0.5,ewma:0.5,ewma:0.5").unwrap_err(),
+            FilterError::TooManyStages
+        );
+    }
+
+    #[test]
+    fn filters_each_channel() {
+        let mut filters = ReadingFilters { temperature: Pipeline::parse("clamp:1").unwrap(), ..Default::default() };
+        let raw = SensorReading { temperature: 20.0, humidity: 40.0, pressure: 1000.0, gas_resistance: 5.0, iaq: None };
+        filters.apply(&raw);
+
+        let filtered = filters.apply(&SensorReading { temperature: 25.0, humidity: 45.0, ..raw });
+
+        assert_eq!(filtered, SensorReading { temperature: 21.0, humidity: 45.0, ..raw });
+    }
+}
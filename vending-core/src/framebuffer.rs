@@ -0,0 +1,352 @@
+//! Drawing off-screen and sending the result in bulk.
+//!
+//! [`DoubleBuffered`] renders into a back buffer in RAM instead of drawing on
+//! the panel pixel by pixel. It keeps a second, front buffer with what the
+//! panel shows, so [`DoubleBuffered::flush`] can compare the two and send
+//! each band of changed rows as a single [`Window`]. A page that's redrawn on
+//! top of itself therefore costs nothing but the comparison, and a page only
+//! reaches the panel once it's drawn completely. The flush isn't synchronised
+//! with the panel's refresh, so a large change can still tear.
+//!
+//! How a window is sent is up to the panel's [`WindowTarget`] implementation;
+//! by default it goes through `fill_contiguous`.
+//!
+//! The buffers take 2 × 2 bytes per pixel, 300 kB for the 320×240 panel of
+//! the ESP32-S3-BOX, which is why the firmware only places them in PSRAM
+//! with the `framebuffer` feature.
+
+use embedded_graphics::{
+    pixelcolor::{raw::RawU16, Rgb565},
+    prelude::*,
+    primitives::Rectangle,
+};
+
+use crate::redraw::{RedrawStats, WINDOW_BYTES};
+
+/// Tallest panel the buffers can follow.
+pub const MAX_HEIGHT: usize = 320;
+
+/// Pixels of one buffer for a panel of `size`.
+pub const fn buffer_len(size: Size) -> usize {
+    size.width as usize * size.height as usize
+}
+
+/// Rows × columns of the back buffer that [`DoubleBuffered::flush`] sends at
+/// once.
+pub struct Window<'a> {
+    area: Rectangle,
+    back: &'a [u16],
+    width: usize,
+}
+
+impl<'a> Window<'a> {
+    /// Where the window goes on the panel.
+    pub fn area(&self) -> Rectangle {
+        self.area
+    }
+
+    /// Raw Rgb565 values of each row, top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u16]> + '_ {
+        let (left, right) = (self.area.top_left.x as usize, self.area.top_left.x as usize + self.area.size.width as usize);
+        let top = self.area.top_left.y as usize;
+        (top..top + self.area.size.height as usize).map(move |y| &self.back[y * self.width + left..y * self.width + right])
+    }
+
+    pub fn pixels(&self) -> impl Iterator<Item = Rgb565> + '_ {
+        self.rows().flatten().map(|raw| Rgb565::from(RawU16::new(*raw)))
+    }
+}
+
+/// A panel [`DoubleBuffered`] can send windows to.
+pub trait WindowTarget: DrawTarget<Color = Rgb565> {
+    /// Shows `window` on the panel. Panels that can take the raw values in
+    /// one transfer should override this.
+    fn write_window(&mut self, window: &Window<'_>) -> Result<(), Self::Error> {
+        self.fill_contiguous(&window.area(), window.pixels())
+    }
+}
+
+/// Draws into `back` and sends what changed to `panel` on
+/// [`Self::flush`].
+pub struct DoubleBuffered<'a, D> {
+    panel: D,
+    size: Size,
+    /// Raw Rgb565 values, row by row.
+    back: &'a mut [u16],
+    /// What the panel shows, as far as it was sent from here.
+    front: &'a mut [u16],
+    /// Rows drawn into since the last flush.
+    dirty: [bool; MAX_HEIGHT],
+    /// The panel's contents are unknown, so everything is sent on the next
+    /// flush.
+    stale: bool,
+}
+
+impl<'a, D: WindowTarget> DoubleBuffered<'a, D> {
+    /// Uses `back` and `front` as the buffers for `panel`, `None` if either is
+    /// shorter than [`buffer_len`] or the panel is taller than
+    /// [`MAX_HEIGHT`]. The buffers may hold anything; the first flush sends
+    /// the whole back buffer.
+    pub fn new(panel: D, back: &'a mut [u16], front: &'a mut [u16]) -> Option<Self> {
+        let size = panel.bounding_box().size;
+        let len = buffer_len(size);
+        if back.len() < len || front.len() < len || size.height as usize > MAX_HEIGHT {
+            return None;
+        }
+
+        Some(Self {
+            panel,
+            size,
+            back: &mut back[..len],
+            front: &mut front[..len],
+            dirty: [false; MAX_HEIGHT],
+            stale: true,
+        })
+    }
+
+    pub fn panel(&self) -> &D {
+        &self.panel
+    }
+
+    /// The panel itself. Whatever is drawn on it directly has to be followed
+    /// by [`Self::invalidate`].
+    pub fn panel_mut(&mut self) -> &mut D {
+        &mut self.panel
+    }
+
+    /// Sends the whole back buffer on the next flush, e.g. after drawing on
+    /// the panel directly.
+    pub fn invalidate(&mut self) {
+        self.stale = true;
+    }
+
+    /// Sends the rows that differ from what the panel shows, returning what
+    /// was sent.
+    pub fn flush(&mut self) -> Result<RedrawStats, D::Error> {
+        let mut stats = RedrawStats::default();
+        // first row and columns of the changed rows above the current one
+        let mut band: Option<(usize, usize, usize)> = None;
+
+        for y in 0..=self.size.height as usize {
+            band = match (band, self.changed_columns(y)) {
+                (Some((top, left, right)), Some((first, last))) => Some((top, left.min(first), right.max(last))),
+                (None, Some((first, last))) => Some((y, first, last)),
+                (Some((top, left, right)), None) => {
+                    self.send(top..y, left..right + 1, &mut stats)?;
+                    None
+                }
+                (None, None) => None,
+            };
+        }
+
+        self.dirty = [false; MAX_HEIGHT];
+        self.stale = false;
+        Ok(stats)
+    }
+
+    /// First and last column of row `y` that have to be sent, `None` past the
+    /// bottom row or if it's unchanged.
+    fn changed_columns(&self, y: usize) -> Option<(usize, usize)> {
+        let width = self.size.width as usize;
+        if self.stale && y < self.size.height as usize {
+            return Some((0, width - 1));
+        }
+        if !self.dirty.get(y).copied().unwrap_or(false) || y >= self.size.height as usize {
+            return None;
+        }
+
+        let row = y * width..(y + 1) * width;
+        let (back, front) = (&self.back[row.clone()], &self.front[row]);
+        let first = (0..width).find(|&x| back[x] != front[x])?;
+        let last = (first..width).rfind(|&x| back[x] != front[x]).unwrap_or(first);
+        Some((first, last))
+    }
+
+    /// Sends the `rows` × `columns` part of the back buffer as one window.
+    fn send(&mut self, rows: core::ops::Range<usize>, columns: core::ops::Range<usize>, stats: &mut RedrawStats) -> Result<(), D::Error> {
+        let width = self.size.width as usize;
+        let area = Rectangle::new(
+            Point::new(columns.start as i32, rows.start as i32),
+            Size::new(columns.len() as u32, rows.len() as u32),
+        );
+
+        self.panel.write_window(&Window { area, back: self.back, width })?;
+
+        for y in rows {
+            let row = y * width + columns.start..y * width + columns.end;
+            self.front[row.clone()].copy_from_slice(&self.back[row]);
+        }
+        stats.writes += 1;
+        stats.bytes += WINDOW_BYTES + area.size.width as u64 * area.size.height as u64 * 2;
+        Ok(())
+    }
+
+    fn index(&self, point: Point) -> Option<usize> {
+        let inside = (0..self.size.width as i32).contains(&point.x) && (0..self.size.height as i32).contains(&point.y);
+        inside.then(|| point.y as usize * self.size.width as usize + point.x as usize)
+    }
+}
+
+impl<D: WindowTarget> Dimensions for DoubleBuffered<'_, D> {
+    fn bounding_box(&self) -> Rectangle {
+        Rectangle::new(Point::zero(), self.size)
+    }
+}
+
+impl<D: WindowTarget> DrawTarget for DoubleBuffered<'_, D> {
+    type Color = Rgb565;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let Some(index) = self.index(point) {
+                self.back[index] = RawU16::from(color).into_inner();
+                self.dirty[point.y as usize] = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        let Some(bottom_right) = area.bottom_right() else {
+            return Ok(());
+        };
+
+        let raw = RawU16::from(color).into_inner();
+        let width = self.size.width as usize;
+        for y in area.top_left.y as usize..=bottom_right.y as usize {
+            self.back[y * width + area.top_left.x as usize..=y * width + bottom_right.x as usize].fill(raw);
+            self.dirty[y] = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use embedded_graphics::{
+        mono_font::{ascii::FONT_10X20, MonoTextStyle},
+        primitives::PrimitiveStyle,
+        text::Text,
+    };
+
+    /// Remembers the windows it was sent and the pixels of the last one.
+    struct Panel {
+        windows: std::vec::Vec<Rectangle>,
+        pixels: std::vec::Vec<Rgb565>,
+    }
+
+    impl DrawTarget for Panel {
+        type Color = Rgb565;
+        type Error = core::convert::Infallible;
+
+        fn draw_iter<I: IntoIterator<Item = Pixel<Rgb565>>>(&mut self, _: I) -> Result<(), Self::Error> {
+            unreachable!("sent pixel by pixel")
+        }
+
+        fn fill_contiguous<I: IntoIterator<Item = Rgb565>>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error> {
+            self.windows.push(*area);
+            self.pixels = colors.into_iter().collect();
+            Ok(())
+        }
+    }
+
+    impl WindowTarget for Panel {}
+
+    impl OriginDimensions for Panel {
+        fn size(&self) -> Size {
+            Size::new(320, 240)
+        }
+    }
+
+    fn buffered<'a>(back: &'a mut [u16], front: &'a mut [u16]) -> DoubleBuffered<'a, Panel> {
+        DoubleBuffered::new(Panel { windows: std::vec::Vec::new(), pixels: std::vec::Vec::new() }, back, front).unwrap()
+    }
+
+    #[test]
+    fn first_flush_sends_everything() {
+        let (mut back, mut front) = (std::vec![0; 320 * 240], std::vec![0; 320 * 240]);
+        let mut display = buffered(&mut back, &mut front);
+
+        let stats = display.flush().unwrap();
+
+        assert_eq!(display.panel().windows, [Rectangle::new(Point::zero(), Size::new(320, 240))]);
+        assert_eq!(stats, RedrawStats { writes: 1, bytes: WINDOW_BYTES + 320 * 240 * 2 });
+    }
+
+    #[test]
+    fn sends_only_changed_rows_and_columns() {
+        let (mut back, mut front) = (std::vec![0; 320 * 240], std::vec![0; 320 * 240]);
+        let mut display = buffered(&mut back, &mut front);
+        display.flush().unwrap();
+
+        Rectangle::new(Point::new(10, 20), Size::new(5, 3))
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::RED))
+            .draw(&mut display)
+            .unwrap();
+        Pixel(Point::new(100, 21), Rgb565::BLUE).draw(&mut display).unwrap();
+        Pixel(Point::new(50, 200), Rgb565::GREEN).draw(&mut display).unwrap();
+        display.flush().unwrap();
+
+        assert_eq!(
+            display.panel().windows[1..],
+            [
+                Rectangle::new(Point::new(10, 20), Size::new(91, 3)),
+                Rectangle::new(Point::new(50, 200), Size::new(1, 1)),
+            ]
+        );
+        assert_eq!(display.panel().pixels, [Rgb565::GREEN]);
+    }
+
+    #[test]
+    fn redrawing_the_same_sends_nothing() {
+        let (mut back, mut front) = (std::vec![0; 320 * 240], std::vec![0; 320 * 240]);
+        let mut display = buffered(&mut back, &mut front);
+        let draw = |display: &mut DoubleBuffered<Panel>| {
+            display.clear(Rgb565::WHITE).unwrap();
+            Text::new("Status", Point::new(120, 30), MonoTextStyle::new(&FONT_10X20, Rgb565::BLACK)).draw(display).unwrap();
+        };
+
+        draw(&mut display);
+        display.flush().unwrap();
+        draw(&mut display);
+
+        assert_eq!(display.flush().unwrap(), RedrawStats::default());
+        assert_eq!(display.panel().windows.len(), 1);
+    }
+
+    #[test]
+    fn invalidate_sends_everything_again() {
+        let (mut back, mut front) = (std::vec![0; 320 * 240], std::vec![0; 320 * 240]);
+        let mut display = buffered(&mut back, &mut front);
+        display.flush().unwrap();
+
+        display.invalidate();
+
+        assert_eq!(display.flush().unwrap().writes, 1);
+        assert_eq!(display.panel().pixels.len(), 320 * 240);
+    }
+
+    #[test]
+    fn windows_are_sent_row_by_row() {
+        let back: std::vec::Vec<u16> = (0..320 * 3).map(|i| i as u16).collect();
+        let window = Window { area: Rectangle::new(Point::new(2, 1), Size::new(3, 2)), back: &back, width: 320 };
+
+        assert_eq!(window.rows().collect::<std::vec::Vec<_>>(), [&[322, 323, 324][..], &[642, 643, 644][..]]);
+        assert_eq!(window.pixels().count(), 6);
+    }
+
+    #[test]
+    fn rejects_short_buffers() {
+        let (mut back, mut front) = (std::vec![0; 320 * 239], std::vec![0; 320 * 240]);
+        let panel = Panel { windows: std::vec::Vec::new(), pixels: std::vec::Vec::new() };
+
+        assert!(DoubleBuffered::new(panel, &mut back, &mut front).is_none());
+    }
+}
@@ -0,0 +1,247 @@
+//! Recent values of each [`Metric`] for the trend charts.
+//!
+//! Readings are averaged into fixed-length buckets, one ring of [`POINTS`]
+//! buckets per [`Span`], so the last hour and the last day take the same
+//! memory. Buckets nobody recorded into, e.g. while the sensor was offline,
+//! are kept as NaN so the chart shows a gap instead of joining across it.
+
+use heapless::Deque;
+
+use crate::alarm::Metric;
+use crate::sensor::SensorReading;
+
+/// Points of a chart, whichever its span.
+pub const POINTS: usize = 60;
+
+/// How far back a chart goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Span {
+    Hour,
+    Day,
+}
+
+impl Span {
+    pub const ALL: [Span; 2] = [Span::Hour, Span::Day];
+
+    /// Length of a bucket, so [`POINTS`] of them cover the span.
+    pub const fn bucket_ms(&self) -> u64 {
+        match self {
+            Span::Hour => 60 * 60 * 1000 / POINTS as u64,
+            Span::Day => 24 * 60 * 60 * 1000 / POINTS as u64,
+        }
+    }
+
+    /// The name on the display.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Span::Hour => "1 hour",
+            Span::Day => "24 hours",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Bucket averages of one metric over one span, oldest first.
+#[derive(Debug, Clone)]
+pub struct Trend {
+    bucket_ms: u64,
+    points: Deque<f32, POINTS>,
+    /// Start of the bucket values are added to, `None` before the first one.
+    bucket_start: Option<u64>,
+    sum: f32,
+    count: u32,
+}
+
+impl Trend {
+    pub const fn new(span: Span) -> Self {
+        Self { bucket_ms: span.bucket_ms(), points: Deque::new(), bucket_start: None, sum: 0.0, count: 0 }
+    }
+
+    /// Adds a value measured at `now_ms`. NaN is ignored.
+    pub fn record(&mut self, value: f32, now_ms: u64) {
+        if value.is_nan() {
+            return;
+        }
+
+        let start = now_ms - now_ms % self.bucket_ms;
+        match self.bucket_start {
+            None => self.bucket_start = Some(start),
+            Some(current) if start > current => {
+                self.close_bucket();
+                // buckets that passed without a value, at most a chart full
+                let skipped = ((start - current) / self.bucket_ms - 1).min(POINTS as u64);
+                for _ in 0..skipped {
+                    self.push(f32::NAN);
+                }
+                self.bucket_start = Some(start);
+            }
+            // a clock running backwards keeps adding to the current bucket
+            Some(_) => {}
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// The last [`POINTS`] buckets, oldest first, ending with the one still
+    /// being filled. Empty buckets are NaN.
+    pub fn points(&self) -> impl Iterator<Item = f32> + '_ {
+        let current = (self.count > 0).then(|| self.sum / self.count as f32);
+        let skip = (self.points.len() + current.is_some() as usize).saturating_sub(POINTS);
+        self.points.iter().copied().chain(current).skip(skip)
+    }
+
+    /// Lowest and highest point, `None` while there are none.
+    pub fn range(&self) -> Option<(f32, f32)> {
+        self.points().filter(|value| !value.is_nan()).fold(None, |range, value| match range {
+            None => Some((value, value)),
+            Some((min, max)) => Some((min.min(value), max.max(value))),
+        })
+    }
+
+    fn close_bucket(&mut self) {
+        let mean = if self.count > 0 { self.sum / self.count as f32 } else { f32::NAN };
+        self.push(mean);
+        self.sum = 0.0;
+        self.count = 0;
+    }
+
+    fn push(&mut self, value: f32) {
+        if self.points.is_full() {
+            self.points.pop_front();
+        }
+        self.points.push_back(value).ok();
+    }
+}
+
+/// A [`Trend`] for every metric and span.
+#[derive(Debug, Clone)]
+pub struct SensorHistory {
+    trends: [[Trend; 2]; 4],
+}
+
+impl SensorHistory {
+    pub const fn new() -> Self {
+        const EMPTY: [Trend; 2] = [Trend::new(Span::Hour), Trend::new(Span::Day)];
+        Self { trends: [EMPTY; 4] }
+    }
+
+    /// Adds the values of a reading taken at `now_ms`. The IAQ is skipped
+    /// during the burn-in.
+    pub fn record(&mut self, reading: &SensorReading, now_ms: u64) {
+        for metric in Metric::ALL {
+            if let Some(value) = metric.value(reading) {
+                for trend in &mut self.trends[metric.index()] {
+                    trend.record(value, now_ms);
+                }
+            }
+        }
+    }
+
+    pub fn trend(&self, metric: Metric, span: Span) -> &Trend {
+        &self.trends[metric.index()][span.index()]
+    }
+}
+
+impl Default for SensorHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000;
+
+    fn points(trend: &Trend) -> std::vec::Vec<f32> {
+        trend.points().collect()
+    }
+
+    #[test]
+    fn averages_values_of_a_bucket() {
+        let mut trend = Trend::new(Span::Hour);
+
+        trend.record(20.0, 0);
+        trend.record(22.0, 30_000);
+        assert_eq!(points(&trend), [21.0]);
+
+        trend.record(25.0, MINUTE);
+        assert_eq!(points(&trend), [21.0, 25.0]);
+    }
+
+    #[test]
+    fn keeps_the_last_points() {
+        let mut trend = Trend::new(Span::Hour);
+
+        for minute in 0..100 {
+            trend.record(minute as f32, minute * MINUTE);
+        }
+
+        let points = points(&trend);
+        assert_eq!(points.len(), POINTS);
+        assert_eq!(points.first(), Some(&40.0));
+        assert_eq!(points.last(), Some(&99.0));
+    }
+
+    #[test]
+    fn missed_buckets_are_gaps() {
+        let mut trend = Trend::new(Span::Hour);
+
+        trend.record(20.0, 0);
+        trend.record(21.0, 3 * MINUTE);
+
+        let points = points(&trend);
+        assert_eq!(points.len(), 4);
+        assert!(points[1].is_nan() && points[2].is_nan());
+        assert_eq!(trend.range(), Some((20.0, 21.0)));
+    }
+
+    #[test]
+    fn long_outage_empties_the_chart() {
+        let mut trend = Trend::new(Span::Hour);
+
+        trend.record(20.0, 0);
+        trend.record(21.0, 24 * 60 * MINUTE);
+
+        let points = points(&trend);
+        assert_eq!(points.len(), POINTS);
+        assert_eq!(trend.range(), Some((21.0, 21.0)));
+    }
+
+    #[test]
+    fn day_buckets_span_24_minutes() {
+        let mut trend = Trend::new(Span::Day);
+
+        for minute in 0..48 {
+            trend.record(minute as f32, minute * MINUTE);
+        }
+
+        assert_eq!(points(&trend), [11.5, 35.5]);
+    }
+
+    #[test]
+    fn ignores_nan() {
+        let mut trend = Trend::new(Span::Hour);
+
+        trend.record(f32::NAN, 0);
+
+        assert_eq!(trend.points().count(), 0);
+        assert_eq!(trend.range(), None);
+    }
+
+    #[test]
+    fn records_every_metric() {
+        let mut history = SensorHistory::new();
+        let reading = SensorReading { temperature: 21.5, humidity: 40.0, pressure: 1013.0, gas_resistance: 50_000.0, iaq: None };
+
+        history.record(&reading, 0);
+
+        assert_eq!(points(history.trend(Metric::Pressure, Span::Day)), [1013.0]);
+        assert_eq!(history.trend(Metric::Iaq, Span::Hour).points().count(), 0);
+    }
+}
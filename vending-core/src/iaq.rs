@@ -0,0 +1,200 @@
+//! Indoor air quality estimated from the BME680 gas resistance.
+//!
+//! The gas sensor's resistance drops as volatile organic compounds rise, but
+//! its absolute value differs from sensor to sensor and drifts while the
+//! heater warms up. [`AirQuality`] therefore averages the first readings into a
+//! clean-air baseline and scores later readings relative to it, with a quarter
+//! of the score coming from how far humidity is from a comfortable 40 %.
+//!
+//! The score is reported on the 0–500 scale of Bosch's IAQ index, where lower
+//! is better.
+
+/// Humidity considered ideal, in %.
+const HUMIDITY_BASELINE: f32 = 40.0;
+/// Share of the humidity in the score.
+const HUMIDITY_WEIGHTING: f32 = 0.25;
+
+/// IAQ index from 0 (excellent) to 500 (extremely polluted).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Iaq(pub u16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IaqCategory {
+    Excellent,
+    Good,
+    LightlyPolluted,
+    ModeratelyPolluted,
+    HeavilyPolluted,
+    SeverelyPolluted,
+    ExtremelyPolluted,
+}
+
+impl Iaq {
+    pub fn category(&self) -> IaqCategory {
+        match self.0 {
+            0..=50 => IaqCategory::Excellent,
+            51..=100 => IaqCategory::Good,
+            101..=150 => IaqCategory::LightlyPolluted,
+            151..=200 => IaqCategory::ModeratelyPolluted,
+            201..=250 => IaqCategory::HeavilyPolluted,
+            251..=350 => IaqCategory::SeverelyPolluted,
+            _ => IaqCategory::ExtremelyPolluted,
+        }
+    }
+}
+
+impl IaqCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IaqCategory::Excellent => "Excellent",
+            IaqCategory::Good => "Good",
+            IaqCategory::LightlyPolluted => "Light",
+            IaqCategory::ModeratelyPolluted => "Moderate",
+            IaqCategory::HeavilyPolluted => "Heavy",
+            IaqCategory::SeverelyPolluted => "Severe",
+            IaqCategory::ExtremelyPolluted => "Extreme",
+        }
+    }
+}
+
+/// Baseline calibration and scoring of gas readings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AirQuality {
+    burn_in_samples: u32,
+    samples: u32,
+    gas_sum: f32,
+    gas_baseline: Option<f32>,
+}
+
+impl AirQuality {
+    /// Calibrates on the first `burn_in_samples` readings, which should span
+    /// a few minutes of heater operation in clean air.
+    pub const fn new(burn_in_samples: u32) -> Self {
+        Self { burn_in_samples, samples: 0, gas_sum: 0.0, gas_baseline: None }
+    }
+
+    /// The clean-air gas resistance in Ω, once burn-in is over.
+    pub fn baseline(&self) -> Option<f32> {
+        self.gas_baseline
+    }
+
+    /// Feeds a reading, returning its score or `None` during burn-in.
+    pub fn update(&mut self, gas_resistance: f32, humidity: f32) -> Option<Iaq> {
+        let Some(baseline) = self.gas_baseline else {
+            self.samples += 1;
+            self.gas_sum += gas_resistance;
+            if self.samples >= self.burn_in_samples {
+                self.gas_baseline = Some(self.gas_sum / self.samples as f32);
+            }
+            return None;
+        };
+
+        // Air cleaner than during calibration means the baseline was too low
+        let baseline = if gas_resistance > baseline {
+            self.gas_baseline = Some(gas_resistance);
+            gas_resistance
+        } else {
+            baseline
+        };
+
+        Some(score(gas_resistance, baseline, humidity))
+    }
+}
+
+/// 0 (clean air at ideal humidity) to 500.
+fn score(gas_resistance: f32, gas_baseline: f32, humidity: f32) -> Iaq {
+    let humidity_share = HUMIDITY_WEIGHTING * 100.0;
+    let humidity_offset = humidity - HUMIDITY_BASELINE;
+    let humidity_score = if humidity_offset > 0.0 {
+        (100.0 - HUMIDITY_BASELINE - humidity_offset) / (100.0 - HUMIDITY_BASELINE) * humidity_share
+    } else {
+        (HUMIDITY_BASELINE + humidity_offset) / HUMIDITY_BASELINE * humidity_share
+    };
+
+    let gas_share = 100.0 - humidity_share;
+    let gas_score = if gas_resistance < gas_baseline {
+        gas_resistance / gas_baseline * gas_share
+    } else {
+        gas_share
+    };
+
+    // 100 is the best quality, turn it into an index where 0 is
+    let quality = (humidity_score + gas_score).clamp(0.0, 100.0);
+    Iaq(((100.0 - quality) * 5.0 + 0.5) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibrated(baseline: f32) -> AirQuality {
+        let mut air_quality = AirQuality::new(3);
+        for _ in 0..3 {
+            assert_eq!(air_quality.update(baseline, 40.0), None);
+        }
+        air_quality
+    }
+
+    #[test]
+    fn burn_in_averages_baseline() {
+        let mut air_quality = AirQuality::new(3);
+
+        assert_eq!(air_quality.update(40_000.0, 40.0), None);
+        assert_eq!(air_quality.update(50_000.0, 40.0), None);
+        assert_eq!(air_quality.baseline(), None);
+        assert_eq!(air_quality.update(60_000.0, 40.0), None);
+
+        assert_eq!(air_quality.baseline(), Some(50_000.0));
+    }
+
+    #[test]
+    fn clean_air_at_ideal_humidity_is_excellent() {
+        let mut air_quality = calibrated(50_000.0);
+
+        let iaq = air_quality.update(50_000.0, 40.0).unwrap();
+
+        assert_eq!(iaq, Iaq(0));
+        assert_eq!(iaq.category(), IaqCategory::Excellent);
+    }
+
+    #[test]
+    fn falling_resistance_raises_index() {
+        let mut air_quality = calibrated(50_000.0);
+
+        // Half the baseline resistance loses half of the 75 gas points
+        assert_eq!(air_quality.update(25_000.0, 40.0), Some(Iaq(188)));
+        assert_eq!(air_quality.update(5_000.0, 40.0), Some(Iaq(338)));
+    }
+
+    #[test]
+    fn humidity_is_compensated() {
+        let mut air_quality = calibrated(50_000.0);
+
+        // 70 % is halfway from ideal to saturated, losing half of the 25 humidity points
+        assert_eq!(air_quality.update(50_000.0, 70.0), Some(Iaq(63)));
+        // 20 % is halfway to bone dry
+        assert_eq!(air_quality.update(50_000.0, 20.0), Some(Iaq(63)));
+        assert_eq!(air_quality.update(50_000.0, 0.0), Some(Iaq(125)));
+    }
+
+    #[test]
+    fn cleaner_air_raises_baseline() {
+        let mut air_quality = calibrated(50_000.0);
+
+        assert_eq!(air_quality.update(60_000.0, 40.0), Some(Iaq(0)));
+
+        assert_eq!(air_quality.baseline(), Some(60_000.0));
+        assert_eq!(air_quality.update(30_000.0, 40.0), Some(Iaq(188)));
+    }
+
+    #[test]
+    fn categories() {
+        assert_eq!(Iaq(50).category(), IaqCategory::Excellent);
+        assert_eq!(Iaq(51).category(), IaqCategory::Good);
+        assert_eq!(Iaq(150).category(), IaqCategory::LightlyPolluted);
+        assert_eq!(Iaq(200).category(), IaqCategory::ModeratelyPolluted);
+        assert_eq!(Iaq(250).category(), IaqCategory::HeavilyPolluted);
+        assert_eq!(Iaq(350).category(), IaqCategory::SeverelyPolluted);
+        assert_eq!(Iaq(500).category(), IaqCategory::ExtremelyPolluted);
+    }
+}
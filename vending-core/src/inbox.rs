@@ -0,0 +1,448 @@
+//! Waiting for the broker's next MQTT packet without losing half of it.
+//!
+//! The firmware waits for commands, the ping timer and queued events at the
+//! same time. rust-mqtt's `receive_message` reads the fixed header and then
+//! the rest of the packet, and dropping it in between, because the timer
+//! fired, leaves the session reading from the middle of a packet.
+//!
+//! [`Inbox`] sits between the TLS session and the MQTT client.
+//! [`Inbox::wait_packet`] reads into a buffer until a whole packet is there,
+//! never past its end, and keeps what it read when it's dropped, so it can
+//! lose a `select`. Once it returned, `receive_message` gets the packet from
+//! the buffer and finishes without waiting on the network. The client reads
+//! and writes through [`Inbox::io`], which hands it one whole packet per read.
+//!
+//! While publishing with QoS 1 or pinging, the client reads the next packet
+//! as the reply and loses a PUBLISH the broker sent in between, such as a set
+//! command. Reads that weren't preceded by `wait_packet` therefore hold
+//! PUBLISH packets back and return the next packet after them; the held ones
+//! come out of `wait_packet` first afterwards.
+//!
+//! This is only as cancel-safe as the transport's own `read`, which must not
+//! lose data when dropped before it returned.
+
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use heapless::Vec;
+
+/// Bytes of a fixed header: the packet type and at most four bytes of
+/// remaining length.
+const MAX_HEADER_LEN: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The remaining length doesn't end within four bytes.
+    Malformed,
+    /// The packet doesn't fit the buffer.
+    TooLarge,
+}
+
+// MQTT control packet type in the high nibble of the first byte
+const PUBLISH: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboxError<E> {
+    Io(E),
+    /// The broker closed the connection.
+    Closed,
+    Frame(FrameError),
+}
+
+impl<E> From<FrameError> for InboxError<E> {
+    fn from(error: FrameError) -> Self {
+        InboxError::Frame(error)
+    }
+}
+
+impl<E: embedded_io_async::Error> embedded_io_async::Error for InboxError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            InboxError::Io(e) => e.kind(),
+            InboxError::Closed => ErrorKind::ConnectionAborted,
+            InboxError::Frame(_) => ErrorKind::InvalidData,
+        }
+    }
+}
+
+/// Length of the packet at the start of `bytes`, once its fixed header is
+/// there, if it fits `N` bytes.
+fn packet_len<const N: usize>(bytes: &[u8]) -> Result<Option<usize>, FrameError> {
+    let mut remaining = 0;
+    for (i, &byte) in bytes.iter().enumerate().skip(1) {
+        remaining |= usize::from(byte & 0x7F) << (7 * (i - 1));
+        if byte & 0x80 == 0 {
+            let len = i + 1 + remaining;
+            return if len > N { Err(FrameError::TooLarge) } else { Ok(Some(len)) };
+        }
+        if i + 1 == MAX_HEADER_LEN {
+            return Err(FrameError::Malformed);
+        }
+    }
+    Ok(None)
+}
+
+/// Bytes of the packet at the front of the stream.
+#[derive(Debug)]
+pub struct PacketBuffer<const N: usize> {
+    data: [u8; N],
+    filled: usize,
+    /// Bytes already handed to the client.
+    taken: usize,
+}
+
+impl<const N: usize> PacketBuffer<N> {
+    pub const fn new() -> Self {
+        Self { data: [0; N], filled: 0, taken: 0 }
+    }
+
+    /// Length of the whole packet, once its fixed header is buffered.
+    pub fn packet_len(&self) -> Result<Option<usize>, FrameError> {
+        packet_len::<N>(&self.data[..self.filled])
+    }
+
+    pub fn is_complete(&self) -> Result<bool, FrameError> {
+        Ok(self.packet_len()? == Some(self.filled))
+    }
+
+    /// Where the next read goes, which ends with the packet, or with the
+    /// next byte of the fixed header while its length isn't known yet.
+    pub fn unfilled(&mut self) -> Result<&mut [u8], FrameError> {
+        let end = match self.packet_len()? {
+            Some(len) => len,
+            None => self.filled.max(1) + 1,
+        };
+        Ok(&mut self.data[self.filled..end])
+    }
+
+    /// `len` bytes were read into [`PacketBuffer::unfilled`].
+    pub fn fill(&mut self, len: usize) {
+        self.filled += len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.taken == self.filled
+    }
+
+    /// Whether a whole PUBLISH is buffered and none of it was taken yet.
+    pub fn is_publish(&self) -> Result<bool, FrameError> {
+        Ok(self.taken == 0 && self.data[0] >> 4 == PUBLISH && self.is_complete()?)
+    }
+
+    /// The whole packet, emptying the buffer.
+    fn take_packet(&mut self) -> &[u8] {
+        let filled = self.filled;
+        self.filled = 0;
+        self.taken = 0;
+        &self.data[..filled]
+    }
+
+    /// Puts a whole packet into the empty buffer.
+    fn put_packet(&mut self, packet: &[u8]) {
+        self.data[..packet.len()].copy_from_slice(packet);
+        self.filled = packet.len();
+    }
+
+    /// Moves buffered bytes to `buf`, emptying the buffer once all are taken.
+    pub fn take(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.filled - self.taken);
+        buf[..len].copy_from_slice(&self.data[self.taken..][..len]);
+        self.taken += len;
+        if self.is_empty() {
+            self.filled = 0;
+            self.taken = 0;
+        }
+        len
+    }
+}
+
+impl<const N: usize> Default for PacketBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Inner<T, const N: usize> {
+    io: T,
+    packet: PacketBuffer<N>,
+    /// Whole PUBLISH packets that arrived while the client waited for a
+    /// reply, oldest first.
+    held: Vec<u8, N>,
+}
+
+impl<T: Read, const N: usize> Inner<T, N> {
+    /// Reads until the buffered packet is complete.
+    async fn fill_packet(&mut self) -> Result<(), InboxError<T::Error>> {
+        while !self.packet.is_complete()? {
+            match self.io.read(self.packet.unfilled()?).await {
+                Ok(0) => return Err(InboxError::Closed),
+                Ok(len) => self.packet.fill(len),
+                Err(e) => return Err(InboxError::Io(e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the oldest held PUBLISH into the empty packet buffer.
+    fn release_held(&mut self) -> bool {
+        // held packets were complete, so their length is known
+        let Ok(Some(len)) = packet_len::<N>(&self.held) else {
+            return false;
+        };
+        self.packet.put_packet(&self.held[..len]);
+        self.held.rotate_left(len);
+        self.held.truncate(self.held.len() - len);
+        true
+    }
+}
+
+/// Transport of one connection with the packet buffer in front of it. `N`
+/// should match the client's receive buffer.
+pub struct Inbox<T, const N: usize> {
+    inner: Mutex<NoopRawMutex, Inner<T, N>>,
+}
+
+impl<T: Read + Write, const N: usize> Inbox<T, N> {
+    pub fn new(io: T) -> Self {
+        Self { inner: Mutex::new(Inner { io, packet: PacketBuffer::new(), held: Vec::new() }) }
+    }
+
+    /// Returns once the next packet is buffered, a held PUBLISH first.
+    /// Cancel-safe.
+    pub async fn wait_packet(&self) -> Result<(), InboxError<T::Error>> {
+        let mut inner = self.inner.lock().await;
+        if inner.packet.is_empty() && inner.release_held() {
+            return Ok(());
+        }
+        inner.fill_packet().await
+    }
+
+    /// Handle for the MQTT client.
+    pub fn io(&self) -> InboxIo<'_, T, N> {
+        InboxIo(self)
+    }
+}
+
+pub struct InboxIo<'a, T, const N: usize>(&'a Inbox<T, N>);
+
+impl<T: ErrorType, const N: usize> ErrorType for InboxIo<'_, T, N> {
+    type Error = InboxError<T::Error>;
+}
+
+impl<T: Read, const N: usize> Read for InboxIo<'_, T, N> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut inner = self.0.inner.lock().await;
+        if inner.packet.is_empty() {
+            // the client waits for a reply, which a PUBLISH isn't
+            inner.fill_packet().await?;
+            while inner.packet.is_publish()? {
+                let Inner { packet, held, .. } = &mut *inner;
+                let publish = packet.take_packet();
+                // with no room left it's lost, as it would be in the client
+                held.extend_from_slice(publish).ok();
+                inner.fill_packet().await?;
+            }
+        }
+        Ok(inner.packet.take(buf))
+    }
+}
+
+impl<T: Write, const N: usize> Write for InboxIo<'_, T, N> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.inner.lock().await.io.write(buf).await.map_err(InboxError::Io)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.inner.lock().await.io.flush().await.map_err(InboxError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use embassy_futures::block_on;
+    use std::vec::Vec;
+
+    // PUBLISH of "a/b" with payload "hi", QoS 0
+    const PUBLISH: [u8; 9] = [0x30, 7, 0, 3, b'a', b'/', b'b', b'h', b'i'];
+
+    /// Broker sending `stream`, which stalls once before every read.
+    struct FakeBroker {
+        stream: Vec<u8>,
+        stalled: bool,
+        written: Vec<u8>,
+    }
+
+    impl FakeBroker {
+        fn new(stream: &[u8]) -> Self {
+            Self { stream: stream.to_vec(), stalled: false, written: Vec::new() }
+        }
+    }
+
+    impl ErrorType for FakeBroker {
+        type Error = ErrorKind;
+    }
+
+    impl Read for FakeBroker {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            core::future::poll_fn(|cx| {
+                self.stalled = !self.stalled;
+                if self.stalled {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+            .await;
+            let len = buf.len().min(self.stream.len());
+            buf[..len].copy_from_slice(&self.stream[..len]);
+            self.stream.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for FakeBroker {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn into_broker<const N: usize>(inbox: Inbox<FakeBroker, N>) -> FakeBroker {
+        inbox.inner.into_inner().io
+    }
+
+    fn read_all<const N: usize>(inbox: &Inbox<FakeBroker, N>, len: usize) -> Vec<u8> {
+        let mut received = vec![0; len];
+        let mut io = inbox.io();
+        block_on(io.read_exact(&mut received)).unwrap();
+        received
+    }
+
+    #[test]
+    fn finds_the_packet_length() {
+        let mut packet: PacketBuffer<256> = PacketBuffer::new();
+        assert_eq!(packet.packet_len(), Ok(None));
+
+        packet.unfilled().unwrap()[..2].copy_from_slice(&[0x30, 0x80]);
+        packet.fill(2);
+        assert_eq!(packet.packet_len(), Ok(None));
+        assert_eq!(packet.unfilled().unwrap().len(), 1);
+
+        packet.unfilled().unwrap()[0] = 0x01;
+        packet.fill(1);
+        // 128 bytes after a three byte header
+        assert_eq!(packet.packet_len(), Ok(Some(131)));
+        assert_eq!(packet.unfilled().unwrap().len(), 128);
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        let mut packet: PacketBuffer<64> = PacketBuffer::new();
+        packet.unfilled().unwrap().copy_from_slice(&[0x30, 0xFF]);
+        packet.fill(2);
+        for _ in 0..3 {
+            packet.unfilled().unwrap()[0] = 0xFF;
+            packet.fill(1);
+        }
+        assert_eq!(packet.packet_len(), Err(FrameError::Malformed));
+
+        let mut packet: PacketBuffer<64> = PacketBuffer::new();
+        packet.unfilled().unwrap().copy_from_slice(&[0x30, 0x7F]);
+        packet.fill(2);
+        assert_eq!(packet.packet_len(), Err(FrameError::TooLarge));
+    }
+
+    #[test]
+    fn buffers_one_packet_and_no_more() {
+        let mut stream = PUBLISH.to_vec();
+        stream.extend_from_slice(&[0xD0, 0]);
+        let inbox: Inbox<_, 64> = Inbox::new(FakeBroker::new(&stream));
+
+        block_on(inbox.wait_packet()).unwrap();
+        assert_eq!(read_all(&inbox, PUBLISH.len()), PUBLISH);
+        // the PINGRESP after it is still in the stream
+        assert_eq!(into_broker(inbox).stream, [0xD0, 0]);
+    }
+
+    #[test]
+    fn keeps_what_was_read_when_cancelled() {
+        let inbox: Inbox<_, 64> = Inbox::new(FakeBroker::new(&PUBLISH));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // give up after each read, as when the ping timer wins the select
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            let mut wait = pin!(inbox.wait_packet());
+            if wait.as_mut().poll(&mut cx).is_ready() {
+                break;
+            }
+            if wait.as_mut().poll(&mut cx).is_ready() {
+                break;
+            }
+        }
+
+        assert!(polls > 1);
+        assert_eq!(read_all(&inbox, PUBLISH.len()), PUBLISH);
+    }
+
+    #[test]
+    fn reads_past_the_buffer_from_the_transport() {
+        let mut stream = PUBLISH.to_vec();
+        stream.extend_from_slice(&[0xD0, 0]);
+        let inbox: Inbox<_, 64> = Inbox::new(FakeBroker::new(&stream));
+
+        block_on(inbox.wait_packet()).unwrap();
+        assert_eq!(read_all(&inbox, stream.len()), stream);
+    }
+
+    #[test]
+    fn writes_go_to_the_transport() {
+        let inbox: Inbox<_, 64> = Inbox::new(FakeBroker::new(&[]));
+        block_on(inbox.io().write_all(&[0xC0, 0])).unwrap();
+
+        assert_eq!(into_broker(inbox).written, [0xC0, 0]);
+    }
+
+    #[test]
+    fn reports_a_closed_connection() {
+        let inbox: Inbox<_, 64> = Inbox::new(FakeBroker::new(&PUBLISH[..4]));
+
+        assert_eq!(block_on(inbox.wait_packet()), Err(InboxError::Closed));
+    }
+
+    #[test]
+    fn holds_a_publish_sent_before_the_reply() {
+        // a set command arrives between a PINGREQ and its PINGRESP
+        let mut stream = PUBLISH.to_vec();
+        stream.extend_from_slice(&[0xD0, 0]);
+        let inbox: Inbox<_, 64> = Inbox::new(FakeBroker::new(&stream));
+
+        let mut reply = [0; 64];
+        let len = block_on(inbox.io().read(&mut reply)).unwrap();
+        assert_eq!(reply[..len], [0xD0, 0]);
+
+        block_on(inbox.wait_packet()).unwrap();
+        assert_eq!(read_all(&inbox, PUBLISH.len()), PUBLISH);
+    }
+
+    #[test]
+    fn reads_one_packet_at_a_time() {
+        let mut stream = vec![0x40, 2, 0, 7];
+        stream.extend_from_slice(&[0xD0, 0]);
+        let inbox: Inbox<_, 64> = Inbox::new(FakeBroker::new(&stream));
+
+        let mut reply = [0; 64];
+        let len = block_on(inbox.io().read(&mut reply)).unwrap();
+        assert_eq!(reply[..len], [0x40, 2, 0, 7]);
+        let len = block_on(inbox.io().read(&mut reply)).unwrap();
+        assert_eq!(reply[..len], [0xD0, 0]);
+    }
+}
@@ -0,0 +1,104 @@
+//! Scheduling MQTT pings so the broker doesn't drop an idle session.
+//!
+//! The broker disconnects a client that stays silent for longer than the
+//! keep-alive interval it negotiated, and a broker that silently died is only
+//! noticed by asking it something. [`KeepAlive`] pings after half the interval
+//! without traffic and gives the broker the other half to answer, so a dead
+//! broker is detected at most one interval after it was last heard from.
+//!
+//! Times are milliseconds since boot.
+
+use core::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAlive {
+    interval: Duration,
+    last_activity_ms: u64,
+}
+
+impl KeepAlive {
+    /// `interval` is the keep-alive sent in CONNECT, the session counts as
+    /// active at `now_ms`.
+    pub fn new(interval: Duration, now_ms: u64) -> Self {
+        Self { interval, last_activity_ms: now_ms }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Quiet time after which a ping is sent.
+    pub fn ping_interval(&self) -> Duration {
+        self.interval / 2
+    }
+
+    /// How long the broker has to answer a ping before it's considered dead.
+    pub fn response_timeout(&self) -> Duration {
+        self.interval - self.ping_interval()
+    }
+
+    /// Something was received from the broker at `now_ms`, which includes
+    /// acknowledged publishes and ping responses.
+    pub fn activity(&mut self, now_ms: u64) {
+        self.last_activity_ms = self.last_activity_ms.max(now_ms);
+    }
+
+    pub fn next_ping_ms(&self) -> u64 {
+        self.last_activity_ms + self.ping_interval().as_millis() as u64
+    }
+
+    pub fn ping_due(&self, now_ms: u64) -> bool {
+        now_ms >= self.next_ping_ms()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn pings_after_half_the_interval() {
+        let keep_alive = KeepAlive::new(INTERVAL, 1_000);
+
+        assert_eq!(keep_alive.next_ping_ms(), 31_000);
+        assert!(!keep_alive.ping_due(30_999));
+        assert!(keep_alive.ping_due(31_000));
+    }
+
+    #[test]
+    fn activity_postpones_ping() {
+        let mut keep_alive = KeepAlive::new(INTERVAL, 0);
+
+        keep_alive.activity(20_000);
+
+        assert!(!keep_alive.ping_due(31_000));
+        assert_eq!(keep_alive.next_ping_ms(), 50_000);
+    }
+
+    #[test]
+    fn activity_never_goes_back_in_time() {
+        let mut keep_alive = KeepAlive::new(INTERVAL, 20_000);
+
+        keep_alive.activity(10_000);
+
+        assert_eq!(keep_alive.next_ping_ms(), 50_000);
+    }
+
+    #[test]
+    fn dead_broker_is_detected_within_one_interval() {
+        let keep_alive = KeepAlive::new(INTERVAL, 5_000);
+
+        let gave_up_at = keep_alive.next_ping_ms() + keep_alive.response_timeout().as_millis() as u64;
+
+        assert_eq!(gave_up_at, 5_000 + INTERVAL.as_millis() as u64);
+    }
+
+    #[test]
+    fn odd_intervals_add_up() {
+        let keep_alive = KeepAlive::new(Duration::from_millis(15_001), 0);
+
+        assert_eq!(keep_alive.ping_interval() + keep_alive.response_timeout(), keep_alive.interval());
+    }
+}
@@ -0,0 +1,81 @@
+//! How long a task keeps the executor busy.
+//!
+//! All tasks share one executor thread, so a task that calls a blocking
+//! driver delays touch input and networking by as long as the call takes.
+//! [`LatencyMonitor`] collects those blocking sections and checks them against
+//! a budget.
+
+/// Blocking sections of one task, in µs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyMonitor {
+    budget_us: u64,
+    max_us: u64,
+    sections: u32,
+    over_budget: u32,
+}
+
+impl LatencyMonitor {
+    pub const fn new(budget_us: u64) -> Self {
+        Self { budget_us, max_us: 0, sections: 0, over_budget: 0 }
+    }
+
+    pub fn budget_us(&self) -> u64 {
+        self.budget_us
+    }
+
+    /// Records a section that blocked for `elapsed_us`, returning whether it
+    /// stayed within the budget.
+    pub fn record(&mut self, elapsed_us: u64) -> bool {
+        self.sections = self.sections.saturating_add(1);
+        self.max_us = self.max_us.max(elapsed_us);
+
+        let within_budget = elapsed_us <= self.budget_us;
+        if !within_budget {
+            self.over_budget = self.over_budget.saturating_add(1);
+        }
+        within_budget
+    }
+
+    /// The longest section so far.
+    pub fn max_us(&self) -> u64 {
+        self.max_us
+    }
+
+    pub fn sections(&self) -> u32 {
+        self.sections
+    }
+
+    /// Sections that took longer than the budget.
+    pub fn over_budget(&self) -> u32 {
+        self.over_budget
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_longest_section() {
+        let mut monitor = LatencyMonitor::new(10_000);
+
+        assert!(monitor.record(1_200));
+        assert!(monitor.record(3_400));
+        assert!(monitor.record(800));
+
+        assert_eq!(monitor.max_us(), 3_400);
+        assert_eq!(monitor.sections(), 3);
+        assert_eq!(monitor.over_budget(), 0);
+    }
+
+    #[test]
+    fn counts_sections_over_budget() {
+        let mut monitor = LatencyMonitor::new(10_000);
+
+        assert!(monitor.record(10_000));
+        assert!(!monitor.record(1_500_000));
+
+        assert_eq!(monitor.over_budget(), 1);
+        assert_eq!(monitor.max_us(), 1_500_000);
+    }
+}
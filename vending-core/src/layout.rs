@@ -0,0 +1,95 @@
+//! Placement of catalog items on the 320x240 inventory screen.
+//!
+//! Three item fields fit on the screen at once, so a longer catalog is split
+//! into pages of [`ROWS_PER_PAGE`] items.
+
+use core::ops::Range;
+
+use embedded_graphics::{
+    geometry::{Point, Size},
+    primitives::Rectangle,
+};
+
+use crate::catalog::ItemId;
+
+pub const ROWS_PER_PAGE: usize = 3;
+/// `pos_y` of the first field on a page.
+pub const FIRST_ROW_Y: i32 = 17;
+/// Vertical distance between two fields.
+pub const ROW_SPACING: i32 = 70;
+/// Height of the touchable part of a field.
+pub const ROW_HEIGHT: u32 = 38;
+/// Left edge of the buy button at the right end of each field.
+pub const BUTTON_X: i32 = 230;
+pub const BUTTON_WIDTH: u32 = 80;
+
+/// Number of inventory pages needed for `items` items, at least one.
+pub fn page_count(items: usize) -> usize {
+    items.div_ceil(ROWS_PER_PAGE).max(1)
+}
+
+/// The page `id` is shown on.
+pub fn page_of(id: ItemId) -> usize {
+    id / ROWS_PER_PAGE
+}
+
+/// Ids of the items shown on `page` when the catalog holds `items` items.
+pub fn page_items(page: usize, items: usize) -> Range<ItemId> {
+    let start = (page * ROWS_PER_PAGE).min(items);
+    let end = (start + ROWS_PER_PAGE).min(items);
+    start..end
+}
+
+/// `pos_y` of the field showing `id` on its page.
+pub fn row_y(id: ItemId) -> i32 {
+    FIRST_ROW_Y + (id % ROWS_PER_PAGE) as i32 * ROW_SPACING
+}
+
+/// Area of the buy button of `id`'s field, in display coordinates.
+pub fn buy_button(id: ItemId) -> Rectangle {
+    Rectangle::new(Point::new(BUTTON_X, row_y(id)), Size::new(BUTTON_WIDTH, ROW_HEIGHT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn three_items_fit_on_one_page() {
+        assert_eq!(page_count(0), 1);
+        assert_eq!(page_count(3), 1);
+        assert_eq!(page_count(4), 2);
+        assert_eq!(page_items(0, 3), 0..3);
+    }
+
+    #[test]
+    fn fourth_item_starts_second_page() {
+        assert_eq!(page_of(3), 1);
+        assert_eq!(page_items(1, 4), 3..4);
+        assert_eq!(row_y(3), FIRST_ROW_Y);
+    }
+
+    #[test]
+    fn page_past_the_end_is_empty() {
+        assert!(page_items(2, 4).is_empty());
+    }
+
+    #[test]
+    fn rows_match_original_positions() {
+        assert_eq!([row_y(0), row_y(1), row_y(2)], [17, 87, 157]);
+    }
+
+    #[test]
+    fn buy_buttons_follow_rows() {
+        assert_eq!(buy_button(0), Rectangle::new(Point::new(230, 17), Size::new(80, 38)));
+        assert_eq!(buy_button(2).top_left, Point::new(230, 157));
+        assert_eq!(buy_button(3), buy_button(0));
+    }
+
+    #[test]
+    fn buy_buttons_on_a_page_do_not_overlap() {
+        for id in 0..ROWS_PER_PAGE - 1 {
+            assert!(buy_button(id).intersection(&buy_button(id + 1)).is_zero_sized());
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Hardware independent logic of the ESP32S3-BOX vending machine.
+//!
+//! The crate is `no_std` so the firmware can link it, but it doesn't touch any
+//! peripherals, which lets the whole thing be tested on the host with a plain
+//! `cargo test` from this directory. The `std` feature adds `snapshot`, for
+//! rendering screens in memory on the host.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod alarm;
+pub mod app;
+pub mod bme680;
+pub mod catalog;
+pub mod command;
+pub mod connection;
+pub mod derived;
+pub mod event;
+pub mod filter;
+pub mod framebuffer;
+pub mod history;
+pub mod inbox;
+pub mod iaq;
+pub mod keepalive;
+pub mod latency;
+pub mod layout;
+pub mod nav;
+pub mod pages;
+pub mod persist;
+pub mod publish;
+pub mod queue;
+pub mod redraw;
+pub mod screen;
+pub mod sensor;
+pub mod sensor_config;
+#[cfg(any(test, feature = "std"))]
+pub mod snapshot;
+pub mod telemetry;
+pub mod touch;
+pub mod ui;
+pub mod vending;
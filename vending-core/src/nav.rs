@@ -0,0 +1,222 @@
+//! Moving between the pages of the touch screen.
+//!
+//! The firmware's pages say what should happen after they handled a touch
+//! with a [`Transition`], and the [`Navigator`] keeps the stack of pages that
+//! were opened on top of each other, so going back returns to where the user
+//! came from. [`GestureTracker`] turns the touch reports between a press and
+//! the release into a tap or a swipe.
+
+use embedded_graphics::geometry::Point;
+use heapless::Vec;
+
+/// Horizontal distance a touch has to move to count as a swipe.
+pub const SWIPE_MIN_DISTANCE: i32 = 60;
+
+/// What one touch, from press to release, was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// Released close to where it was pressed.
+    Tap(Point),
+    /// Moved right to left.
+    SwipeLeft,
+    /// Moved left to right.
+    SwipeRight,
+}
+
+/// Follows a touch from the first report that it's pressed to the one that
+/// it was released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GestureTracker {
+    start: Option<Point>,
+    last: Point,
+}
+
+impl GestureTracker {
+    pub const fn new() -> Self {
+        Self { start: None, last: Point::zero() }
+    }
+
+    /// The touch is pressed at `point`.
+    pub fn press(&mut self, point: Point) {
+        self.start.get_or_insert(point);
+        self.last = point;
+    }
+
+    /// The touch was released, `None` if it wasn't pressed.
+    pub fn release(&mut self) -> Option<Gesture> {
+        let start = self.start.take()?;
+        let moved = self.last - start;
+
+        if moved.x.abs() >= SWIPE_MIN_DISTANCE && moved.x.abs() > moved.y.abs() {
+            Some(if moved.x < 0 { Gesture::SwipeLeft } else { Gesture::SwipeRight })
+        } else {
+            Some(Gesture::Tap(start))
+        }
+    }
+}
+
+/// What a page wants to happen after it handled a touch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition<P> {
+    /// Keep showing the page.
+    Stay,
+    /// Enter the page again, after it changed what it shows.
+    Redraw,
+    /// Open `P` on top of the page, to come back to it later.
+    Push(P),
+    /// Go back to the page below.
+    Pop,
+    /// Show `P` instead of the page and everything below it.
+    Replace(P),
+}
+
+/// Stack of up to `N` open pages; the top one is shown.
+#[derive(Debug, Clone)]
+pub struct Navigator<P, const N: usize> {
+    stack: Vec<P, N>,
+}
+
+impl<P: Copy + PartialEq, const N: usize> Navigator<P, N> {
+    /// Starts out showing `root`.
+    pub fn new(root: P) -> Self {
+        let mut stack = Vec::new();
+        stack.push(root).ok();
+        Self { stack }
+    }
+
+    /// The page that's shown.
+    pub fn current(&self) -> P {
+        // the stack is never emptied below the root
+        self.stack[self.stack.len() - 1]
+    }
+
+    /// Pages open, the shown one included.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Applies `transition`, returning the page that was left and the one
+    /// that's shown now, or `None` if the shown page stays.
+    ///
+    /// Pushing onto a full stack replaces the top page, and the last page
+    /// can't be popped.
+    pub fn apply(&mut self, transition: Transition<P>) -> Option<(P, P)> {
+        let left = self.current();
+        match transition {
+            Transition::Stay => return None,
+            Transition::Redraw => {}
+            Transition::Push(page) => {
+                if self.stack.is_full() {
+                    self.stack.pop();
+                }
+                self.stack.push(page).ok();
+            }
+            Transition::Pop if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            Transition::Pop => return None,
+            Transition::Replace(page) if page == left && self.stack.len() == 1 => return None,
+            Transition::Replace(page) => {
+                self.stack.clear();
+                self.stack.push(page).ok();
+            }
+        }
+        Some((left, self.current()))
+    }
+}
+
+/// The entry `step` places after `item` in `items`, wrapping around at both
+/// ends. An `item` that isn't in `items` counts as the first.
+pub fn cycle<T: Copy + PartialEq>(items: &[T], item: T, step: isize) -> T {
+    let len = items.len() as isize;
+    let index = items.iter().position(|i| *i == item).unwrap_or(0) as isize;
+    items[(index + step).rem_euclid(len) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Page {
+        Home,
+        Charts,
+        Status,
+    }
+
+    #[test]
+    fn pop_returns_to_the_page_below() {
+        let mut navigator: Navigator<Page, 4> = Navigator::new(Page::Home);
+
+        assert_eq!(navigator.apply(Transition::Push(Page::Charts)), Some((Page::Home, Page::Charts)));
+        assert_eq!(navigator.depth(), 2);
+        assert_eq!(navigator.apply(Transition::Pop), Some((Page::Charts, Page::Home)));
+        assert_eq!(navigator.apply(Transition::Pop), None);
+        assert_eq!(navigator.current(), Page::Home);
+    }
+
+    #[test]
+    fn replace_clears_the_stack() {
+        let mut navigator: Navigator<Page, 4> = Navigator::new(Page::Home);
+        navigator.apply(Transition::Push(Page::Charts));
+
+        assert_eq!(navigator.apply(Transition::Replace(Page::Status)), Some((Page::Charts, Page::Status)));
+        assert_eq!(navigator.depth(), 1);
+        assert_eq!(navigator.apply(Transition::Replace(Page::Status)), None);
+        assert_eq!(navigator.apply(Transition::Stay), None);
+        assert_eq!(navigator.apply(Transition::Redraw), Some((Page::Status, Page::Status)));
+    }
+
+    #[test]
+    fn push_onto_full_stack_replaces_top() {
+        let mut navigator: Navigator<Page, 2> = Navigator::new(Page::Home);
+        navigator.apply(Transition::Push(Page::Charts));
+
+        assert_eq!(navigator.apply(Transition::Push(Page::Status)), Some((Page::Charts, Page::Status)));
+        assert_eq!(navigator.depth(), 2);
+        assert_eq!(navigator.apply(Transition::Pop), Some((Page::Status, Page::Home)));
+    }
+
+    #[test]
+    fn short_touch_is_a_tap() {
+        let mut tracker = GestureTracker::new();
+
+        tracker.press(Point::new(100, 120));
+        tracker.press(Point::new(110, 125));
+
+        assert_eq!(tracker.release(), Some(Gesture::Tap(Point::new(100, 120))));
+        assert_eq!(tracker.release(), None);
+    }
+
+    #[test]
+    fn horizontal_moves_are_swipes() {
+        let mut tracker = GestureTracker::new();
+
+        tracker.press(Point::new(250, 120));
+        tracker.press(Point::new(150, 130));
+        assert_eq!(tracker.release(), Some(Gesture::SwipeLeft));
+
+        tracker.press(Point::new(50, 120));
+        tracker.press(Point::new(200, 100));
+        assert_eq!(tracker.release(), Some(Gesture::SwipeRight));
+    }
+
+    #[test]
+    fn vertical_moves_are_taps() {
+        let mut tracker = GestureTracker::new();
+
+        tracker.press(Point::new(100, 20));
+        tracker.press(Point::new(170, 200));
+
+        assert_eq!(tracker.release(), Some(Gesture::Tap(Point::new(100, 20))));
+    }
+
+    #[test]
+    fn cycle_wraps_around() {
+        let pages = [Page::Home, Page::Charts, Page::Status];
+
+        assert_eq!(cycle(&pages, Page::Status, 1), Page::Home);
+        assert_eq!(cycle(&pages, Page::Home, -1), Page::Status);
+        assert_eq!(cycle(&pages, Page::Charts, 1), Page::Status);
+    }
+}
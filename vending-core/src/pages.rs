@@ -0,0 +1,652 @@
+//! The pages of the touch screen and the [`Router`] moving between them.
+//!
+//! Each page implements [`Page`]: it draws itself when it's entered, takes in
+//! new readings and inventory changes whether it's shown or not, and handles
+//! taps on the zones it registered. The home button and horizontal swipes
+//! move through [`PageId::MENU`]; a page opened on top of another, like the
+//! chart of an alarm, goes back with the button or a swipe to the right.
+//! A new screen is a new `Page` in [`Pages`], the firmware and the simulator
+//! only feed the router.
+//!
+//! The pages draw on any [`Screen`] and read the [`App`] through [`Shared`],
+//! only for as long as they copy out what they draw. Nothing appears until
+//! [`Router::flush`].
+
+use core::fmt::Write;
+
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*};
+use heapless::String;
+
+use crate::{
+    alarm::Metric,
+    app::{App, Shared, Update, CATALOG_CAPACITY},
+    catalog::ItemId,
+    derived::DerivedMetrics,
+    history::Span,
+    layout,
+    nav::{self, Gesture, Navigator, Transition},
+    redraw::RedrawStats,
+    screen::Screen,
+    sensor::{self, SensorFault},
+    touch::TouchZones,
+    ui::{
+        AirQualityPanel, AlarmBanner, DerivedMetricsPage, InventoryHeader, InventoryRow, ListPage, SensorGauge,
+        SensorOfflinePanel, TrendChart,
+    },
+};
+
+// pages opened on top of each other, the menu page at the bottom included
+const NAVIGATION_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageId {
+    Inventory,
+    Sensors,
+    DerivedMetrics,
+    Charts,
+    Status,
+    Settings,
+}
+
+impl PageId {
+    /// In the order the home button and swipes go through.
+    pub const MENU: [PageId; 6] =
+        [PageId::Inventory, PageId::Sensors, PageId::DerivedMetrics, PageId::Charts, PageId::Status, PageId::Settings];
+}
+
+/// What a touch on a registered zone of the current page does.
+#[derive(Debug, Clone, Copy)]
+pub enum TouchTarget {
+    Buy(ItemId),
+    ChartMetric(Metric),
+    ChartSpan(Span),
+}
+
+/// What the pages draw on, shared by all of them.
+pub struct Context<S> {
+    pub screen: S,
+    pub touch_zones: TouchZones<TouchTarget, CATALOG_CAPACITY>,
+    /// Milliseconds since boot when the router was called.
+    pub now_ms: u64,
+}
+
+pub trait Page<S: Screen, A: Shared<App>> {
+    /// Draws the whole page and registers its touch zones.
+    fn enter(&mut self, ctx: &mut Context<S>, app: &A) -> Result<(), S::Error>;
+
+    /// Takes in `update`, redrawing what changed if the page is `shown`.
+    fn update(&mut self, _ctx: &mut Context<S>, _app: &A, _update: &Update, _shown: bool) -> Result<(), S::Error> {
+        Ok(())
+    }
+
+    /// Shows the next or previous part of a page that doesn't fit on the
+    /// screen, returning `false` at its end so the next page is shown instead.
+    fn scroll(&mut self, _ctx: &mut Context<S>, _app: &A, _forward: bool) -> Result<bool, S::Error> {
+        Ok(false)
+    }
+
+    /// Called before another page is entered.
+    fn exit(&mut self) {}
+
+    /// Handles a tap on one of the zones the page registered, other than a
+    /// buy button.
+    fn handle_touch(&mut self, _target: TouchTarget) -> Transition<PageId> {
+        Transition::Stay
+    }
+}
+
+/// The products, a few to a page.
+pub struct InventoryPage {
+    page: usize,
+}
+
+impl InventoryPage {
+    fn draw_rows<S: Screen, A: Shared<App>>(&self, ctx: &mut Context<S>, app: &A) -> Result<(), S::Error> {
+        // copied out so the app isn't borrowed for the whole redraw
+        let catalog = app.with(|app| app.catalog().clone());
+
+        for id in layout::page_items(self.page, catalog.len()) {
+            InventoryRow { id, item: &catalog.items()[id] }.draw(&mut ctx.screen)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Screen, A: Shared<App>> Page<S, A> for InventoryPage {
+    fn enter(&mut self, ctx: &mut Context<S>, app: &A) -> Result<(), S::Error> {
+        let items = app.with(|app| app.catalog().len());
+
+        ctx.screen.clear(Rgb565::WHITE)?;
+        InventoryHeader { page: self.page, pages: layout::page_count(items) }.draw(&mut ctx.screen)?;
+        for id in layout::page_items(self.page, items) {
+            ctx.touch_zones.register(layout::buy_button(id), TouchTarget::Buy(id)).ok();
+        }
+        self.draw_rows(ctx, app)
+    }
+
+    fn update(&mut self, ctx: &mut Context<S>, app: &A, update: &Update, shown: bool) -> Result<(), S::Error> {
+        match (update, shown) {
+            (Update::Inventory, true) => self.draw_rows(ctx, app),
+            _ => Ok(()),
+        }
+    }
+
+    fn scroll(&mut self, ctx: &mut Context<S>, app: &A, forward: bool) -> Result<bool, S::Error> {
+        let pages = layout::page_count(app.with(|app| app.catalog().len()));
+        match forward {
+            true if self.page + 1 < pages => self.page += 1,
+            false if self.page > 0 => self.page -= 1,
+            _ => return Ok(false),
+        }
+
+        ctx.touch_zones.clear();
+        self.enter(ctx, app)?;
+        Ok(true)
+    }
+
+    fn exit(&mut self) {
+        // coming back starts at the first products again
+        self.page = 0;
+    }
+}
+
+/// Gauges of the latest reading above the air quality.
+pub struct SensorsPage {
+    gauges: [SensorGauge; 3],
+    air_quality: AirQualityPanel,
+    fault: Option<SensorFault>,
+}
+
+impl<S: Screen, A: Shared<App>> Page<S, A> for SensorsPage {
+    fn enter(&mut self, ctx: &mut Context<S>, _app: &A) -> Result<(), S::Error> {
+        ctx.screen.clear(Rgb565::WHITE)?;
+        for gauge in &self.gauges {
+            gauge.draw(&mut ctx.screen)?;
+        }
+        match self.fault {
+            Some(fault) => SensorOfflinePanel { fault }.draw(&mut ctx.screen),
+            None => self.air_quality.draw(&mut ctx.screen),
+        }
+    }
+
+    fn update(&mut self, ctx: &mut Context<S>, _app: &A, update: &Update, shown: bool) -> Result<(), S::Error> {
+        match update {
+            Update::SensorFault(fault) => {
+                // Keep the last values on the gauges, but show they're stale
+                if shown && self.fault != Some(*fault) {
+                    SensorOfflinePanel { fault: *fault }.draw(&mut ctx.screen)?;
+                }
+                self.fault = Some(*fault);
+            }
+            Update::Reading(reading) => {
+                // Redraw only the gauges whose value visibly changed
+                for gauge in &mut self.gauges {
+                    let value = gauge.metric.value(reading).unwrap_or_default();
+                    if !shown {
+                        gauge.value = value;
+                    } else if sensor::needs_redraw(gauge.value, value) {
+                        gauge.value = value;
+                        gauge.draw(&mut ctx.screen)?;
+                    }
+                }
+
+                let panel = AirQualityPanel { gas_resistance: reading.gas_resistance, iaq: reading.iaq };
+                if shown && (panel != self.air_quality || self.fault.is_some()) {
+                    panel.draw(&mut ctx.screen)?;
+                }
+                self.air_quality = panel;
+                self.fault = None;
+            }
+            Update::Inventory | Update::AlarmBanner(_) => {}
+        }
+        Ok(())
+    }
+}
+
+pub struct DerivedPage {
+    shown: DerivedMetricsPage,
+}
+
+impl<S: Screen, A: Shared<App>> Page<S, A> for DerivedPage {
+    fn enter(&mut self, ctx: &mut Context<S>, _app: &A) -> Result<(), S::Error> {
+        self.shown.draw(&mut ctx.screen)
+    }
+
+    fn update(&mut self, ctx: &mut Context<S>, app: &A, update: &Update, shown: bool) -> Result<(), S::Error> {
+        if let Update::Reading(reading) = update {
+            let sea_level_pressure = app.with(|app| app.sea_level_pressure);
+            let page = DerivedMetricsPage { metrics: DerivedMetrics::from_reading(reading, sea_level_pressure) };
+            if shown && page != self.shown {
+                page.draw_values(&mut ctx.screen)?;
+            }
+            self.shown = page;
+        }
+        Ok(())
+    }
+}
+
+/// Trend of one metric over the last hour or day.
+pub struct ChartsPage {
+    pub metric: Metric,
+    span: Span,
+}
+
+impl ChartsPage {
+    fn draw<S: Screen, A: Shared<App>>(&self, ctx: &mut Context<S>, app: &A, whole: bool) -> Result<(), S::Error> {
+        // copied out so the app isn't borrowed for the whole redraw
+        let trend = app.with(|app| app.history.trend(self.metric, self.span).clone());
+        let chart = TrendChart { metric: self.metric, span: self.span, trend: &trend };
+        if whole {
+            chart.draw(&mut ctx.screen)
+        } else {
+            chart.draw_plot(&mut ctx.screen)
+        }
+    }
+}
+
+impl<S: Screen, A: Shared<App>> Page<S, A> for ChartsPage {
+    fn enter(&mut self, ctx: &mut Context<S>, app: &A) -> Result<(), S::Error> {
+        let previous = nav::cycle(&Metric::ALL, self.metric, -1);
+        let next = nav::cycle(&Metric::ALL, self.metric, 1);
+        ctx.touch_zones.register(TrendChart::PREVIOUS, TouchTarget::ChartMetric(previous)).ok();
+        ctx.touch_zones.register(TrendChart::NEXT, TouchTarget::ChartMetric(next)).ok();
+        for span in Span::ALL {
+            ctx.touch_zones.register(TrendChart::span_button(span), TouchTarget::ChartSpan(span)).ok();
+        }
+        self.draw(ctx, app, true)
+    }
+
+    fn update(&mut self, ctx: &mut Context<S>, app: &A, update: &Update, shown: bool) -> Result<(), S::Error> {
+        match (update, shown) {
+            (Update::Reading(_), true) => self.draw(ctx, app, false),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_touch(&mut self, target: TouchTarget) -> Transition<PageId> {
+        match target {
+            TouchTarget::ChartMetric(metric) => self.metric = metric,
+            TouchTarget::ChartSpan(span) => self.span = span,
+            TouchTarget::Buy(_) => return Transition::Stay,
+        }
+        Transition::Redraw
+    }
+}
+
+/// How the device is doing, refreshed with every reading.
+pub struct StatusPage {
+    fault: Option<SensorFault>,
+}
+
+impl StatusPage {
+    fn draw<S: Screen, A: Shared<App>>(&self, ctx: &mut Context<S>, app: &A, whole: bool) -> Result<(), S::Error> {
+        let seconds = ctx.now_ms / 1000;
+        let mut uptime: String<24> = String::new();
+        write!(uptime, "{} d {:02}:{:02}", seconds / 86_400, seconds / 3_600 % 24, seconds / 60 % 60).ok();
+
+        let (alarms, queued, dropped) = app.with(|app| (app.alarms.active().count(), app.queue.len(), app.queue.dropped()));
+        let mut active_alarms: String<24> = String::new();
+        write!(active_alarms, "{}", alarms).ok();
+        let mut queued_events: String<24> = String::new();
+        write!(queued_events, "{}", queued).ok();
+        let mut dropped_events: String<24> = String::new();
+        write!(dropped_events, "{}", dropped).ok();
+
+        let rows = [
+            ("Uptime", uptime.as_str()),
+            ("Sensor", self.fault.map_or("ok", |fault| fault.as_str())),
+            ("Active alarms", active_alarms.as_str()),
+            ("Queued events", queued_events.as_str()),
+            ("Dropped events", dropped_events.as_str()),
+        ];
+        let page = ListPage { title: "Status", rows: &rows };
+        if whole {
+            page.draw(&mut ctx.screen)
+        } else {
+            page.draw_values(&mut ctx.screen)
+        }
+    }
+}
+
+impl<S: Screen, A: Shared<App>> Page<S, A> for StatusPage {
+    fn enter(&mut self, ctx: &mut Context<S>, app: &A) -> Result<(), S::Error> {
+        self.draw(ctx, app, true)
+    }
+
+    fn update(&mut self, ctx: &mut Context<S>, app: &A, update: &Update, shown: bool) -> Result<(), S::Error> {
+        match update {
+            Update::Reading(_) => self.fault = None,
+            Update::SensorFault(fault) => self.fault = Some(*fault),
+            Update::Inventory | Update::AlarmBanner(_) => return Ok(()),
+        }
+        if shown {
+            self.draw(ctx, app, false)?;
+        }
+        Ok(())
+    }
+}
+
+/// The settings the device runs with, changed in `cfg.toml` and over MQTT.
+pub struct SettingsPage;
+
+impl SettingsPage {
+    fn draw<S: Screen, A: Shared<App>>(&self, ctx: &mut Context<S>, app: &A, whole: bool) -> Result<(), S::Error> {
+        let (config, telemetry_format, sea_level_pressure) =
+            app.with(|app| (app.config(), app.telemetry_format, app.sea_level_pressure));
+
+        let mut oversampling: String<24> = String::new();
+        write!(
+            oversampling,
+            "T{} H{} P{}",
+            config.temperature_oversampling.factor(),
+            config.humidity_oversampling.factor(),
+            config.pressure_oversampling.factor()
+        )
+        .ok();
+        let mut filter: String<24> = String::new();
+        write!(filter, "{}", config.filter.size()).ok();
+        let mut heater: String<24> = String::new();
+        write!(heater, "{}°C {} ms", config.heater_temperature, config.heater_duration_ms).ok();
+        let mut sea_level: String<24> = String::new();
+        write!(sea_level, "{:.2} hPa", sea_level_pressure).ok();
+
+        let rows = [
+            ("Telemetry", telemetry_format.name()),
+            ("Oversampling", oversampling.as_str()),
+            ("IIR filter", filter.as_str()),
+            ("Heater", heater.as_str()),
+            ("Sea level", sea_level.as_str()),
+        ];
+        let page = ListPage { title: "Settings", rows: &rows };
+        if whole {
+            page.draw(&mut ctx.screen)
+        } else {
+            page.draw_values(&mut ctx.screen)
+        }
+    }
+}
+
+impl<S: Screen, A: Shared<App>> Page<S, A> for SettingsPage {
+    fn enter(&mut self, ctx: &mut Context<S>, app: &A) -> Result<(), S::Error> {
+        self.draw(ctx, app, true)
+    }
+
+    fn update(&mut self, ctx: &mut Context<S>, app: &A, update: &Update, shown: bool) -> Result<(), S::Error> {
+        // a changed sensor config is applied before the next reading
+        match (update, shown) {
+            (Update::Reading(_), true) => self.draw(ctx, app, false),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// One of every page.
+pub struct Pages {
+    inventory: InventoryPage,
+    sensors: SensorsPage,
+    derived: DerivedPage,
+    charts: ChartsPage,
+    status: StatusPage,
+    settings: SettingsPage,
+}
+
+impl Pages {
+    fn new() -> Self {
+        Self {
+            inventory: InventoryPage { page: 0 },
+            sensors: SensorsPage {
+                gauges: SensorGauge::METRICS.map(|metric| SensorGauge { metric, value: 0.0 }),
+                air_quality: AirQualityPanel { gas_resistance: 0.0, iaq: None },
+                fault: None,
+            },
+            derived: DerivedPage { shown: DerivedMetricsPage { metrics: DerivedMetrics::default() } },
+            charts: ChartsPage { metric: Metric::Temperature, span: Span::Hour },
+            status: StatusPage { fault: None },
+            settings: SettingsPage,
+        }
+    }
+
+    fn get<S: Screen, A: Shared<App>>(&mut self, id: PageId) -> &mut dyn Page<S, A> {
+        match id {
+            PageId::Inventory => &mut self.inventory,
+            PageId::Sensors => &mut self.sensors,
+            PageId::DerivedMetrics => &mut self.derived,
+            PageId::Charts => &mut self.charts,
+            PageId::Status => &mut self.status,
+            PageId::Settings => &mut self.settings,
+        }
+    }
+}
+
+/// Shows one page at a time, with the alarm banner on top of it.
+pub struct Router<S> {
+    ctx: Context<S>,
+    pages: Pages,
+    navigator: Navigator<PageId, NAVIGATION_DEPTH>,
+    alarm_banner: Option<AlarmBanner>,
+}
+
+impl<S: Screen> Router<S> {
+    pub fn new(screen: S) -> Self {
+        Self {
+            ctx: Context { screen, touch_zones: TouchZones::new(), now_ms: 0 },
+            pages: Pages::new(),
+            navigator: Navigator::new(PageId::MENU[0]),
+            alarm_banner: None,
+        }
+    }
+
+    pub fn screen(&self) -> &S {
+        &self.ctx.screen
+    }
+
+    pub fn current(&self) -> PageId {
+        self.navigator.current()
+    }
+
+    /// Draws the first page.
+    pub fn start<A: Shared<App>>(&mut self, app: &A, now_ms: u64) -> Result<(), S::Error> {
+        self.ctx.now_ms = now_ms;
+        self.enter(app, self.navigator.current())
+    }
+
+    /// Passes `update` to every page, or puts up or takes down the alarm
+    /// banner.
+    pub fn update<A: Shared<App>>(&mut self, app: &A, update: Update, now_ms: u64) -> Result<(), S::Error> {
+        self.ctx.now_ms = now_ms;
+        if let Update::AlarmBanner(banner) = update {
+            return self.set_alarm_banner(app, banner);
+        }
+        let current = self.navigator.current();
+        for id in PageId::MENU {
+            self.pages.get(id).update(&mut self.ctx, app, &update, id == current)?;
+        }
+        Ok(())
+    }
+
+    /// The home button goes back from a page opened on top of another and
+    /// otherwise forward through the menu.
+    pub fn button<A: Shared<App>>(&mut self, app: &A, now_ms: u64) -> Result<(), S::Error> {
+        self.ctx.now_ms = now_ms;
+        if self.navigator.depth() > 1 {
+            self.navigate(app, Transition::Pop)
+        } else {
+            self.step(app, true)
+        }
+    }
+
+    /// Follows `gesture`, returning the item whose buy button was tapped.
+    /// Selling it is up to the caller, who passes on an [`Update::Inventory`]
+    /// afterwards.
+    pub fn gesture<A: Shared<App>>(&mut self, app: &A, gesture: Gesture, now_ms: u64) -> Result<Option<ItemId>, S::Error> {
+        self.ctx.now_ms = now_ms;
+        match gesture {
+            Gesture::Tap(point) => {
+                // the banner opens the chart of the alarm
+                if let Some(banner) = self.alarm_banner.filter(|_| AlarmBanner::AREA.contains(point)) {
+                    self.pages.charts.metric = banner.metric;
+                    let transition = if self.navigator.current() == PageId::Charts {
+                        Transition::Redraw
+                    } else {
+                        Transition::Push(PageId::Charts)
+                    };
+                    self.navigate(app, transition)?;
+                } else if let Some(target) = self.ctx.touch_zones.hit(point).copied() {
+                    if let TouchTarget::Buy(id) = target {
+                        return Ok(Some(id));
+                    }
+                    let transition = self.pages.get::<S, A>(self.navigator.current()).handle_touch(target);
+                    self.navigate(app, transition)?;
+                }
+            }
+            Gesture::SwipeLeft => self.step(app, true)?,
+            Gesture::SwipeRight if self.navigator.depth() > 1 => self.navigate(app, Transition::Pop)?,
+            Gesture::SwipeRight => self.step(app, false)?,
+        }
+        Ok(None)
+    }
+
+    /// Shows what the pages drew, which the [`Screen`] may have held back.
+    pub fn flush(&mut self) -> Result<RedrawStats, S::Error> {
+        self.ctx.screen.flush()
+    }
+
+    fn set_alarm_banner<A: Shared<App>>(&mut self, app: &A, banner: Option<AlarmBanner>) -> Result<(), S::Error> {
+        let cleared = banner.is_none() && self.alarm_banner.is_some();
+        self.alarm_banner = banner;
+        if cleared {
+            // Nothing left to cover, bring back what was under the banner
+            self.enter(app, self.navigator.current())
+        } else {
+            self.draw_alarm_banner()
+        }
+    }
+
+    /// Scrolls the current page, or moves to the next or previous page of
+    /// the menu at its end.
+    fn step<A: Shared<App>>(&mut self, app: &A, forward: bool) -> Result<(), S::Error> {
+        let current = self.navigator.current();
+        if self.pages.get(current).scroll(&mut self.ctx, app, forward)? {
+            self.draw_alarm_banner()
+        } else {
+            let next = nav::cycle(&PageId::MENU, current, if forward { 1 } else { -1 });
+            self.navigate(app, Transition::Replace(next))
+        }
+    }
+
+    fn navigate<A: Shared<App>>(&mut self, app: &A, transition: Transition<PageId>) -> Result<(), S::Error> {
+        match self.navigator.apply(transition) {
+            Some((left, entered)) => {
+                self.pages.get::<S, A>(left).exit();
+                self.enter(app, entered)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn enter<A: Shared<App>>(&mut self, app: &A, id: PageId) -> Result<(), S::Error> {
+        self.ctx.touch_zones.clear();
+        self.pages.get(id).enter(&mut self.ctx, app)?;
+        self.draw_alarm_banner()
+    }
+
+    fn draw_alarm_banner(&mut self) -> Result<(), S::Error> {
+        // The banner stays on top of every page until the alarms clear
+        match self.alarm_banner {
+            Some(banner) => banner.draw(&mut self.ctx.screen),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::cell::RefCell;
+
+    use crate::{
+        alarm::Level,
+        app::PRODUCTS,
+        catalog::Catalog,
+        sensor::SensorReading,
+        snapshot::Snapshot,
+        vending::VendingMachine,
+    };
+
+    fn router() -> (Router<Snapshot>, RefCell<App>) {
+        let mut app = App::new();
+        app.machine = VendingMachine::with_catalog(Catalog::from_items(&PRODUCTS).unwrap());
+        let app = RefCell::new(app);
+        let mut router = Router::new(Snapshot::new(Size::new(320, 240)));
+        router.start(&app, 0).unwrap();
+        (router, app)
+    }
+
+    #[test]
+    fn tapping_buy_names_the_item() {
+        let (mut router, app) = router();
+
+        let tapped = router.gesture(&app, Gesture::Tap(layout::buy_button(1).center()), 0).unwrap();
+
+        assert_eq!(tapped, Some(1));
+        assert_eq!(router.gesture(&app, Gesture::Tap(Point::new(160, 230)), 0).unwrap(), None);
+    }
+
+    #[test]
+    fn button_and_swipes_go_through_the_menu() {
+        let (mut router, app) = router();
+
+        router.button(&app, 0).unwrap();
+        assert_eq!(router.current(), PageId::Sensors);
+        router.gesture(&app, Gesture::SwipeLeft, 0).unwrap();
+        assert_eq!(router.current(), PageId::DerivedMetrics);
+        router.gesture(&app, Gesture::SwipeRight, 0).unwrap();
+        router.gesture(&app, Gesture::SwipeRight, 0).unwrap();
+        assert_eq!(router.current(), PageId::Inventory);
+    }
+
+    #[test]
+    fn banner_opens_the_chart_and_button_goes_back() {
+        let (mut router, app) = router();
+        router.button(&app, 0).unwrap();
+        let banner = AlarmBanner { metric: Metric::Humidity, level: Level::Low, others: 0 };
+        router.update(&app, Update::AlarmBanner(Some(banner)), 0).unwrap();
+
+        router.gesture(&app, Gesture::Tap(Point::new(160, 8)), 0).unwrap();
+        assert_eq!(router.current(), PageId::Charts);
+        assert_eq!(router.pages.charts.metric, Metric::Humidity);
+
+        router.button(&app, 0).unwrap();
+        assert_eq!(router.current(), PageId::Sensors);
+    }
+
+    #[test]
+    fn cleared_banner_brings_back_the_page() {
+        let (mut router, app) = router();
+        let before = router.screen().clone();
+        let banner = AlarmBanner { metric: Metric::Iaq, level: Level::High, others: 0 };
+
+        router.update(&app, Update::AlarmBanner(Some(banner)), 0).unwrap();
+        assert_ne!(*router.screen(), before);
+        router.update(&app, Update::AlarmBanner(None), 0).unwrap();
+        assert_eq!(*router.screen(), before);
+    }
+
+    #[test]
+    fn readings_reach_pages_that_arent_shown() {
+        let (mut router, app) = router();
+        let reading = SensorReading { temperature: 24.0, humidity: 45.0, pressure: 1009.0, gas_resistance: 90_000.0, iaq: None };
+
+        router.update(&app, Update::Reading(reading), 0).unwrap();
+        let mut shown = Snapshot::new(Size::new(320, 240));
+        shown.clear(Rgb565::WHITE).unwrap();
+        for metric in SensorGauge::METRICS {
+            SensorGauge { metric, value: metric.value(&reading).unwrap() }.draw(&mut shown).unwrap();
+        }
+        router.button(&app, 0).unwrap();
+
+        let gauges = SensorGauge::area(Metric::Temperature);
+        assert!(gauges.points().all(|point| router.screen().pixel(point) == shown.pixel(point)));
+    }
+}
@@ -0,0 +1,460 @@
+//! Keeping stock counts across reboots.
+//!
+//! [`InventoryStore`] appends a small record with all counts to a flash
+//! partition every time they change. Records are written to consecutive slots
+//! and the partition is used as a ring of erase sectors, so every sector gets
+//! erased equally often. Each record carries a sequence number and a CRC, and
+//! on boot the newest record with a valid CRC wins; a record torn by a power
+//! loss is simply skipped.
+//!
+//! Record layout, all fields little endian `u32`:
+//!
+//! | magic | sequence | count | amounts\[N\] | crc32 |
+//!
+//! Where the partition is comes from the partition table flashed with the
+//! app, see [`find_partition`].
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::Vec;
+
+const MAGIC: u32 = 0x494e_5654;
+const HEADER_LEN: usize = 12;
+const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+/// Largest slot the store can handle, enough for 60 items.
+const MAX_SLOT_SIZE: usize = 256;
+
+/// Where the ESP-IDF bootloader expects the partition table.
+pub const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+const PARTITION_TABLE_LEN: u32 = 0xC00;
+const PARTITION_ENTRY_LEN: usize = 32;
+const PARTITION_MAGIC: [u8; 2] = [0xAA, 0x50];
+/// Type of the data partitions in the table.
+pub const DATA_PARTITION: u8 = 0x01;
+/// Subtype of the `inventory` partition in partitions.csv.
+pub const INVENTORY_PARTITION_SUBTYPE: u8 = 0x40;
+
+/// Region of flash given by an entry of the partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError<E> {
+    Flash(E),
+    /// More amounts were passed than the store has room for.
+    TooManyItems,
+}
+
+/// Log of the stock counts of up to `N` items in a region of `F`.
+pub struct InventoryStore<F, const N: usize> {
+    flash: F,
+    offset: u32,
+    sectors: u32,
+    next_slot: u32,
+    sequence: u32,
+}
+
+impl<F: NorFlash, const N: usize> InventoryStore<F, N> {
+    const SLOT_SIZE: usize = {
+        let len = HEADER_LEN + 4 * N + 4;
+        let align = if F::WRITE_SIZE > F::READ_SIZE { F::WRITE_SIZE } else { F::READ_SIZE };
+        len.div_ceil(align) * align
+    };
+    const SLOTS_PER_SECTOR: u32 = (F::ERASE_SIZE / Self::SLOT_SIZE) as u32;
+
+    /// Uses `size` bytes of `flash` starting at `offset`; both must be
+    /// multiples of the erase size. Call [`restore`](Self::restore) before
+    /// saving so the log continues where it left off.
+    pub fn new(flash: F, offset: u32, size: u32) -> Self {
+        assert!(Self::SLOT_SIZE <= MAX_SLOT_SIZE, "too many items for one record");
+        assert!((offset as usize).is_multiple_of(F::ERASE_SIZE) && (size as usize).is_multiple_of(F::ERASE_SIZE));
+        assert!(size as usize >= 2 * F::ERASE_SIZE, "need at least two sectors");
+
+        Self {
+            flash,
+            offset,
+            sectors: size / F::ERASE_SIZE as u32,
+            next_slot: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Sequence number of the last record read or written.
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// Finds the newest valid record and returns its amounts, or `None` if
+    /// nothing was saved yet.
+    pub fn restore(&mut self) -> Result<Option<Vec<u32, N>>, StoreError<F::Error>> {
+        let mut buf = [0; MAX_SLOT_SIZE];
+        let mut latest: Option<(u32, u32, Vec<u32, N>)> = None;
+
+        for slot in 0..self.slots() {
+            self.read_slot(slot, &mut buf)?;
+            let Some((sequence, amounts)) = decode::<N>(&buf[..Self::SLOT_SIZE]) else {
+                continue;
+            };
+
+            let newer = match &latest {
+                Some((_, latest_sequence, _)) => (sequence.wrapping_sub(*latest_sequence) as i32) > 0,
+                None => true,
+            };
+            if newer {
+                latest = Some((slot, sequence, amounts));
+            }
+        }
+
+        Ok(latest.map(|(slot, sequence, amounts)| {
+            self.next_slot = slot + 1;
+            self.sequence = sequence;
+            amounts
+        }))
+    }
+
+    /// Appends a record with `amounts`, erasing the oldest sector when the
+    /// current one is full.
+    pub fn save(&mut self, amounts: &[u32]) -> Result<(), StoreError<F::Error>> {
+        if amounts.len() > N {
+            return Err(StoreError::TooManyItems);
+        }
+
+        let mut buf = [0; MAX_SLOT_SIZE];
+        loop {
+            let slot = self.next_slot % self.slots();
+            self.next_slot = slot + 1;
+
+            if slot.is_multiple_of(Self::SLOTS_PER_SECTOR) {
+                let from = self.slot_address(slot);
+                self.flash
+                    .erase(from, from + F::ERASE_SIZE as u32)
+                    .map_err(StoreError::Flash)?;
+            } else {
+                // A torn write leaves a slot that can't be written again
+                // without erasing, move on to the next one.
+                self.read_slot(slot, &mut buf)?;
+                if buf[..Self::SLOT_SIZE].iter().any(|&b| b != 0xff) {
+                    continue;
+                }
+            }
+
+            let sequence = self.sequence.wrapping_add(1);
+            let len = encode(&mut buf[..Self::SLOT_SIZE], sequence, amounts);
+            self.flash
+                .write(self.slot_address(slot), &buf[..len])
+                .map_err(StoreError::Flash)?;
+            self.sequence = sequence;
+            return Ok(());
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    fn slots(&self) -> u32 {
+        self.sectors * Self::SLOTS_PER_SECTOR
+    }
+
+    fn slot_address(&self, slot: u32) -> u32 {
+        let sector = slot / Self::SLOTS_PER_SECTOR;
+        let index = slot % Self::SLOTS_PER_SECTOR;
+        self.offset + sector * F::ERASE_SIZE as u32 + index * Self::SLOT_SIZE as u32
+    }
+
+    fn read_slot(&mut self, slot: u32, buf: &mut [u8; MAX_SLOT_SIZE]) -> Result<(), StoreError<F::Error>> {
+        let address = self.slot_address(slot);
+        self.flash
+            .read(address, &mut buf[..Self::SLOT_SIZE])
+            .map_err(StoreError::Flash)
+    }
+}
+
+/// Looks up the first partition of type `kind` and `subtype` in the
+/// partition table of `flash`, `None` if there is none.
+pub fn find_partition<F: ReadNorFlash>(flash: &mut F, kind: u8, subtype: u8) -> Result<Option<Partition>, F::Error> {
+    let mut entry = [0; PARTITION_ENTRY_LEN];
+    for at in (PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + PARTITION_TABLE_LEN).step_by(PARTITION_ENTRY_LEN) {
+        flash.read(at, &mut entry)?;
+        // the table ends with an MD5 entry or erased flash
+        if entry[0..2] != PARTITION_MAGIC {
+            break;
+        }
+        if entry[2] == kind && entry[3] == subtype {
+            let word = |at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap());
+            return Ok(Some(Partition { offset: word(4), size: word(8) }));
+        }
+    }
+    Ok(None)
+}
+
+/// Writes a record into `buf` (a whole slot) and returns the number of bytes
+/// that need to be written, padded to the slot size.
+fn encode(buf: &mut [u8], sequence: u32, amounts: &[u32]) -> usize {
+    buf.fill(0xff);
+    buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    buf[4..8].copy_from_slice(&sequence.to_le_bytes());
+    buf[8..12].copy_from_slice(&(amounts.len() as u32).to_le_bytes());
+    for (i, amount) in amounts.iter().enumerate() {
+        let at = HEADER_LEN + 4 * i;
+        buf[at..at + 4].copy_from_slice(&amount.to_le_bytes());
+    }
+
+    let crc_at = HEADER_LEN + 4 * amounts.len();
+    let crc = CRC.checksum(&buf[..crc_at]);
+    buf[crc_at..crc_at + 4].copy_from_slice(&crc.to_le_bytes());
+    buf.len()
+}
+
+fn decode<const N: usize>(slot: &[u8]) -> Option<(u32, Vec<u32, N>)> {
+    let word = |at: usize| u32::from_le_bytes(slot[at..at + 4].try_into().unwrap());
+
+    if word(0) != MAGIC {
+        return None;
+    }
+    let count = word(8) as usize;
+    if count > N {
+        return None;
+    }
+    let crc_at = HEADER_LEN + 4 * count;
+    if CRC.checksum(&slot[..crc_at]) != word(crc_at) {
+        return None;
+    }
+
+    let amounts = (0..count).map(|i| word(HEADER_LEN + 4 * i)).collect();
+    Some((word(4), amounts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    const SECTOR: usize = 256;
+
+    /// NOR flash in RAM: erasing sets bytes to 0xff, writing can only clear bits.
+    struct MemFlash {
+        data: std::vec::Vec<u8>,
+        erases: std::vec::Vec<u32>,
+        /// Makes the next write stop after this many bytes, like a power loss.
+        tear_after: Option<usize>,
+    }
+
+    impl MemFlash {
+        fn new(sectors: usize) -> Self {
+            Self {
+                data: vec![0xff; sectors * SECTOR],
+                erases: vec![0; sectors],
+                tear_after: None,
+            }
+        }
+    }
+
+    impl ErrorType for MemFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MemFlash {
+        const READ_SIZE: usize = 4;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MemFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.data[from as usize..to as usize].fill(0xff);
+            for sector in from as usize / SECTOR..to as usize / SECTOR {
+                self.erases[sector] += 1;
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let len = self.tear_after.take().unwrap_or(bytes.len());
+            for (i, &byte) in bytes[..len].iter().enumerate() {
+                let cell = &mut self.data[offset as usize + i];
+                if *cell & byte != byte {
+                    return Err(NorFlashErrorKind::Other);
+                }
+                *cell = byte;
+            }
+            Ok(())
+        }
+    }
+
+    type Store<'a> = InventoryStore<&'a mut MemFlash, 4>;
+
+    fn reopen(flash: &mut MemFlash) -> Option<Vec<u32, 4>> {
+        let size = flash.capacity() as u32;
+        Store::new(flash, 0, size).restore().unwrap()
+    }
+
+    #[test]
+    fn blank_flash_has_no_inventory() {
+        let mut flash = MemFlash::new(2);
+
+        assert_eq!(reopen(&mut flash), None);
+    }
+
+    #[test]
+    fn saved_amounts_survive_reboot() {
+        let mut flash = MemFlash::new(2);
+
+        let mut store = Store::new(&mut flash, 0, 2 * SECTOR as u32);
+        store.restore().unwrap();
+        store.save(&[10, 9, 11]).unwrap();
+        store.save(&[9, 9, 11]).unwrap();
+
+        assert_eq!(reopen(&mut flash).unwrap(), [9, 9, 11]);
+    }
+
+    #[test]
+    fn log_continues_after_reboot() {
+        let mut flash = MemFlash::new(2);
+
+        for amount in 0..5 {
+            let mut store = Store::new(&mut flash, 0, 2 * SECTOR as u32);
+            store.restore().unwrap();
+            store.save(&[amount]).unwrap();
+            assert_eq!(store.sequence(), amount + 1);
+        }
+
+        assert_eq!(reopen(&mut flash).unwrap(), [4]);
+    }
+
+    #[test]
+    fn sectors_wear_evenly() {
+        let mut flash = MemFlash::new(4);
+
+        let mut store = Store::new(&mut flash, 0, 4 * SECTOR as u32);
+        for amount in 0..1000 {
+            store.save(&[amount, 1, 2]).unwrap();
+        }
+
+        assert_eq!(reopen(&mut flash).unwrap(), [999, 1, 2]);
+        let min = flash.erases.iter().min().unwrap();
+        let max = flash.erases.iter().max().unwrap();
+        assert!(max - min <= 1, "uneven wear: {:?}", flash.erases);
+    }
+
+    #[test]
+    fn corrupted_record_falls_back_to_previous() {
+        let mut flash = MemFlash::new(2);
+
+        let mut store = Store::new(&mut flash, 0, 2 * SECTOR as u32);
+        store.save(&[5, 5]).unwrap();
+        store.save(&[4, 5]).unwrap();
+        let slot_size = Store::SLOT_SIZE;
+        flash.data[slot_size + HEADER_LEN] = 0x00;
+
+        assert_eq!(reopen(&mut flash).unwrap(), [5, 5]);
+    }
+
+    #[test]
+    fn torn_write_is_skipped() {
+        let mut flash = MemFlash::new(2);
+
+        Store::new(&mut flash, 0, 2 * SECTOR as u32).save(&[3, 3]).unwrap();
+
+        flash.tear_after = Some(8);
+        let mut store = Store::new(&mut flash, 0, 2 * SECTOR as u32);
+        store.restore().unwrap();
+        store.save(&[2, 3]).unwrap();
+        assert_eq!(reopen(&mut flash).unwrap(), [3, 3]);
+
+        let mut store = Store::new(&mut flash, 0, 2 * SECTOR as u32);
+        store.restore().unwrap();
+        store.save(&[2, 3]).unwrap();
+        assert_eq!(reopen(&mut flash).unwrap(), [2, 3]);
+    }
+
+    #[test]
+    fn newest_record_wins_across_sectors() {
+        let mut flash = MemFlash::new(2);
+        let slots = (SECTOR / Store::SLOT_SIZE) as u32 * 2;
+
+        let mut store = Store::new(&mut flash, 0, 2 * SECTOR as u32);
+        for amount in 0..slots + 3 {
+            store.save(&[amount]).unwrap();
+        }
+
+        assert_eq!(reopen(&mut flash).unwrap(), [slots + 2]);
+    }
+
+    #[test]
+    fn too_many_items_is_rejected() {
+        let mut flash = MemFlash::new(2);
+
+        let mut store = Store::new(&mut flash, 0, 2 * SECTOR as u32);
+
+        assert_eq!(store.save(&[1, 2, 3, 4, 5]), Err(StoreError::TooManyItems));
+    }
+
+    #[test]
+    fn store_uses_only_its_region() {
+        let mut flash = MemFlash::new(4);
+
+        let mut store = Store::new(&mut flash, SECTOR as u32, 2 * SECTOR as u32);
+        for amount in 0..100 {
+            store.save(&[amount]).unwrap();
+        }
+
+        assert_eq!((flash.erases[0], flash.erases[3]), (0, 0));
+        assert!(flash.erases[1] > 0 && flash.erases[2] > 0);
+        assert!(flash.data[..SECTOR].iter().all(|&b| b == 0xff));
+        assert!(flash.data[3 * SECTOR..].iter().all(|&b| b == 0xff));
+    }
+
+    /// Flash holding the partition table of partitions.csv, up to `entries`.
+    fn flash_with_partitions(entries: usize) -> MemFlash {
+        let partitions: [(u8, u8, u32, u32, &str); 4] = [
+            (0x01, 0x02, 0x9000, 0x6000, "nvs"),
+            (0x01, 0x01, 0xf000, 0x1000, "phy_init"),
+            (0x00, 0x00, 0x10000, 0x3f0000, "factory"),
+            (0x01, 0x40, 0x400000, 0x4000, "inventory"),
+        ];
+        let mut flash = MemFlash::new((PARTITION_TABLE_OFFSET + PARTITION_TABLE_LEN) as usize / SECTOR);
+        for (i, (kind, subtype, offset, size, label)) in partitions.into_iter().take(entries).enumerate() {
+            let entry = &mut flash.data[PARTITION_TABLE_OFFSET as usize + i * PARTITION_ENTRY_LEN..][..PARTITION_ENTRY_LEN];
+            entry[0..4].copy_from_slice(&[0xAA, 0x50, kind, subtype]);
+            entry[4..8].copy_from_slice(&offset.to_le_bytes());
+            entry[8..12].copy_from_slice(&size.to_le_bytes());
+            entry[12..12 + label.len()].copy_from_slice(label.as_bytes());
+            // the rest of the label and the flags
+            entry[12 + label.len()..].fill(0);
+        }
+        flash
+    }
+
+    #[test]
+    fn finds_the_inventory_partition() {
+        let mut flash = flash_with_partitions(4);
+
+        let partition = find_partition(&mut flash, DATA_PARTITION, INVENTORY_PARTITION_SUBTYPE).unwrap();
+
+        assert_eq!(partition, Some(Partition { offset: 0x400000, size: 0x4000 }));
+    }
+
+    #[test]
+    fn missing_partition_is_none() {
+        let mut flash = flash_with_partitions(3);
+        // an MD5 entry ends the table as well
+        flash.data[PARTITION_TABLE_OFFSET as usize + 3 * PARTITION_ENTRY_LEN..][..2].copy_from_slice(&[0xEB, 0xEB]);
+
+        assert_eq!(find_partition(&mut flash, DATA_PARTITION, INVENTORY_PARTITION_SUBTYPE), Ok(None));
+        assert_eq!(find_partition(&mut flash_with_partitions(0), DATA_PARTITION, INVENTORY_PARTITION_SUBTYPE), Ok(None));
+    }
+}
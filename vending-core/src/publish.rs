@@ -0,0 +1,210 @@
+//! Publishing a batch of MQTT messages over a connection that may die halfway.
+//!
+//! [`publish_all`] sends the messages in order and records an [`Outcome`] for
+//! each. Once the transport reports that the connection itself is gone, the
+//! rest of the batch is skipped instead of failing one by one, and the
+//! [`PublishReport`] tells the caller to reconnect.
+
+use core::future::Future;
+
+use heapless::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl<'a> Message<'a> {
+    pub const fn new(topic: &'a str, payload: &'a [u8], qos: QoS, retain: bool) -> Self {
+        Self { topic, payload, qos, retain }
+    }
+}
+
+/// Something messages can be published over, usually an MQTT client.
+pub trait Publisher {
+    type Error;
+
+    fn publish(&mut self, message: &Message<'_>) -> impl Future<Output = Result<(), Self::Error>>;
+
+    /// Whether `error` means the connection is unusable, as opposed to the
+    /// broker refusing just this one message.
+    fn is_connection_lost(error: &Self::Error) -> bool;
+
+    /// Whether the broker answered the message with `error`, so it won't go
+    /// through on a retry either, as opposed to its acknowledgement not
+    /// having arrived.
+    fn is_refused(error: &Self::Error) -> bool;
+
+    /// Whether something arrived that should be handled before publishing
+    /// more, such as a command.
+    fn incoming_pending(&mut self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome<E> {
+    Sent,
+    Failed(E),
+    /// Not attempted because the connection was lost on an earlier message.
+    Skipped,
+}
+
+/// What happened to each message of a batch, in the order they were given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishReport<E, const N: usize> {
+    outcomes: Vec<Outcome<E>, N>,
+    connection_lost: bool,
+}
+
+impl<E, const N: usize> PublishReport<E, N> {
+    pub fn outcomes(&self) -> &[Outcome<E>] {
+        &self.outcomes
+    }
+
+    pub fn sent(&self) -> usize {
+        self.outcomes.iter().filter(|outcome| matches!(outcome, Outcome::Sent)).count()
+    }
+
+    pub fn all_sent(&self) -> bool {
+        self.sent() == self.outcomes.len()
+    }
+
+    /// The session has to be torn down and reconnected.
+    pub fn connection_lost(&self) -> bool {
+        self.connection_lost
+    }
+}
+
+/// Publishes `messages` in order. The report holds `N` outcomes, so there
+/// mustn't be more messages than that.
+pub async fn publish_all<P: Publisher, const N: usize>(
+    publisher: &mut P,
+    messages: &[Message<'_>],
+) -> PublishReport<P::Error, N> {
+    assert!(messages.len() <= N, "more messages than the report can hold");
+
+    let mut report = PublishReport { outcomes: Vec::new(), connection_lost: false };
+    for message in messages {
+        let outcome = if report.connection_lost {
+            Outcome::Skipped
+        } else {
+            match publisher.publish(message).await {
+                Ok(()) => Outcome::Sent,
+                Err(error) => {
+                    report.connection_lost = P::is_connection_lost(&error);
+                    Outcome::Failed(error)
+                }
+            }
+        };
+        report.outcomes.push(outcome).ok();
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use std::vec::Vec;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum MockError {
+        Refused,
+        Network,
+    }
+
+    /// Fails the messages at the given positions with the given errors.
+    struct MockPublisher {
+        failures: Vec<(usize, MockError)>,
+        published: Vec<std::string::String>,
+        attempts: usize,
+    }
+
+    impl MockPublisher {
+        fn new(failures: &[(usize, MockError)]) -> Self {
+            Self { failures: failures.to_vec(), published: Vec::new(), attempts: 0 }
+        }
+    }
+
+    impl Publisher for MockPublisher {
+        type Error = MockError;
+
+        async fn publish(&mut self, message: &Message<'_>) -> Result<(), MockError> {
+            let attempt = self.attempts;
+            self.attempts += 1;
+            match self.failures.iter().find(|(at, _)| *at == attempt) {
+                Some((_, error)) => Err(*error),
+                None => {
+                    self.published.push(message.topic.into());
+                    Ok(())
+                }
+            }
+        }
+
+        fn is_connection_lost(error: &MockError) -> bool {
+            *error == MockError::Network
+        }
+
+        fn is_refused(error: &MockError) -> bool {
+            *error == MockError::Refused
+        }
+    }
+
+    const MESSAGES: [Message<'static>; 3] = [
+        Message::new("espbox/sensor/Temperature", b"21.50", QoS::AtLeastOnce, true),
+        Message::new("espbox/sensor/Pressure", b"1013.25", QoS::AtLeastOnce, true),
+        Message::new("espbox/sensor/Humidity", b"40.00", QoS::AtLeastOnce, true),
+    ];
+
+    #[test]
+    fn publishes_everything_in_order() {
+        let mut publisher = MockPublisher::new(&[]);
+
+        let report = block_on(publish_all::<_, 4>(&mut publisher, &MESSAGES));
+
+        assert!(report.all_sent());
+        assert!(!report.connection_lost());
+        assert_eq!(publisher.published, MESSAGES.map(|m| m.topic));
+    }
+
+    #[test]
+    fn refused_message_does_not_stop_the_batch() {
+        let mut publisher = MockPublisher::new(&[(0, MockError::Refused)]);
+
+        let report = block_on(publish_all::<_, 4>(&mut publisher, &MESSAGES));
+
+        assert_eq!(report.outcomes(), [Outcome::Failed(MockError::Refused), Outcome::Sent, Outcome::Sent]);
+        assert_eq!(report.sent(), 2);
+        assert!(!report.connection_lost());
+    }
+
+    #[test]
+    fn network_error_skips_the_rest() {
+        let mut publisher = MockPublisher::new(&[(1, MockError::Network)]);
+
+        let report = block_on(publish_all::<_, 4>(&mut publisher, &MESSAGES));
+
+        assert_eq!(report.outcomes(), [Outcome::Sent, Outcome::Failed(MockError::Network), Outcome::Skipped]);
+        assert!(report.connection_lost());
+        assert_eq!(publisher.attempts, 2);
+    }
+
+    #[test]
+    fn empty_batch() {
+        let report = block_on(publish_all::<_, 1>(&mut MockPublisher::new(&[]), &[]));
+
+        assert!(report.all_sent());
+        assert!(report.outcomes().is_empty());
+    }
+}
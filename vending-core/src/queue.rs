@@ -0,0 +1,179 @@
+//! Store-and-forward queue for events that happen while the broker is
+//! unreachable.
+//!
+//! Events are pushed as they happen and only removed once they were
+//! published, so they go out in their original order after a reconnect. When
+//! the queue fills up during a long outage the [`OverflowPolicy`] decides which
+//! events are given up.
+
+use heapless::Deque;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Make room by dropping the oldest event, keeping the most recent state.
+    DropOldest,
+    /// Reject new events, keeping the start of the outage.
+    DropNewest,
+}
+
+impl OverflowPolicy {
+    /// Parses the `offline_queue_overflow` setting of `cfg.toml`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "drop-oldest" => Some(OverflowPolicy::DropOldest),
+            "drop-newest" => Some(OverflowPolicy::DropNewest),
+            _ => None,
+        }
+    }
+}
+
+/// Up to `N` events waiting to be published.
+#[derive(Debug, Clone)]
+pub struct OfflineQueue<T, const N: usize> {
+    events: Deque<T, N>,
+    policy: OverflowPolicy,
+    dropped: u32,
+}
+
+impl<T, const N: usize> OfflineQueue<T, N> {
+    pub const fn new(policy: OverflowPolicy) -> Self {
+        Self { events: Deque::new(), policy, dropped: 0 }
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: OverflowPolicy) {
+        self.policy = policy;
+    }
+
+    /// Queues `event`, dropping one event according to the policy if the
+    /// queue is full. Returns whether `event` was queued.
+    pub fn push(&mut self, event: T) -> bool {
+        if self.events.is_full() {
+            self.dropped = self.dropped.wrapping_add(1);
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    self.events.pop_front();
+                }
+                OverflowPolicy::DropNewest => return false,
+            }
+        }
+        self.events.push_back(event).is_ok()
+    }
+
+    /// The oldest event, which is the next one to publish.
+    pub fn front(&self) -> Option<&T> {
+        self.events.front()
+    }
+
+    /// Removes the oldest event once it has been published, unless it's no
+    /// longer `published` because it was dropped to make room meanwhile.
+    pub fn complete(&mut self, published: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        if self.events.front() == Some(published) {
+            self.events.pop_front();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Events lost to overflow since boot.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain<const N: usize>(queue: &mut OfflineQueue<u32, N>) -> std::vec::Vec<u32> {
+        let mut drained = std::vec::Vec::new();
+        while let Some(&event) = queue.front() {
+            assert!(queue.complete(&event));
+            drained.push(event);
+        }
+        drained
+    }
+
+    #[test]
+    fn drains_in_order() {
+        let mut queue = OfflineQueue::<u32, 4>::new(OverflowPolicy::DropOldest);
+        for event in 1..=3 {
+            assert!(queue.push(event));
+        }
+
+        assert_eq!(drain(&mut queue), [1, 2, 3]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drop_oldest_keeps_recent_events() {
+        let mut queue = OfflineQueue::<u32, 3>::new(OverflowPolicy::DropOldest);
+        for event in 1..=5 {
+            assert!(queue.push(event));
+        }
+
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), [3, 4, 5]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_early_events() {
+        let mut queue = OfflineQueue::<u32, 3>::new(OverflowPolicy::DropNewest);
+        for event in 1..=3 {
+            assert!(queue.push(event));
+        }
+
+        assert!(!queue.push(4));
+        assert!(!queue.push(5));
+
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), [1, 2, 3]);
+    }
+
+    #[test]
+    fn failed_publish_keeps_event_queued() {
+        let mut queue = OfflineQueue::<u32, 3>::new(OverflowPolicy::DropOldest);
+        queue.push(1);
+        queue.push(2);
+
+        // publishing 1 failed, so it's not completed and comes up again
+        assert_eq!(queue.front(), Some(&1));
+        assert_eq!(queue.front(), Some(&1));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn complete_ignores_event_dropped_while_publishing() {
+        let mut queue = OfflineQueue::<u32, 2>::new(OverflowPolicy::DropOldest);
+        queue.push(1);
+        queue.push(2);
+        let publishing = *queue.front().unwrap();
+
+        queue.push(3);
+
+        assert!(!queue.complete(&publishing));
+        assert_eq!(drain(&mut queue), [2, 3]);
+    }
+
+    #[test]
+    fn policy_names() {
+        assert_eq!(OverflowPolicy::from_name("drop-oldest"), Some(OverflowPolicy::DropOldest));
+        assert_eq!(OverflowPolicy::from_name("drop-newest"), Some(OverflowPolicy::DropNewest));
+        assert_eq!(OverflowPolicy::from_name("block"), None);
+    }
+}
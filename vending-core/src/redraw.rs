@@ -0,0 +1,444 @@
+//! Sending only the parts of the screen that changed.
+//!
+//! The display has no framebuffer on our side, so a page switch used to clear
+//! the whole panel over SPI and draw the new page on top, which flickers.
+//! [`DirtyRegions`] sits between the widgets and the display and follows the
+//! screen in tiles of [`TILE_SIZE`] pixels. Filling whole tiles is deferred:
+//! a tile is only filled right before something is drawn into it, or on
+//! [`DirtyRegions::flush`], and not at all if it already shows that color.
+//! Everything else is passed through, so what ends up on the panel is the
+//! same as when drawing to it directly.
+
+use embedded_graphics::{
+    pixelcolor::raw::RawData,
+    prelude::*,
+    primitives::Rectangle,
+};
+use heapless::Vec;
+
+/// Width and height of a tile, in pixels.
+pub const TILE_SIZE: u32 = 16;
+// tiles per side, enough for 320 pixels in either orientation
+const MAX_TILES: usize = 20;
+// pixels of a draw_iter passed on at once
+const CHUNK: usize = 32;
+
+/// Bytes sent for one write, with the pixel data counted separately: the
+/// column and row address commands with their arguments and the memory write
+/// command, as for the ILI9342C.
+pub const WINDOW_BYTES: u64 = 11;
+
+/// What was sent to the display since the last [`DirtyRegions::flush`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RedrawStats {
+    /// Windows written, each a few command bytes on top of the pixels.
+    pub writes: u32,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Content<C> {
+    /// Before the first fill, or after [`DirtyRegions::invalidate`].
+    Unknown,
+    Solid(C),
+    Mixed,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Tile<C> {
+    shown: Content<C>,
+    /// Fill that covered the whole tile and wasn't sent yet.
+    pending: Option<C>,
+}
+
+/// A display that skips fills of tiles which already show the color.
+pub struct DirtyRegions<D: DrawTarget> {
+    inner: D,
+    area: Rectangle,
+    tiles: [[Tile<D::Color>; MAX_TILES]; MAX_TILES],
+    stats: RedrawStats,
+}
+
+impl<D: DrawTarget> DirtyRegions<D> {
+    /// Wraps `inner`, whose contents are unknown at first. Displays larger
+    /// than 320×320 are only followed in their top left part.
+    pub fn new(inner: D) -> Self {
+        let area = inner.bounding_box();
+        let clamped = Size::new(
+            area.size.width.min(MAX_TILES as u32 * TILE_SIZE),
+            area.size.height.min(MAX_TILES as u32 * TILE_SIZE),
+        );
+        Self {
+            area: Rectangle::new(area.top_left, clamped),
+            inner,
+            tiles: [[Tile { shown: Content::Unknown, pending: None }; MAX_TILES]; MAX_TILES],
+            stats: RedrawStats::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// The display itself. Whatever is drawn on it directly has to be
+    /// followed by [`Self::invalidate`].
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    /// Forgets what the display shows, e.g. after drawing on it directly.
+    pub fn invalidate(&mut self) {
+        for tile in self.tiles.iter_mut().flat_map(|row| row.iter_mut()) {
+            tile.shown = Content::Unknown;
+        }
+    }
+
+    /// Sends the deferred fills, returning what was sent since the last
+    /// flush.
+    pub fn flush(&mut self) -> Result<RedrawStats, D::Error> {
+        self.apply_pending(self.area)?;
+        Ok(core::mem::take(&mut self.stats))
+    }
+
+    fn bytes_per_pixel() -> u64 {
+        (<D::Color as PixelColor>::Raw::BITS_PER_PIXEL as u64).div_ceil(8)
+    }
+
+    fn record(&mut self, writes: u32, pixels: u64) {
+        self.stats.writes += writes;
+        self.stats.bytes += writes as u64 * WINDOW_BYTES + pixels * Self::bytes_per_pixel();
+    }
+
+    fn tile_rect(&self, column: usize, row: usize) -> Rectangle {
+        let top_left = self.area.top_left + Point::new((column as u32 * TILE_SIZE) as i32, (row as u32 * TILE_SIZE) as i32);
+        Rectangle::new(top_left, Size::new(TILE_SIZE, TILE_SIZE)).intersection(&self.area)
+    }
+
+    /// Columns and rows of the tiles `area` touches, empty if it's outside.
+    fn tiles_of(&self, area: &Rectangle) -> (core::ops::Range<usize>, core::ops::Range<usize>) {
+        let area = area.intersection(&self.area);
+        let Some(bottom_right) = area.bottom_right() else {
+            return (0..0, 0..0);
+        };
+        let first = area.top_left - self.area.top_left;
+        let last = bottom_right - self.area.top_left;
+        let tile = TILE_SIZE as i32;
+        (
+            (first.x / tile) as usize..(last.x / tile) as usize + 1,
+            (first.y / tile) as usize..(last.y / tile) as usize + 1,
+        )
+    }
+
+    fn tile_at(&self, point: Point) -> Option<(usize, usize)> {
+        if !self.area.contains(point) {
+            return None;
+        }
+        let offset = point - self.area.top_left;
+        Some(((offset.x / TILE_SIZE as i32) as usize, (offset.y / TILE_SIZE as i32) as usize))
+    }
+
+    /// Sends the deferred fills of the tiles `area` touches, one write per
+    /// run of neighbouring tiles of the same color.
+    fn apply_pending(&mut self, area: Rectangle) -> Result<(), D::Error> {
+        let (columns, rows) = self.tiles_of(&area);
+        for row in rows {
+            let mut run: Option<(usize, usize, D::Color)> = None;
+            for column in columns.clone() {
+                let tile = &mut self.tiles[row][column];
+                let needed = match tile.pending.take() {
+                    Some(color) if tile.shown != Content::Solid(color) => {
+                        tile.shown = Content::Solid(color);
+                        Some(color)
+                    }
+                    _ => None,
+                };
+
+                match (run, needed) {
+                    (Some((first, _, color)), Some(next)) if next == color => run = Some((first, column, color)),
+                    (_, needed) => {
+                        if let Some((first, last, color)) = run {
+                            self.fill_run(self.area, row, first, last, color)?;
+                        }
+                        run = needed.map(|color| (column, column, color));
+                    }
+                }
+            }
+            if let Some((first, last, color)) = run {
+                self.fill_run(self.area, row, first, last, color)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills the part of `area` in the tiles `first..=last` of `row`.
+    fn fill_run(&mut self, area: Rectangle, row: usize, first: usize, last: usize, color: D::Color) -> Result<(), D::Error> {
+        let start = self.tile_rect(first, row);
+        let end = self.tile_rect(last, row);
+        let tiles = Rectangle::with_corners(start.top_left, end.bottom_right().unwrap_or(end.top_left));
+        let part = area.intersection(&tiles);
+        self.record(1, part.size.width as u64 * part.size.height as u64);
+        self.inner.fill_solid(&part, color)
+    }
+}
+
+impl<D: DrawTarget> Dimensions for DirtyRegions<D> {
+    fn bounding_box(&self) -> Rectangle {
+        self.inner.bounding_box()
+    }
+}
+
+impl<D: DrawTarget> DrawTarget for DirtyRegions<D> {
+    type Color = D::Color;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let display = self.inner.bounding_box();
+        let mut chunk: Vec<Pixel<D::Color>, CHUNK> = Vec::new();
+        for pixel in pixels.into_iter().filter(|pixel| display.contains(pixel.0)) {
+            if let Some((column, row)) = self.tile_at(pixel.0) {
+                if self.tiles[row][column].pending.is_some() {
+                    self.apply_pending(self.tile_rect(column, row))?;
+                }
+                self.tiles[row][column].shown = Content::Mixed;
+            }
+
+            if chunk.is_full() {
+                // drawn pixel by pixel, each in its own window
+                self.record(chunk.len() as u32, chunk.len() as u64);
+                self.inner.draw_iter(chunk.iter().copied())?;
+                chunk.clear();
+            }
+            chunk.push(pixel).ok();
+        }
+        self.record(chunk.len() as u32, chunk.len() as u64);
+        self.inner.draw_iter(chunk)
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        self.apply_pending(*area)?;
+        let (columns, rows) = self.tiles_of(area);
+        for row in rows {
+            for column in columns.clone() {
+                self.tiles[row][column].shown = Content::Mixed;
+            }
+        }
+
+        let drawn = area.intersection(&self.inner.bounding_box());
+        self.record(1, drawn.size.width as u64 * drawn.size.height as u64);
+        self.inner.fill_contiguous(area, colors)
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let drawn = area.intersection(&self.inner.bounding_box());
+        let area = drawn.intersection(&self.area);
+        if area != drawn {
+            // partly outside of the tiles, which can't follow it
+            return self.fill_contiguous(&drawn, core::iter::repeat(color));
+        }
+        let (columns, rows) = self.tiles_of(&area);
+
+        for row in rows {
+            // tiles only partly covered are sent right away, a run at a time
+            let mut run: Option<(usize, usize)> = None;
+            for column in columns.clone() {
+                let tile_rect = self.tile_rect(column, row);
+                let tile = &mut self.tiles[row][column];
+                let showing = tile.pending.map_or(tile.shown, Content::Solid);
+
+                let send = if area.intersection(&tile_rect) == tile_rect {
+                    tile.pending = Some(color);
+                    false
+                } else {
+                    showing != Content::Solid(color)
+                };
+
+                if send {
+                    self.apply_pending(tile_rect)?;
+                    self.tiles[row][column].shown = Content::Mixed;
+                    run = Some((run.map_or(column, |(first, _)| first), column));
+                } else if let Some((first, last)) = run.take() {
+                    self.fill_run(area, row, first, last, color)?;
+                }
+            }
+            if let Some((first, last)) = run {
+                self.fill_run(area, row, first, last, color)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use embedded_graphics::{
+        mono_font::{ascii::FONT_10X20, MonoTextStyle},
+        pixelcolor::Rgb565,
+        primitives::PrimitiveStyle,
+        text::Text,
+    };
+
+    use crate::derived::DerivedMetrics;
+    use crate::ui::{DerivedMetricsPage, ListPage};
+
+    /// 320x240 pixels in memory, counting what it was sent the way
+    /// [`RedrawStats`] does.
+    struct FrameBuffer {
+        pixels: std::vec::Vec<Rgb565>,
+        sent: RedrawStats,
+    }
+
+    impl FrameBuffer {
+        fn new() -> Self {
+            Self { pixels: std::vec![Rgb565::BLACK; 320 * 240], sent: RedrawStats::default() }
+        }
+
+        fn count(&mut self, writes: u32, pixels: u64) {
+            self.sent.writes += writes;
+            self.sent.bytes += writes as u64 * WINDOW_BYTES + pixels * 2;
+        }
+    }
+
+    impl DrawTarget for FrameBuffer {
+        type Color = Rgb565;
+        type Error = core::convert::Infallible;
+
+        fn draw_iter<I: IntoIterator<Item = Pixel<Rgb565>>>(&mut self, pixels: I) -> Result<(), Self::Error> {
+            for Pixel(point, color) in pixels {
+                if self.bounding_box().contains(point) {
+                    self.pixels[point.y as usize * 320 + point.x as usize] = color;
+                    self.count(1, 1);
+                }
+            }
+            Ok(())
+        }
+
+        fn fill_contiguous<I: IntoIterator<Item = Rgb565>>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error> {
+            let drawn = area.intersection(&self.bounding_box());
+            self.count(1, drawn.size.width as u64 * drawn.size.height as u64);
+            for (point, color) in area.points().zip(colors) {
+                if drawn.contains(point) {
+                    self.pixels[point.y as usize * 320 + point.x as usize] = color;
+                }
+            }
+            Ok(())
+        }
+
+        fn fill_solid(&mut self, area: &Rectangle, color: Rgb565) -> Result<(), Self::Error> {
+            self.fill_contiguous(area, core::iter::repeat(color))
+        }
+    }
+
+    impl OriginDimensions for FrameBuffer {
+        fn size(&self) -> Size {
+            Size::new(320, 240)
+        }
+    }
+
+    fn list_page<D: DrawTarget<Color = Rgb565>>(target: &mut D) {
+        let rows = [("Uptime", "0 d 01:23"), ("Sensor", "ok"), ("Active alarms", "1")];
+        ListPage { title: "Status", rows: &rows }.draw(target).ok();
+    }
+
+    fn derived_page<D: DrawTarget<Color = Rgb565>>(target: &mut D) {
+        let metrics = DerivedMetrics { dew_point: 8.1, heat_index: 22.4, absolute_humidity: 9.3, altitude: 312.0 };
+        DerivedMetricsPage { metrics }.draw(target).ok();
+    }
+
+    /// The drawing done between two flushes in the tests.
+    fn step<D: DrawTarget<Color = Rgb565>>(target: &mut D, step: usize) {
+        let fill = |color| PrimitiveStyle::with_fill(color);
+        match step {
+            0 => target.clear(Rgb565::WHITE).ok(),
+            1 => {
+                list_page(target);
+                Some(())
+            }
+            2 => {
+                derived_page(target);
+                Some(())
+            }
+            // partly covered tiles, off the tile grid and off the screen
+            3 => Rectangle::new(Point::new(5, 7), Size::new(50, 40)).into_styled(fill(Rgb565::RED)).draw(target).ok(),
+            4 => Rectangle::new(Point::new(300, 230), Size::new(50, 40)).into_styled(fill(Rgb565::BLUE)).draw(target).ok(),
+            5 => {
+                target.clear(Rgb565::WHITE).ok();
+                Text::new("Hi", Point::new(3, 20), MonoTextStyle::new(&FONT_10X20, Rgb565::BLACK)).draw(target).ok();
+                Rectangle::new(Point::new(0, 0), Size::new(32, 32)).into_styled(fill(Rgb565::WHITE)).draw(target).ok()
+            }
+            _ => {
+                list_page(target);
+                Some(())
+            }
+        };
+    }
+
+    #[test]
+    fn shows_the_same_as_drawing_directly() {
+        let mut direct = FrameBuffer::new();
+        let mut tracked = DirtyRegions::new(FrameBuffer::new());
+
+        for n in 0..7 {
+            step(&mut direct, n);
+            step(&mut tracked, n);
+            let sent = tracked.flush().unwrap();
+
+            assert!(direct.pixels == tracked.inner().pixels, "differs after step {}", n);
+            assert_eq!(tracked.inner_mut().sent, sent, "stats of step {}", n);
+            tracked.inner_mut().sent = RedrawStats::default();
+        }
+    }
+
+    #[test]
+    fn skips_fills_of_the_same_color() {
+        let mut tracked = DirtyRegions::new(FrameBuffer::new());
+
+        tracked.clear(Rgb565::WHITE).unwrap();
+        assert_eq!(tracked.flush().unwrap().bytes, 320 * 240 * 2 + 15 * WINDOW_BYTES);
+
+        tracked.clear(Rgb565::WHITE).unwrap();
+        Rectangle::new(Point::new(10, 10), Size::new(5, 5))
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+            .draw(&mut tracked)
+            .unwrap();
+        assert_eq!(tracked.flush().unwrap(), RedrawStats::default());
+    }
+
+    #[test]
+    fn defers_fills_until_flushed() {
+        let mut tracked = DirtyRegions::new(FrameBuffer::new());
+
+        tracked.clear(Rgb565::WHITE).unwrap();
+        tracked.clear(Rgb565::RED).unwrap();
+        assert_eq!(tracked.inner().sent, RedrawStats::default());
+
+        tracked.flush().unwrap();
+        assert!(tracked.inner().pixels.iter().all(|pixel| *pixel == Rgb565::RED));
+        assert_eq!(tracked.inner().sent.writes, 15);
+    }
+
+    #[test]
+    fn page_switch_sends_less_than_a_full_redraw() {
+        let mut direct = FrameBuffer::new();
+        let mut tracked = DirtyRegions::new(FrameBuffer::new());
+        list_page(&mut direct);
+        list_page(&mut tracked);
+        tracked.flush().unwrap();
+        direct.sent = RedrawStats::default();
+
+        derived_page(&mut direct);
+        derived_page(&mut tracked);
+        let sent = tracked.flush().unwrap();
+
+        // the white around the text isn't sent again
+        assert!(sent.bytes * 3 < direct.sent.bytes, "{:?} vs {:?}", sent, direct.sent);
+    }
+}
@@ -0,0 +1,30 @@
+//! What the pages of the touch screen draw on.
+//!
+//! The firmware's pages only need a [`DrawTarget`] and a way to show what
+//! they drew, which [`Screen`] puts together. Besides the ILI9342C behind
+//! [`DirtyRegions`] or [`DoubleBuffered`], it's implemented by the in-memory
+//! `Snapshot` of the `std` feature, so the same drawing code can be rendered
+//! and compared on the host.
+
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*};
+
+use crate::framebuffer::{DoubleBuffered, WindowTarget};
+use crate::redraw::{DirtyRegions, RedrawStats};
+
+pub trait Screen: DrawTarget<Color = Rgb565> {
+    /// Shows everything drawn since the last flush, returning what was sent
+    /// to the panel for it.
+    fn flush(&mut self) -> Result<RedrawStats, Self::Error>;
+}
+
+impl<D: DrawTarget<Color = Rgb565>> Screen for DirtyRegions<D> {
+    fn flush(&mut self) -> Result<RedrawStats, Self::Error> {
+        DirtyRegions::flush(self)
+    }
+}
+
+impl<D: WindowTarget> Screen for DoubleBuffered<'_, D> {
+    fn flush(&mut self) -> Result<RedrawStats, Self::Error> {
+        DoubleBuffered::flush(self)
+    }
+}
@@ -0,0 +1,191 @@
+//! Environmental readings from the BME680, and what went wrong when there
+//! are none.
+
+use core::time::Duration;
+
+use crate::connection::Backoff;
+use crate::iaq::Iaq;
+
+/// One measurement of all BME680 channels.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SensorReading {
+    /// °C
+    pub temperature: f32,
+    /// % relative humidity
+    pub humidity: f32,
+    /// hPa
+    pub pressure: f32,
+    /// Ω
+    pub gas_resistance: f32,
+    /// Scored from the gas resistance, `None` until the sensor is calibrated.
+    pub iaq: Option<Iaq>,
+}
+
+/// Why the BME680 didn't deliver a reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorFault {
+    /// Nothing acknowledged the address, e.g. a loose wire or a sensor
+    /// without power.
+    Nack,
+    /// The bus or the measurement didn't finish in time.
+    Timeout,
+    /// Any other bus or driver error.
+    Bus,
+    /// The sensor answered with values outside its operating range.
+    Implausible,
+    /// The gas resistance was converted before the heater reached its
+    /// temperature, e.g. with too short a heater duration.
+    GasInvalid,
+}
+
+impl SensorFault {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SensorFault::Nack => "no ack",
+            SensorFault::Timeout => "timeout",
+            SensorFault::Bus => "bus error",
+            SensorFault::Implausible => "implausible",
+            SensorFault::GasInvalid => "gas invalid",
+        }
+    }
+}
+
+/// Rejects readings outside the BME680's operating range, which a glitching
+/// bus produces instead of an error.
+pub fn check_plausible(reading: &SensorReading) -> Result<(), SensorFault> {
+    let plausible = (-40.0..=85.0).contains(&reading.temperature)
+        && (0.0..=100.0).contains(&reading.humidity)
+        && (300.0..=1100.0).contains(&reading.pressure)
+        && reading.gas_resistance > 0.0
+        && reading.gas_resistance.is_finite();
+
+    if plausible {
+        Ok(())
+    } else {
+        Err(SensorFault::Implausible)
+    }
+}
+
+/// Consecutive sensor faults and the delay before the next attempt.
+#[derive(Debug, Clone)]
+pub struct SensorHealth {
+    backoff: Backoff,
+    fault: Option<SensorFault>,
+    consecutive_faults: u32,
+}
+
+impl SensorHealth {
+    pub const fn new(backoff: Backoff) -> Self {
+        Self { backoff, fault: None, consecutive_faults: 0 }
+    }
+
+    /// Records a failed attempt, returning how long to wait before retrying.
+    pub fn fault(&mut self, fault: SensorFault) -> Duration {
+        self.fault = Some(fault);
+        self.consecutive_faults = self.consecutive_faults.saturating_add(1);
+        self.backoff.ceiling(self.consecutive_faults)
+    }
+
+    /// Records a successful reading, returning whether the sensor was
+    /// faulty before.
+    pub fn recovered(&mut self) -> bool {
+        self.consecutive_faults = 0;
+        self.fault.take().is_some()
+    }
+
+    /// The latest fault, while the sensor hasn't recovered from it.
+    pub fn current_fault(&self) -> Option<SensorFault> {
+        self.fault
+    }
+
+    pub fn consecutive_faults(&self) -> u32 {
+        self.consecutive_faults
+    }
+}
+
+/// Smallest change that shows on the sensor screen, which prints two decimals.
+pub const DISPLAY_RESOLUTION: f32 = 0.01;
+
+/// Whether a widget currently showing `shown` has to be redrawn for `value`.
+/// Smaller changes are skipped to save redraws.
+pub fn needs_redraw(shown: f32, value: f32) -> bool {
+    let change = value - shown;
+    change >= DISPLAY_RESOLUTION || change <= -DISPLAY_RESOLUTION || shown.is_nan() != value.is_nan()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading() -> SensorReading {
+        SensorReading { temperature: 21.5, humidity: 40.0, pressure: 1013.25, gas_resistance: 50_000.0, iaq: None }
+    }
+
+    #[test]
+    fn plausible_reading() {
+        assert_eq!(check_plausible(&reading()), Ok(()));
+    }
+
+    #[test]
+    fn implausible_readings() {
+        for reading in [
+            SensorReading { temperature: 120.0, ..reading() },
+            SensorReading { temperature: f32::NAN, ..reading() },
+            SensorReading { humidity: 100.5, ..reading() },
+            SensorReading { pressure: 0.0, ..reading() },
+            SensorReading { gas_resistance: 0.0, ..reading() },
+            SensorReading { gas_resistance: f32::INFINITY, ..reading() },
+        ] {
+            assert_eq!(check_plausible(&reading), Err(SensorFault::Implausible), "{:?}", reading);
+        }
+    }
+
+    #[test]
+    fn faults_back_off() {
+        let mut health = SensorHealth::new(Backoff::new(Duration::from_secs(1), Duration::from_secs(60)));
+
+        assert_eq!(health.fault(SensorFault::Nack), Duration::from_secs(1));
+        assert_eq!(health.fault(SensorFault::Nack), Duration::from_secs(2));
+        assert_eq!(health.fault(SensorFault::Timeout), Duration::from_secs(4));
+        for _ in 0..10 {
+            health.fault(SensorFault::Timeout);
+        }
+
+        assert_eq!(health.fault(SensorFault::Timeout), Duration::from_secs(60));
+        assert_eq!(health.current_fault(), Some(SensorFault::Timeout));
+        assert_eq!(health.consecutive_faults(), 14);
+    }
+
+    #[test]
+    fn recovery_resets_backoff() {
+        let mut health = SensorHealth::new(Backoff::new(Duration::from_secs(1), Duration::from_secs(60)));
+        assert!(!health.recovered());
+        health.fault(SensorFault::Bus);
+        health.fault(SensorFault::Bus);
+
+        assert!(health.recovered());
+        assert!(!health.recovered());
+        assert_eq!(health.current_fault(), None);
+        assert_eq!(health.fault(SensorFault::Bus), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn small_changes_are_not_redrawn() {
+        assert!(!needs_redraw(21.5, 21.5));
+        assert!(!needs_redraw(21.5, 21.505));
+        assert!(!needs_redraw(21.5, 21.495));
+    }
+
+    #[test]
+    fn visible_changes_are_redrawn() {
+        assert!(needs_redraw(21.5, 21.52));
+        assert!(needs_redraw(21.5, 21.48));
+        assert!(needs_redraw(0.0, 1013.25));
+    }
+
+    #[test]
+    fn nan_is_redrawn_once() {
+        assert!(needs_redraw(21.5, f32::NAN));
+        assert!(needs_redraw(f32::NAN, 21.5));
+    }
+}
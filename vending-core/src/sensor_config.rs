@@ -0,0 +1,324 @@
+//! Measurement settings of the BME680, changeable at runtime over MQTT.
+//!
+//! The defaults come from `cfg.toml`. An operator publishes a JSON object with
+//! any of the fields of [`SensorConfig`] to [`CONFIG_SET_TOPIC`], e.g.
+//! `{"heater_temperature": 300, "filter": 7}`, and fields that are left out
+//! keep their value. The machine applies a valid change between two
+//! measurements and publishes the settings that are then active, retained, on
+//! [`CONFIG_TOPIC`]. Invalid changes are answered on [`CONFIG_ACK_TOPIC`].
+
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+pub const CONFIG_TOPIC: &str = "espbox/sensor/config";
+pub const CONFIG_SET_TOPIC: &str = "espbox/sensor/config/set";
+pub const CONFIG_ACK_TOPIC: &str = "espbox/sensor/config/ack";
+
+/// Heater temperatures the BME680 supports, in °C.
+pub const HEATER_TEMPERATURE_RANGE: core::ops::RangeInclusive<u16> = 200..=400;
+/// Longest heating duration the BME680 can encode, in ms.
+pub const MAX_HEATER_DURATION_MS: u16 = 4032;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Oversampling {
+    /// The value isn't measured.
+    Skipped,
+    X1,
+    X2,
+    X4,
+    X8,
+    X16,
+}
+
+impl Oversampling {
+    /// `0` skips the measurement.
+    pub fn from_factor(factor: u8) -> Option<Self> {
+        match factor {
+            0 => Some(Oversampling::Skipped),
+            1 => Some(Oversampling::X1),
+            2 => Some(Oversampling::X2),
+            4 => Some(Oversampling::X4),
+            8 => Some(Oversampling::X8),
+            16 => Some(Oversampling::X16),
+            _ => None,
+        }
+    }
+
+    pub fn factor(&self) -> u8 {
+        match self {
+            Oversampling::Skipped => 0,
+            Oversampling::X1 => 1,
+            Oversampling::X2 => 2,
+            Oversampling::X4 => 4,
+            Oversampling::X8 => 8,
+            Oversampling::X16 => 16,
+        }
+    }
+}
+
+/// Coefficient of the IIR filter smoothing temperature and pressure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterSize {
+    Size0,
+    Size1,
+    Size3,
+    Size7,
+    Size15,
+    Size31,
+    Size63,
+    Size127,
+}
+
+impl FilterSize {
+    pub fn from_size(size: u8) -> Option<Self> {
+        match size {
+            0 => Some(FilterSize::Size0),
+            1 => Some(FilterSize::Size1),
+            3 => Some(FilterSize::Size3),
+            7 => Some(FilterSize::Size7),
+            15 => Some(FilterSize::Size15),
+            31 => Some(FilterSize::Size31),
+            63 => Some(FilterSize::Size63),
+            127 => Some(FilterSize::Size127),
+            _ => None,
+        }
+    }
+
+    pub fn size(&self) -> u8 {
+        match self {
+            FilterSize::Size0 => 0,
+            FilterSize::Size1 => 1,
+            FilterSize::Size3 => 3,
+            FilterSize::Size7 => 7,
+            FilterSize::Size15 => 15,
+            FilterSize::Size31 => 31,
+            FilterSize::Size63 => 63,
+            FilterSize::Size127 => 127,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The payload isn't a JSON object with only [`SensorConfig`] fields.
+    Malformed,
+    /// No field was given.
+    Empty,
+    InvalidOversampling,
+    InvalidFilter,
+    HeaterTemperatureOutOfRange,
+    HeaterDurationOutOfRange,
+}
+
+impl ConfigError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigError::Malformed => "malformed payload",
+            ConfigError::Empty => "nothing to set",
+            ConfigError::InvalidOversampling => "oversampling must be 0, 1, 2, 4, 8 or 16",
+            ConfigError::InvalidFilter => "filter must be 0, 1, 3, 7, 15, 31, 63 or 127",
+            ConfigError::HeaterTemperatureOutOfRange => "heater temperature out of range",
+            ConfigError::HeaterDurationOutOfRange => "heater duration out of range",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SensorConfig {
+    pub humidity_oversampling: Oversampling,
+    pub pressure_oversampling: Oversampling,
+    pub temperature_oversampling: Oversampling,
+    pub filter: FilterSize,
+    /// Gas sensor hot plate target, °C
+    pub heater_temperature: u16,
+    pub heater_duration_ms: u16,
+}
+
+/// [`SensorConfig`] as it's published and set, with plain numbers.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigDocument {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    humidity_oversampling: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pressure_oversampling: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature_oversampling: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heater_temperature: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heater_duration_ms: Option<u16>,
+}
+
+impl SensorConfig {
+    /// Settings the firmware used before they became configurable.
+    pub const DEFAULT: Self = Self {
+        humidity_oversampling: Oversampling::X2,
+        pressure_oversampling: Oversampling::X4,
+        temperature_oversampling: Oversampling::X8,
+        filter: FilterSize::Size3,
+        heater_temperature: 320,
+        heater_duration_ms: 1500,
+    };
+
+    /// Validates settings given as plain numbers, like those of `cfg.toml`.
+    pub fn new(
+        humidity_oversampling: u8,
+        pressure_oversampling: u8,
+        temperature_oversampling: u8,
+        filter: u8,
+        heater_temperature: u16,
+        heater_duration_ms: u16,
+    ) -> Result<Self, ConfigError> {
+        let oversampling = |factor| Oversampling::from_factor(factor).ok_or(ConfigError::InvalidOversampling);
+
+        let config = Self {
+            humidity_oversampling: oversampling(humidity_oversampling)?,
+            pressure_oversampling: oversampling(pressure_oversampling)?,
+            temperature_oversampling: oversampling(temperature_oversampling)?,
+            filter: FilterSize::from_size(filter).ok_or(ConfigError::InvalidFilter)?,
+            heater_temperature,
+            heater_duration_ms,
+        };
+        config.validate_heater()?;
+        Ok(config)
+    }
+
+    fn validate_heater(&self) -> Result<(), ConfigError> {
+        if !HEATER_TEMPERATURE_RANGE.contains(&self.heater_temperature) {
+            return Err(ConfigError::HeaterTemperatureOutOfRange);
+        }
+        if !(1..=MAX_HEATER_DURATION_MS).contains(&self.heater_duration_ms) {
+            return Err(ConfigError::HeaterDurationOutOfRange);
+        }
+        Ok(())
+    }
+
+    /// This config with the changes of a JSON `payload` received on
+    /// [`CONFIG_SET_TOPIC`].
+    pub fn update(&self, payload: &[u8]) -> Result<Self, ConfigError> {
+        let (changes, _) = serde_json_core::from_slice::<ConfigDocument>(payload).map_err(|_| ConfigError::Malformed)?;
+
+        if changes.humidity_oversampling.is_none()
+            && changes.pressure_oversampling.is_none()
+            && changes.temperature_oversampling.is_none()
+            && changes.filter.is_none()
+            && changes.heater_temperature.is_none()
+            && changes.heater_duration_ms.is_none()
+        {
+            return Err(ConfigError::Empty);
+        }
+
+        let oversampling = |factor: Option<u8>, current| match factor {
+            Some(factor) => Oversampling::from_factor(factor).ok_or(ConfigError::InvalidOversampling),
+            None => Ok(current),
+        };
+        let updated = Self {
+            humidity_oversampling: oversampling(changes.humidity_oversampling, self.humidity_oversampling)?,
+            pressure_oversampling: oversampling(changes.pressure_oversampling, self.pressure_oversampling)?,
+            temperature_oversampling: oversampling(changes.temperature_oversampling, self.temperature_oversampling)?,
+            filter: match changes.filter {
+                Some(size) => FilterSize::from_size(size).ok_or(ConfigError::InvalidFilter)?,
+                None => self.filter,
+            },
+            heater_temperature: changes.heater_temperature.unwrap_or(self.heater_temperature),
+            heater_duration_ms: changes.heater_duration_ms.unwrap_or(self.heater_duration_ms),
+        };
+
+        updated.validate_heater()?;
+        Ok(updated)
+    }
+
+    /// Whether the gas readings of `other` are comparable to these, so the air
+    /// quality baseline still holds.
+    pub fn same_heater_profile(&self, other: &SensorConfig) -> bool {
+        self.heater_temperature == other.heater_temperature && self.heater_duration_ms == other.heater_duration_ms
+    }
+
+    /// The document published on [`CONFIG_TOPIC`].
+    pub fn to_json(&self) -> String<192> {
+        let document = ConfigDocument {
+            humidity_oversampling: Some(self.humidity_oversampling.factor()),
+            pressure_oversampling: Some(self.pressure_oversampling.factor()),
+            temperature_oversampling: Some(self.temperature_oversampling.factor()),
+            filter: Some(self.filter.size()),
+            heater_temperature: Some(self.heater_temperature),
+            heater_duration_ms: Some(self.heater_duration_ms),
+        };
+        serde_json_core::to_string(&document).unwrap_or_default()
+    }
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_matches_former_settings() {
+        assert_eq!(SensorConfig::new(2, 4, 8, 3, 320, 1500), Ok(SensorConfig::DEFAULT));
+    }
+
+    #[test]
+    fn new_validates() {
+        assert_eq!(SensorConfig::new(3, 4, 8, 3, 320, 1500), Err(ConfigError::InvalidOversampling));
+        assert_eq!(SensorConfig::new(2, 4, 8, 4, 320, 1500), Err(ConfigError::InvalidFilter));
+        assert_eq!(SensorConfig::new(2, 4, 8, 3, 450, 1500), Err(ConfigError::HeaterTemperatureOutOfRange));
+        assert_eq!(SensorConfig::new(2, 4, 8, 3, 320, 0), Err(ConfigError::HeaterDurationOutOfRange));
+        assert_eq!(SensorConfig::new(2, 4, 8, 3, 320, 5000), Err(ConfigError::HeaterDurationOutOfRange));
+        assert!(SensorConfig::new(0, 16, 1, 127, 200, 4032).is_ok());
+    }
+
+    #[test]
+    fn update_keeps_missing_fields() {
+        let updated = SensorConfig::DEFAULT.update(br#"{"heater_temperature": 300, "filter": 7}"#).unwrap();
+
+        assert_eq!(
+            updated,
+            SensorConfig { heater_temperature: 300, filter: FilterSize::Size7, ..SensorConfig::DEFAULT }
+        );
+    }
+
+    #[test]
+    fn update_rejects_invalid_changes() {
+        let config = SensorConfig::DEFAULT;
+
+        assert_eq!(config.update(b"{}"), Err(ConfigError::Empty));
+        assert_eq!(config.update(b"300"), Err(ConfigError::Malformed));
+        assert_eq!(config.update(br#"{"heater": 300}"#), Err(ConfigError::Malformed));
+        assert_eq!(config.update(br#"{"humidity_oversampling": 5}"#), Err(ConfigError::InvalidOversampling));
+        assert_eq!(config.update(br#"{"filter": 2}"#), Err(ConfigError::InvalidFilter));
+        assert_eq!(config.update(br#"{"heater_temperature": 150}"#), Err(ConfigError::HeaterTemperatureOutOfRange));
+        assert_eq!(config.update(br#"{"heater_duration_ms": 0}"#), Err(ConfigError::HeaterDurationOutOfRange));
+    }
+
+    #[test]
+    fn heater_profile_comparison() {
+        let config = SensorConfig::DEFAULT;
+
+        assert!(config.same_heater_profile(&config.update(br#"{"filter": 0}"#).unwrap()));
+        assert!(!config.same_heater_profile(&config.update(br#"{"heater_duration_ms": 150}"#).unwrap()));
+    }
+
+    #[test]
+    fn json_document() {
+        assert_eq!(
+            SensorConfig::DEFAULT.to_json(),
+            r#"{"humidity_oversampling":2,"pressure_oversampling":4,"temperature_oversampling":8,"filter":3,"heater_temperature":320,"heater_duration_ms":1500}"#
+        );
+    }
+
+    #[test]
+    fn published_document_can_be_set() {
+        let config = SensorConfig::new(1, 2, 4, 15, 250, 100).unwrap();
+
+        assert_eq!(SensorConfig::DEFAULT.update(config.to_json().as_bytes()), Ok(config));
+    }
+}
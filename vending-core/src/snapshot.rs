@@ -0,0 +1,301 @@
+//! Rendering screens in memory on the host.
+//!
+//! [`Snapshot`] is a [`Screen`] that keeps every pixel, so the widgets the
+//! firmware draws can be rendered on Linux, written out as PPM images and
+//! compared against golden images checked in under `snapshots/`. Only built
+//! with the `std` feature, or for the tests.
+//!
+//! A test fails if its golden image is missing or differs, leaving the
+//! rendering next to it as `<name>.actual.ppm`. After a deliberate change
+//! to the looks, `UPDATE_SNAPSHOTS=1 cargo test` rewrites the golden images.
+
+use std::{fs, io, path::Path, string::String, vec::Vec};
+
+use embedded_graphics::{
+    pixelcolor::{Rgb565, Rgb888},
+    prelude::*,
+    primitives::Rectangle,
+};
+
+use crate::redraw::RedrawStats;
+use crate::screen::Screen;
+
+/// Set to rewrite the golden images instead of comparing with them.
+pub const UPDATE_VAR: &str = "UPDATE_SNAPSHOTS";
+
+/// A screen in memory, black until something is drawn on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    size: Size,
+    pixels: Vec<Rgb565>,
+}
+
+impl Snapshot {
+    pub fn new(size: Size) -> Self {
+        Self { size, pixels: std::vec![Rgb565::BLACK; size.width as usize * size.height as usize] }
+    }
+
+    pub fn pixel(&self, point: Point) -> Option<Rgb565> {
+        self.index(point).map(|index| self.pixels[index])
+    }
+
+    /// Pixels that are different in `other`, all of them if the sizes differ.
+    pub fn differences(&self, other: &Snapshot) -> usize {
+        if self.size != other.size {
+            return self.pixels.len().max(other.pixels.len());
+        }
+        self.pixels.iter().zip(&other.pixels).filter(|(a, b)| a != b).count()
+    }
+
+    /// Binary PPM (P6) with 8 bits per channel.
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = std::format!("P6\n{} {}\n255\n", self.size.width, self.size.height).into_bytes();
+        for pixel in &self.pixels {
+            let rgb = Rgb888::from(*pixel);
+            ppm.extend_from_slice(&[rgb.r(), rgb.g(), rgb.b()]);
+        }
+        ppm
+    }
+
+    /// Reads what [`Self::to_ppm`] wrote, `None` for anything else.
+    pub fn from_ppm(ppm: &[u8]) -> Option<Self> {
+        // the three header fields are each followed by a single newline
+        let mut fields = ppm.splitn(4, |byte| *byte == b'\n');
+        let (magic, size, max, data) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+        if magic != b"P6" || max != b"255" {
+            return None;
+        }
+        let size = core::str::from_utf8(size).ok()?;
+        let (width, height) = size.split_once(' ')?;
+        let size = Size::new(width.parse().ok()?, height.parse().ok()?);
+        if data.len() != size.width as usize * size.height as usize * 3 {
+            return None;
+        }
+
+        let pixels = data.chunks_exact(3).map(|rgb| Rgb565::from(Rgb888::new(rgb[0], rgb[1], rgb[2]))).collect();
+        Some(Self { size, pixels })
+    }
+
+    /// Compares with the golden image at `path`, or replaces it if
+    /// [`UPDATE_VAR`] is set. On a mismatch the rendering is written next to
+    /// it with the extension `actual.ppm`.
+    pub fn check(&self, path: &Path) -> Result<(), String> {
+        if std::env::var_os(UPDATE_VAR).is_some() {
+            return write(path, &self.to_ppm()).map_err(|e| std::format!("writing {}: {}", path.display(), e));
+        }
+
+        let actual = path.with_extension("actual.ppm");
+        let problem = match fs::read(path) {
+            Ok(ppm) => match Self::from_ppm(&ppm) {
+                Some(golden) if golden == *self => {
+                    fs::remove_file(&actual).ok();
+                    return Ok(());
+                }
+                Some(golden) => std::format!("{} pixels differ from", self.differences(&golden)),
+                None => "can't read".into(),
+            },
+            Err(e) => std::format!("{} for", e),
+        };
+
+        write(&actual, &self.to_ppm()).ok();
+        Err(std::format!(
+            "{} {}; the rendering is in {}, rerun with {}=1 to accept it",
+            problem,
+            path.display(),
+            actual.display(),
+            UPDATE_VAR
+        ))
+    }
+
+    fn index(&self, point: Point) -> Option<usize> {
+        let inside = (0..self.size.width as i32).contains(&point.x) && (0..self.size.height as i32).contains(&point.y);
+        inside.then(|| point.y as usize * self.size.width as usize + point.x as usize)
+    }
+}
+
+fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, contents)
+}
+
+impl Dimensions for Snapshot {
+    fn bounding_box(&self) -> Rectangle {
+        Rectangle::new(Point::zero(), self.size)
+    }
+}
+
+impl DrawTarget for Snapshot {
+    type Color = Rgb565;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let Some(index) = self.index(point) {
+                self.pixels[index] = color;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Screen for Snapshot {
+    /// Everything is shown as soon as it's drawn, so nothing is sent.
+    fn flush(&mut self) -> Result<RedrawStats, Self::Error> {
+        Ok(RedrawStats::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{cell::RefCell, path::PathBuf};
+
+    use embedded_graphics::primitives::PrimitiveStyle;
+
+    use crate::alarm::{Level, Metric};
+    use crate::app::{App, Update, PRODUCTS};
+    use crate::catalog::{Catalog, Item};
+    use crate::derived::DerivedMetrics;
+    use crate::history::{Span, Trend};
+    use crate::iaq::Iaq;
+    use crate::pages::Router;
+    use crate::sensor::{SensorFault, SensorReading};
+    use crate::ui::{
+        AirQualityPanel, AlarmBanner, DerivedMetricsPage, InventoryHeader, InventoryRow, ListPage, SensorOfflinePanel, TrendChart,
+    };
+    use crate::vending::VendingMachine;
+
+    fn screen() -> Snapshot {
+        let mut screen = Snapshot::new(Size::new(320, 240));
+        screen.clear(Rgb565::WHITE).unwrap();
+        screen
+    }
+
+    fn app() -> RefCell<App> {
+        let mut app = App::new();
+        app.machine = VendingMachine::with_catalog(Catalog::from_items(&PRODUCTS).unwrap());
+        RefCell::new(app)
+    }
+
+    fn assert_snapshot(name: &str, screen: &Snapshot) {
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "snapshots", &std::format!("{}.ppm", name)].iter().collect();
+        if let Err(e) = screen.check(&path) {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn ppm_round_trips() {
+        let mut screen = screen();
+        Pixel(Point::new(3, 4), Rgb565::new(1, 2, 3)).draw(&mut screen).unwrap();
+
+        let read = Snapshot::from_ppm(&screen.to_ppm()).unwrap();
+
+        assert_eq!(read, screen);
+        assert_eq!(read.pixel(Point::new(3, 4)), Some(Rgb565::new(1, 2, 3)));
+        assert_eq!(Snapshot::from_ppm(b"P3\n1 1\n255\n0 0 0"), None);
+        assert_eq!(Snapshot::from_ppm(b"P6\n2 1\n255\n\0\0\0"), None);
+    }
+
+    #[test]
+    fn counts_differences() {
+        let mut other = screen();
+        Rectangle::new(Point::new(10, 10), Size::new(4, 5))
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::RED))
+            .draw(&mut other)
+            .unwrap();
+
+        assert_eq!(screen().differences(&other), 20);
+        assert_eq!(screen().differences(&Snapshot::new(Size::new(10, 10))), 320 * 240);
+    }
+
+    #[test]
+    fn inventory_rows() {
+        let items = [Item::new("Hotdog", 2.50, 10), Item::new("Sandwich", 3.50, 0), Item::new("Energy Drink", 2.00, 11)];
+
+        let mut screen = screen();
+        InventoryHeader { page: 0, pages: 2 }.draw(&mut screen).unwrap();
+        for (id, item) in items.iter().enumerate() {
+            InventoryRow { id, item }.draw(&mut screen).unwrap();
+        }
+
+        assert_snapshot("inventory_rows", &screen);
+    }
+
+    #[test]
+    fn sensors_air_quality() {
+        let mut screen = screen();
+        AirQualityPanel { gas_resistance: 182_400.0, iaq: Some(Iaq(64)) }.draw(&mut screen).unwrap();
+
+        assert_snapshot("sensors_air_quality", &screen);
+    }
+
+    #[test]
+    fn sensors_offline() {
+        let mut screen = screen();
+        SensorOfflinePanel { fault: SensorFault::Nack }.draw(&mut screen).unwrap();
+
+        assert_snapshot("sensors_offline", &screen);
+    }
+
+    #[test]
+    fn alarm_banner_over_status() {
+        let mut screen = screen();
+        let rows = [("Wi-Fi", "connected"), ("MQTT", "connected"), ("Queued events", "0"), ("Uptime", "2 h 14 min")];
+        ListPage { title: "Status", rows: &rows }.draw(&mut screen).unwrap();
+        AlarmBanner { metric: Metric::Temperature, level: Level::High, others: 1 }.draw(&mut screen).unwrap();
+
+        assert_snapshot("alarm_banner_over_status", &screen);
+    }
+
+    #[test]
+    fn derived_metrics() {
+        let mut screen = screen();
+        let metrics = DerivedMetrics { dew_point: 9.4, heat_index: 22.1, absolute_humidity: 9.7, altitude: 212.0 };
+        DerivedMetricsPage { metrics }.draw(&mut screen).unwrap();
+
+        assert_snapshot("derived_metrics", &screen);
+    }
+
+    #[test]
+    fn trend_chart() {
+        let mut trend = Trend::new(Span::Hour);
+        // nothing recorded in minute 7, which leaves a gap
+        let values = [(0, 21.0), (1, 21.4), (2, 22.0), (3, 22.9), (4, 23.5), (5, 23.1), (6, 22.4), (8, 21.8), (9, 21.2)];
+        for (minute, value) in values {
+            trend.record(value, minute * Span::Hour.bucket_ms());
+        }
+
+        let mut screen = screen();
+        TrendChart { metric: Metric::Temperature, span: Span::Hour, trend: &trend }.draw(&mut screen).unwrap();
+
+        assert_snapshot("trend_chart", &screen);
+    }
+
+    #[test]
+    fn inventory_page() {
+        let app = app();
+        let mut router = Router::new(screen());
+        router.start(&app, 0).unwrap();
+
+        assert_snapshot("inventory_page", router.screen());
+    }
+
+    #[test]
+    fn sensors_page() {
+        let app = app();
+        let mut router = Router::new(screen());
+        router.start(&app, 0).unwrap();
+        router.button(&app, 0).unwrap();
+        let reading = SensorReading { temperature: 23.4, humidity: 41.0, pressure: 1012.6, gas_resistance: 182_400.0, iaq: Some(Iaq(64)) };
+        router.update(&app, Update::Reading(reading), 0).unwrap();
+
+        assert_snapshot("sensors_page", router.screen());
+    }
+}
@@ -0,0 +1,335 @@
+//! Telemetry published once per measurement cycle.
+//!
+//! In [`TelemetryFormat::PerTopic`] mode every value goes to its own topic as a
+//! bare number, which is what the machine always did. The other formats put
+//! everything into a single [`Telemetry`] document on [`TELEMETRY_TOPIC`]:
+//!
+//! ```json
+//! {"seq":42,"uptime_ms":2520000,
+//!  "temperature":{"value":21.5,"unit":"°C"},"humidity":{"value":40.2,"unit":"%"},
+//!  "pressure":{"value":1013.2,"unit":"hPa"},"gas_resistance":{"value":51234.0,"unit":"Ohm"},
+//!  "dew_point":{"value":7.6,"unit":"°C"},"heat_index":{"value":20.7,"unit":"°C"},
+//!  "absolute_humidity":{"value":7.5,"unit":"g/m³"},"altitude":{"value":0.5,"unit":"m"},
+//!  "raw":{"temperature":21.6,"humidity":40.1,"pressure":1013.3,"gas_resistance":50876.0},
+//!  "iaq":42,"inventory":{"Hotdog":10,"Sandwich":9,"Energy Drink":11}}
+//! ```
+
+use serde::ser::{Serialize, SerializeMap, SerializeStruct, Serializer};
+
+use crate::catalog::Catalog;
+use crate::derived::DerivedMetrics;
+use crate::sensor::SensorReading;
+
+pub const TELEMETRY_TOPIC: &str = "espbox/telemetry";
+/// Buffer size that fits a document for a full catalog of up to 8 items.
+pub const MAX_DOCUMENT_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryFormat {
+    /// One bare value per `espbox/sensor/...` and `espbox/inventory/...` topic.
+    PerTopic,
+    Json,
+    Cbor,
+}
+
+impl TelemetryFormat {
+    /// Parses the `telemetry_format` setting of `cfg.toml`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "per-topic" => Some(TelemetryFormat::PerTopic),
+            "json" => Some(TelemetryFormat::Json),
+            "cbor" => Some(TelemetryFormat::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The name in `cfg.toml`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TelemetryFormat::PerTopic => "per-topic",
+            TelemetryFormat::Json => "json",
+            TelemetryFormat::Cbor => "cbor",
+        }
+    }
+}
+
+/// The document didn't fit into the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DocumentTooLarge;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// [`TelemetryFormat::PerTopic`] publishes bare values, not a document.
+    NoDocument,
+    TooLarge,
+}
+
+impl From<DocumentTooLarge> for EncodeError {
+    fn from(_: DocumentTooLarge) -> Self {
+        EncodeError::TooLarge
+    }
+}
+
+/// Everything published in one cycle.
+#[derive(Debug, Clone, Copy)]
+pub struct Telemetry<'a, const N: usize> {
+    /// Counts documents since boot, so gaps show lost messages.
+    pub sequence: u32,
+    pub uptime_ms: u64,
+    /// The [filtered](crate::filter) reading.
+    pub reading: SensorReading,
+    /// The reading as it came from the sensor.
+    pub raw: SensorReading,
+    pub derived: DerivedMetrics,
+    pub catalog: &'a Catalog<N>,
+}
+
+impl<const N: usize> Telemetry<'_, N> {
+    /// Serializes into `buf`, returning the length of the document.
+    pub fn encode(&self, format: TelemetryFormat, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        match format {
+            TelemetryFormat::Json => Ok(self.to_json(buf)?),
+            TelemetryFormat::Cbor => Ok(self.to_cbor(buf)?),
+            TelemetryFormat::PerTopic => Err(EncodeError::NoDocument),
+        }
+    }
+
+    pub fn to_json(&self, buf: &mut [u8]) -> Result<usize, DocumentTooLarge> {
+        serde_json_core::to_slice(self, buf).map_err(|_| DocumentTooLarge)
+    }
+
+    pub fn to_cbor(&self, buf: &mut [u8]) -> Result<usize, DocumentTooLarge> {
+        let mut serializer = minicbor_serde::Serializer::new(minicbor::encode::write::Cursor::new(buf));
+        self.serialize(&mut serializer).map_err(|_| DocumentTooLarge)?;
+        Ok(serializer.into_encoder().into_writer().position())
+    }
+}
+
+impl<const N: usize> Serialize for Telemetry<'_, N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = if self.reading.iaq.is_some() { 13 } else { 12 };
+        let mut document = serializer.serialize_struct("Telemetry", fields)?;
+        document.serialize_field("seq", &self.sequence)?;
+        document.serialize_field("uptime_ms", &self.uptime_ms)?;
+        document.serialize_field("temperature", &Measurement(self.reading.temperature, "°C"))?;
+        document.serialize_field("humidity", &Measurement(self.reading.humidity, "%"))?;
+        document.serialize_field("pressure", &Measurement(self.reading.pressure, "hPa"))?;
+        document.serialize_field("gas_resistance", &Measurement(self.reading.gas_resistance, "Ohm"))?;
+        document.serialize_field("dew_point", &Measurement(self.derived.dew_point, "°C"))?;
+        document.serialize_field("heat_index", &Measurement(self.derived.heat_index, "°C"))?;
+        document.serialize_field("absolute_humidity", &Measurement(self.derived.absolute_humidity, "g/m³"))?;
+        document.serialize_field("altitude", &Measurement(self.derived.altitude, "m"))?;
+        document.serialize_field("raw", &RawValues(&self.raw))?;
+        // left out during the gas sensor's burn-in
+        if let Some(iaq) = self.reading.iaq {
+            document.serialize_field("iaq", &iaq.0)?;
+        }
+        document.serialize_field("inventory", &Inventory(self.catalog))?;
+        document.end()
+    }
+}
+
+struct Measurement(f32, &'static str);
+
+impl Serialize for Measurement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut measurement = serializer.serialize_struct("Measurement", 2)?;
+        measurement.serialize_field("value", &self.0)?;
+        measurement.serialize_field("unit", self.1)?;
+        measurement.end()
+    }
+}
+
+/// Bare values of the unfiltered reading, in the units of the filtered ones.
+struct RawValues<'a>(&'a SensorReading);
+
+impl Serialize for RawValues<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut raw = serializer.serialize_struct("RawValues", 4)?;
+        raw.serialize_field("temperature", &self.0.temperature)?;
+        raw.serialize_field("humidity", &self.0.humidity)?;
+        raw.serialize_field("pressure", &self.0.pressure)?;
+        raw.serialize_field("gas_resistance", &self.0.gas_resistance)?;
+        raw.end()
+    }
+}
+
+/// Item names mapped to their stock.
+struct Inventory<'a, const N: usize>(&'a Catalog<N>);
+
+impl<const N: usize> Serialize for Inventory<'_, N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut inventory = serializer.serialize_map(Some(self.0.len()))?;
+        for item in self.0.items() {
+            inventory.serialize_entry(item.name, &item.amount)?;
+        }
+        inventory.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::Item;
+    use crate::iaq::Iaq;
+
+    fn catalog() -> Catalog<8> {
+        Catalog::from_items(&[Item::new("Hotdog", 2.50, 10), Item::new("Energy Drink", 2.00, 11)]).unwrap()
+    }
+
+    fn reading() -> SensorReading {
+        SensorReading {
+            temperature: 21.5,
+            humidity: 40.25,
+            pressure: 1013.5,
+            gas_resistance: 51234.0,
+            iaq: None,
+        }
+    }
+
+    fn derived() -> DerivedMetrics {
+        DerivedMetrics { dew_point: 7.5, heat_index: 20.75, absolute_humidity: 7.5, altitude: -2.0 }
+    }
+
+    #[test]
+    fn json_document() {
+        let catalog = catalog();
+        let telemetry = Telemetry { sequence: 42, uptime_ms: 2_520_000, reading: reading(), raw: reading(), derived: derived(), catalog: &catalog };
+        let mut buf = [0; MAX_DOCUMENT_LEN];
+
+        let len = telemetry.encode(TelemetryFormat::Json, &mut buf).unwrap();
+
+        assert_eq!(
+            core::str::from_utf8(&buf[..len]).unwrap(),
+            concat!(
+                r#"{"seq":42,"uptime_ms":2520000,"#,
+                r#""temperature":{"value":21.5,"unit":"°C"},"humidity":{"value":40.25,"unit":"%"},"#,
+                r#""pressure":{"value":1013.5,"unit":"hPa"},"gas_resistance":{"value":51234.0,"unit":"Ohm"},"#,
+                r#""dew_point":{"value":7.5,"unit":"°C"},"heat_index":{"value":20.75,"unit":"°C"},"#,
+                r#""absolute_humidity":{"value":7.5,"unit":"g/m³"},"altitude":{"value":-2.0,"unit":"m"},"#,
+                r#""raw":{"temperature":21.5,"humidity":40.25,"pressure":1013.5,"gas_resistance":51234.0},"#,
+                r#""inventory":{"Hotdog":10,"Energy Drink":11}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn cbor_document() {
+        let catalog = catalog();
+        let telemetry = Telemetry { sequence: 1, uptime_ms: 1000, reading: reading(), raw: reading(), derived: derived(), catalog: &catalog };
+        let mut buf = [0; MAX_DOCUMENT_LEN];
+
+        let len = telemetry.encode(TelemetryFormat::Cbor, &mut buf).unwrap();
+
+        let mut decoder = minicbor::Decoder::new(&buf[..len]);
+        assert_eq!(decoder.map().unwrap(), Some(12));
+        assert_eq!(decoder.str().unwrap(), "seq");
+        assert_eq!(decoder.u32().unwrap(), 1);
+        assert_eq!(decoder.str().unwrap(), "uptime_ms");
+        assert_eq!(decoder.u64().unwrap(), 1000);
+        assert_eq!(decoder.str().unwrap(), "temperature");
+        assert_eq!(decoder.map().unwrap(), Some(2));
+        assert_eq!(decoder.str().unwrap(), "value");
+        assert_eq!(decoder.f32().unwrap(), 21.5);
+        assert_eq!(decoder.str().unwrap(), "unit");
+        assert_eq!(decoder.str().unwrap(), "°C");
+        for _ in 0..8 {
+            decoder.skip().unwrap();
+            decoder.skip().unwrap();
+        }
+        assert_eq!(decoder.str().unwrap(), "inventory");
+        assert_eq!(decoder.map().unwrap(), Some(2));
+        assert_eq!((decoder.str().unwrap(), decoder.u32().unwrap()), ("Hotdog", 10));
+        assert_eq!((decoder.str().unwrap(), decoder.u32().unwrap()), ("Energy Drink", 11));
+        assert_eq!(decoder.position(), len);
+    }
+
+    #[test]
+    fn cbor_is_smaller_than_json() {
+        let catalog = catalog();
+        let telemetry = Telemetry { sequence: 1, uptime_ms: 1000, reading: reading(), raw: reading(), derived: derived(), catalog: &catalog };
+        let mut buf = [0; MAX_DOCUMENT_LEN];
+
+        let cbor = telemetry.to_cbor(&mut buf).unwrap();
+        let json = telemetry.to_json(&mut buf).unwrap();
+
+        assert!(cbor < json);
+    }
+
+    #[test]
+    fn full_catalog_fits_buffer() {
+        let mut catalog = Catalog::<8>::new();
+        for _ in 0..8 {
+            catalog.add(Item::new("Sparkling Elderflower", 9.99, 999)).unwrap();
+        }
+        let reading = SensorReading {
+            temperature: -12.345678,
+            humidity: 99.99999,
+            pressure: 1099.9999,
+            gas_resistance: 1_234_567.9,
+            iaq: Some(Iaq(500)),
+        };
+        let telemetry = Telemetry {
+            sequence: u32::MAX,
+            uptime_ms: u64::MAX,
+            reading,
+            raw: reading,
+            derived: DerivedMetrics {
+                dew_point: -12.345678,
+                heat_index: -12.345678,
+                absolute_humidity: 12.345678,
+                altitude: -1234.5678,
+            },
+            catalog: &catalog,
+        };
+        let mut buf = [0; MAX_DOCUMENT_LEN];
+
+        assert!(telemetry.to_json(&mut buf).is_ok());
+        assert!(telemetry.to_cbor(&mut buf).is_ok());
+    }
+
+    #[test]
+    fn iaq_once_calibrated() {
+        let catalog = catalog();
+        let reading = SensorReading { iaq: Some(Iaq(42)), ..reading() };
+        let telemetry = Telemetry { sequence: 1, uptime_ms: 1000, reading, raw: reading, derived: derived(), catalog: &catalog };
+        let mut buf = [0; MAX_DOCUMENT_LEN];
+
+        let len = telemetry.to_json(&mut buf).unwrap();
+        assert!(core::str::from_utf8(&buf[..len]).unwrap().contains(r#""gas_resistance":51234.0},"iaq":42,"inventory""#));
+
+        let len = telemetry.to_cbor(&mut buf).unwrap();
+        assert_eq!(minicbor::Decoder::new(&buf[..len]).map().unwrap(), Some(13));
+    }
+
+    #[test]
+    fn small_buffer_is_reported() {
+        let catalog = catalog();
+        let telemetry = Telemetry { sequence: 1, uptime_ms: 1000, reading: reading(), raw: reading(), derived: derived(), catalog: &catalog };
+        let mut buf = [0; 32];
+
+        assert_eq!(telemetry.to_json(&mut buf), Err(DocumentTooLarge));
+        assert_eq!(telemetry.to_cbor(&mut buf), Err(DocumentTooLarge));
+        assert_eq!(telemetry.encode(TelemetryFormat::Json, &mut buf), Err(EncodeError::TooLarge));
+    }
+
+    #[test]
+    fn per_topic_has_no_document() {
+        let catalog = catalog();
+        let telemetry = Telemetry { sequence: 1, uptime_ms: 1000, reading: reading(), raw: reading(), derived: derived(), catalog: &catalog };
+        let mut buf = [0; MAX_DOCUMENT_LEN];
+
+        assert_eq!(telemetry.encode(TelemetryFormat::PerTopic, &mut buf), Err(EncodeError::NoDocument));
+    }
+
+    #[test]
+    fn format_names() {
+        assert_eq!(TelemetryFormat::from_name("per-topic"), Some(TelemetryFormat::PerTopic));
+        assert_eq!(TelemetryFormat::from_name("json"), Some(TelemetryFormat::Json));
+        assert_eq!(TelemetryFormat::from_name("cbor"), Some(TelemetryFormat::Cbor));
+        assert_eq!(TelemetryFormat::from_name("xml"), None);
+        for format in [TelemetryFormat::PerTopic, TelemetryFormat::Json, TelemetryFormat::Cbor] {
+            assert_eq!(TelemetryFormat::from_name(format.name()), Some(format));
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Mapping touches to the UI elements under them.
+//!
+//! The TT21100 reports touches in the panel's own coordinates, which only line
+//! up with what's drawn for one display orientation. [`TouchTransform`] moves
+//! them into display coordinates and [`TouchZones`] finds the element that was
+//! drawn there.
+
+use embedded_graphics::{
+    geometry::{Point, Size},
+    primitives::Rectangle,
+};
+use heapless::Vec;
+use mipidsi::Orientation;
+
+/// Converts raw touch panel coordinates into display coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TouchTransform {
+    orientation: Orientation,
+    panel: Size,
+}
+
+impl TouchTransform {
+    /// `panel` is the size of the touch panel, which matches the display size
+    /// in the portrait orientations.
+    pub const fn new(orientation: Orientation, panel: Size) -> Self {
+        Self { orientation, panel }
+    }
+
+    /// Size of the display for the configured orientation.
+    pub fn display_size(&self) -> Size {
+        match self.orientation {
+            Orientation::Portrait(_) | Orientation::PortraitInverted(_) => self.panel,
+            Orientation::Landscape(_) | Orientation::LandscapeInverted(_) => {
+                Size::new(self.panel.height, self.panel.width)
+            }
+        }
+    }
+
+    /// Maps a touch at `raw` to the display pixel under it, or `None` if the
+    /// touch is outside of the panel.
+    pub fn to_display(&self, raw: Point) -> Option<Point> {
+        let w = self.panel.width as i32;
+        let h = self.panel.height as i32;
+        if !(0..w).contains(&raw.x) || !(0..h).contains(&raw.y) {
+            return None;
+        }
+
+        // The panel's y axis runs opposite to the display's in Portrait.
+        let (x, y) = (raw.x, h - 1 - raw.y);
+
+        let (point, mirrored) = match self.orientation {
+            Orientation::Portrait(mirrored) => (Point::new(x, y), mirrored),
+            Orientation::Landscape(mirrored) => (Point::new(h - 1 - y, x), mirrored),
+            Orientation::PortraitInverted(mirrored) => (Point::new(w - 1 - x, h - 1 - y), mirrored),
+            Orientation::LandscapeInverted(mirrored) => (Point::new(y, w - 1 - x), mirrored),
+        };
+
+        if mirrored {
+            let width = self.display_size().width as i32;
+            Some(Point::new(width - 1 - point.x, point.y))
+        } else {
+            Some(point)
+        }
+    }
+}
+
+/// Returned when registering more zones than fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZonesFull;
+
+/// Up to `N` touchable areas of the current screen, each tagged with what
+/// should happen when it's touched.
+#[derive(Debug, Clone)]
+pub struct TouchZones<T, const N: usize> {
+    zones: Vec<(Rectangle, T), N>,
+}
+
+impl<T, const N: usize> TouchZones<T, N> {
+    pub const fn new() -> Self {
+        Self { zones: Vec::new() }
+    }
+
+    /// Makes `area` (in display coordinates) dispatch to `target`. Zones
+    /// registered later take precedence where they overlap earlier ones.
+    pub fn register(&mut self, area: Rectangle, target: T) -> Result<(), ZonesFull> {
+        self.zones.push((area, target)).map_err(|_| ZonesFull)
+    }
+
+    /// Forgets all zones, e.g. before drawing another screen.
+    pub fn clear(&mut self) {
+        self.zones.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// The target of the topmost zone containing `point`.
+    pub fn hit(&self, point: Point) -> Option<&T> {
+        self.zones
+            .iter()
+            .rev()
+            .find(|(area, _)| area.contains(point))
+            .map(|(_, target)| target)
+    }
+}
+
+impl<T, const N: usize> Default for TouchZones<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PANEL: Size = Size::new(320, 240);
+
+    fn corners(orientation: Orientation) -> [Point; 4] {
+        let transform = TouchTransform::new(orientation, PANEL);
+        [Point::new(0, 0), Point::new(319, 0), Point::new(0, 239), Point::new(319, 239)]
+            .map(|raw| transform.to_display(raw).unwrap())
+    }
+
+    #[test]
+    fn portrait() {
+        assert_eq!(
+            corners(Orientation::Portrait(false)),
+            [Point::new(0, 239), Point::new(319, 239), Point::new(0, 0), Point::new(319, 0)]
+        );
+    }
+
+    #[test]
+    fn portrait_mirrored() {
+        assert_eq!(
+            corners(Orientation::Portrait(true)),
+            [Point::new(319, 239), Point::new(0, 239), Point::new(319, 0), Point::new(0, 0)]
+        );
+    }
+
+    #[test]
+    fn landscape() {
+        assert_eq!(
+            corners(Orientation::Landscape(false)),
+            [Point::new(0, 0), Point::new(0, 319), Point::new(239, 0), Point::new(239, 319)]
+        );
+    }
+
+    #[test]
+    fn landscape_mirrored() {
+        assert_eq!(
+            corners(Orientation::Landscape(true)),
+            [Point::new(239, 0), Point::new(239, 319), Point::new(0, 0), Point::new(0, 319)]
+        );
+    }
+
+    #[test]
+    fn portrait_inverted() {
+        assert_eq!(
+            corners(Orientation::PortraitInverted(false)),
+            [Point::new(319, 0), Point::new(0, 0), Point::new(319, 239), Point::new(0, 239)]
+        );
+    }
+
+    #[test]
+    fn portrait_inverted_mirrored() {
+        assert_eq!(
+            corners(Orientation::PortraitInverted(true)),
+            [Point::new(0, 0), Point::new(319, 0), Point::new(0, 239), Point::new(319, 239)]
+        );
+    }
+
+    #[test]
+    fn landscape_inverted() {
+        assert_eq!(
+            corners(Orientation::LandscapeInverted(false)),
+            [Point::new(239, 319), Point::new(239, 0), Point::new(0, 319), Point::new(0, 0)]
+        );
+    }
+
+    #[test]
+    fn landscape_inverted_mirrored() {
+        assert_eq!(
+            corners(Orientation::LandscapeInverted(true)),
+            [Point::new(0, 319), Point::new(0, 0), Point::new(239, 319), Point::new(239, 0)]
+        );
+    }
+
+    #[test]
+    fn portrait_inverted_matches_box_calibration() {
+        // The ESP32S3-BOX runs PortraitInverted(false), where only x is mirrored.
+        let transform = TouchTransform::new(Orientation::PortraitInverted(false), PANEL);
+
+        assert_eq!(transform.to_display(Point::new(50, 100)), Some(Point::new(269, 100)));
+    }
+
+    #[test]
+    fn every_touch_lands_on_the_display() {
+        for orientation in [
+            Orientation::Portrait(false),
+            Orientation::Landscape(true),
+            Orientation::PortraitInverted(false),
+            Orientation::LandscapeInverted(true),
+        ] {
+            let transform = TouchTransform::new(orientation, PANEL);
+            let display = Rectangle::new(Point::zero(), transform.display_size());
+
+            for x in (0..320).step_by(7) {
+                for y in (0..240).step_by(7) {
+                    assert!(display.contains(transform.to_display(Point::new(x, y)).unwrap()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn touch_outside_panel_is_dropped() {
+        let transform = TouchTransform::new(Orientation::PortraitInverted(false), PANEL);
+
+        assert_eq!(transform.to_display(Point::new(320, 10)), None);
+        assert_eq!(transform.to_display(Point::new(10, 240)), None);
+        assert_eq!(transform.to_display(Point::new(-1, 10)), None);
+    }
+
+    #[test]
+    fn landscape_swaps_display_size() {
+        assert_eq!(TouchTransform::new(Orientation::Landscape(false), PANEL).display_size(), Size::new(240, 320));
+        assert_eq!(TouchTransform::new(Orientation::Portrait(true), PANEL).display_size(), PANEL);
+    }
+
+    #[test]
+    fn hit_finds_zone_under_point() {
+        let mut zones = TouchZones::<u8, 4>::new();
+        zones.register(Rectangle::new(Point::new(0, 0), Size::new(10, 10)), 1).unwrap();
+        zones.register(Rectangle::new(Point::new(20, 0), Size::new(10, 10)), 2).unwrap();
+
+        assert_eq!(zones.hit(Point::new(5, 5)), Some(&1));
+        assert_eq!(zones.hit(Point::new(29, 9)), Some(&2));
+        assert_eq!(zones.hit(Point::new(15, 5)), None);
+        assert_eq!(zones.hit(Point::new(30, 5)), None);
+    }
+
+    #[test]
+    fn later_zone_wins_overlap() {
+        let mut zones = TouchZones::<u8, 4>::new();
+        zones.register(Rectangle::new(Point::new(0, 0), Size::new(100, 100)), 1).unwrap();
+        zones.register(Rectangle::new(Point::new(40, 40), Size::new(20, 20)), 2).unwrap();
+
+        assert_eq!(zones.hit(Point::new(50, 50)), Some(&2));
+        assert_eq!(zones.hit(Point::new(10, 10)), Some(&1));
+    }
+
+    #[test]
+    fn registry_is_bounded_and_clearable() {
+        let mut zones = TouchZones::<u8, 1>::new();
+        let area = Rectangle::new(Point::zero(), Size::new(10, 10));
+
+        zones.register(area, 1).unwrap();
+        assert_eq!(zones.register(area, 2), Err(ZonesFull));
+
+        zones.clear();
+        assert!(zones.is_empty());
+        assert_eq!(zones.hit(Point::new(5, 5)), None);
+    }
+}
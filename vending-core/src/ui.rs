@@ -0,0 +1,811 @@
+//! Widgets the pages of the touch screen are drawn with.
+//!
+//! They draw onto any [`DrawTarget`], so besides the ILI9342C they can be
+//! rendered into a buffer on the host.
+
+use core::fmt::Write;
+
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10, FONT_9X15_BOLD},
+        iso_8859_1::FONT_10X20 as FONT_10X20_LATIN1,
+        MonoTextStyle,
+    },
+    pixelcolor::Rgb565,
+    prelude::*,
+    primitives::{Circle, CornerRadii, Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, RoundedRectangle, StrokeAlignment},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use heapless::String;
+
+use crate::alarm::{Level, Metric};
+use crate::catalog::{Item, ItemId};
+use crate::derived::DerivedMetrics;
+use crate::history::{Span, Trend, POINTS};
+use crate::iaq::{Iaq, IaqCategory};
+use crate::layout;
+use crate::sensor::SensorFault;
+
+/// Title of an inventory page in the strip above the first row, which the
+/// [`AlarmBanner`] covers while an alarm is raised.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InventoryHeader {
+    pub page: usize,
+    pub pages: usize,
+}
+
+impl InventoryHeader {
+    pub const AREA: Rectangle = AlarmBanner::AREA;
+
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        Self::AREA.into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_DARK_SLATE_GRAY)).draw(target)?;
+
+        let middle = Self::AREA.center().y;
+        let left = TextStyleBuilder::new().alignment(Alignment::Left).baseline(Baseline::Middle).build();
+        let right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Middle).build();
+        Text::with_text_style("Inventory", Point::new(10, middle), MonoTextStyle::new(&FONT_9X15_BOLD, Rgb565::WHITE), left)
+            .draw(target)?;
+
+        if self.pages > 1 {
+            let mut page: String<16> = String::new();
+            write!(page, "{}/{}", self.page + 1, self.pages).ok();
+            Text::with_text_style(&page, Point::new(310, middle), MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE), right)
+                .draw(target)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// One product on the inventory screen: a frame with its name, stock and
+/// price, and the buy button at [`layout::buy_button`], greyed out when the
+/// product is sold out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InventoryRow<'a> {
+    pub id: ItemId,
+    pub item: &'a Item,
+}
+
+impl InventoryRow<'_> {
+    const FRAME_X: i32 = 10;
+    const CORNER_RADIUS: u32 = 6;
+    /// Room for the widest price, `999.99`.
+    const PRICE_WIDTH: u32 = 68;
+
+    /// Everything the row draws on, the gap to the next row excluded.
+    pub fn area(id: ItemId) -> Rectangle {
+        Rectangle::new(Point::new(0, layout::row_y(id)), Size::new(320, layout::ROW_HEIGHT))
+    }
+
+    /// Draws the row over whatever was in its area before.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let area = Self::area(self.id);
+        area.into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE)).draw(target)?;
+
+        let frame = Rectangle::new(
+            Point::new(Self::FRAME_X, area.top_left.y),
+            Size::new((layout::BUTTON_X - 2 * Self::FRAME_X) as u32, layout::ROW_HEIGHT),
+        );
+        let frame_style = PrimitiveStyleBuilder::new()
+            .stroke_color(Rgb565::CSS_LIGHT_GRAY)
+            .stroke_width(2)
+            .stroke_alignment(StrokeAlignment::Inside)
+            .fill_color(Rgb565::CSS_WHITE_SMOKE)
+            .build();
+        RoundedRectangle::new(frame, CornerRadii::new(Size::new_equal(Self::CORNER_RADIUS)))
+            .into_styled(frame_style)
+            .draw(target)?;
+
+        let top = TextStyleBuilder::new().alignment(Alignment::Left).baseline(Baseline::Top).build();
+        let top_right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Top).build();
+        // long names are cut off before the price
+        let name_area = Rectangle::new(frame.top_left + Point::new(8, 4), Size::new(frame.size.width - 16 - Self::PRICE_WIDTH, 15));
+        Text::with_text_style(self.item.name, name_area.top_left, MonoTextStyle::new(&FONT_9X15_BOLD, Rgb565::BLACK), top)
+            .draw(&mut target.clipped(&name_area))?;
+
+        let mut stock: String<16> = String::new();
+        let stock_color = if self.item.in_stock() {
+            write!(stock, "{} left", self.item.amount).ok();
+            Rgb565::CSS_DIM_GRAY
+        } else {
+            stock.push_str("Sold out").ok();
+            Rgb565::RED
+        };
+        let stock_position = frame.top_left + Point::new(8, 24);
+        Text::with_text_style(&stock, stock_position, MonoTextStyle::new(&FONT_6X10, stock_color), top).draw(target)?;
+
+        let mut price: String<16> = String::new();
+        write!(price, "{:.2}", self.item.price).ok();
+        let price_position = Point::new(frame.top_left.x + frame.size.width as i32 - 8, frame.top_left.y + 10);
+        Text::with_text_style(&price, price_position, MonoTextStyle::new(&FONT_10X20, Rgb565::BLACK), top_right)
+            .draw(target)?;
+
+        let button = layout::buy_button(self.id);
+        let button_color = if self.item.in_stock() { Rgb565::CSS_FOREST_GREEN } else { Rgb565::CSS_GRAY };
+        RoundedRectangle::new(button, CornerRadii::new(Size::new_equal(Self::CORNER_RADIUS)))
+            .into_styled(PrimitiveStyle::with_fill(button_color))
+            .draw(target)?;
+        let centered = TextStyleBuilder::new().alignment(Alignment::Center).baseline(Baseline::Middle).build();
+        Text::with_text_style("Buy", button.center(), MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE), centered)
+            .draw(target)?;
+
+        Ok(())
+    }
+}
+
+/// One value of the latest reading as a bar between the lowest and highest
+/// value it usually takes, with the number below. The sensor screen shows
+/// the [`Self::METRICS`] side by side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorGauge {
+    pub metric: Metric,
+    pub value: f32,
+}
+
+impl SensorGauge {
+    /// Left to right.
+    pub const METRICS: [Metric; 3] = [Metric::Temperature, Metric::Humidity, Metric::Pressure];
+    const WIDTH: u32 = 90;
+    const SPACING: i32 = 100;
+    const TOP: i32 = 24;
+    const HEIGHT: u32 = 164;
+    const BAR_WIDTH: u32 = 18;
+    const BAR_TOP: i32 = 24;
+    const BAR_HEIGHT: u32 = 86;
+
+    /// Where the gauge of `metric` goes, in the column of the IAQ's neighbour
+    /// for the IAQ, which has no gauge.
+    pub fn area(metric: Metric) -> Rectangle {
+        let column = Self::METRICS.iter().position(|shown| *shown == metric).unwrap_or(Self::METRICS.len() - 1);
+        Rectangle::new(Point::new(15 + column as i32 * Self::SPACING, Self::TOP), Size::new(Self::WIDTH, Self::HEIGHT))
+    }
+
+    /// (lowest, highest, unit) of the bar.
+    fn scale(&self) -> (f32, f32, &'static str) {
+        match self.metric {
+            Metric::Temperature => (-10.0, 50.0, "°C"),
+            Metric::Humidity => (0.0, 100.0, "%"),
+            Metric::Pressure => (950.0, 1050.0, "hPa"),
+            Metric::Iaq => (0.0, 500.0, ""),
+        }
+    }
+
+    fn color(&self) -> Rgb565 {
+        match self.metric {
+            Metric::Temperature => Rgb565::CSS_ORANGE_RED,
+            Metric::Humidity => Rgb565::CSS_DODGER_BLUE,
+            Metric::Pressure => Rgb565::CSS_MEDIUM_PURPLE,
+            Metric::Iaq => Rgb565::CSS_GREEN,
+        }
+    }
+
+    /// Draws the gauge over whatever was in its area before.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let area = Self::area(self.metric);
+        let frame = PrimitiveStyleBuilder::new()
+            .fill_color(Rgb565::WHITE)
+            .stroke_color(Rgb565::CSS_DIM_GRAY)
+            .stroke_width(2)
+            .stroke_alignment(StrokeAlignment::Inside)
+            .build();
+        RoundedRectangle::new(area, CornerRadii::new(Size::new(8, 8))).into_styled(frame).draw(target)?;
+
+        let centered = TextStyleBuilder::new().alignment(Alignment::Center).baseline(Baseline::Middle).build();
+        let center = area.center().x;
+        Text::with_text_style(
+            self.metric.label(),
+            Point::new(center, area.top_left.y + 12),
+            MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_DIM_GRAY),
+            centered,
+        )
+        .draw(target)?;
+
+        let (lowest, highest, unit) = self.scale();
+        let bar = Rectangle::new(
+            Point::new(center - Self::BAR_WIDTH as i32 / 2, area.top_left.y + Self::BAR_TOP),
+            Size::new(Self::BAR_WIDTH, Self::BAR_HEIGHT),
+        );
+        bar.into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_LIGHT_GRAY)).draw(target)?;
+        // NaN fills nothing
+        let fraction = ((self.value - lowest) / (highest - lowest)).clamp(0.0, 1.0);
+        let filled = if fraction.is_nan() { 0 } else { libm::roundf(fraction * Self::BAR_HEIGHT as f32) as u32 };
+        Rectangle::new(bar.top_left + Point::new(0, (Self::BAR_HEIGHT - filled) as i32), Size::new(Self::BAR_WIDTH, filled))
+            .into_styled(PrimitiveStyle::with_fill(self.color()))
+            .draw(target)?;
+
+        let mut value: String<16> = String::new();
+        write!(value, "{:.1}", self.value).ok();
+        let below_bar = bar.top_left.y + Self::BAR_HEIGHT as i32;
+        Text::with_text_style(&value, Point::new(center, below_bar + 18), MonoTextStyle::new(&FONT_10X20, Rgb565::BLACK), centered)
+            .draw(target)?;
+        Text::with_text_style(
+            unit,
+            Point::new(center, below_bar + 40),
+            MonoTextStyle::new(&FONT_10X20_LATIN1, Rgb565::CSS_DIM_GRAY),
+            centered,
+        )
+        .draw(target)?;
+
+        Ok(())
+    }
+}
+
+/// Gas resistance and the air quality scored from it, shown in a strip below
+/// the gauges of the sensor screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AirQualityPanel {
+    /// Ω
+    pub gas_resistance: f32,
+    pub iaq: Option<Iaq>,
+}
+
+impl AirQualityPanel {
+    pub const AREA: Rectangle = Rectangle::new(Point::new(0, 196), Size::new(320, 44));
+    const BACKGROUND: Rgb565 = Rgb565::WHITE;
+
+    /// Draws the panel over whatever was in its area before.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        Self::AREA.into_styled(PrimitiveStyle::with_fill(Self::BACKGROUND)).draw(target)?;
+
+        let middle = Self::AREA.center().y;
+        let left = TextStyleBuilder::new().alignment(Alignment::Left).baseline(Baseline::Middle).build();
+        let right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Middle).build();
+
+        let mut gas: String<24> = String::new();
+        write!(gas, "Gas {:.1} kOhm", self.gas_resistance / 1000.0).ok();
+        Text::with_text_style(&gas, Point::new(10, middle), MonoTextStyle::new(&FONT_10X20, Rgb565::BLACK), left)
+            .draw(target)?;
+
+        let mut iaq: String<24> = String::new();
+        let color = match self.iaq {
+            Some(score) => {
+                write!(iaq, "IAQ {} {}", score.0, score.category().as_str()).ok();
+                category_color(score.category())
+            }
+            None => {
+                iaq.push_str("IAQ warming up").ok();
+                Rgb565::CSS_GRAY
+            }
+        };
+        Text::with_text_style(&iaq, Point::new(310, middle), MonoTextStyle::new(&FONT_10X20, color), right)
+            .draw(target)?;
+
+        Ok(())
+    }
+}
+
+/// Shown instead of the [`AirQualityPanel`] while the sensor delivers no
+/// readings, so the gauges are known to be stale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorOfflinePanel {
+    pub fault: SensorFault,
+}
+
+impl SensorOfflinePanel {
+    pub const AREA: Rectangle = AirQualityPanel::AREA;
+
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        Self::AREA.into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE)).draw(target)?;
+
+        let centered = TextStyleBuilder::new().alignment(Alignment::Center).baseline(Baseline::Middle).build();
+        let mut text: String<40> = String::new();
+        write!(text, "Sensor offline: {}", self.fault.as_str()).ok();
+        Text::with_text_style(&text, Self::AREA.center(), MonoTextStyle::new(&FONT_10X20, Rgb565::RED), centered)
+            .draw(target)?;
+
+        Ok(())
+    }
+}
+
+/// Strip across the top of every screen while an alarm is raised, naming
+/// the first one and counting the `others`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlarmBanner {
+    pub metric: Metric,
+    pub level: Level,
+    pub others: usize,
+}
+
+impl AlarmBanner {
+    /// Above the first inventory row.
+    pub const AREA: Rectangle = Rectangle::new(Point::zero(), Size::new(320, 17));
+
+    /// The banner for the alarms `active`, `None` if there are none.
+    pub fn from_active(mut active: impl Iterator<Item = (Metric, Level)>) -> Option<Self> {
+        let (metric, level) = active.next()?;
+        Some(Self { metric, level, others: active.count() })
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        Self::AREA.into_styled(PrimitiveStyle::with_fill(Rgb565::RED)).draw(target)?;
+
+        let centered = TextStyleBuilder::new().alignment(Alignment::Center).baseline(Baseline::Middle).build();
+        let mut text: String<40> = String::new();
+        write!(text, "ALARM {} {}", self.metric.label(), self.level.as_str()).ok();
+        if self.others > 0 {
+            write!(text, " (+{})", self.others).ok();
+        }
+        Text::with_text_style(&text, Self::AREA.center(), MonoTextStyle::new(&FONT_9X15_BOLD, Rgb565::WHITE), centered)
+            .draw(target)?;
+
+        Ok(())
+    }
+}
+
+/// Full screen list of the [`DerivedMetrics`] of the latest reading.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DerivedMetricsPage {
+    pub metrics: DerivedMetrics,
+}
+
+impl DerivedMetricsPage {
+    pub const AREA: Rectangle = Rectangle::new(Point::zero(), Size::new(320, 240));
+    const FIRST_ROW_Y: i32 = 70;
+    const ROW_SPACING: i32 = 45;
+
+    /// Draws the title and labels along with the values.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        Self::AREA.into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE)).draw(target)?;
+
+        let title = TextStyleBuilder::new().alignment(Alignment::Center).baseline(Baseline::Middle).build();
+        Text::with_text_style("Derived metrics", Point::new(160, 25), MonoTextStyle::new(&FONT_10X20, Rgb565::BLACK), title)
+            .draw(target)?;
+
+        self.draw_values(target)
+    }
+
+    /// Redraws just the values, for a page that's already shown.
+    pub fn draw_values<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let left = TextStyleBuilder::new().alignment(Alignment::Left).baseline(Baseline::Middle).build();
+        let right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Middle).build();
+        let label_style = MonoTextStyle::new(&FONT_10X20_LATIN1, Rgb565::CSS_DIM_GRAY);
+        let value_style = MonoTextStyle::new(&FONT_10X20_LATIN1, Rgb565::BLACK);
+
+        let rows = [
+            ("Dew point", self.metrics.dew_point, "°C"),
+            ("Heat index", self.metrics.heat_index, "°C"),
+            ("Abs. humidity", self.metrics.absolute_humidity, "g/m³"),
+            ("Altitude", self.metrics.altitude, "m"),
+        ];
+        for (row, (label, value, unit)) in rows.into_iter().enumerate() {
+            let y = Self::FIRST_ROW_Y + row as i32 * Self::ROW_SPACING;
+
+            // clear the old value, which may have been wider
+            Rectangle::new(Point::new(160, y - 12), Size::new(160, 24))
+                .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+                .draw(target)?;
+
+            let mut text: String<24> = String::new();
+            write!(text, "{:.1} {}", value, unit).ok();
+            Text::with_text_style(label, Point::new(10, y), label_style, left).draw(target)?;
+            Text::with_text_style(&text, Point::new(310, y), value_style, right).draw(target)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Full screen list of labelled values under a title, for pages that only
+/// show text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListPage<'a> {
+    pub title: &'a str,
+    pub rows: &'a [(&'a str, &'a str)],
+}
+
+impl ListPage<'_> {
+    pub const AREA: Rectangle = Rectangle::new(Point::zero(), Size::new(320, 240));
+    /// Rows that fit below the title.
+    pub const MAX_ROWS: usize = 6;
+    const FIRST_ROW_Y: i32 = 62;
+    const ROW_SPACING: i32 = 32;
+
+    /// Draws the title along with the rows.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        Self::AREA.into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE)).draw(target)?;
+
+        let title = TextStyleBuilder::new().alignment(Alignment::Center).baseline(Baseline::Middle).build();
+        Text::with_text_style(self.title, Point::new(160, 32), MonoTextStyle::new(&FONT_10X20, Rgb565::BLACK), title)
+            .draw(target)?;
+
+        self.draw_values(target)
+    }
+
+    /// Redraws just the rows, for a page that's already shown. Rows past
+    /// [`Self::MAX_ROWS`] are left out.
+    pub fn draw_values<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let left = TextStyleBuilder::new().alignment(Alignment::Left).baseline(Baseline::Middle).build();
+        let right = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Middle).build();
+        let label_style = MonoTextStyle::new(&FONT_10X20_LATIN1, Rgb565::CSS_DIM_GRAY);
+        let value_style = MonoTextStyle::new(&FONT_10X20_LATIN1, Rgb565::BLACK);
+
+        for (row, (label, value)) in self.rows.iter().take(Self::MAX_ROWS).enumerate() {
+            let y = Self::FIRST_ROW_Y + row as i32 * Self::ROW_SPACING;
+
+            Rectangle::new(Point::new(0, y - 12), Size::new(320, 24))
+                .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+                .draw(target)?;
+            Text::with_text_style(label, Point::new(10, y), label_style, left).draw(target)?;
+            Text::with_text_style(value, Point::new(310, y), value_style, right).draw(target)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Line graph of one [`Trend`] with its lowest and highest point marked,
+/// between buttons to switch the metric and the span.
+#[derive(Debug, Clone, Copy)]
+pub struct TrendChart<'a> {
+    pub metric: Metric,
+    pub span: Span,
+    pub trend: &'a Trend,
+}
+
+impl TrendChart<'_> {
+    pub const AREA: Rectangle = Rectangle::new(Point::zero(), Size::new(320, 240));
+    /// Touching it shows the previous metric.
+    pub const PREVIOUS: Rectangle = Rectangle::new(Point::new(0, 18), Size::new(60, 32));
+    /// Touching it shows the next metric.
+    pub const NEXT: Rectangle = Rectangle::new(Point::new(260, 18), Size::new(60, 32));
+    /// Where the line is drawn, right of the min/max labels.
+    const PLOT: Rectangle = Rectangle::new(Point::new(56, 60), Size::new(254, 126));
+    /// The plot with room for the labels and markers, cleared on every redraw.
+    const PLOT_AREA: Rectangle = Rectangle::new(Point::new(0, 56), Size::new(320, 134));
+
+    /// Touching it shows the chart over `span`.
+    pub fn span_button(span: Span) -> Rectangle {
+        match span {
+            Span::Hour => Rectangle::new(Point::new(10, 196), Size::new(145, 38)),
+            Span::Day => Rectangle::new(Point::new(165, 196), Size::new(145, 38)),
+        }
+    }
+
+    /// Draws the title and buttons along with the plot.
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        Self::AREA.into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE)).draw(target)?;
+
+        let centered = TextStyleBuilder::new().alignment(Alignment::Center).baseline(Baseline::Middle).build();
+        let title_style = MonoTextStyle::new(&FONT_10X20, Rgb565::BLACK);
+        Text::with_text_style("<", Self::PREVIOUS.center(), title_style, centered).draw(target)?;
+        Text::with_text_style(">", Self::NEXT.center(), title_style, centered).draw(target)?;
+        Text::with_text_style(self.metric.label(), Point::new(160, Self::PREVIOUS.center().y), title_style, centered)
+            .draw(target)?;
+
+        for span in Span::ALL {
+            let button = Self::span_button(span);
+            let (fill, text) = if span == self.span {
+                (Rgb565::CSS_DIM_GRAY, Rgb565::WHITE)
+            } else {
+                (Rgb565::CSS_LIGHT_GRAY, Rgb565::BLACK)
+            };
+            button.into_styled(PrimitiveStyle::with_fill(fill)).draw(target)?;
+            Text::with_text_style(span.label(), button.center(), MonoTextStyle::new(&FONT_10X20, text), centered)
+                .draw(target)?;
+        }
+
+        self.draw_plot(target)
+    }
+
+    /// Redraws just the plot, for a chart that's already shown.
+    pub fn draw_plot<D: DrawTarget<Color = Rgb565>>(&self, target: &mut D) -> Result<(), D::Error> {
+        Self::PLOT_AREA.into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE)).draw(target)?;
+
+        let centered = TextStyleBuilder::new().alignment(Alignment::Center).baseline(Baseline::Middle).build();
+        let Some((min, max)) = self.trend.range() else {
+            let style = MonoTextStyle::new(&FONT_10X20, Rgb565::CSS_GRAY);
+            return Text::with_text_style("No data yet", Self::PLOT.center(), style, centered).draw(target).map(|_| ());
+        };
+
+        let top = Self::PLOT.top_left.y;
+        let bottom = top + Self::PLOT.size.height as i32 - 1;
+        let left = Self::PLOT.top_left.x;
+        let right = left + Self::PLOT.size.width as i32 - 1;
+
+        // min and max on the axis, at the height of the gridlines they label
+        let axis = PrimitiveStyle::with_stroke(Rgb565::CSS_LIGHT_GRAY, 1);
+        let label_style = MonoTextStyle::new(&FONT_6X10, Rgb565::CSS_DIM_GRAY);
+        let right_aligned = TextStyleBuilder::new().alignment(Alignment::Right).baseline(Baseline::Middle).build();
+        for (value, y) in [(max, top), (min, bottom)] {
+            Line::new(Point::new(left, y), Point::new(right, y)).into_styled(axis).draw(target)?;
+            let mut label: String<16> = String::new();
+            write!(label, "{:.1}", value).ok();
+            Text::with_text_style(&label, Point::new(left - 4, y), label_style, right_aligned).draw(target)?;
+        }
+
+        // the newest point is always at the right edge
+        let offset = POINTS - self.trend.points().count();
+        let to_point = |index: usize, value: f32| {
+            let x = left + ((offset + index) * (right - left) as usize / (POINTS - 1)) as i32;
+            let y = if max > min {
+                bottom - libm::roundf((value - min) / (max - min) * (bottom - top) as f32) as i32
+            } else {
+                (top + bottom) / 2
+            };
+            Point::new(x, y)
+        };
+
+        let line = PrimitiveStyle::with_stroke(Rgb565::BLUE, 2);
+        let mut previous = None;
+        let mut lowest = None;
+        let mut highest = None;
+        for (index, value) in self.trend.points().enumerate() {
+            if value.is_nan() {
+                // a gap, don't join across it
+                previous = None;
+                continue;
+            }
+            let point = to_point(index, value);
+            Line::new(previous.unwrap_or(point), point).into_styled(line).draw(target)?;
+            previous = Some(point);
+
+            if value == min && lowest.is_none() {
+                lowest = Some(point);
+            }
+            if value == max && highest.is_none() {
+                highest = Some(point);
+            }
+        }
+
+        for (point, color) in [(lowest, Rgb565::CSS_DARK_CYAN), (highest, Rgb565::RED)] {
+            if let Some(point) = point {
+                Circle::with_center(point, 7).into_styled(PrimitiveStyle::with_fill(color)).draw(target)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn category_color(category: IaqCategory) -> Rgb565 {
+    match category {
+        IaqCategory::Excellent | IaqCategory::Good => Rgb565::CSS_GREEN,
+        IaqCategory::LightlyPolluted => Rgb565::CSS_GOLDENROD,
+        IaqCategory::ModeratelyPolluted => Rgb565::CSS_DARK_ORANGE,
+        IaqCategory::HeavilyPolluted | IaqCategory::SeverelyPolluted => Rgb565::RED,
+        IaqCategory::ExtremelyPolluted => Rgb565::CSS_PURPLE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remembers the pixels drawn on a 320x240 screen.
+    struct Recorder {
+        pixels: std::vec::Vec<(Point, Rgb565)>,
+    }
+
+    impl DrawTarget for Recorder {
+        type Color = Rgb565;
+        type Error = core::convert::Infallible;
+
+        fn draw_iter<I: IntoIterator<Item = Pixel<Rgb565>>>(&mut self, pixels: I) -> Result<(), Self::Error> {
+            self.pixels.extend(pixels.into_iter().map(|Pixel(point, color)| (point, color)));
+            Ok(())
+        }
+    }
+
+    impl OriginDimensions for Recorder {
+        fn size(&self) -> Size {
+            Size::new(320, 240)
+        }
+    }
+
+    fn draw(panel: AirQualityPanel) -> std::vec::Vec<(Point, Rgb565)> {
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+        panel.draw(&mut recorder).unwrap();
+        recorder.pixels
+    }
+
+    #[test]
+    fn inventory_row_stays_inside_its_area() {
+        let item = Item::new("A rather long product name", 999.99, 999);
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+
+        InventoryRow { id: 4, item: &item }.draw(&mut recorder).unwrap();
+
+        assert!(recorder.pixels.iter().all(|(point, _)| InventoryRow::area(4).contains(*point)));
+        assert!(recorder.pixels.iter().any(|(point, color)| {
+            layout::buy_button(4).contains(*point) && *color == Rgb565::CSS_FOREST_GREEN
+        }));
+    }
+
+    #[test]
+    fn inventory_row_greys_out_sold_out_items() {
+        let item = Item::new("Hotdog", 2.50, 0);
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+
+        InventoryRow { id: 0, item: &item }.draw(&mut recorder).unwrap();
+
+        assert!(!recorder.pixels.iter().any(|(_, color)| *color == Rgb565::CSS_FOREST_GREEN));
+        assert!(recorder.pixels.iter().any(|(point, color)| layout::buy_button(0).contains(*point) && *color == Rgb565::CSS_GRAY));
+        assert!(recorder.pixels.iter().any(|(_, color)| *color == Rgb565::RED));
+    }
+
+    #[test]
+    fn inventory_header_stays_under_the_banner() {
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+
+        InventoryHeader { page: 1, pages: 3 }.draw(&mut recorder).unwrap();
+
+        assert!(recorder.pixels.iter().all(|(point, _)| AlarmBanner::AREA.contains(*point)));
+    }
+
+    #[test]
+    fn derived_metrics_values_stay_right_of_labels() {
+        let page = DerivedMetricsPage {
+            metrics: DerivedMetrics { dew_point: -12.3, heat_index: 41.1, absolute_humidity: 13.8, altitude: -1234.5 },
+        };
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+
+        page.draw_values(&mut recorder).unwrap();
+
+        let drawn = |x_range: core::ops::Range<i32>| {
+            recorder.pixels.iter().any(|(point, color)| x_range.contains(&point.x) && *color == Rgb565::BLACK)
+        };
+        assert!(drawn(160..320));
+        assert!(!drawn(0..160));
+    }
+
+    #[test]
+    fn sensor_gauges_stay_inside_their_areas() {
+        for (metric, value) in [(Metric::Temperature, -40.0), (Metric::Humidity, 100.0), (Metric::Pressure, 1234.5)] {
+            let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+
+            SensorGauge { metric, value }.draw(&mut recorder).unwrap();
+
+            let area = SensorGauge::area(metric);
+            assert!(recorder.pixels.iter().all(|(point, _)| area.contains(*point)));
+            assert!(area.bottom_right().unwrap().y < AirQualityPanel::AREA.top_left.y);
+            assert!(area.top_left.y >= AlarmBanner::AREA.size.height as i32);
+        }
+    }
+
+    #[test]
+    fn sensor_gauge_fills_the_bar_with_the_value() {
+        let count = |value: f32| {
+            let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+            SensorGauge { metric: Metric::Humidity, value }.draw(&mut recorder).unwrap();
+            recorder.pixels.iter().filter(|(_, color)| *color == Rgb565::CSS_DODGER_BLUE).count()
+        };
+
+        assert_eq!(count(0.0), 0);
+        assert_eq!(count(f32::NAN), 0);
+        assert_eq!(count(50.0) * 2, count(100.0));
+        assert_eq!(count(100.0), count(150.0));
+    }
+
+    #[test]
+    fn stays_inside_its_area() {
+        let pixels = draw(AirQualityPanel { gas_resistance: 1_234_567.0, iaq: Some(Iaq(500)) });
+
+        assert!(pixels.iter().all(|(point, _)| AirQualityPanel::AREA.contains(*point)));
+    }
+
+    #[test]
+    fn sensor_offline_stays_inside_its_area() {
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+
+        SensorOfflinePanel { fault: SensorFault::Implausible }.draw(&mut recorder).unwrap();
+
+        assert!(recorder.pixels.iter().all(|(point, _)| SensorOfflinePanel::AREA.contains(*point)));
+        assert!(recorder.pixels.iter().any(|(_, color)| *color == Rgb565::RED));
+    }
+
+    #[test]
+    fn alarm_banner_stays_inside_its_area() {
+        let banner = AlarmBanner { metric: Metric::Temperature, level: Level::High, others: 3 };
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+
+        banner.draw(&mut recorder).unwrap();
+
+        assert!(recorder.pixels.iter().all(|(point, _)| AlarmBanner::AREA.contains(*point)));
+        assert!(recorder.pixels.iter().any(|(_, color)| *color == Rgb565::WHITE));
+    }
+
+    #[test]
+    fn alarm_banner_counts_others() {
+        let active = [(Metric::Humidity, Level::Low), (Metric::Iaq, Level::High)];
+
+        assert_eq!(AlarmBanner::from_active(core::iter::empty()), None);
+        assert_eq!(
+            AlarmBanner::from_active(active.into_iter()),
+            Some(AlarmBanner { metric: Metric::Humidity, level: Level::Low, others: 1 })
+        );
+    }
+
+    #[test]
+    fn list_page_stays_on_screen() {
+        let rows = [("Uptime", "12 d 3 h"); 8];
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+
+        ListPage { title: "Status", rows: &rows }.draw(&mut recorder).unwrap();
+
+        assert!(recorder.pixels.iter().all(|(point, _)| ListPage::AREA.contains(*point)));
+        // the banner strip is only cleared, not drawn on
+        assert!(recorder.pixels.iter().all(|(point, color)| point.y >= 17 || *color == Rgb565::WHITE));
+    }
+
+    fn trend(values: &[f32]) -> Trend {
+        let mut trend = Trend::new(Span::Hour);
+        for (minute, value) in values.iter().enumerate() {
+            trend.record(*value, minute as u64 * 60_000);
+        }
+        trend
+    }
+
+    fn draw_chart(trend: &Trend) -> std::vec::Vec<(Point, Rgb565)> {
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+        TrendChart { metric: Metric::Temperature, span: Span::Hour, trend }.draw(&mut recorder).unwrap();
+        recorder.pixels
+    }
+
+    #[test]
+    fn trend_chart_stays_on_screen() {
+        let pixels = draw_chart(&trend(&[-12.3, 1013.2, 0.0]));
+
+        assert!(pixels.iter().all(|(point, _)| TrendChart::AREA.contains(*point)));
+    }
+
+    #[test]
+    fn trend_chart_plot_stays_inside_its_area() {
+        let trend = trend(&[20.0, 25.0, 21.0]);
+        let mut recorder = Recorder { pixels: std::vec::Vec::new() };
+
+        TrendChart { metric: Metric::Humidity, span: Span::Day, trend: &trend }.draw_plot(&mut recorder).unwrap();
+
+        assert!(recorder.pixels.iter().all(|(point, _)| TrendChart::PLOT_AREA.contains(*point)));
+    }
+
+    #[test]
+    fn trend_chart_without_points_draws_no_line() {
+        let pixels = draw_chart(&Trend::new(Span::Hour));
+
+        assert!(!pixels.iter().any(|(_, color)| *color == Rgb565::BLUE));
+        assert!(pixels.iter().any(|(_, color)| *color == Rgb565::CSS_GRAY));
+    }
+
+    #[test]
+    fn trend_chart_marks_min_and_max() {
+        let pixels = draw_chart(&trend(&[22.0, 30.0, 25.0, 18.0, 24.0]));
+        let marker = |color| pixels.iter().rev().find(|(_, drawn)| *drawn == color).map(|(point, _)| *point).unwrap();
+
+        let highest = marker(Rgb565::RED);
+        let lowest = marker(Rgb565::CSS_DARK_CYAN);
+        assert!(highest.x < lowest.x);
+        assert!(highest.y < lowest.y);
+    }
+
+    #[test]
+    fn trend_chart_leaves_gaps() {
+        let mut trend = Trend::new(Span::Hour);
+        trend.record(20.0, 0);
+        trend.record(21.0, 60_000);
+        // ten minutes without readings
+        trend.record(22.0, 12 * 60_000);
+        trend.record(23.0, 13 * 60_000);
+        let pixels = draw_chart(&trend);
+
+        let line_at = |x: i32| pixels.iter().any(|(point, color)| point.x == x && *color == Rgb565::BLUE);
+        // 14 points, the last one at the right edge of the plot
+        let x = |index: usize| 56 + ((POINTS - 14 + index) * 253 / (POINTS - 1)) as i32;
+        assert!(line_at(x(0) + 2));
+        assert!(!line_at(x(6)));
+    }
+
+    #[test]
+    fn iaq_is_colored_by_category() {
+        let colors = |iaq| {
+            draw(AirQualityPanel { gas_resistance: 50_000.0, iaq })
+                .into_iter()
+                .map(|(_, color)| color)
+                .collect::<std::vec::Vec<_>>()
+        };
+
+        assert!(colors(Some(Iaq(20))).contains(&Rgb565::CSS_GREEN));
+        assert!(colors(Some(Iaq(300))).contains(&Rgb565::RED));
+        assert!(!colors(None).contains(&Rgb565::CSS_GREEN));
+        assert!(colors(None).contains(&Rgb565::CSS_GRAY));
+    }
+}
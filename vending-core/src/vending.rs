@@ -0,0 +1,128 @@
+//! Stock keeping and purchasing.
+
+use crate::catalog::{Catalog, ItemId};
+
+/// Why a purchase didn't go through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PurchaseError {
+    /// There is no item with the requested id.
+    UnknownItem,
+    /// The item exists but its stock is used up.
+    OutOfStock,
+}
+
+/// Outcome of a successful purchase.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Purchase {
+    pub item: ItemId,
+    pub price: f32,
+    pub remaining: u32,
+}
+
+/// Sells the items of a [`Catalog`] with room for `N` of them.
+#[derive(Debug, Clone)]
+pub struct VendingMachine<const N: usize> {
+    catalog: Catalog<N>,
+}
+
+impl<const N: usize> VendingMachine<N> {
+    pub const fn new() -> Self {
+        Self { catalog: Catalog::new() }
+    }
+
+    pub fn with_catalog(catalog: Catalog<N>) -> Self {
+        Self { catalog }
+    }
+
+    pub fn catalog(&self) -> &Catalog<N> {
+        &self.catalog
+    }
+
+    pub fn catalog_mut(&mut self) -> &mut Catalog<N> {
+        &mut self.catalog
+    }
+
+    /// Sells one unit of `id`, decrementing its stock.
+    pub fn purchase(&mut self, id: ItemId) -> Result<Purchase, PurchaseError> {
+        let item = self.catalog.get_mut(id).ok_or(PurchaseError::UnknownItem)?;
+        if !item.in_stock() {
+            return Err(PurchaseError::OutOfStock);
+        }
+        item.amount -= 1;
+
+        Ok(Purchase {
+            item: id,
+            price: item.price,
+            remaining: item.amount,
+        })
+    }
+}
+
+impl<const N: usize> Default for VendingMachine<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::Item;
+
+    fn machine() -> VendingMachine<2> {
+        let catalog = Catalog::from_items(&[Item::new("Hotdog", 2.50, 2), Item::new("Sandwich", 3.50, 0)]).unwrap();
+        VendingMachine::with_catalog(catalog)
+    }
+
+    #[test]
+    fn purchase_decrements_stock() {
+        let mut machine = machine();
+
+        let purchase = machine.purchase(0).unwrap();
+
+        assert_eq!(purchase, Purchase { item: 0, price: 2.50, remaining: 1 });
+        assert_eq!(machine.catalog().get(0).unwrap().amount, 1);
+    }
+
+    #[test]
+    fn purchase_until_sold_out() {
+        let mut machine = machine();
+
+        assert!(machine.purchase(0).is_ok());
+        assert!(machine.purchase(0).is_ok());
+        assert_eq!(machine.purchase(0), Err(PurchaseError::OutOfStock));
+        assert_eq!(machine.catalog().get(0).unwrap().amount, 0);
+    }
+
+    #[test]
+    fn out_of_stock_item_is_rejected() {
+        let mut machine = machine();
+
+        assert!(!machine.catalog().get(1).unwrap().in_stock());
+        assert_eq!(machine.purchase(1), Err(PurchaseError::OutOfStock));
+    }
+
+    #[test]
+    fn unknown_item_is_rejected() {
+        let mut machine = machine();
+
+        assert_eq!(machine.purchase(2), Err(PurchaseError::UnknownItem));
+        assert!(machine.catalog().get(2).is_none());
+    }
+
+    #[test]
+    fn purchase_leaves_other_items_alone() {
+        let mut machine = machine();
+
+        machine.purchase(0).unwrap();
+
+        assert_eq!(machine.catalog().get(1), Some(&Item::new("Sandwich", 3.50, 0)));
+    }
+
+    #[test]
+    fn empty_machine_sells_nothing() {
+        let mut machine = VendingMachine::<4>::new();
+
+        assert_eq!(machine.purchase(0), Err(PurchaseError::UnknownItem));
+    }
+}